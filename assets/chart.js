@@ -0,0 +1,79 @@
+// Plot the power history from /api/history on the /chart page, polling it
+// as often as the device adds a point.
+const canvas = document.getElementById('chart');
+const summary = document.getElementById('summary');
+const context = canvas.getContext('2d');
+const MARGIN = 40;
+
+// 1, 2 or 5 times a power of ten, at least `value`
+function niceCeiling(value) {
+  const magnitude = Math.pow(10, Math.floor(Math.log10(value)));
+  for (const step of [1, 2, 5, 10]) {
+    if (step * magnitude >= value) return step * magnitude;
+  }
+}
+
+function draw(points) {
+  const width = canvas.width - MARGIN;
+  const height = canvas.height - MARGIN;
+  context.clearRect(0, 0, canvas.width, canvas.height);
+  context.font = '12px sans-serif';
+  context.strokeStyle = '#888';
+  context.fillStyle = '#444';
+  context.strokeRect(MARGIN, 0, width, height);
+  if (points.length < 2) {
+    context.fillText('Collecting readings...', MARGIN + 10, 20);
+    return;
+  }
+
+  const maxWatts = niceCeiling(Math.max(10, ...points.map(p => p.watts)));
+  const first = points[0].uptime_s;
+  const span = points[points.length - 1].uptime_s - first;
+  const x = p => MARGIN + (p.uptime_s - first) / span * width;
+  const y = watts => height - watts / maxWatts * height;
+
+  context.textAlign = 'right';
+  for (let i = 0; i <= 4; i++) {
+    const watts = maxWatts * i / 4;
+    context.fillText(watts + ' W', MARGIN - 4, y(watts) + 4);
+  }
+  context.textAlign = 'center';
+  for (let i = 0; i <= 4; i++) {
+    const minutesAgo = Math.round(span * (4 - i) / 4 / 60);
+    context.fillText(minutesAgo ? '-' + minutesAgo + ' min' : 'now', MARGIN + width * i / 4, height + 16);
+  }
+
+  context.strokeStyle = '#c60';
+  context.lineWidth = 2;
+  context.beginPath();
+  points.forEach((p, i) => i ? context.lineTo(x(p), y(p.watts)) : context.moveTo(x(p), y(p.watts)));
+  context.stroke();
+  context.lineWidth = 1;
+
+  const last = points[points.length - 1];
+  const average = points.reduce((sum, p) => sum + p.watts, 0) / points.length;
+  summary.textContent = 'Now ' + last.watts.toFixed(0) + ' W (' + last.amps.toFixed(2) +
+    ' A), average ' + average.toFixed(0) + ' W';
+}
+
+// Seconds between points, learned from the history itself
+let intervalS = 5;
+
+function poll() {
+  fetch('/api/history').then(r => r.json()).then(points => {
+    if (points.length > 1) intervalS = Math.max(1, points[1].uptime_s - points[0].uptime_s);
+    draw(points);
+  }).catch(() => {})
+    .finally(() => setTimeout(poll, intervalS * 1000));
+}
+
+// Name the chart after the device and the clamp, when they have names
+fetch('/api/v1/status').then(r => r.json()).then(status => {
+  const names = [status.device_name, status.channel_label].filter(name => name);
+  if (names.length) {
+    document.querySelector('h1').textContent = names.join(': ');
+    document.title = names.join(': ');
+  }
+}).catch(() => {});
+
+poll();
@@ -0,0 +1,54 @@
+// Fill the SSID dropdown of the setup form with the networks in range.
+const select = document.getElementById('wifi_ssid');
+const other = document.getElementById('wifi_ssid_other');
+const button = document.getElementById('scan');
+const security = document.getElementById('wifi_auth');
+// From the last scan, to pick the security of the network chosen
+let found = [];
+
+// "Other..." is for hidden networks, typed in by hand
+function showOther() {
+  other.hidden = select.value != '';
+}
+
+function pickSecurity() {
+  const network = found.find(network => network.ssid == select.value);
+  if (!network) {
+    return;
+  }
+  const known = [...security.options].some(option => option.value == network.auth);
+  security.value = known ? network.auth : 'auto';
+}
+
+// Texts in the page's language, from the page itself
+const scanLabel = button.textContent;
+const otherLabel = select.options[select.options.length - 1].text;
+const labels = button.dataset;
+
+function scan() {
+  button.disabled = true;
+  button.textContent = labels.scanning;
+  fetch('/api/scan').then(r => r.json()).then(networks => {
+    found = networks;
+    const current = select.value;
+    select.innerHTML = '';
+    for (const network of networks) {
+      const open = network.auth == 'open' ? ', ' + labels.open : '';
+      select.add(new Option(network.ssid + ' (' + network.rssi + ' dBm' + open + ')', network.ssid));
+    }
+    if (current && !networks.some(network => network.ssid == current)) {
+      select.add(new Option(current + ' (' + labels.notFound + ')', current), 0);
+    }
+    select.add(new Option(otherLabel, ''));
+    select.value = current || (networks.length ? networks[0].ssid : '');
+  }).catch(() => {}).finally(() => {
+    button.disabled = false;
+    button.textContent = scanLabel;
+    showOther();
+  });
+}
+
+select.addEventListener('change', showOther);
+select.addEventListener('change', pickSecurity);
+button.addEventListener('click', scan);
+scan();
@@ -0,0 +1,30 @@
+// Post a sample reading to the webhook typed in, before saving it.
+const webhook = document.getElementById('webhook');
+const testButton = document.getElementById('test_webhook');
+const result = document.getElementById('webhook_result');
+
+// Texts in the page's language, from the page itself
+const testLabel = testButton.textContent;
+
+function testWebhook() {
+  testButton.disabled = true;
+  testButton.textContent = testButton.dataset.testing;
+  result.textContent = '';
+  const form = new URLSearchParams({webhook: webhook.value});
+  fetch('/api/webhook/test', {method: 'POST', body: form}).then(r => r.json()).then(answer => {
+    if (answer.code) {
+      result.textContent = answer.error;
+      return;
+    }
+    const status = answer.http_status ? 'HTTP ' + answer.http_status + ', ' : '';
+    const error = answer.ok ? '' : ' (' + answer.error + ')';
+    result.textContent = status + answer.latency_ms + ' ms' + error;
+  }).catch(err => {
+    result.textContent = err;
+  }).finally(() => {
+    testButton.disabled = false;
+    testButton.textContent = testLabel;
+  });
+}
+
+testButton.addEventListener('click', testWebhook);
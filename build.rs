@@ -1,25 +1,121 @@
-#[toml_cfg::toml_config]
-pub struct Config {
-    #[default("")]
-    wifi_ssid: &'static str,
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::Path;
+use std::process::Command;
+use std::{env, fs};
 
-    #[default("")]
-    wifi_psk: &'static str,
+use flate2::write::GzEncoder;
+use flate2::Compression;
 
-    #[default("")]
-    hostname: &'static str,
+fn main() {
+    // `cfg.toml` is optional: its values are only compile-time defaults and
+    // the device is meant to be provisioned at runtime through the setup AP.
+    println!("cargo:rerun-if-changed=cfg.toml");
+
+    bundle_assets();
+    git_hash();
+
+    embuild::espidf::sysenv::output();
 }
 
-fn main() {
-    // Check that cfg.toml exists
-    if !std::path::Path::new("cfg.toml").exists() {
-        panic!("You need to create a `cfg.toml` file with your Wi-Fi credentials");
-    }
+/// Expose the commit being built as `GIT_HASH`, or `unknown` outside a git
+/// checkout, for the version shown on the boot splash.
+fn git_hash() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", hash);
+}
 
-    let app_config = CONFIG;
-    if app_config.wifi_ssid == "" || app_config.wifi_psk == "" {
-        panic!("You need to set your Wi-Fi credentials in `cfg.toml`");
+/// FNV-1a, stable across toolchains unlike `DefaultHasher`.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Generate the table of files under `assets/` for `src/assets.rs`. Their
+/// URLs include a hash of the contents, so browsers can cache them forever,
+/// except for `.html` pages, which are served at `/<stem>` and revalidated.
+/// A page refers to the other files as `{{asset:style.css}}`, replaced here
+/// with their hashed URL. Everything is stored gzipped when that is smaller.
+fn bundle_assets() {
+    println!("cargo:rerun-if-changed=assets");
+
+    let mut paths: Vec<_> = fs::read_dir("assets")
+        .expect("Cannot read assets/")
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+    // Pages last, so the URLs they refer to are known
+    paths.sort_by_key(|path| {
+        path.extension()
+            .is_some_and(|extension| extension == "html")
+    });
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let bundle_dir = Path::new(&out_dir).join("assets");
+    fs::create_dir_all(&bundle_dir).unwrap();
+
+    let mut urls: Vec<(String, String)> = Vec::new();
+    let mut table = String::from("pub static ASSETS: &[Asset] = &[\n");
+    for path in paths {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let (stem, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+        let content_type = match extension {
+            "html" => "text/html",
+            "css" => "text/css",
+            "js" => "text/javascript",
+            "svg" => "image/svg+xml",
+            "png" => "image/png",
+            _ => "application/octet-stream",
+        };
+        let mut contents = fs::read(&path).unwrap();
+        let (url, cache_control) = if extension == "html" {
+            let mut page = String::from_utf8(contents).expect("Pages must be UTF-8");
+            for (asset, url) in &urls {
+                page = page.replace(&format!("{{{{asset:{}}}}}", asset), url);
+            }
+            assert!(
+                !page.contains("{{asset:"),
+                "{} refers to a missing asset",
+                name
+            );
+            contents = page.into_bytes();
+            (format!("/{}", stem), "PAGE_CACHE_CONTROL")
+        } else {
+            let hash = fnv1a(&contents);
+            let url = format!("/static/{}.{:08x}.{}", stem, hash, extension);
+            (url, "CACHE_CONTROL")
+        };
+        urls.push((name.to_string(), url.clone()));
+
+        let gzipped = gzip(&contents);
+        let gzip = gzipped.len() < contents.len();
+        let bundled = bundle_dir.join(name);
+        fs::write(&bundled, if gzip { &gzipped } else { &contents }).unwrap();
+        writeln!(
+            table,
+            "    Asset {{ name: {:?}, url: {:?}, content_type: {:?}, cache_control: {}, gzip: {}, body: include_bytes!({:?}) }},",
+            name, url, content_type, cache_control, gzip, bundled
+        )
+        .unwrap();
     }
+    table.push_str("];\n");
 
-    embuild::espidf::sysenv::output();
+    fs::write(Path::new(&out_dir).join("assets.rs"), table).unwrap();
+}
+
+fn gzip(contents: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(contents).unwrap();
+    encoder.finish().unwrap()
 }
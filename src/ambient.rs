@@ -0,0 +1,264 @@
+//! Display brightness selection, optionally following the ambient light
+//! measured with an LDR, and dimming or turning the display off at night.
+
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::display::Brightness;
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::uptime::unix_now;
+
+pub const NVS_BRIGHTNESS: &str = "brightness";
+pub const NVS_NIGHT: &str = "night";
+pub const NVS_NIGHT_START: &str = "night_start";
+pub const NVS_NIGHT_END: &str = "night_end";
+pub const NVS_NIGHT_DISPLAY: &str = "night_display";
+pub const NVS_UTC_OFFSET: &str = "utc_offset";
+
+/// Seconds between redraws with the inverted night display.
+pub const NIGHT_REFRESH_S: u64 = 10;
+
+/// Night forced on or off through the API, until the next reboot; `None`
+/// follows the trigger.
+pub(crate) static NIGHT_OVERRIDE: Lazy<Mutex<Option<bool>>> = Lazy::new(|| Mutex::new(None));
+
+/// Light level, as a fraction of the ADC full scale, above which each
+/// brightness step after the dimmest one is used.
+#[cfg(feature = "ambient-ldr")]
+const THRESHOLDS: [f32; 4] = [0.05, 0.2, 0.5, 0.8];
+/// How far past a threshold the light level has to go to change step, so a
+/// level sitting on a threshold doesn't make the display flicker.
+#[cfg(feature = "ambient-ldr")]
+const HYSTERESIS: f32 = 0.03;
+/// Weight of each new reading in the smoothed light level.
+#[cfg(feature = "ambient-ldr")]
+const SMOOTHING: f32 = 0.2;
+/// Full scale of the ADC with 11 dB attenuation.
+#[cfg(feature = "ambient-ldr")]
+const FULL_SCALE_MV: f32 = 3100.0;
+
+/// How the display brightness is chosen, as set on the setup page.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BrightnessSetting {
+    /// Follow the ambient light, or the default brightness without a sensor.
+    #[default]
+    Auto,
+    Fixed(Brightness),
+}
+
+impl BrightnessSetting {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BrightnessSetting::Auto => "auto",
+            BrightnessSetting::Fixed(brightness) => brightness.name(),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BrightnessSetting> {
+        match name {
+            "auto" => Some(BrightnessSetting::Auto),
+            name => Brightness::from_name(name).map(BrightnessSetting::Fixed),
+        }
+    }
+
+    pub fn from_nvs(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> Self {
+        Self::from_name(&read_str_from_nvs_or_default(nvs, NVS_BRIGHTNESS, "auto"))
+            .unwrap_or_default()
+    }
+}
+
+/// Parse a time of day such as `22:30` into minutes since midnight.
+pub fn parse_time_of_day(value: &str) -> Option<u32> {
+    let (hours, minutes) = value.split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+pub fn format_time_of_day(minutes: u32) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// When the display switches to its night setting.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NightTrigger {
+    #[default]
+    Never,
+    /// From `start` to `end`, in local minutes since midnight. Needs the
+    /// clock synced over SNTP.
+    Schedule { start: u32, end: u32 },
+    /// While the LDR reads dark.
+    Dark,
+}
+
+impl NightTrigger {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NightTrigger::Never => "never",
+            NightTrigger::Schedule { .. } => "schedule",
+            NightTrigger::Dark => "dark",
+        }
+    }
+}
+
+/// What the display does at night.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NightDisplay {
+    #[default]
+    Off,
+    Dimmed(Brightness),
+    /// Inverted at the dimmest brightness and only redrawn every
+    /// `NIGHT_REFRESH_S`, to cut both the light and the I2C traffic.
+    Inverted,
+}
+
+impl NightDisplay {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NightDisplay::Off => "off",
+            NightDisplay::Dimmed(brightness) => brightness.name(),
+            NightDisplay::Inverted => "inverted",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<NightDisplay> {
+        match name {
+            "off" => Some(NightDisplay::Off),
+            "inverted" => Some(NightDisplay::Inverted),
+            name => Brightness::from_name(name).map(NightDisplay::Dimmed),
+        }
+    }
+}
+
+/// Everything deciding how bright the display is, as set on the setup page.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DimmingPolicy {
+    pub brightness: BrightnessSetting,
+    pub night: NightTrigger,
+    pub night_display: NightDisplay,
+    /// Local time offset from UTC, for the schedule.
+    pub utc_offset_min: i32,
+}
+
+impl DimmingPolicy {
+    pub fn from_nvs(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> Self {
+        let night = match read_str_from_nvs_or_default(nvs, NVS_NIGHT, "never").as_str() {
+            "schedule" => match (
+                parse_time_of_day(&read_str_from_nvs_or_default(nvs, NVS_NIGHT_START, "")),
+                parse_time_of_day(&read_str_from_nvs_or_default(nvs, NVS_NIGHT_END, "")),
+            ) {
+                (Some(start), Some(end)) => NightTrigger::Schedule { start, end },
+                _ => NightTrigger::Never,
+            },
+            "dark" => NightTrigger::Dark,
+            _ => NightTrigger::Never,
+        };
+        DimmingPolicy {
+            brightness: BrightnessSetting::from_nvs(nvs),
+            night,
+            night_display: NightDisplay::from_name(&read_str_from_nvs_or_default(
+                nvs,
+                NVS_NIGHT_DISPLAY,
+                "off",
+            ))
+            .unwrap_or_default(),
+            utc_offset_min: read_str_from_nvs_or_default(nvs, NVS_UTC_OFFSET, "0")
+                .parse::<f32>()
+                .map_or(0, |hours| (hours * 60.0).round() as i32),
+        }
+    }
+
+    fn is_night(&self, dark: bool) -> bool {
+        if let Some(night) = *NIGHT_OVERRIDE.lock().unwrap() {
+            return night;
+        }
+        match self.night {
+            NightTrigger::Never => false,
+            NightTrigger::Dark => dark,
+            NightTrigger::Schedule { start, end } => {
+                let Some(now) = unix_now() else {
+                    return false;
+                };
+                let minute = (now as i64 / 60 + self.utc_offset_min as i64).rem_euclid(1440) as u32;
+                if start <= end {
+                    (start..end).contains(&minute)
+                } else {
+                    // Spans midnight
+                    minute >= start || minute < end
+                }
+            }
+        }
+    }
+
+    /// Brightness the display should be at, or `None` to turn it off.
+    /// `auto_brightness` is the one following the ambient light.
+    #[cfg(not(feature = "headless"))]
+    pub fn brightness(&self, auto_brightness: Brightness, dark: bool) -> Option<Brightness> {
+        if self.is_night(dark) {
+            return match self.night_display {
+                NightDisplay::Off => None,
+                NightDisplay::Dimmed(brightness) => Some(brightness),
+                NightDisplay::Inverted => Some(Brightness::Dimmest),
+            };
+        }
+        match self.brightness {
+            BrightnessSetting::Fixed(brightness) => Some(brightness),
+            BrightnessSetting::Auto => Some(auto_brightness),
+        }
+    }
+
+    /// Whether the display should be inverted and redrawn slowly.
+    pub fn is_inverted(&self, dark: bool) -> bool {
+        self.night_display == NightDisplay::Inverted && self.is_night(dark)
+    }
+}
+
+#[cfg(feature = "ambient-ldr")]
+fn brightness_for(level: f32) -> Brightness {
+    Brightness::ALL[THRESHOLDS.iter().filter(|t| level >= **t).count()]
+}
+
+/// Smoothed light level from an LDR wired between 3V3 and the ADC pin, with
+/// a pull-down resistor, so more light reads as a higher voltage.
+#[cfg(feature = "ambient-ldr")]
+#[derive(Debug)]
+pub struct AmbientLight {
+    level: Option<f32>,
+    brightness: Brightness,
+}
+
+#[cfg(feature = "ambient-ldr")]
+impl Default for AmbientLight {
+    fn default() -> Self {
+        AmbientLight {
+            level: None,
+            brightness: Brightness::Dim,
+        }
+    }
+}
+
+#[cfg(feature = "ambient-ldr")]
+impl AmbientLight {
+    /// Feed a new reading of the LDR and return the brightness to use.
+    pub fn update(&mut self, millivolts: u16) -> Brightness {
+        let reading = (millivolts as f32 / FULL_SCALE_MV).clamp(0.0, 1.0);
+        let level = match self.level {
+            Some(level) => level + (reading - level) * SMOOTHING,
+            None => reading,
+        };
+        self.level = Some(level);
+
+        let lowest = brightness_for(level - HYSTERESIS);
+        let highest = brightness_for(level + HYSTERESIS);
+        if self.brightness < lowest || self.brightness > highest {
+            self.brightness = brightness_for(level);
+        }
+        self.brightness
+    }
+
+    /// Whether the room is dark enough to also keep the LED off.
+    pub fn is_dark(&self) -> bool {
+        self.brightness == Brightness::Dimmest
+    }
+}
@@ -1,14 +1,57 @@
+//! Current from the clamp, as the RMS of its signal over a whole number of
+//! mains cycles. Only sampling needs the ADC, so the rest builds and is
+//! tested on the host with `rustc --edition 2021 --test src/amps.rs`.
+
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::hal::adc;
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::hal::adc::{AdcChannelDriver, AdcDriver};
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::hal::gpio::ADCPin;
-use esp_idf_svc::sys::{adc_atten_t, EspError};
-use std::time::SystemTime;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sys::{adc_atten_t, esp_timer_get_time, EspError};
+#[cfg(target_os = "espidf")]
+use std::sync::Mutex;
 
 // SCT-013-030 has a 1V output for 30A
 // 30A = 1V
 // 1A = 0.0333V
+#[cfg(target_os = "espidf")]
 const FACTOR: f32 = 1. / 0.0333;
 
+// How long we sample for. At 50Hz this covers 6 full cycles, so even after
+// trimming the partial cycles at both ends we keep at least 4 of them.
+#[cfg(target_os = "espidf")]
+const SAMPLING_WINDOW_US: u32 = 120_000;
+
+// Upper bound on the samples kept per window (8 bytes each, on the heap).
+#[cfg(target_os = "espidf")]
+const MAX_SAMPLES: usize = 4096;
+
+// Readings must drop this far below the DC level before a rising crossing is
+// accepted again, so ADC noise around the mean does not count as a cycle.
+const CROSSING_HYSTERESIS_MV: f32 = 8.0;
+
+/// A single ADC reading, timestamped relative to the start of the window.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub offset_us: u32,
+    pub millivolts: u16,
+}
+
+/// Kept between readings: taken every second, 32 KB allocated and freed
+/// each time would fragment the heap the TLS and HTTP stacks need large
+/// blocks from, while one buffer held for good leaves it as it is.
+#[cfg(target_os = "espidf")]
+static SAMPLES: Mutex<Vec<Sample>> = Mutex::new(Vec::new());
+
+#[cfg(target_os = "espidf")]
+fn now_us() -> i64 {
+    // Safe: esp_timer is started by the IDF before app_main runs
+    unsafe { esp_timer_get_time() }
+}
+
+#[cfg(target_os = "espidf")]
 pub fn read_amps<const A: adc_atten_t, T, ADC: adc::Adc>(
     driver: &mut AdcDriver<ADC>,
     chan_driver: &mut AdcChannelDriver<A, T>,
@@ -16,33 +59,261 @@ pub fn read_amps<const A: adc_atten_t, T, ADC: adc::Adc>(
 where
     T: ADCPin<Adc = ADC>,
 {
-    // Since we are working with 50Hz AC, we have a cycle every 20ms
-    // We will sample for 500ms to get 25 samples
-
-    let mut count: usize = 0;
-    let start = SystemTime::now();
-    let mut end = SystemTime::now();
-    let mut highest_peak = 0.0f32;
-
-    while end.duration_since(start).unwrap().as_millis() < 100 {
-        let val = driver.read(chan_driver)?;
-        highest_peak = highest_peak.max(val as f32).max(40f32);
-        count += 1;
-        // FreeRtos::delay_ms(1u32);
-        end = SystemTime::now();
-    }
-
-    log::info!("Read {} samples", count);
-    log::info!("Highest peak: {}", highest_peak);
-    let peak = float_remap(highest_peak, 40.0, 1250.0, 0.0, 1.250);
-    let effective_volts = peak * 0.70710678118f32;
-    log::info!("Peak: {}V", peak);
+    let mut samples = SAMPLES.lock().unwrap();
+    sample_window(driver, chan_driver, &mut samples)?;
+    log::info!("Read {} samples", samples.len());
+
+    let rms_mv = match exact_cycle_rms(&samples) {
+        Some(window) => {
+            log::info!(
+                "Trimmed to {} cycles over {}us ({:.2}Hz)",
+                window.cycles,
+                window.duration_us,
+                window.frequency_hz()
+            );
+            window.rms_mv
+        }
+        None => {
+            // No load (or no mains) gives a flat signal without crossings,
+            // so fall back to the RMS of the whole window.
+            log::info!("No full mains cycle detected, using the whole window");
+            rms_between(&samples).unwrap_or(0.0)
+        }
+    };
+
+    let effective_volts = rms_mv / 1000.0;
     log::info!("Effv: {}V", effective_volts);
     log::info!("Amps: {}A", effective_volts * FACTOR);
 
     Ok(effective_volts * FACTOR)
 }
 
-fn float_remap(value: f32, in_min: f32, in_max: f32, out_min: f32, out_max: f32) -> f32 {
-    return (value - in_min) * (out_max - out_min) / (in_max - in_min) + out_min;
+#[cfg(target_os = "espidf")]
+fn sample_window<const A: adc_atten_t, T, ADC: adc::Adc>(
+    driver: &mut AdcDriver<ADC>,
+    chan_driver: &mut AdcChannelDriver<A, T>,
+    samples: &mut Vec<Sample>,
+) -> Result<(), EspError>
+where
+    T: ADCPin<Adc = ADC>,
+{
+    samples.clear();
+    samples.reserve_exact(MAX_SAMPLES);
+    let start = now_us();
+
+    while samples.len() < MAX_SAMPLES {
+        let millivolts = driver.read(chan_driver)?;
+        let offset_us = (now_us() - start) as u32;
+        if offset_us >= SAMPLING_WINDOW_US {
+            break;
+        }
+        samples.push(Sample {
+            offset_us,
+            millivolts,
+        });
+    }
+
+    Ok(())
+}
+
+/// RMS of a window trimmed to a whole number of mains cycles.
+#[derive(Debug)]
+pub struct CycleWindow {
+    pub rms_mv: f32,
+    pub cycles: usize,
+    pub duration_us: u32,
+}
+
+impl CycleWindow {
+    pub fn frequency_hz(&self) -> f32 {
+        self.cycles as f32 * 1_000_000.0 / self.duration_us as f32
+    }
+}
+
+/// Find the rising crossings of the DC level and compute the RMS only between
+/// the first and the last one, so the window always spans complete cycles.
+/// Returns `None` when fewer than one full cycle was captured.
+pub fn exact_cycle_rms(samples: &[Sample]) -> Option<CycleWindow> {
+    let level = mean_mv(samples)?;
+
+    let mut first = None;
+    let mut last = None;
+    let mut cycles = 0;
+    let mut armed = false;
+    for (i, sample) in samples.iter().enumerate() {
+        let mv = sample.millivolts as f32;
+        if mv < level - CROSSING_HYSTERESIS_MV {
+            armed = true;
+        } else if armed && mv >= level {
+            armed = false;
+            if first.is_none() {
+                first = Some(i);
+            } else {
+                cycles += 1;
+            }
+            last = Some(i);
+        }
+    }
+
+    let (first, last) = (first?, last?);
+    if cycles == 0 {
+        return None;
+    }
+
+    Some(CycleWindow {
+        rms_mv: rms_between(&samples[first..=last])?,
+        cycles,
+        duration_us: samples[last].offset_us - samples[first].offset_us,
+    })
+}
+
+fn mean_mv(samples: &[Sample]) -> Option<f32> {
+    if samples.is_empty() {
+        return None;
+    }
+    let sum: f32 = samples.iter().map(|s| s.millivolts as f32).sum();
+    Some(sum / samples.len() as f32)
+}
+
+/// Time-weighted RMS (around the DC level) of the given samples. Each sample
+/// is weighted by the time until the next one, so uneven ADC timing caused by
+/// interrupts or Wi-Fi activity does not bias the result.
+fn rms_between(samples: &[Sample]) -> Option<f32> {
+    if samples.len() < 2 {
+        return None;
+    }
+
+    let mut weighted_sum = 0.0f32;
+    let mut total_us = 0.0f32;
+    for pair in samples.windows(2) {
+        let dt = (pair[1].offset_us - pair[0].offset_us) as f32;
+        weighted_sum += pair[0].millivolts as f32 * dt;
+        total_us += dt;
+    }
+    if total_us <= 0.0 {
+        return None;
+    }
+    let level = weighted_sum / total_us;
+
+    let mut square_sum = 0.0f32;
+    for pair in samples.windows(2) {
+        let dt = (pair[1].offset_us - pair[0].offset_us) as f32;
+        let ac = pair[0].millivolts as f32 - level;
+        square_sum += ac * ac * dt;
+    }
+
+    Some((square_sum / total_us).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD_US: f32 = 20_000.0;
+    const STEP_US: u32 = 50;
+    const BIAS_MV: f32 = 1650.0;
+    const AMPLITUDE_MV: f32 = 500.0;
+
+    /// Pseudo-random noise of up to `amplitude` either way, the same on
+    /// every run.
+    fn noise(seed: &mut u32, amplitude: f32) -> f32 {
+        *seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (*seed >> 8) as f32 / (1 << 24) as f32 * 2.0 * amplitude - amplitude
+    }
+
+    /// A 50 Hz sine around `BIAS_MV` over `duration_us`, starting at `phase`
+    /// radians.
+    fn sine(duration_us: u32, phase: f32, noise_mv: f32) -> Vec<Sample> {
+        let mut seed = 1;
+        (0..duration_us / STEP_US)
+            .map(|i| {
+                let offset_us = i * STEP_US;
+                let angle = phase + std::f32::consts::TAU * offset_us as f32 / PERIOD_US;
+                let mv = BIAS_MV + AMPLITUDE_MV * angle.sin() + noise(&mut seed, noise_mv);
+                Sample {
+                    offset_us,
+                    millivolts: mv.round() as u16,
+                }
+            })
+            .collect()
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn finds_whole_cycles_of_a_sine() {
+        // Starting on a crossing, the first one only counts once armed
+        let window = exact_cycle_rms(&sine(120_000, 0.0, 0.0)).unwrap();
+        assert_eq!(window.cycles, 4);
+        assert_close(window.duration_us as f32, 80_000.0, STEP_US as f32);
+        assert_close(window.frequency_hz(), 50.0, 0.1);
+        assert_close(window.rms_mv, AMPLITUDE_MV / 2f32.sqrt(), 2.0);
+    }
+
+    #[test]
+    fn trims_partial_cycles_at_both_ends() {
+        // Neither end falls on a crossing, yet the RMS is the sine's
+        let samples = sine(113_000, 1.0, 0.0);
+        let window = exact_cycle_rms(&samples).unwrap();
+        assert_eq!(window.cycles, 4);
+        assert_close(window.rms_mv, AMPLITUDE_MV / 2f32.sqrt(), 2.0);
+        // Without trimming the partial cycles bias it
+        let whole = rms_between(&samples).unwrap();
+        assert!((whole - AMPLITUDE_MV / 2f32.sqrt()).abs() > 2.0);
+    }
+
+    #[test]
+    fn ignores_noise_around_the_crossings() {
+        let window = exact_cycle_rms(&sine(120_000, 0.0, 6.0)).unwrap();
+        assert_eq!(window.cycles, 4);
+        assert_close(window.frequency_hz(), 50.0, 0.5);
+        assert_close(window.rms_mv, AMPLITUDE_MV / 2f32.sqrt(), 4.0);
+    }
+
+    #[test]
+    fn needs_a_full_cycle() {
+        assert!(exact_cycle_rms(&sine(15_000, 0.0, 0.0)).is_none());
+        assert!(exact_cycle_rms(&[]).is_none());
+    }
+
+    #[test]
+    fn finds_no_cycle_in_a_flat_signal() {
+        let mut seed = 1;
+        let samples: Vec<Sample> = (0..2400)
+            .map(|i| Sample {
+                offset_us: i * STEP_US,
+                millivolts: (BIAS_MV + noise(&mut seed, 3.0)).round() as u16,
+            })
+            .collect();
+        assert!(exact_cycle_rms(&samples).is_none());
+        assert_close(rms_between(&samples).unwrap(), 0.0, 3.0);
+    }
+
+    #[test]
+    fn weighs_samples_by_time() {
+        // A burst of samples at the same level doesn't outweigh the rest
+        let mut samples: Vec<Sample> = (0..10)
+            .map(|i| Sample {
+                offset_us: i,
+                millivolts: 100,
+            })
+            .collect();
+        samples.push(Sample {
+            offset_us: 10,
+            millivolts: 200,
+        });
+        samples.push(Sample {
+            offset_us: 20,
+            millivolts: 200,
+        });
+        assert_close(rms_between(&samples).unwrap(), 50.0, 0.1);
+    }
 }
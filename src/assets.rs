@@ -0,0 +1,31 @@
+//! Static files for the web pages, embedded in the firmware by `build.rs`.
+//!
+//! The contents stay in the memory-mapped flash and are sent from there, so
+//! serving them costs no heap. Their URLs carry a hash of the contents, which
+//! lets browsers keep them cached until a new firmware changes them. Pages
+//! (`.html`) keep a plain URL instead and are checked on every load. Most
+//! files are stored gzipped, which every browser accepts.
+
+pub const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+pub const PAGE_CACHE_CONTROL: &str = "no-cache";
+
+pub struct Asset {
+    /// File name under `assets/`.
+    pub name: &'static str,
+    pub url: &'static str,
+    pub content_type: &'static str,
+    pub cache_control: &'static str,
+    /// `body` is gzipped and sent with `Content-Encoding: gzip`.
+    pub gzip: bool,
+    pub body: &'static [u8],
+}
+
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+/// URL of the asset from `assets/<name>`, hashed unless it is a page.
+pub fn url(name: &str) -> &'static str {
+    ASSETS
+        .iter()
+        .find(|asset| asset.name == name)
+        .map_or("", |asset| asset.url)
+}
@@ -0,0 +1,140 @@
+//! HTTP Basic authentication for the endpoints that change settings or act
+//! on the device, so not everyone on the LAN can rewrite the Wi-Fi
+//! credentials or restart it. The user is always `admin`; the password is
+//! kept in NVS and, unless `cfg.toml` sets one, generated on first boot and
+//! shown on the display in setup mode until it is changed.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use esp_idf_svc::http::server::{EspHttpConnection, Request};
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::EspError;
+use once_cell::sync::Lazy;
+
+use crate::error;
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+
+pub const NVS_ADMIN_PASSWORD: &str = "admin_pass";
+/// Set while the password is still the generated one.
+const NVS_ADMIN_GENERATED: &str = "admin_gen";
+pub const USER: &str = "admin";
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+const GENERATED_LEN: usize = 10;
+/// Letters and digits that can't be mistaken for one another on the display.
+const GENERATED_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+pub(crate) static ADMIN_PASSWORD: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new(String::new()));
+static GENERATED: AtomicBool = AtomicBool::new(false);
+
+/// Only call before Wi-Fi and the ADC are started: without the RF subsystem
+/// running, the hardware RNG needs the bootloader's entropy source, which
+/// takes over the SAR ADC.
+pub(crate) fn generate_password() -> String {
+    // Safe: nothing else uses the ADC or the radio this early in boot
+    unsafe { esp_idf_svc::sys::bootloader_random_enable() };
+    let password = (0..GENERATED_LEN)
+        .map(|_| {
+            // Safe: reads a hardware register
+            let random = unsafe { esp_idf_svc::sys::esp_random() } as usize;
+            GENERATED_CHARS[random % GENERATED_CHARS.len()] as char
+        })
+        .collect();
+    // Safe: as above, and hands the ADC back for the readings
+    unsafe { esp_idf_svc::sys::bootloader_random_disable() };
+    password
+}
+
+/// Read the password from NVS, storing the configured or a generated one
+/// the first time.
+pub fn load(
+    app_config: &crate::Config,
+    nvs: &mut nvs::EspNvs<nvs::NvsDefault>,
+) -> Result<(), EspError> {
+    let mut password = read_str_from_nvs_or_default(nvs, NVS_ADMIN_PASSWORD, "");
+    if password.is_empty() {
+        let generated = app_config.admin_password.is_empty();
+        password = if generated {
+            generate_password()
+        } else {
+            app_config.admin_password.to_string()
+        };
+        nvs.set_str(NVS_ADMIN_PASSWORD, &password)?;
+        nvs.set_u8(NVS_ADMIN_GENERATED, generated as u8)?;
+    }
+    let generated = nvs.get_u8(NVS_ADMIN_GENERATED).ok().flatten() == Some(1);
+    GENERATED.store(generated, Ordering::Relaxed);
+    *ADMIN_PASSWORD.lock().unwrap() = password;
+    Ok(())
+}
+
+/// Store a password someone chose.
+pub fn set_password(
+    nvs: &mut nvs::EspNvs<nvs::NvsDefault>,
+    password: String,
+) -> Result<(), EspError> {
+    nvs.set_str(NVS_ADMIN_PASSWORD, &password)?;
+    *ADMIN_PASSWORD.lock().unwrap() = password;
+    forget_generated(nvs)
+}
+
+/// Stop showing the password, after it was changed in NVS.
+pub fn forget_generated(nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> Result<(), EspError> {
+    GENERATED.store(false, Ordering::Relaxed);
+    nvs.remove(NVS_ADMIN_GENERATED).map(|_| ())
+}
+
+/// The password for the display: only the generated one, since anyone
+/// standing at the device can read it, while a chosen one is for whoever
+/// chose it to remember.
+pub fn generated_password() -> Option<String> {
+    GENERATED
+        .load(Ordering::Relaxed)
+        .then(|| ADMIN_PASSWORD.lock().unwrap().clone())
+}
+
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut output = String::new();
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+/// Whether an `Authorization` header value has the admin credentials.
+fn is_valid(authorization: &str) -> bool {
+    let password = ADMIN_PASSWORD.lock().unwrap();
+    let credentials = base64(format!("{}:{}", USER, password).as_bytes());
+    authorization.strip_prefix("Basic ") == Some(credentials.as_str())
+}
+
+pub fn is_authorized(req: &Request<&mut EspHttpConnection<'_>>) -> bool {
+    req.header("Authorization").is_some_and(is_valid)
+}
+
+/// Answer a request without valid credentials, making browsers prompt
+/// for them.
+pub fn reject(req: Request<&mut EspHttpConnection<'_>>) -> Result<(), EspIOError> {
+    log::warn!("Unauthorized request to {}", req.uri());
+    error::respond_with(
+        req,
+        401,
+        &format!("Log in as {}", USER),
+        &[("WWW-Authenticate", "Basic realm=\"amp-sensor\"")],
+    )
+}
@@ -0,0 +1,60 @@
+//! Readings posted to the webhook several at a time, as a JSON array, rather
+//! than one per request. Fewer requests mean fewer TLS handshakes and less
+//! time with the radio busy when reporting often; the collector gets each
+//! reading up to a batch late.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::template::Context;
+
+pub const NVS_BATCH_SIZE: &str = "hook_batch";
+/// One reading per request, as a plain object as before batches.
+pub const DEFAULT_SIZE: usize = 1;
+/// Keeps a request's body within a few kilobytes.
+pub const MAX_SIZE: usize = 20;
+
+static SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_SIZE);
+
+/// Whether `size` can be saved: empty for the default, or 1 to `MAX_SIZE`.
+pub fn is_valid_size(size: &str) -> bool {
+    size.is_empty()
+        || size
+            .parse::<usize>()
+            .map_or(false, |size| (1..=MAX_SIZE).contains(&size))
+}
+
+/// Read the batch size from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let size = read_str_from_nvs_or_default(nvs, NVS_BATCH_SIZE, "")
+        .parse()
+        .ok()
+        .filter(|size| (1..=MAX_SIZE).contains(size))
+        .unwrap_or(DEFAULT_SIZE);
+    SIZE.store(size, Ordering::Relaxed);
+}
+
+/// Readings per request.
+pub fn size() -> usize {
+    SIZE.load(Ordering::Relaxed)
+}
+
+/// Whether requests carry an array of readings rather than a single one.
+pub fn is_enabled() -> bool {
+    size() > 1
+}
+
+/// Collects the reports until there are enough for a request.
+#[derive(Debug, Default)]
+pub struct Batcher {
+    pending: Vec<Context>,
+}
+
+impl Batcher {
+    /// Add a report, returning the batch once it is full.
+    pub fn add(&mut self, report: Context) -> Option<Vec<Context>> {
+        self.pending.push(report);
+        (self.pending.len() >= size()).then(|| std::mem::take(&mut self.pending))
+    }
+}
@@ -0,0 +1,125 @@
+//! Method, content type and body of the webhook requests, for REST APIs that
+//! want their own format rather than the firmware's JSON, e.g.
+//! `{"value":{{watts|round:1}},"sensor":"{{hostname}}"}`. Its placeholders
+//! are those of the URL, see `template`. Empty sends the firmware's own
+//! body: JSON, SenML with the `application/senml+json` content type, or
+//! form data such as `amps=1.23&watts=283.5` with a form content type or
+//! `GET`, which sends it in the query string instead.
+
+use std::sync::Mutex;
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::senml;
+
+pub const NVS_WEBHOOK_BODY: &str = "hook_body";
+pub const NVS_WEBHOOK_METHOD: &str = "hook_method";
+/// Empty for `DEFAULT_CONTENT_TYPE`.
+pub const NVS_WEBHOOK_TYPE: &str = "hook_type";
+pub const DEFAULT_CONTENT_TYPE: &str = "application/json";
+pub const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+/// Longest template `read_str_from_nvs` reads back whole.
+const MAX_TEMPLATE_LEN: usize = 127;
+const MAX_CONTENT_TYPE_LEN: usize = 127;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Method {
+    #[default]
+    Post,
+    Put,
+    /// The body goes in the query string.
+    Get,
+}
+
+impl Method {
+    pub const ALL: [Method; 3] = [Method::Post, Method::Put, Method::Get];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Get => "GET",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Method> {
+        Method::ALL.into_iter().find(|method| method.name() == name)
+    }
+}
+
+static TEMPLATE: Mutex<String> = Mutex::new(String::new());
+static METHOD: Mutex<Method> = Mutex::new(Method::Post);
+static CONTENT_TYPE: Mutex<String> = Mutex::new(String::new());
+
+/// Whether `template` can be saved: empty, or a single line that fits in
+/// NVS.
+pub fn is_valid(template: &str) -> bool {
+    template.len() <= MAX_TEMPLATE_LEN && !template.chars().any(char::is_control)
+}
+
+/// Whether `method` can be saved: empty for `POST`, or one of `Method`.
+pub fn is_valid_method(method: &str) -> bool {
+    method.is_empty() || Method::from_name(method).is_some()
+}
+
+/// Whether `content_type` can be saved: empty for the default, or a
+/// `type/subtype` with optional parameters.
+pub fn is_valid_content_type(content_type: &str) -> bool {
+    content_type.is_empty()
+        || (content_type.len() <= MAX_CONTENT_TYPE_LEN
+            && content_type.contains('/')
+            && !content_type.chars().any(char::is_control))
+}
+
+/// Read the template, method and content type from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let template = read_str_from_nvs_or_default(nvs, NVS_WEBHOOK_BODY, "");
+    *TEMPLATE.lock().unwrap() = if is_valid(&template) {
+        template
+    } else {
+        String::new()
+    };
+    let method = read_str_from_nvs_or_default(nvs, NVS_WEBHOOK_METHOD, "");
+    *METHOD.lock().unwrap() = Method::from_name(&method).unwrap_or_default();
+    let content_type = read_str_from_nvs_or_default(nvs, NVS_WEBHOOK_TYPE, "");
+    *CONTENT_TYPE.lock().unwrap() = if is_valid_content_type(&content_type) {
+        content_type
+    } else {
+        String::new()
+    };
+}
+
+/// The template in use, empty for the firmware's own body.
+pub fn template() -> String {
+    TEMPLATE.lock().unwrap().clone()
+}
+
+pub fn method() -> Method {
+    *METHOD.lock().unwrap()
+}
+
+/// The content type as saved, empty for the default.
+pub fn content_type_setting() -> String {
+    CONTENT_TYPE.lock().unwrap().clone()
+}
+
+/// The content type to send.
+pub fn content_type() -> String {
+    let content_type = content_type_setting();
+    if content_type.is_empty() {
+        DEFAULT_CONTENT_TYPE.to_string()
+    } else {
+        content_type
+    }
+}
+
+/// Whether bodies are form data rather than JSON: in the query string of a
+/// `GET`, or with a form content type.
+pub fn is_form() -> bool {
+    method() == Method::Get || content_type().starts_with(FORM_CONTENT_TYPE)
+}
+
+/// Whether bodies are a SenML pack, see `senml`.
+pub fn is_senml() -> bool {
+    !is_form() && content_type().starts_with(senml::CONTENT_TYPE)
+}
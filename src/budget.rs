@@ -0,0 +1,156 @@
+use crate::energy::EnergyCounters;
+use crate::notify;
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::units::WattHours;
+
+/// Fraction of the budget at which we start warning.
+const WARNING_FRACTION: f64 = 0.8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BudgetLevel {
+    #[default]
+    Ok,
+    /// Above 80% of the budget.
+    Warning,
+    /// Above 100% of the budget.
+    Exceeded,
+}
+
+impl BudgetLevel {
+    fn for_usage(used: WattHours, budget_kwh: Option<f32>) -> Self {
+        match budget_kwh {
+            Some(budget_kwh) if used.kwh() >= budget_kwh as f64 => BudgetLevel::Exceeded,
+            Some(budget_kwh) if used.kwh() >= budget_kwh as f64 * WARNING_FRACTION => {
+                BudgetLevel::Warning
+            }
+            _ => BudgetLevel::Ok,
+        }
+    }
+
+    /// Short marker shown next to the readout on the display.
+    pub fn marker(&self) -> &'static str {
+        match self {
+            BudgetLevel::Ok => "",
+            BudgetLevel::Warning => "!",
+            BudgetLevel::Exceeded => "!!",
+        }
+    }
+}
+
+/// Daily and monthly energy budgets, in kWh. `None` disables the budget.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Budget {
+    pub daily_kwh: Option<f32>,
+    pub monthly_kwh: Option<f32>,
+}
+
+fn parse_budget(value: String) -> Option<f32> {
+    value.parse::<f32>().ok().filter(|kwh| *kwh > 0.0)
+}
+
+impl Budget {
+    pub fn from_nvs(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> Self {
+        Budget {
+            daily_kwh: parse_budget(read_str_from_nvs_or_default(nvs, "budget_day", "")),
+            monthly_kwh: parse_budget(read_str_from_nvs_or_default(nvs, "budget_month", "")),
+        }
+    }
+}
+
+/// Price of the energy, to show what it costs.
+#[derive(Clone, Debug, Default)]
+pub struct Tariff {
+    pub price_kwh: Option<f32>,
+    /// Shown after amounts, e.g. `EUR`.
+    pub currency: String,
+}
+
+impl Tariff {
+    pub fn from_nvs(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> Self {
+        Tariff {
+            price_kwh: parse_budget(read_str_from_nvs_or_default(nvs, "price_kwh", "")),
+            currency: read_str_from_nvs_or_default(nvs, "currency", ""),
+        }
+    }
+
+    pub fn cost(&self, energy: WattHours) -> Option<f64> {
+        self.price_kwh.map(|price| energy.kwh() * price as f64)
+    }
+}
+
+/// Tracks which budget thresholds have been crossed so each alert fires once.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BudgetAlerts {
+    pub daily: BudgetLevel,
+    pub monthly: BudgetLevel,
+}
+
+impl BudgetAlerts {
+    /// Re-evaluate the budgets, firing an alert for every threshold crossed
+    /// since the last call. Returns the worst of both levels.
+    pub fn update(&mut self, budget: &Budget, counters: &EnergyCounters) -> BudgetLevel {
+        let daily = BudgetLevel::for_usage(counters.today_wh, budget.daily_kwh);
+        let monthly = BudgetLevel::for_usage(counters.month_wh, budget.monthly_kwh);
+
+        if daily > self.daily {
+            Self::alert("daily", daily, counters.today_wh, budget.daily_kwh);
+        }
+        if monthly > self.monthly {
+            Self::alert("monthly", monthly, counters.month_wh, budget.monthly_kwh);
+        }
+
+        // Levels also go down when the counters roll over, re-arming the alerts
+        self.daily = daily;
+        self.monthly = monthly;
+        self.level()
+    }
+
+    /// One line per budget in warning or exceeded, for the alarms page.
+    pub fn alarms(&self, budget: &Budget, counters: &EnergyCounters) -> Vec<String> {
+        [
+            ("Day", self.daily, counters.today_wh, budget.daily_kwh),
+            ("Month", self.monthly, counters.month_wh, budget.monthly_kwh),
+        ]
+        .into_iter()
+        .filter(|(_, level, _, _)| *level != BudgetLevel::Ok)
+        .map(|(period, level, used, budget_kwh)| {
+            format!(
+                "{} {:.1}/{:.1}kWh{}",
+                period,
+                used.kwh(),
+                budget_kwh.unwrap_or_default(),
+                level.marker()
+            )
+        })
+        .collect()
+    }
+
+    pub fn level(&self) -> BudgetLevel {
+        self.daily.max(self.monthly)
+    }
+
+    fn alert(period: &str, level: BudgetLevel, used: WattHours, budget_kwh: Option<f32>) {
+        log::warn!(
+            "Energy budget alert: {} usage {:.3}kWh is {:?} (budget {:.3}kWh)",
+            period,
+            used.kwh(),
+            level,
+            budget_kwh.unwrap_or_default()
+        );
+        let state = match level {
+            BudgetLevel::Exceeded => "exceeded",
+            _ => "nearly used",
+        };
+        notify::push(
+            format!(
+                "Energy budget {}: {} usage {:.1} of {:.1} kWh",
+                state,
+                period,
+                used.kwh(),
+                budget_kwh.unwrap_or_default()
+            ),
+            level == BudgetLevel::Exceeded,
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! OLED burn-in protection: the rendered content moves by a pixel every few
+//! minutes, and the display can be blanked after a while without anyone
+//! pressing the button.
+
+use embedded_graphics::prelude::Point;
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+
+/// NVS keys take up to 15 characters.
+pub const NVS_IDLE_MINUTES: &str = "disp_idle_min";
+
+/// How long the content stays at each offset.
+const SHIFT_EVERY_S: u64 = 180;
+/// Offsets cycled through, small enough to keep everything on screen.
+const SHIFTS: [Point; 4] = [
+    Point::new(0, 0),
+    Point::new(1, 0),
+    Point::new(1, 1),
+    Point::new(0, 1),
+];
+
+#[derive(Debug)]
+pub struct BurnInGuard {
+    /// Blank the display after this many seconds without a button press,
+    /// `None` to keep it always on.
+    idle_timeout_s: Option<u64>,
+    last_activity_s: u64,
+}
+
+impl BurnInGuard {
+    pub fn new(idle_minutes: u32, now_s: u64) -> Self {
+        BurnInGuard {
+            idle_timeout_s: (idle_minutes > 0).then_some(idle_minutes as u64 * 60),
+            last_activity_s: now_s,
+        }
+    }
+
+    /// Idle timeout set on the setup page, in minutes; 0 disables it.
+    pub fn idle_minutes_from_nvs(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> u32 {
+        read_str_from_nvs_or_default(nvs, NVS_IDLE_MINUTES, "0")
+            .parse()
+            .unwrap_or(0)
+    }
+
+    /// Offset to draw the content at.
+    pub fn shift(&self, now_s: u64) -> Point {
+        SHIFTS[(now_s / SHIFT_EVERY_S % SHIFTS.len() as u64) as usize]
+    }
+
+    /// Whether the display should be blanked.
+    pub fn is_idle(&self, now_s: u64) -> bool {
+        self.idle_timeout_s.map_or(false, |timeout| {
+            now_s.saturating_sub(self.last_activity_s) >= timeout
+        })
+    }
+
+    /// Record a button press, restarting the idle timeout.
+    pub fn wake(&mut self, now_s: u64) {
+        self.last_activity_s = now_s;
+    }
+}
@@ -0,0 +1,92 @@
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::gpio;
+use esp_idf_svc::sys::esp_timer_get_time;
+
+/// Holding the button at least this long counts as a long press.
+const LONG_PRESS_US: i64 = 2_000_000;
+/// Holding it on this long asks for a factory reset instead.
+const VERY_LONG_PRESS_US: i64 = 10_000_000;
+/// Releasing it after this long, but before a long press, is a medium press.
+const MEDIUM_PRESS_US: i64 = 600_000;
+/// Presses shorter than this are contact bounce.
+const DEBOUNCE_US: i64 = 30_000;
+const POLL_MS: u32 = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Press {
+    Short,
+    Medium,
+    Long,
+    VeryLong,
+}
+
+/// Turns the level of an active-low button, such as BOOT, into short,
+/// medium and long presses, all on release. A very long press fires as soon
+/// as the threshold is reached, without waiting for the release, and
+/// nothing else follows, so holding on for a factory reset doesn't first
+/// act as a long press.
+#[derive(Debug, Default)]
+pub struct Button {
+    pressed_since_us: Option<i64>,
+    very_long_press_fired: bool,
+}
+
+impl Button {
+    /// Sample the button once. Call often enough to not miss short presses.
+    pub fn poll<P: gpio::Pin>(
+        &mut self,
+        pin: &gpio::PinDriver<'_, P, gpio::Input>,
+    ) -> Option<Press> {
+        // Safe: esp_timer is started by the IDF before app_main runs
+        let now_us = unsafe { esp_timer_get_time() };
+        match (pin.is_low(), self.pressed_since_us) {
+            (true, None) => {
+                self.pressed_since_us = Some(now_us);
+                self.very_long_press_fired = false;
+                None
+            }
+            (true, Some(since))
+                if !self.very_long_press_fired && now_us - since >= VERY_LONG_PRESS_US =>
+            {
+                self.very_long_press_fired = true;
+                Some(Press::VeryLong)
+            }
+            (true, Some(_)) => None,
+            (false, Some(since)) => {
+                self.pressed_since_us = None;
+                let held_us = now_us - since;
+                if self.very_long_press_fired || held_us < DEBOUNCE_US {
+                    None
+                } else if held_us >= VERY_LONG_PRESS_US {
+                    // Released while we were busy elsewhere
+                    Some(Press::VeryLong)
+                } else if held_us >= LONG_PRESS_US {
+                    Some(Press::Long)
+                } else if held_us >= MEDIUM_PRESS_US {
+                    Some(Press::Medium)
+                } else {
+                    Some(Press::Short)
+                }
+            }
+            (false, None) => None,
+        }
+    }
+
+    /// Sleep for about `ms` milliseconds while watching the button, returning
+    /// early as soon as a press is detected.
+    pub fn wait<P: gpio::Pin>(
+        &mut self,
+        pin: &gpio::PinDriver<'_, P, gpio::Input>,
+        ms: u32,
+    ) -> Option<Press> {
+        let mut waited = 0;
+        while waited < ms {
+            if let Some(press) = self.poll(pin) {
+                return Some(press);
+            }
+            FreeRtos::delay_ms(POLL_MS);
+            waited += POLL_MS;
+        }
+        self.poll(pin)
+    }
+}
@@ -0,0 +1,229 @@
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use esp_idf_svc::sys::EspError;
+use once_cell::sync::Lazy;
+
+use crate::nvs;
+use crate::uptime::unix_now;
+
+const NVS_POINTS: &str = "cal_points";
+const NVS_CALIBRATED_AT: &str = "cal_at";
+
+pub const MAX_POINTS: usize = 8;
+
+/// A raw reading paired with what a reference meter showed at the same time.
+#[derive(Clone, Copy, Debug)]
+pub struct CalibrationPoint {
+    pub raw_amps: f32,
+    pub reference_amps: f32,
+}
+
+/// Linear correction `amps = gain * raw + offset` fitted to the points.
+#[derive(Clone, Debug)]
+pub struct Calibration {
+    pub gain: f32,
+    pub offset: f32,
+    pub points: heapless::Vec<CalibrationPoint, MAX_POINTS>,
+    /// Unix time of the last change to the points, or 0 if unknown.
+    pub calibrated_at: u64,
+    /// Latest uncorrected reading, used when adding a new point.
+    pub last_raw_amps: f32,
+    /// Set when the points changed and still need to be written to NVS.
+    dirty: bool,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            gain: 1.0,
+            offset: 0.0,
+            points: heapless::Vec::new(),
+            calibrated_at: 0,
+            last_raw_amps: 0.0,
+            dirty: false,
+        }
+    }
+}
+
+pub(crate) static CALIBRATION: Lazy<Mutex<Calibration>> =
+    Lazy::new(|| Mutex::new(Calibration::default()));
+
+impl Calibration {
+    pub fn apply(&self, raw_amps: f32) -> f32 {
+        (self.gain * raw_amps + self.offset).max(0.0)
+    }
+
+    /// Pair the latest raw reading with a reference value and refit. The
+    /// oldest point is dropped when the table is full.
+    pub fn add_point(&mut self, reference_amps: f32) -> CalibrationPoint {
+        if self.points.is_full() {
+            self.points.remove(0);
+        }
+        let point = CalibrationPoint {
+            raw_amps: self.last_raw_amps,
+            reference_amps,
+        };
+        // Cannot fail, we just made room
+        let _ = self.points.push(point);
+        self.refit();
+        point
+    }
+
+    pub fn reset(&mut self) {
+        self.points.clear();
+        self.refit();
+    }
+
+    /// Least-squares fit of gain and offset. A single point only fixes the gain.
+    fn refit(&mut self) {
+        let n = self.points.len() as f32;
+        let (gain, offset) = match self.points.len() {
+            0 => (1.0, 0.0),
+            1 => {
+                let p = self.points[0];
+                if p.raw_amps > 0.0 {
+                    (p.reference_amps / p.raw_amps, 0.0)
+                } else {
+                    (1.0, p.reference_amps)
+                }
+            }
+            _ => {
+                let sum_x: f32 = self.points.iter().map(|p| p.raw_amps).sum();
+                let sum_y: f32 = self.points.iter().map(|p| p.reference_amps).sum();
+                let sum_xx: f32 = self.points.iter().map(|p| p.raw_amps * p.raw_amps).sum();
+                let sum_xy: f32 = self
+                    .points
+                    .iter()
+                    .map(|p| p.raw_amps * p.reference_amps)
+                    .sum();
+                let denominator = n * sum_xx - sum_x * sum_x;
+                if denominator.abs() < f32::EPSILON {
+                    // All points at the same raw value, only an offset makes sense
+                    (1.0, (sum_y - sum_x) / n)
+                } else {
+                    let gain = (n * sum_xy - sum_x * sum_y) / denominator;
+                    (gain, (sum_y - gain * sum_x) / n)
+                }
+            }
+        };
+        self.gain = gain;
+        self.offset = offset;
+        self.calibrated_at = unix_now().unwrap_or(0);
+        self.dirty = true;
+        log::info!("Calibration: gain={} offset={}A", gain, offset);
+    }
+
+    /// Corrected value minus reference value for a point.
+    pub fn residual(&self, point: &CalibrationPoint) -> f32 {
+        self.apply(point.raw_amps) - point.reference_amps
+    }
+
+    pub fn rms_residual(&self) -> f32 {
+        if self.points.is_empty() {
+            return 0.0;
+        }
+        let sum: f32 = self.points.iter().map(|p| self.residual(p).powi(2)).sum();
+        (sum / self.points.len() as f32).sqrt()
+    }
+
+    pub fn report_csv(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "# firmware,{}", env!("CARGO_PKG_VERSION")).unwrap();
+        writeln!(out, "# calibrated_at,{}", self.calibrated_at).unwrap();
+        writeln!(out, "# gain,{}", self.gain).unwrap();
+        writeln!(out, "# offset_amps,{}", self.offset).unwrap();
+        writeln!(out, "# rms_residual_amps,{}", self.rms_residual()).unwrap();
+        writeln!(out, "raw_amps,reference_amps,corrected_amps,residual_amps").unwrap();
+        for p in &self.points {
+            writeln!(
+                out,
+                "{},{},{},{}",
+                p.raw_amps,
+                p.reference_amps,
+                self.apply(p.raw_amps),
+                self.residual(p)
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    pub fn report_json(&self) -> String {
+        let mut out = String::new();
+        write!(
+            out,
+            "{{\"firmware\":\"{}\",\"calibrated_at\":{},\"gain\":{},\"offset_amps\":{},\
+             \"rms_residual_amps\":{},\"points\":[",
+            env!("CARGO_PKG_VERSION"),
+            self.calibrated_at,
+            self.gain,
+            self.offset,
+            self.rms_residual()
+        )
+        .unwrap();
+        for (i, p) in self.points.iter().enumerate() {
+            write!(
+                out,
+                "{}{{\"raw_amps\":{},\"reference_amps\":{},\"corrected_amps\":{},\"residual_amps\":{}}}",
+                if i > 0 { "," } else { "" },
+                p.raw_amps,
+                p.reference_amps,
+                self.apply(p.raw_amps),
+                self.residual(p)
+            )
+            .unwrap();
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+/// Loads the calibration points from NVS and writes them back on changes.
+pub struct CalibrationStore {
+    nvs: nvs::EspNvs<nvs::NvsDefault>,
+}
+
+impl CalibrationStore {
+    pub fn start(nvs: nvs::EspNvs<nvs::NvsDefault>) -> Result<Self, EspError> {
+        let mut buf = [0u8; MAX_POINTS * 8];
+        let mut calibration = CALIBRATION.lock().unwrap();
+        if let Some(raw) = nvs.get_raw(NVS_POINTS, &mut buf)? {
+            for chunk in raw.chunks_exact(8) {
+                let _ = calibration.points.push(CalibrationPoint {
+                    raw_amps: f32::from_le_bytes(chunk[..4].try_into().unwrap()),
+                    reference_amps: f32::from_le_bytes(chunk[4..].try_into().unwrap()),
+                });
+            }
+        }
+        calibration.refit();
+        calibration.calibrated_at = nvs.get_u64(NVS_CALIBRATED_AT)?.unwrap_or(0);
+        calibration.dirty = false;
+
+        Ok(CalibrationStore { nvs })
+    }
+
+    /// Call periodically from the main loop to persist changes made over HTTP.
+    pub fn tick(&mut self) {
+        let mut calibration = match CALIBRATION.try_lock() {
+            Ok(calibration) if calibration.dirty => calibration,
+            _ => return,
+        };
+        calibration.dirty = false;
+
+        let mut buf = heapless::Vec::<u8, { MAX_POINTS * 8 }>::new();
+        for p in &calibration.points {
+            let _ = buf.extend_from_slice(&p.raw_amps.to_le_bytes());
+            let _ = buf.extend_from_slice(&p.reference_amps.to_le_bytes());
+        }
+        if let Err(err) = self.nvs.set_raw(NVS_POINTS, &buf) {
+            log::warn!("Error setting {} in NVS: {:?}", NVS_POINTS, err);
+        }
+        if let Err(err) = self
+            .nvs
+            .set_u64(NVS_CALIBRATED_AT, calibration.calibrated_at)
+        {
+            log::warn!("Error setting {} in NVS: {:?}", NVS_CALIBRATED_AT, err);
+        }
+    }
+}
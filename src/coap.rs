@@ -0,0 +1,200 @@
+//! Readings posted to a CoAP resource, next to or instead of the webhook. A
+//! CoAP request is a single UDP datagram of a few dozen bytes, with no
+//! connection or handshake, which suits constrained collectors and keeps the
+//! radio busy for far less time than an HTTPS post.
+//!
+//! Every interval the reading goes to the resource of a `coap://` URL as a
+//! non-confirmable POST of `{"amps":..,"watts":..,"kwh":..,"timestamp":..}`.
+//! Nothing is retried: a lost reading is followed by the next one. DTLS
+//! (`coaps://`) is not supported.
+
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::template::Context;
+use crate::uptime::session_uptime_s;
+
+pub const NVS_COAP_URL: &str = "coap_url";
+pub const NVS_COAP_INTERVAL: &str = "coap_interval";
+pub const DEFAULT_INTERVAL_S: u64 = 10;
+const DEFAULT_PORT: u16 = 5683;
+
+const VERSION: u8 = 1;
+const TYPE_NON_CONFIRMABLE: u8 = 1;
+/// Code 0.02.
+const CODE_POST: u8 = 2;
+const OPTION_URI_PATH: u16 = 11;
+const OPTION_CONTENT_FORMAT: u16 = 12;
+const OPTION_URI_QUERY: u16 = 15;
+const CONTENT_FORMAT_JSON: u8 = 50;
+const PAYLOAD_MARKER: u8 = 0xff;
+
+/// Requests sent since boot, for `/api/v1/outputs`.
+pub(crate) static SENT: AtomicU32 = AtomicU32::new(0);
+
+/// Host, port, path segments and query parameters of a `coap://` URL.
+fn parse_url(url: &str) -> Option<(&str, u16, Vec<&str>, Vec<&str>)> {
+    let rest = url.strip_prefix("coap://")?;
+    let (authority, resource) = rest.split_once('/').unwrap_or((rest, ""));
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, DEFAULT_PORT),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let (path, query) = resource.split_once('?').unwrap_or((resource, ""));
+    let segments = path.split('/').filter(|s| !s.is_empty()).collect();
+    let parameters = query.split('&').filter(|s| !s.is_empty()).collect();
+    Some((host, port, segments, parameters))
+}
+
+/// Whether `url` can be saved: empty, or a `coap://` URL.
+pub fn is_valid_url(url: &str) -> bool {
+    url.is_empty() || parse_url(url).is_some()
+}
+
+/// Whether `interval` can be saved: empty for the default, or whole seconds.
+pub fn is_valid_interval(interval: &str) -> bool {
+    interval.is_empty() || interval.parse::<u64>().map_or(false, |seconds| seconds > 0)
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CoapSettings {
+    pub url: String,
+    pub interval_s: u64,
+}
+
+impl CoapSettings {
+    pub fn from_nvs(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> Self {
+        let url = read_str_from_nvs_or_default(nvs, NVS_COAP_URL, "");
+        let interval = read_str_from_nvs_or_default(nvs, NVS_COAP_INTERVAL, "");
+        CoapSettings {
+            url: if is_valid_url(&url) {
+                url
+            } else {
+                String::new()
+            },
+            interval_s: interval.parse().unwrap_or(DEFAULT_INTERVAL_S).max(1),
+        }
+    }
+}
+
+/// Append an option, its number given as the delta from the previous one.
+fn push_option(message: &mut Vec<u8>, delta: u16, value: &[u8]) {
+    // Values up to 12 fit in the first byte, then one or two more bytes
+    fn nibble(n: usize) -> (u8, Vec<u8>) {
+        match n {
+            0..=12 => (n as u8, vec![]),
+            13..=268 => (13, vec![(n - 13) as u8]),
+            _ => (14, ((n - 269) as u16).to_be_bytes().to_vec()),
+        }
+    }
+    let (delta, delta_ext) = nibble(delta as usize);
+    let (length, length_ext) = nibble(value.len());
+    message.push(delta << 4 | length);
+    message.extend(delta_ext);
+    message.extend(length_ext);
+    message.extend_from_slice(value);
+}
+
+/// A non-confirmable POST of `payload` to the resource.
+fn post_message(
+    message_id: u16,
+    segments: &[&str],
+    parameters: &[&str],
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut message = vec![VERSION << 6 | TYPE_NON_CONFIRMABLE << 4, CODE_POST];
+    message.extend_from_slice(&message_id.to_be_bytes());
+    // Options go in increasing order of their numbers
+    let mut previous = 0;
+    for segment in segments {
+        push_option(&mut message, OPTION_URI_PATH - previous, segment.as_bytes());
+        previous = OPTION_URI_PATH;
+    }
+    push_option(
+        &mut message,
+        OPTION_CONTENT_FORMAT - previous,
+        &[CONTENT_FORMAT_JSON],
+    );
+    previous = OPTION_CONTENT_FORMAT;
+    for parameter in parameters {
+        push_option(
+            &mut message,
+            OPTION_URI_QUERY - previous,
+            parameter.as_bytes(),
+        );
+        previous = OPTION_URI_QUERY;
+    }
+    message.push(PAYLOAD_MARKER);
+    message.extend_from_slice(payload);
+    message
+}
+
+/// Posts the readings every interval while connected to Wi-Fi.
+pub struct CoapPublisher {
+    settings: CoapSettings,
+    socket: Option<UdpSocket>,
+    message_id: u16,
+    next_publish_s: u64,
+}
+
+impl CoapPublisher {
+    pub fn new(settings: CoapSettings) -> Self {
+        CoapPublisher {
+            settings,
+            socket: None,
+            // Safe: reads the hardware random number generator
+            message_id: unsafe { esp_idf_svc::sys::esp_random() } as u16,
+            next_publish_s: 0,
+        }
+    }
+
+    /// Call from the main loop while connected to Wi-Fi.
+    pub fn tick(&mut self, context: &Context) {
+        let now = session_uptime_s();
+        if self.settings.url.is_empty() || now < self.next_publish_s {
+            return;
+        }
+        self.next_publish_s = now + self.settings.interval_s;
+        match self.post(context) {
+            Ok(()) => {
+                SENT.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => log::warn!("Could not post to {}: {:?}", self.settings.url, err),
+        }
+    }
+
+    fn post(&mut self, context: &Context) -> anyhow::Result<()> {
+        let Some((host, port, segments, parameters)) = parse_url(&self.settings.url) else {
+            anyhow::bail!("invalid URL");
+        };
+        let Some(address) = (host, port).to_socket_addrs()?.next() else {
+            anyhow::bail!("{} not found", host);
+        };
+        if self.socket.is_none() {
+            self.socket = Some(UdpSocket::bind("0.0.0.0:0")?);
+        }
+        let Some(socket) = self.socket.as_ref() else {
+            return Ok(());
+        };
+
+        let mut payload = format!(
+            "{{\"amps\":{},\"watts\":{},\"kwh\":{:.3}",
+            context.amps.0,
+            context.watts.0,
+            context.energy.kwh()
+        );
+        if context.timestamp != 0 {
+            payload.push_str(&format!(",\"timestamp\":{}", context.timestamp));
+        }
+        payload.push('}');
+        self.message_id = self.message_id.wrapping_add(1);
+        let message = post_message(self.message_id, &segments, &parameters, payload.as_bytes());
+        socket.send_to(&message, address)?;
+        Ok(())
+    }
+}
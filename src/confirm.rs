@@ -0,0 +1,76 @@
+//! One-time tokens confirming actions that can't be undone, so a stray or
+//! prefetched request can't trigger them on its own. The client asks for a
+//! token first and sends it back with the action.
+
+use std::sync::Mutex;
+
+use esp_idf_svc::http::server::{EspHttpConnection, Request};
+use esp_idf_svc::io::EspIOError;
+use serde_json::json;
+
+use crate::error;
+use crate::form::{self, MAX_FORM_LEN};
+use crate::uptime::session_uptime_s;
+
+/// How long a token stays valid.
+pub const TOKEN_TIMEOUT_S: u64 = 60;
+
+pub struct Confirmation {
+    /// The token handed out last, and the session uptime it expires at.
+    token: Mutex<Option<(String, u64)>>,
+}
+
+impl Confirmation {
+    pub const fn new() -> Self {
+        Confirmation {
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Hand out a new token, replacing the previous one.
+    pub fn issue(&self) -> String {
+        // Safe: the hardware RNG is seeded by the bootloader
+        let token = format!("{:08x}", unsafe { esp_idf_svc::sys::esp_random() });
+        *self.token.lock().unwrap() = Some((token.clone(), session_uptime_s() + TOKEN_TIMEOUT_S));
+        token
+    }
+
+    /// Whether `given` is the current token, which is then used up.
+    pub fn check(&self, given: &str) -> bool {
+        let mut token = self.token.lock().unwrap();
+        let valid = token
+            .as_ref()
+            .is_some_and(|(issued, expires_s)| issued == given && session_uptime_s() < *expires_s);
+        if valid {
+            *token = None;
+        }
+        valid
+    }
+}
+
+/// Answer a POST confirmed by `confirmation`. Without a `token` field it
+/// answers with a new token as JSON; with the current one it runs `action`
+/// and answers `done`. Other tokens are refused.
+pub fn respond(
+    mut req: Request<&mut EspHttpConnection<'_>>,
+    confirmation: &Confirmation,
+    done: &str,
+    action: impl FnOnce(),
+) -> Result<(), EspIOError> {
+    let body = form::read_body(&mut req, MAX_FORM_LEN)?.unwrap_or_default();
+    let fields = form::parse(&body);
+    let Some(given) = form::field(&fields, "token") else {
+        let body = json!({ "token": confirmation.issue(), "expires_in_s": TOKEN_TIMEOUT_S });
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write(body.to_string().as_bytes())?;
+        return Ok(());
+    };
+
+    if !confirmation.check(given) {
+        return error::respond(req, 403, "Invalid or expired token, ask for a new one");
+    }
+    action();
+    req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
+        .write(done.as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,54 @@
+//! CORS headers for the read-only JSON endpoints, so dashboards served from
+//! elsewhere can fetch the readings straight from the browser. The allowed
+//! origin is kept in NVS; by default any page may read them, like anyone on
+//! the LAN already can. The endpoints that change settings don't send it,
+//! so other pages still can't use them.
+
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+
+pub const NVS_CORS_ORIGIN: &str = "cors_origin";
+/// Used while no origin is saved.
+pub const DEFAULT_ORIGIN: &str = "*";
+
+pub(crate) static ALLOWED_ORIGIN: Lazy<Mutex<String>> =
+    Lazy::new(|| Mutex::new(DEFAULT_ORIGIN.to_string()));
+
+/// Whether `origin` can be saved: empty for the default, `*`, or a scheme
+/// and host with an optional port, e.g. `https://dash.example.com:8443`.
+pub fn is_valid_origin(origin: &str) -> bool {
+    if origin.is_empty() || origin == "*" {
+        return true;
+    }
+    let Some(host) = origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"))
+    else {
+        return false;
+    };
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c))
+}
+
+/// Read the allowed origin from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let origin = read_str_from_nvs_or_default(nvs, NVS_CORS_ORIGIN, "");
+    let origin = if is_valid_origin(&origin) && !origin.is_empty() {
+        origin
+    } else {
+        DEFAULT_ORIGIN.to_string()
+    };
+    log::info!("Allowing cross-origin reads from {}", origin);
+    *ALLOWED_ORIGIN.lock().unwrap() = origin;
+}
+
+/// Value of the `Access-Control-Allow-Origin` header.
+pub fn allowed_origin() -> String {
+    ALLOWED_ORIGIN.lock().unwrap().clone()
+}
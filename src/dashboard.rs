@@ -0,0 +1,357 @@
+//! Dashboard mode: one device polls the `/api/v1/status` of its peers and
+//! shows every circuit, its own included, at `/dashboard` and as JSON at
+//! `/api/dashboard`. The peers are kept in NVS as a list of addresses such
+//! as `garage.local http://10.0.0.7`; an empty list turns the polling off.
+//!
+//! The polling runs on a thread of its own, so a peer that is slow to answer
+//! never holds up sampling or the web server.
+
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::EspError;
+use esp_idf_svc::{hal, http};
+use serde::{Deserialize, Serialize};
+
+use crate::assets;
+use crate::cors;
+use crate::energy::ENERGY;
+use crate::http_server::{html_escape, CURRENT_KNOWN_HOSTNAME};
+use crate::labels;
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::pwa;
+use crate::units::Amps;
+use crate::uptime::session_uptime_s;
+use crate::AC_VOLTS;
+
+pub const NVS_PEERS: &str = "peers";
+pub const MAX_PEERS: usize = 8;
+/// Longest list that fits in an NVS string read back whole.
+const MAX_SETTING_LEN: usize = 127;
+const POLL_EVERY_S: u64 = 10;
+/// TLS to a peer behind HTTPS needs the room.
+const STACK_SIZE: usize = 10 * 1024;
+const MAX_STATUS_LEN: usize = 1024;
+
+/// The part of a peer's `/api/v1/status` shown on the dashboard.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct PeerStatus {
+    amps: Option<f32>,
+    watts: Option<f32>,
+    energy_kwh: f64,
+    hostname: String,
+    /// Missing on peers with older firmware.
+    #[serde(default)]
+    device_name: String,
+    #[serde(default)]
+    channel_label: String,
+}
+
+#[derive(Clone, Debug)]
+struct Peer {
+    /// As typed on the setup page.
+    address: String,
+    url: String,
+    /// Last status received, kept while the peer is unreachable.
+    status: Option<PeerStatus>,
+    /// Session uptime the status was received at.
+    updated_s: u64,
+    /// Why the last poll failed, `None` after a successful one.
+    error: Option<String>,
+}
+
+static PEERS: Mutex<Vec<Peer>> = Mutex::new(Vec::new());
+
+/// Base URL of a peer as typed on the setup page, e.g. `garage.local`.
+fn peer_url(address: &str) -> String {
+    let address = address.trim_end_matches('/');
+    if address.starts_with("http://") || address.starts_with("https://") {
+        address.to_string()
+    } else {
+        format!("http://{}", address)
+    }
+}
+
+/// Whether `peers` can be saved: up to `MAX_PEERS` addresses separated by
+/// spaces or commas, empty for none.
+pub fn is_valid_peers(peers: &str) -> bool {
+    let addresses: Vec<&str> = split(peers).collect();
+    peers.len() <= MAX_SETTING_LEN
+        && addresses.len() <= MAX_PEERS
+        && addresses.iter().all(|address| {
+            address
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-._:/[]".contains(c))
+        })
+}
+
+fn split(peers: &str) -> impl Iterator<Item = &str> {
+    peers
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|address| !address.is_empty())
+}
+
+/// Read the peers from NVS, keeping what is known about those still listed.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let setting = read_str_from_nvs_or_default(nvs, NVS_PEERS, "");
+    let addresses: Vec<&str> = if is_valid_peers(&setting) {
+        split(&setting).collect()
+    } else {
+        Vec::new()
+    };
+    let mut peers = PEERS.lock().unwrap();
+    let mut known = std::mem::take(&mut *peers);
+    for address in addresses {
+        let url = peer_url(address);
+        let peer = match known.iter().position(|peer| peer.url == url) {
+            Some(index) => known.swap_remove(index),
+            None => Peer {
+                address: address.to_string(),
+                url,
+                status: None,
+                updated_s: 0,
+                error: None,
+            },
+        };
+        peers.push(peer);
+    }
+    if !peers.is_empty() {
+        log::info!("Dashboard polling {} peers", peers.len());
+    }
+}
+
+/// The peers as saved, for the setup page.
+pub fn setting() -> String {
+    PEERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|peer| peer.address.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn fetch_status(url: &str) -> anyhow::Result<PeerStatus> {
+    let httpconnection = http::client::EspHttpConnection::new(&http::client::Configuration {
+        use_global_ca_store: true,
+        crt_bundle_attach: Some(hal::sys::esp_crt_bundle_attach),
+        timeout: Some(Duration::from_secs(5)),
+        ..Default::default()
+    })?;
+    let mut client = embedded_svc::http::client::Client::wrap(httpconnection);
+    let mut response = client.get(&format!("{}/api/v1/status", url))?.submit()?;
+    if response.status() != 200 {
+        anyhow::bail!("HTTP status {}", response.status());
+    }
+
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let read = response.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        if body.len() + read > MAX_STATUS_LEN {
+            anyhow::bail!("status over {} bytes", MAX_STATUS_LEN);
+        }
+        body.extend_from_slice(&buf[..read]);
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+fn poll_loop() {
+    loop {
+        // Poll from a copy, the list may change meanwhile
+        let urls: Vec<String> = PEERS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|peer| peer.url.clone())
+            .collect();
+        for url in urls {
+            let result = fetch_status(&url);
+            if let Err(err) = &result {
+                log::warn!("Could not poll {}: {:?}", url, err);
+            }
+            let mut peers = PEERS.lock().unwrap();
+            let Some(peer) = peers.iter_mut().find(|peer| peer.url == url) else {
+                continue;
+            };
+            match result {
+                Ok(status) => {
+                    peer.status = Some(status);
+                    peer.updated_s = session_uptime_s();
+                    peer.error = None;
+                }
+                Err(err) => peer.error = Some(err.to_string()),
+            }
+        }
+        std::thread::sleep(Duration::from_secs(POLL_EVERY_S));
+    }
+}
+
+/// Start polling the peers. Call once; while none are set it only sleeps.
+pub fn spawn() {
+    std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(poll_loop)
+        .expect("Failed to spawn the dashboard thread");
+}
+
+/// A row of the dashboard.
+#[derive(Serialize)]
+struct Circuit {
+    /// `None` for this device.
+    url: Option<String>,
+    #[serde(flatten)]
+    status: PeerStatus,
+    /// Whether the last poll succeeded; this device is always online.
+    online: bool,
+    /// Seconds since the status was received.
+    age_s: u64,
+    error: Option<String>,
+}
+
+/// Body of `/api/dashboard`.
+#[derive(Serialize)]
+struct Dashboard {
+    circuits: Vec<Circuit>,
+    /// Sum over the circuits with a reading, stale ones included.
+    total_watts: f32,
+}
+
+fn dashboard(amps: Amps) -> Dashboard {
+    let now_s = session_uptime_s();
+    let mut circuits = vec![Circuit {
+        url: None,
+        status: PeerStatus {
+            amps: Some(amps.0),
+            watts: Some((amps * AC_VOLTS).0),
+            energy_kwh: ENERGY.lock().unwrap().total_wh.kwh(),
+            hostname: CURRENT_KNOWN_HOSTNAME.lock().unwrap().clone(),
+            device_name: labels::device_name(),
+            channel_label: labels::channel_label(),
+        },
+        online: true,
+        age_s: 0,
+        error: None,
+    }];
+    circuits.extend(PEERS.lock().unwrap().iter().map(|peer| Circuit {
+        url: Some(peer.url.clone()),
+        status: peer.status.clone().unwrap_or_default(),
+        online: peer.status.is_some() && peer.error.is_none(),
+        age_s: peer.status.as_ref().map_or(0, |_| now_s - peer.updated_s),
+        error: match (&peer.status, &peer.error) {
+            (None, None) => Some("not polled yet".to_string()),
+            (_, error) => error.clone(),
+        },
+    }));
+    let total_watts = circuits
+        .iter()
+        .filter_map(|circuit| circuit.status.watts)
+        .sum();
+    Dashboard {
+        circuits,
+        total_watts,
+    }
+}
+
+fn optional_reading(value: Option<f32>, unit: &str) -> String {
+    value.map_or("-".to_string(), |value| format!("{:.1} {}", value, unit))
+}
+
+fn page(dashboard: &Dashboard) -> String {
+    let mut rows = String::new();
+    for circuit in &dashboard.circuits {
+        let status = &circuit.status;
+        let name = [&status.device_name, &status.hostname]
+            .into_iter()
+            .find(|name| !name.is_empty())
+            .map_or("?", String::as_str);
+        let name = match &circuit.url {
+            Some(url) => format!(
+                "<a href=\"{}/\">{}</a>",
+                html_escape(url),
+                html_escape(name)
+            ),
+            None => html_escape(name),
+        };
+        let state = match (&circuit.error, circuit.online) {
+            (Some(error), _) => html_escape(error),
+            (None, true) => "OK".to_string(),
+            (None, false) => String::new(),
+        };
+        write!(
+            rows,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.1} kWh</td><td>{}</td></tr>",
+            name,
+            html_escape(&status.channel_label),
+            optional_reading(status.watts, "W"),
+            optional_reading(status.amps, "A"),
+            status.energy_kwh,
+            state,
+        )
+        .unwrap();
+    }
+    format!(
+        "<!DOCTYPE html>
+<html><head><title>Dashboard</title>
+<meta http-equiv=\"refresh\" content=\"{}\">
+{}
+<link rel=\"stylesheet\" href=\"{}\"></head>
+<body><h1>Dashboard</h1>
+<table><tr><th>Device</th><th>Circuit</th><th>Power</th><th>Current</th><th>Energy</th><th>Status</th></tr>
+{}
+<tr><th>Total</th><th></th><th>{:.1} W</th><th></th><th></th><th></th></tr></table>
+<a href=\"/\">Back</a></body></html>",
+        POLL_EVERY_S,
+        pwa::head_links(),
+        assets::url("style.css"),
+        rows,
+        dashboard.total_watts,
+    )
+}
+
+/// `/dashboard` and `/api/dashboard`. Like the readings they are open to
+/// anyone on the network.
+pub fn add_dashboard_handlers<'a>(
+    server: &mut EspHttpServer<'a>,
+    expose_value: &'a Arc<Mutex<Amps>>,
+) -> Result<(), EspError> {
+    server.fn_handler(
+        "/dashboard",
+        esp_idf_svc::http::Method::Get,
+        move |req| -> Result<(), EspIOError> {
+            let amps = *expose_value.lock().unwrap();
+            let body = page(&dashboard(amps));
+            req.into_response(200, Some("OK"), &[("Content-Type", "text/html")])?
+                .write(body.as_bytes())?;
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/api/dashboard",
+        esp_idf_svc::http::Method::Get,
+        move |req| -> Result<(), EspIOError> {
+            let amps = *expose_value.lock().unwrap();
+            let body = serde_json::to_string(&dashboard(amps)).unwrap();
+            let origin = cors::allowed_origin();
+            req.into_response(
+                200,
+                Some("OK"),
+                &[
+                    ("Content-Type", "application/json"),
+                    ("Access-Control-Allow-Origin", &origin),
+                ],
+            )?
+            .write(body.as_bytes())?;
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
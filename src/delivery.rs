@@ -0,0 +1,226 @@
+//! Counters of webhook deliveries, so a collector that silently stopped
+//! accepting readings shows up on the display and at `/api/health`, and the
+//! backoff between attempts at a reading it did not take.
+//!
+//! A failed reading is posted again after 2, 4, 8... seconds, up to
+//! `MAX_BACKOFF_S`, each wait stretched by a random part of up to half so a
+//! fleet that lost its collector at once doesn't come back in lockstep.
+//! Readings wait in the `outbox` meanwhile. One the collector refuses with
+//! a 4xx status `MAX_ATTEMPTS` times is given up on, so it doesn't hold up
+//! the others.
+//!
+//! A delivery counts when the collector answers with a 2xx status and, if
+//! one is set, its answer contains the expected text, for collectors that
+//! report errors in the body of a 200.
+
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::template::Context;
+use crate::wifi;
+
+pub const NVS_WEBHOOK_EXPECT: &str = "hook_expect";
+/// Bytes of the answer searched for the expected text.
+pub const MAX_RESPONSE_LEN: usize = 1024;
+/// Longest text `read_str_from_nvs` reads back whole.
+const MAX_EXPECT_LEN: usize = 127;
+
+/// This many failed deliveries in a row mark the device as degraded.
+pub const DEGRADED_AFTER_FAILURES: u32 = 3;
+/// Refused attempts at a reading before giving up on it.
+pub const MAX_ATTEMPTS: u32 = 5;
+/// Wait after the first failure, doubled after each further one.
+const BASE_BACKOFF_S: u64 = 2;
+const MAX_BACKOFF_S: u64 = 120;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DeliveryStats {
+    pub successes: u32,
+    pub failures: u32,
+    /// Failures since the last successful delivery.
+    pub consecutive_failures: u32,
+    /// Unix time of the last successful delivery, if the clock was synced.
+    pub last_success: Option<u64>,
+    /// Why the last failed delivery failed, e.g. `HTTP 503`.
+    pub last_error: Option<String>,
+    /// Status of the collector's last answer, whether it counted or not.
+    pub last_status: Option<u16>,
+}
+
+pub(crate) static DELIVERY_STATS: Lazy<Mutex<DeliveryStats>> =
+    Lazy::new(|| Mutex::new(DeliveryStats::default()));
+
+/// Text the answer must contain, empty to check the status only.
+static EXPECT: Mutex<String> = Mutex::new(String::new());
+
+/// Whether `text` can be saved as the expected answer: empty, or a single
+/// line that fits in NVS.
+pub fn is_valid_expect(text: &str) -> bool {
+    text.len() <= MAX_EXPECT_LEN && !text.chars().any(char::is_control)
+}
+
+/// Read the expected answer from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let text = read_str_from_nvs_or_default(nvs, NVS_WEBHOOK_EXPECT, "");
+    *EXPECT.lock().unwrap() = if is_valid_expect(&text) {
+        text
+    } else {
+        String::new()
+    };
+}
+
+/// The text the answer must contain, empty if any answer will do.
+pub fn expected() -> String {
+    EXPECT.lock().unwrap().clone()
+}
+
+impl DeliveryStats {
+    /// Count a delivery attempt, spooled or live.
+    pub fn record(&mut self, attempt: &Attempt) {
+        self.last_status = attempt.http_status.or(self.last_status);
+        match attempt.outcome() {
+            Ok(()) => {
+                self.successes = self.successes.wrapping_add(1);
+                self.consecutive_failures = 0;
+                self.last_success = crate::uptime::unix_now().or(self.last_success);
+            }
+            Err(error) => {
+                self.failures = self.failures.wrapping_add(1);
+                self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+                self.last_error = Some(error);
+            }
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.consecutive_failures >= DEGRADED_AFTER_FAILURES
+    }
+}
+
+/// Outcome of posting one reading to the webhook.
+#[derive(Debug)]
+pub struct Attempt {
+    /// Status the collector answered with, `None` if it wasn't reached.
+    pub http_status: Option<u16>,
+    pub error: Option<String>,
+    /// Skipped since the collector looked unreachable, see `preflight`.
+    pub offline: bool,
+}
+
+impl Attempt {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Whether the collector rejected the reading itself, which posting it
+    /// again is unlikely to change.
+    pub fn is_refused(&self) -> bool {
+        self.http_status
+            .is_some_and(|status| (400..500).contains(&status))
+    }
+
+    /// Webhook status shown on the display.
+    pub fn label(&self) -> &'static str {
+        match (self.is_ok(), self.offline) {
+            (true, _) => "OK",
+            (false, true) => "OFFLINE",
+            (false, false) => "ERROR",
+        }
+    }
+
+    pub fn outcome(&self) -> Result<(), String> {
+        self.error.clone().map_or(Ok(()), Err)
+    }
+}
+
+/// Post `readings` to the webhook, logging why it failed if it did.
+pub fn attempt(app_config: &crate::Config, url: &String, readings: &[Context]) -> Attempt {
+    let expected = expected();
+    match wifi::send_webhook(app_config, url, readings, !expected.is_empty()) {
+        Ok((status, _)) if !(200..300).contains(&status) => {
+            log::warn!("Webhook answered with HTTP {}", status);
+            Attempt {
+                http_status: Some(status),
+                error: Some(format!("HTTP {}", status)),
+                offline: false,
+            }
+        }
+        Ok((status, answer)) if !answer.contains(&expected) => {
+            log::warn!("Webhook answer lacks {:?}: {:?}", expected, answer);
+            Attempt {
+                http_status: Some(status),
+                error: Some(format!("HTTP {} without {:?}", status, expected)),
+                offline: false,
+            }
+        }
+        Ok((status, _)) => Attempt {
+            http_status: Some(status),
+            error: None,
+            offline: false,
+        },
+        Err(err) if err.is::<wifi::preflight::PreflightError>() => {
+            log::warn!("Skipping webhook: {}", err);
+            Attempt {
+                http_status: None,
+                error: Some(err.to_string()),
+                offline: true,
+            }
+        }
+        Err(err) => {
+            log::warn!("Error sending webhook: {:?}", err);
+            Attempt {
+                http_status: None,
+                error: Some(err.to_string()),
+                offline: false,
+            }
+        }
+    }
+}
+
+/// Wait before attempt `attempts + 1`, jitter included.
+fn backoff_s(attempts: u32) -> u64 {
+    let delay = (BASE_BACKOFF_S << attempts.saturating_sub(1).min(16)).min(MAX_BACKOFF_S);
+    // Safe: reads the hardware random number generator
+    let random = unsafe { esp_idf_svc::sys::esp_random() } as u64;
+    delay + random % (delay / 2 + 1)
+}
+
+/// Backoff between attempts at the oldest reading the webhook has not taken.
+#[derive(Debug, Default)]
+pub struct Retry {
+    /// Failed attempts in a row.
+    attempts: u32,
+    /// Times in a row the collector answered with a 4xx status.
+    refusals: u32,
+    /// Session uptime before which nothing is posted.
+    next_attempt_s: u64,
+}
+
+impl Retry {
+    /// Whether to post at `now_s` rather than wait after a failure.
+    pub fn is_due(&self, now_s: u64) -> bool {
+        now_s >= self.next_attempt_s
+    }
+
+    pub fn succeeded(&mut self) {
+        *self = Retry::default();
+    }
+
+    /// Wait longer before the next attempt. Returns whether to give up on the
+    /// reading, once the collector `refused` it `MAX_ATTEMPTS` times; one it
+    /// can't be reached for is kept, whatever the attempts.
+    pub fn failed(&mut self, refused: bool, now_s: u64) -> bool {
+        self.attempts += 1;
+        self.next_attempt_s = now_s + backoff_s(self.attempts);
+        self.refusals = if refused { self.refusals + 1 } else { 0 };
+        if self.refusals < MAX_ATTEMPTS {
+            return false;
+        }
+        self.refusals = 0;
+        true
+    }
+}
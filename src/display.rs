@@ -1,43 +1,864 @@
 use std::sync::Arc;
 use std::sync::Mutex;
 
+use embedded_graphics::mono_font::ascii::{FONT_5X8, FONT_9X15};
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{Line, PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Baseline, Text};
+#[cfg(not(feature = "headless"))]
 use esp_idf_svc::hal::gpio;
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
 use esp_idf_svc::hal::i2c;
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
 use esp_idf_svc::hal::peripheral::Peripheral;
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
 use esp_idf_svc::hal::prelude::*;
-use ssd1306::mode::DisplayConfig;
-use ssd1306::mode::TerminalDisplaySize;
-use ssd1306::mode::TerminalMode;
-use ssd1306::prelude::Brightness;
-use ssd1306::prelude::WriteOnlyDataCommand;
-use ssd1306::I2CDisplayInterface;
-use ssd1306::Ssd1306;
-
-pub struct DisplayHandler<DI: WriteOnlyDataCommand, SIZE: TerminalDisplaySize> {
-    pub display: Ssd1306<DI, SIZE, TerminalMode>,
-    pub available: bool,
+use qrcodegen::{QrCode, QrCodeEcc};
+#[cfg(all(
+    not(feature = "sh1106"),
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780"))
+))]
+use ssd1306::{
+    mode::{BufferedGraphicsMode, DisplayConfig},
+    prelude::WriteOnlyDataCommand,
+    size::DisplaySize,
+    I2CDisplayInterface, Ssd1306,
+};
+
+use crate::ambient::DimmingPolicy;
+use crate::delivery::DeliveryStats;
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+use crate::mirror;
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::units::{Amps, WattHours, Watts};
+
+pub const NVS_DISPLAY_SDA: &str = "display_sda";
+pub const NVS_DISPLAY_SCL: &str = "display_scl";
+pub const NVS_DISPLAY_ADDRESS: &str = "display_addr";
+pub const NVS_DISPLAY_KHZ: &str = "display_khz";
+pub const NVS_DISPLAY_ROTATION: &str = "display_rot";
+
+/// Height in pixels of a text row, matching the 5x8 font.
+pub const LINE_HEIGHT: i32 = 8;
+
+/// Panel size, selected at build time through the `display-*` features.
+/// Without any of them we drive the original 128x32 panel. If several are
+/// enabled (e.g. `--all-features`), the largest one wins.
+#[cfg(all(
+    not(feature = "sh1106"),
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780")),
+    feature = "display-128x64"
+))]
+pub type Ssd1306Size = ssd1306::size::DisplaySize128x64;
+#[cfg(all(
+    not(feature = "sh1106"),
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780")),
+    feature = "display-64x48",
+    not(feature = "display-128x64")
+))]
+pub type Ssd1306Size = ssd1306::size::DisplaySize64x48;
+#[cfg(all(
+    not(feature = "sh1106"),
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780")),
+    not(any(feature = "display-128x64", feature = "display-64x48"))
+))]
+pub type Ssd1306Size = ssd1306::size::DisplaySize128x32;
+
+// SH1106 modules are 128x64 (most 1.3" ones) or 128x32, the controller has
+// 132 columns of RAM and the crate takes care of the 2 column offset.
+#[cfg(all(
+    feature = "sh1106",
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780")),
+    feature = "display-128x64"
+))]
+const SH1106_SIZE: sh1106::displaysize::DisplaySize =
+    sh1106::displaysize::DisplaySize::Display128x64;
+#[cfg(all(
+    feature = "sh1106",
+    feature = "display-64x48",
+    not(feature = "display-128x64")
+))]
+compile_error!("SH1106 panels are only supported in 128x64 and 128x32 sizes");
+#[cfg(all(
+    feature = "sh1106",
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780")),
+    not(any(feature = "display-128x64", feature = "display-64x48"))
+))]
+const SH1106_SIZE: sh1106::displaysize::DisplaySize =
+    sh1106::displaysize::DisplaySize::Display128x32;
+
+/// Where each element of the main screen goes, in text rows (and pixel
+/// columns for things sharing a row).
+struct Layout {
+    readout: i32,
+    /// Text rows taken by the readout; more than one draws it in a large
+    /// font to read it from across the room.
+    readout_rows: i32,
+    /// Row for a second reading in small text, if there is room for one.
+    watts: Option<i32>,
+    bar: i32,
+    network: i32,
+    /// Pixel column and row of the webhook status.
+    webhook_status: (i32, i32),
+    energy: Option<i32>,
+    /// Row whose right end holds the status icons, possibly the readout's.
+    icons: i32,
+    sparkline: i32,
+    sparkline_rows: i32,
+}
+
+#[cfg(feature = "display-128x64")]
+const LAYOUT: Layout = Layout {
+    readout: 1,
+    readout_rows: 2,
+    watts: Some(0),
+    bar: 3,
+    network: 4,
+    webhook_status: (80, 4),
+    energy: Some(5),
+    icons: 0,
+    sparkline: 6,
+    sparkline_rows: 2,
+};
+#[cfg(all(feature = "display-64x48", not(feature = "display-128x64")))]
+const LAYOUT: Layout = Layout {
+    readout: 0,
+    readout_rows: 1,
+    watts: Some(1),
+    bar: 2,
+    network: 3,
+    webhook_status: (0, 4),
+    energy: None,
+    icons: 0,
+    sparkline: 5,
+    sparkline_rows: 1,
+};
+#[cfg(not(any(feature = "display-128x64", feature = "display-64x48")))]
+const LAYOUT: Layout = Layout {
+    readout: 0,
+    readout_rows: 1,
+    watts: None,
+    bar: 1,
+    network: 2,
+    webhook_status: (80, 2),
+    energy: None,
+    icons: 0,
+    sparkline: 3,
+    sparkline_rows: 1,
+};
+
+/// Result of the last webhook send, for the status icons.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UploadStatus {
+    /// Nothing sent yet, or no webhook configured.
+    #[default]
+    Idle,
+    Ok,
+    /// Failed, to be posted again after a backoff.
+    Retrying,
+    /// Failed and given up on.
+    Failed,
+}
+
+/// Connectivity at a glance, drawn as glyphs at the right end of the top
+/// row. Each subsystem updates its own field.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatusIcons {
+    /// Wi-Fi signal from 0 to 4 bars, `None` while not connected.
+    pub wifi_bars: Option<u8>,
+    pub upload: UploadStatus,
+    /// The setup access point is up.
+    pub setup_ap: bool,
+}
+
+impl StatusIcons {
+    pub fn set_rssi(&mut self, rssi: Option<i8>) {
+        self.wifi_bars = rssi.map(|rssi| match rssi {
+            -55.. => 4,
+            -65..=-56 => 3,
+            -75..=-66 => 2,
+            -85..=-76 => 1,
+            _ => 0,
+        });
+    }
 }
 
-impl<DI, SIZE> DisplayHandler<DI, SIZE>
+/// Width of the status icons, including the gap to their left.
+const ICONS_WIDTH: i32 = 23;
+
+// 5x7 glyphs, one byte per row with the leftmost pixel in bit 4
+const GLYPH_UPLOAD_OK: [u8; 7] = [
+    0b00100, 0b01110, 0b10101, 0b00100, 0b00100, 0b00100, 0b00100,
+];
+const GLYPH_UPLOAD_FAILED: [u8; 7] = [
+    0b00000, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b00000,
+];
+const GLYPH_UPLOAD_RETRYING: [u8; 7] = [
+    0b11111, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b11111,
+];
+const GLYPH_SETUP_AP: [u8; 7] = [
+    0b01110, 0b10001, 0b00100, 0b01010, 0b00100, 0b00100, 0b00100,
+];
+
+/// Reading shown on the first line of the live page.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PrimaryUnit {
+    Amps,
+    #[default]
+    Watts,
+    /// Energy used today.
+    Kwh,
+    /// Cost of the energy used today.
+    Cost,
+}
+
+impl PrimaryUnit {
+    pub const ALL: [PrimaryUnit; 4] = [
+        PrimaryUnit::Amps,
+        PrimaryUnit::Watts,
+        PrimaryUnit::Kwh,
+        PrimaryUnit::Cost,
+    ];
+
+    /// Name used in the settings.
+    pub fn name(self) -> &'static str {
+        match self {
+            PrimaryUnit::Amps => "A",
+            PrimaryUnit::Watts => "W",
+            PrimaryUnit::Kwh => "kWh",
+            PrimaryUnit::Cost => "cost",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PrimaryUnit> {
+        PrimaryUnit::ALL
+            .into_iter()
+            .find(|unit| unit.name() == name)
+    }
+
+    pub fn next(self) -> PrimaryUnit {
+        match self {
+            PrimaryUnit::Amps => PrimaryUnit::Watts,
+            PrimaryUnit::Watts => PrimaryUnit::Kwh,
+            PrimaryUnit::Kwh => PrimaryUnit::Cost,
+            PrimaryUnit::Cost => PrimaryUnit::Amps,
+        }
+    }
+}
+
+/// Everything shown on the main (measuring) screen.
+#[derive(Clone, Debug, Default)]
+pub struct MainScreen {
+    pub amps: Amps,
+    pub watts: Watts,
+    pub primary_unit: PrimaryUnit,
+    pub icons: StatusIcons,
+    /// Cost of `today_wh`, if a price is set.
+    pub today_cost: Option<f64>,
+    pub currency: String,
+    /// Fill of the power bar, 0..=1.
+    pub bar_fraction: f32,
+    pub budget_marker: &'static str,
+    /// IP address or connection state.
+    pub network: String,
+    pub webhook_status: &'static str,
+    pub today_wh: WattHours,
+    pub total_wh: WattHours,
+    pub rssi: Option<i8>,
+    /// Recent watts, oldest first.
+    pub sparkline: Vec<f32>,
+    pub month_wh: WattHours,
+    pub ssid: String,
+    /// Station hostname, which the router knows the web UI by.
+    pub hostname: String,
+    /// One line per active alarm, for the alarms page.
+    pub alarms: Vec<String>,
+    pub uptime_s: u64,
+    pub boots: u32,
+    pub free_heap: u32,
+    /// Why the chip last reset, e.g. `PowerOn` or `Panic`.
+    pub reset_reason: String,
+    /// Times the station got connected again after losing the network.
+    pub wifi_reconnects: u32,
+    /// HTTP status of the last webhook response.
+    pub webhook_http_status: Option<u16>,
+    pub delivery: DeliveryStats,
+}
+
+/// Pages of the normal-mode UI, cycled with short presses of BOOT.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Page {
+    #[default]
+    Live,
+    Energy,
+    Network,
+    Alarms,
+    Diagnostics,
+}
+
+impl Page {
+    pub fn next(self) -> Page {
+        match self {
+            Page::Live => Page::Energy,
+            Page::Energy => Page::Network,
+            Page::Network => Page::Alarms,
+            Page::Alarms => Page::Diagnostics,
+            Page::Diagnostics => Page::Live,
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Page::Live => "LIVE",
+            Page::Energy => "ENERGY",
+            Page::Network => "NETWORK",
+            Page::Alarms => "ALARMS",
+            Page::Diagnostics => "DIAGNOSTICS",
+        }
+    }
+}
+
+/// Keeps track of the page being shown.
+#[derive(Debug, Default)]
+pub struct PageManager {
+    page: Page,
+    /// Offset the pages are drawn at, see `burn_in`.
+    shift: Point,
+    /// The shift changed, so the framebuffer must be cleared before drawing.
+    shift_changed: bool,
+}
+
+impl PageManager {
+    pub fn page(&self) -> Page {
+        self.page
+    }
+
+    pub fn show(&mut self, page: Page) {
+        self.page = page;
+    }
+
+    /// Move the pages by a few pixels, to spread the OLED wear.
+    pub fn set_shift(&mut self, shift: Point) {
+        if shift != self.shift {
+            self.shift = shift;
+            self.shift_changed = true;
+        }
+    }
+
+    /// Draw the current page into the framebuffer. The caller still has to
+    /// `flush()`.
+    pub fn draw<D>(&mut self, d: &mut D, screen: &MainScreen) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        // The live page only redraws its own rows, which would leave the
+        // content at the previous offset behind
+        if std::mem::take(&mut self.shift_changed) {
+            d.clear(BinaryColor::Off)?;
+        }
+        let d = &mut d.translated(self.shift);
+        match self.page {
+            Page::Live => draw_main_screen(d, screen),
+            page => draw_text_page(d, page.title(), &page_lines(page, screen)),
+        }
+    }
+}
+
+/// Panel brightness, mapped onto each controller's own settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Brightness {
+    Dimmest,
+    Dim,
+    Normal,
+    Bright,
+    Brightest,
+}
+
+impl Brightness {
+    /// Every level, from the dimmest to the brightest.
+    pub const ALL: [Brightness; 5] = [
+        Brightness::Dimmest,
+        Brightness::Dim,
+        Brightness::Normal,
+        Brightness::Bright,
+        Brightness::Brightest,
+    ];
+
+    /// Name used in the settings.
+    pub fn name(self) -> &'static str {
+        match self {
+            Brightness::Dimmest => "dimmest",
+            Brightness::Dim => "dim",
+            Brightness::Normal => "normal",
+            Brightness::Bright => "bright",
+            Brightness::Brightest => "brightest",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Brightness> {
+        Brightness::ALL.into_iter().find(|b| b.name() == name)
+    }
+
+    /// Contrast register value, as used by the ssd1306 crate presets.
+    #[cfg(all(
+        feature = "sh1106",
+        not(any(feature = "headless", feature = "tm1637", feature = "hd44780"))
+    ))]
+    fn contrast(self) -> u8 {
+        match self {
+            Brightness::Dimmest => 0x00,
+            Brightness::Dim => 0x2F,
+            Brightness::Normal => 0x5F,
+            Brightness::Bright => 0x9F,
+            Brightness::Brightest => 0xFF,
+        }
+    }
+}
+
+#[cfg(not(feature = "sh1106"))]
+impl From<Brightness> for ssd1306::prelude::Brightness {
+    fn from(brightness: Brightness) -> Self {
+        match brightness {
+            Brightness::Dimmest => ssd1306::prelude::Brightness::DIMMEST,
+            Brightness::Dim => ssd1306::prelude::Brightness::DIM,
+            Brightness::Normal => ssd1306::prelude::Brightness::NORMAL,
+            Brightness::Bright => ssd1306::prelude::Brightness::BRIGHT,
+            Brightness::Brightest => ssd1306::prelude::Brightness::BRIGHTEST,
+        }
+    }
+}
+
+/// How the panel is mounted, clockwise. 180 suits a display upside down in a
+/// DIN rail enclosure; 90 and 270 turn it portrait, which the pages are not
+/// laid out for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Rotation {
+    pub const ALL: [Rotation; 4] = [
+        Rotation::Rotate0,
+        Rotation::Rotate90,
+        Rotation::Rotate180,
+        Rotation::Rotate270,
+    ];
+
+    /// Name used in the settings, the angle in degrees.
+    pub fn name(self) -> &'static str {
+        match self {
+            Rotation::Rotate0 => "0",
+            Rotation::Rotate90 => "90",
+            Rotation::Rotate180 => "180",
+            Rotation::Rotate270 => "270",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Rotation> {
+        Rotation::ALL.into_iter().find(|r| r.name() == name)
+    }
+
+    pub fn from_nvs(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> Self {
+        Self::from_name(&read_str_from_nvs_or_default(
+            nvs,
+            NVS_DISPLAY_ROTATION,
+            "0",
+        ))
+        .unwrap_or_default()
+    }
+}
+
+#[cfg(not(feature = "sh1106"))]
+impl From<Rotation> for ssd1306::rotation::DisplayRotation {
+    fn from(rotation: Rotation) -> Self {
+        match rotation {
+            Rotation::Rotate0 => ssd1306::rotation::DisplayRotation::Rotate0,
+            Rotation::Rotate90 => ssd1306::rotation::DisplayRotation::Rotate90,
+            Rotation::Rotate180 => ssd1306::rotation::DisplayRotation::Rotate180,
+            Rotation::Rotate270 => ssd1306::rotation::DisplayRotation::Rotate270,
+        }
+    }
+}
+
+#[cfg(feature = "sh1106")]
+impl From<Rotation> for sh1106::displayrotation::DisplayRotation {
+    fn from(rotation: Rotation) -> Self {
+        match rotation {
+            Rotation::Rotate0 => sh1106::displayrotation::DisplayRotation::Rotate0,
+            Rotation::Rotate90 => sh1106::displayrotation::DisplayRotation::Rotate90,
+            Rotation::Rotate180 => sh1106::displayrotation::DisplayRotation::Rotate180,
+            Rotation::Rotate270 => sh1106::displayrotation::DisplayRotation::Rotate270,
+        }
+    }
+}
+
+/// A buffered monochrome panel: drawing goes to a framebuffer that is only
+/// sent to the controller on `flush()`. Only `DisplayHandler` turns, dims or
+/// inverts it, so displays without pixels just draw and flush.
+pub trait Panel: DrawTarget<Color = BinaryColor> {
+    #[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+    fn init(&mut self) -> Result<(), Self::Error>;
+    fn clear_buffer(&mut self);
+    fn flush(&mut self) -> Result<(), Self::Error>;
+    #[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+    fn set_brightness(&mut self, brightness: Brightness) -> Result<(), Self::Error>;
+    /// Turn the panel off, keeping its contents, or back on.
+    #[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+    fn set_display_on(&mut self, on: bool) -> Result<(), Self::Error>;
+    /// Swap lit and dark pixels. Only `Coalesced` does, for every controller.
+    #[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+    fn set_inverted(&mut self, _inverted: bool) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(all(
+    not(feature = "sh1106"),
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780"))
+))]
+pub type GraphicsDisplay<DI, SIZE> = Ssd1306<DI, SIZE, BufferedGraphicsMode<SIZE>>;
+
+#[cfg(all(
+    not(feature = "sh1106"),
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780"))
+))]
+impl<DI, SIZE> Panel for GraphicsDisplay<DI, SIZE>
 where
     DI: WriteOnlyDataCommand,
-    SIZE: TerminalDisplaySize,
+    SIZE: DisplaySize,
 {
-    pub fn new(display: Ssd1306<DI, SIZE, TerminalMode>) -> Self {
+    fn init(&mut self) -> Result<(), Self::Error> {
+        DisplayConfig::init(self)
+    }
+
+    fn clear_buffer(&mut self) {
+        Ssd1306::clear_buffer(self)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ssd1306::flush(self)
+    }
+
+    fn set_brightness(&mut self, brightness: Brightness) -> Result<(), Self::Error> {
+        DisplayConfig::set_brightness(self, brightness.into())
+    }
+
+    fn set_display_on(&mut self, on: bool) -> Result<(), Self::Error> {
+        Ssd1306::set_display_on(self, on)
+    }
+}
+
+/// SH1106 graphics mode, wrapped so drawing and flushing share an error type
+/// like they do on the SSD1306.
+#[cfg(all(
+    feature = "sh1106",
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780"))
+))]
+pub struct Sh1106Display<DI>(pub sh1106::mode::GraphicsMode<DI>);
+
+#[cfg(all(
+    feature = "sh1106",
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780"))
+))]
+impl<DI: sh1106::interface::DisplayInterface> OriginDimensions for Sh1106Display<DI> {
+    fn size(&self) -> Size {
+        self.0.size()
+    }
+}
+
+#[cfg(all(
+    feature = "sh1106",
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780"))
+))]
+impl<DI: sh1106::interface::DisplayInterface> DrawTarget for Sh1106Display<DI> {
+    type Color = BinaryColor;
+    type Error = DI::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        self.0.draw_iter(pixels).map_err(|never| match never {})
+    }
+}
+
+#[cfg(all(
+    feature = "sh1106",
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780"))
+))]
+impl<DI: sh1106::interface::DisplayInterface> Panel for Sh1106Display<DI> {
+    fn init(&mut self) -> Result<(), Self::Error> {
+        self.0.init()
+    }
+
+    fn clear_buffer(&mut self) {
+        self.0.clear()
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.flush()
+    }
+
+    fn set_brightness(&mut self, brightness: Brightness) -> Result<(), Self::Error> {
+        self.0.set_contrast(brightness.contrast())
+    }
+
+    fn set_display_on(&mut self, on: bool) -> Result<(), Self::Error> {
+        // The driver doesn't expose the display on/off command, so blank the
+        // panel instead; the next frame drawn brings it back
+        if !on {
+            self.0.clear();
+            self.0.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Draws into its own frame and, on `flush()`, only passes the pixels that
+/// differ from the frame last flushed on to the panel. Pages clear and redraw
+/// every second, so without this the controller's dirty area, and the I2C
+/// transfer, would cover them whole even when only a digit changed; an
+/// unchanged frame isn't sent at all.
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+pub struct Coalesced<P> {
+    panel: P,
+    /// One bit per pixel, row after row.
+    frame: Vec<u8>,
+    /// `frame` as of the last flush, i.e. what the panel shows.
+    flushed: Vec<u8>,
+    width: u32,
+    height: u32,
+    /// Whether `frame` is flushed with its pixels swapped.
+    inverted: bool,
+}
+
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+impl<P: Panel> Coalesced<P> {
+    pub fn new(panel: P) -> Self {
+        let Size { width, height } = panel.bounding_box().size;
+        let bytes = (width * height + 7) as usize / 8;
+        let mut coalesced = Coalesced {
+            panel,
+            frame: vec![0; bytes],
+            flushed: vec![0; bytes],
+            width,
+            height,
+            inverted: false,
+        };
+        coalesced.forget_flushed();
+        coalesced
+    }
+
+    /// Start over from a blank panel buffer, so the next flush sends every
+    /// lit pixel.
+    fn forget_flushed(&mut self) {
+        self.panel.clear_buffer();
+        self.flushed.fill(0);
+        mirror::show_pixels(self.width, self.height, &self.flushed);
+    }
+}
+
+/// The pixels of `frame`, swapped by `mask`, that differ from `flushed`.
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+fn changed_pixels<'a>(
+    frame: &'a [u8],
+    flushed: &'a [u8],
+    mask: u8,
+    width: u32,
+) -> impl Iterator<Item = Pixel<BinaryColor>> + 'a {
+    frame
+        .iter()
+        .zip(flushed)
+        .enumerate()
+        .flat_map(move |(byte, (&new, &old))| {
+            let new = new ^ mask;
+            let diff = new ^ old;
+            (0..8)
+                .filter(move |bit| diff & 1 << bit != 0)
+                .map(move |bit| {
+                    let index = (byte * 8 + bit) as u32;
+                    let point = Point::new((index % width) as i32, (index / width) as i32);
+                    Pixel(point, BinaryColor::from(new & 1 << bit != 0))
+                })
+        })
+}
+
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+impl<P: Panel> Dimensions for Coalesced<P> {
+    fn bounding_box(&self) -> Rectangle {
+        self.panel.bounding_box()
+    }
+}
+
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+impl<P: Panel> DrawTarget for Coalesced<P> {
+    type Color = BinaryColor;
+    type Error = P::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (u32::try_from(point.x), u32::try_from(point.y)) else {
+                continue;
+            };
+            if x >= self.width || y >= self.height {
+                continue;
+            }
+            let index = (y * self.width + x) as usize;
+            let mask = 1 << (index % 8);
+            if color.is_on() {
+                self.frame[index / 8] |= mask;
+            } else {
+                self.frame[index / 8] &= !mask;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+impl<P: Panel> Panel for Coalesced<P> {
+    fn init(&mut self) -> Result<(), Self::Error> {
+        self.panel.init()
+    }
+
+    fn clear_buffer(&mut self) {
+        self.frame.fill(0);
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        let mask = if self.inverted { 0xFF } else { 0 };
+        let mut changed = changed_pixels(&self.frame, &self.flushed, mask, self.width).peekable();
+        if changed.peek().is_none() {
+            return Ok(());
+        }
+        // Handed over one at a time rather than collected, a full redraw
+        // changes every pixel
+        self.panel.draw_iter(changed)?;
+        self.panel.flush()?;
+        for (flushed, &new) in self.flushed.iter_mut().zip(&self.frame) {
+            *flushed = new ^ mask;
+        }
+        mirror::show_pixels(self.width, self.height, &self.flushed);
+        Ok(())
+    }
+
+    fn set_brightness(&mut self, brightness: Brightness) -> Result<(), Self::Error> {
+        self.panel.set_brightness(brightness)
+    }
+
+    fn set_display_on(&mut self, on: bool) -> Result<(), Self::Error> {
+        // The SH1106 has no off command and blanks the panel instead
+        self.forget_flushed();
+        self.panel.set_display_on(on)
+    }
+
+    fn set_inverted(&mut self, inverted: bool) -> Result<(), Self::Error> {
+        // The SH1106 driver doesn't expose the invert command, so swap the
+        // pixels here; the next flush sends all of them
+        self.inverted = inverted;
+        Ok(())
+    }
+}
+
+/// The panel driven by `init_display_i2c`, selected at build time: SSD1306 by
+/// default, SH1106 with the `sh1106` feature.
+#[cfg(all(
+    not(feature = "sh1106"),
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780"))
+))]
+pub type I2cPanel<'a> =
+    Coalesced<GraphicsDisplay<ssd1306::prelude::I2CInterface<i2c::I2cDriver<'a>>, Ssd1306Size>>;
+#[cfg(all(
+    feature = "sh1106",
+    not(any(feature = "headless", feature = "tm1637", feature = "hd44780"))
+))]
+pub type I2cPanel<'a> =
+    Coalesced<Sh1106Display<sh1106::interface::I2cInterface<i2c::I2cDriver<'a>>>>;
+
+/// What the rest of the firmware drives: a `DisplayHandler` with a panel, or
+/// `Headless` when built without one.
+pub trait Display {
+    type Panel: Panel;
+
+    /// Draw with `f` if there is a panel to draw on, see `DisplayHandler::run`.
+    fn run<E: std::fmt::Debug>(&mut self, f: impl FnOnce(&mut Self::Panel) -> Result<(), E>);
+    fn init(&mut self);
+    fn on(&mut self);
+    fn set_dimming(&mut self, dimming: DimmingPolicy);
+    fn apply_dimming(&mut self, auto_brightness: Brightness, dark: bool, blank: bool);
+    /// The reading for displays that only show a number, `None` in setup
+    /// mode. Others draw it with the pages.
+    fn show_reading(&mut self, _watts: Option<Watts>) {}
+    /// The AP credentials for character displays. Others draw the setup
+    /// screen.
+    fn show_setup(&mut self, _ap_ssid: &str, _ap_psk: &str) {}
+    /// The main screen for character displays. Others draw the pages.
+    fn show_screen(&mut self, _screen: &MainScreen) {}
+}
+
+/// The display built into the firmware: none with the `headless` feature, a
+/// 7-segment module with `tm1637`, a character LCD with `hd44780`, the OLED
+/// otherwise.
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+pub type AppDisplay<'a> = DisplayHandler<I2cPanel<'a>>;
+#[cfg(feature = "headless")]
+pub type AppDisplay<'a> = Headless;
+#[cfg(all(feature = "tm1637", not(feature = "headless")))]
+pub type AppDisplay<'a> = crate::tm1637::Tm1637<'a>;
+#[cfg(all(
+    feature = "hd44780",
+    not(any(feature = "headless", feature = "tm1637"))
+))]
+pub type AppDisplay<'a> = crate::hd44780::Hd44780<'a>;
+
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+pub struct DisplayHandler<P: Panel> {
+    pub display: P,
+    pub available: bool,
+    /// Brightness last applied to the panel.
+    brightness: Brightness,
+    /// Whether the panel is switched on; drawing is skipped while it's off.
+    is_on: bool,
+    /// How the brightness is chosen, see `apply_dimming`.
+    dimming: DimmingPolicy,
+    /// Whether the panel was last set inverted, for the night display.
+    inverted: bool,
+}
+
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+impl<P> DisplayHandler<P>
+where
+    P: Panel,
+    P::Error: std::fmt::Debug,
+{
+    pub fn new(display: P) -> Self {
         DisplayHandler {
             display,
             available: false,
+            brightness: Brightness::Dim,
+            is_on: true,
+            dimming: DimmingPolicy::default(),
+            inverted: false,
         }
     }
 
     // Run a FnOnce closure on the display, if it is available
     // Set as unavailable if the closure panics
+    // Drawing only touches the framebuffer, so the closure must call `flush()`
+    // Does nothing while the panel is switched off
     #[inline(always)]
-    pub fn run<E: std::fmt::Debug>(
-        &mut self,
-        f: impl FnOnce(&mut Ssd1306<DI, SIZE, TerminalMode>) -> Result<(), E>,
-    ) {
-        if self.available {
+    pub fn run<E: std::fmt::Debug>(&mut self, f: impl FnOnce(&mut P) -> Result<(), E>) {
+        if self.available && self.is_on {
             let result =
                 std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&mut self.display)));
             if result.is_err() {
@@ -55,69 +876,899 @@ where
     }
 }
 
-impl<DI: WriteOnlyDataCommand, SIZE: TerminalDisplaySize> Drop for DisplayHandler<DI, SIZE> {
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+impl<P: Panel> Drop for DisplayHandler<P> {
     fn drop(&mut self) {
-        self.run(|d| d.clear());
+        if self.available {
+            self.display.clear_buffer();
+            // Nothing left to do about an error at this point
+            let _ = self.display.flush();
+        }
     }
 }
 
-impl<DI, SIZE> DisplayHandler<DI, SIZE>
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+impl<P> DisplayHandler<P>
 where
-    DI: WriteOnlyDataCommand,
-    SIZE: TerminalDisplaySize,
+    P: Panel,
+    P::Error: std::fmt::Debug,
 {
     #[inline(always)]
-    pub fn init(&mut self, brightness: Brightness) {
+    pub fn init(&mut self) {
         if self.available {
             return;
         }
 
         if self.display.init().is_ok() {
             self.available = true;
-            self.run(|d| d.clear());
+            self.run(|d| {
+                d.clear_buffer();
+                d.flush()
+            });
+            let brightness = self.brightness;
+            self.run(|d| d.set_brightness(brightness));
+        }
+    }
+
+    pub fn set_dimming(&mut self, dimming: DimmingPolicy) {
+        self.dimming = dimming;
+    }
+
+    /// Apply the dimming policy. `auto_brightness` and `dark` come from the
+    /// light sensor, if any; `blank` turns the panel off regardless, e.g.
+    /// after the idle timeout.
+    pub fn apply_dimming(&mut self, auto_brightness: Brightness, dark: bool, blank: bool) {
+        match self.dimming.brightness(auto_brightness, dark) {
+            Some(brightness) if !blank => {
+                self.on();
+                self.set_brightness(brightness);
+                self.set_inverted(self.dimming.is_inverted(dark));
+            }
+            _ => self.off(),
+        }
+    }
+
+    fn set_inverted(&mut self, inverted: bool) {
+        if self.available && inverted != self.inverted {
+            self.inverted = inverted;
+            self.run(|d| d.set_inverted(inverted));
+        }
+    }
+
+    /// Change the brightness, only talking to the panel when it changes.
+    fn set_brightness(&mut self, brightness: Brightness) {
+        if self.available && brightness != self.brightness {
+            log::info!("Display brightness: {}", brightness.name());
+            self.brightness = brightness;
             self.run(|d| d.set_brightness(brightness));
         }
     }
+
+    /// Switch the panel off; `run` does nothing until `on()` is called.
+    pub fn off(&mut self) {
+        if self.is_on {
+            self.run(|d| d.set_display_on(false));
+            self.is_on = false;
+        }
+    }
+
+    /// Switch the panel back on after `off()`.
+    pub fn on(&mut self) {
+        if !self.is_on {
+            self.is_on = true;
+            self.run(|d| d.set_display_on(true));
+        }
+    }
 }
 
-pub fn init_display_i2c<'a, I2C: i2c::I2c, SIZE: TerminalDisplaySize>(
-    sda: impl Peripheral<P = impl gpio::InputPin + gpio::OutputPin> + 'a,
-    scl: impl Peripheral<P = impl gpio::InputPin + gpio::OutputPin> + 'a,
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+impl<P> Display for DisplayHandler<P>
+where
+    P: Panel,
+    P::Error: std::fmt::Debug,
+{
+    type Panel = P;
+
+    fn run<E: std::fmt::Debug>(&mut self, f: impl FnOnce(&mut P) -> Result<(), E>) {
+        DisplayHandler::run(self, f)
+    }
+
+    fn init(&mut self) {
+        DisplayHandler::init(self)
+    }
+
+    fn on(&mut self) {
+        DisplayHandler::on(self)
+    }
+
+    fn set_dimming(&mut self, dimming: DimmingPolicy) {
+        DisplayHandler::set_dimming(self, dimming)
+    }
+
+    fn apply_dimming(&mut self, auto_brightness: Brightness, dark: bool, blank: bool) {
+        DisplayHandler::apply_dimming(self, auto_brightness, dark, blank)
+    }
+}
+
+/// No display attached: nothing is ever drawn.
+#[cfg(feature = "headless")]
+pub struct Headless;
+
+/// The panel of displays without pixels. It has no values, so there is never
+/// one to draw on.
+#[cfg(any(feature = "headless", feature = "tm1637", feature = "hd44780"))]
+pub enum NoPanel {}
+
+#[cfg(any(feature = "headless", feature = "tm1637", feature = "hd44780"))]
+impl Dimensions for NoPanel {
+    fn bounding_box(&self) -> Rectangle {
+        match *self {}
+    }
+}
+
+#[cfg(any(feature = "headless", feature = "tm1637", feature = "hd44780"))]
+impl DrawTarget for NoPanel {
+    type Color = BinaryColor;
+    type Error = std::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, _pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        match *self {}
+    }
+}
+
+#[cfg(any(feature = "headless", feature = "tm1637", feature = "hd44780"))]
+impl Panel for NoPanel {
+    fn clear_buffer(&mut self) {
+        match *self {}
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "headless")]
+impl Display for Headless {
+    type Panel = NoPanel;
+
+    fn run<E: std::fmt::Debug>(&mut self, _f: impl FnOnce(&mut NoPanel) -> Result<(), E>) {}
+
+    fn init(&mut self) {}
+
+    fn on(&mut self) {}
+
+    fn set_dimming(&mut self, _dimming: DimmingPolicy) {}
+
+    fn apply_dimming(&mut self, _auto_brightness: Brightness, _dark: bool, _blank: bool) {}
+}
+
+fn text_style() -> MonoTextStyle<'static, BinaryColor> {
+    MonoTextStyle::new(&FONT_5X8, BinaryColor::On)
+}
+
+/// 15 pixels tall, so it spans two text rows.
+fn large_text_style() -> MonoTextStyle<'static, BinaryColor> {
+    MonoTextStyle::new(&FONT_9X15, BinaryColor::On)
+}
+
+/// Clear a text row from a pixel column up to the right edge.
+fn clear_row_from<D>(d: &mut D, column: i32, row: i32) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let width = d.bounding_box().size.width as i32;
+    Rectangle::new(
+        Point::new(column, row * LINE_HEIGHT),
+        Size::new((width - column).max(0) as u32, LINE_HEIGHT as u32),
+    )
+    .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+    .draw(d)
+}
+
+fn clear_row<D>(d: &mut D, row: i32) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    clear_row_from(d, 0, row)
+}
+
+/// Replace the contents of a text row.
+pub fn write_line<D>(d: &mut D, row: i32, text: &str) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    write_at(d, 0, row, text)
+}
+
+/// Replace the contents of a text row from a pixel column onwards, keeping
+/// whatever is drawn to the left of it.
+pub fn write_at<D>(d: &mut D, column: i32, row: i32, text: &str) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    clear_row_from(d, column, row)?;
+    Text::with_baseline(
+        text,
+        Point::new(column, row * LINE_HEIGHT),
+        text_style(),
+        Baseline::Top,
+    )
+    .draw(d)?;
+    Ok(())
+}
+
+/// Replace the contents of `rows` text rows with a single line in the large
+/// font.
+fn write_large<D>(d: &mut D, row: i32, rows: i32, text: &str) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    for row in row..row + rows {
+        clear_row(d, row)?;
+    }
+    Text::with_baseline(
+        text,
+        Point::new(0, row * LINE_HEIGHT),
+        large_text_style(),
+        Baseline::Top,
+    )
+    .draw(d)?;
+    Ok(())
+}
+
+/// Draw a full-width horizontal bar on a text row, filled up to `fraction`
+/// (clamped to 0..=1).
+pub fn draw_bar<D>(d: &mut D, row: i32, fraction: f32) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    clear_row(d, row)?;
+    let width = d.bounding_box().size.width;
+    let top = row * LINE_HEIGHT + 1;
+    let height = LINE_HEIGHT as u32 - 2;
+    Rectangle::new(Point::new(0, top), Size::new(width, height))
+        .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+        .draw(d)?;
+
+    let filled = ((width - 2) as f32 * fraction.clamp(0.0, 1.0)) as u32;
+    if filled > 0 {
+        Rectangle::new(Point::new(1, top + 1), Size::new(filled, height - 2))
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+            .draw(d)?;
+    }
+    Ok(())
+}
+
+/// Draw a sparkline of `values` (oldest first) across `rows` text rows
+/// starting at `row`, one column per value and right-aligned so the newest
+/// value is at the edge. The vertical scale adapts to the largest value shown.
+pub fn draw_sparkline<D>(d: &mut D, row: i32, rows: i32, values: &[f32]) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    for r in row..row + rows {
+        clear_row(d, r)?;
+    }
+    let width = d.bounding_box().size.width as usize;
+    let values = &values[values.len().saturating_sub(width)..];
+    // Keep a floor on the scale so noise on an idle circuit stays flat
+    let max = values.iter().cloned().fold(1.0f32, f32::max);
+
+    let bottom = (row + rows) * LINE_HEIGHT - 1;
+    let left = (width - values.len()) as i32;
+    for (i, value) in values.iter().enumerate() {
+        let height = ((value / max) * (rows * LINE_HEIGHT - 1) as f32).round() as i32;
+        let x = left + i as i32;
+        Line::new(Point::new(x, bottom), Point::new(x, bottom - height))
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(d)?;
+    }
+    Ok(())
+}
+
+fn format_duration(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86_400, seconds / 3600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        format!("{}d{:02}h{:02}m", days, hours, minutes)
+    } else {
+        format!("{}h{:02}m{:02}s", hours, minutes, seconds % 60)
+    }
+}
+
+/// Webhook status for the main screen, which turns into a count once
+/// deliveries keep failing so a dead collector doesn't go unnoticed.
+fn webhook_indicator(screen: &MainScreen) -> String {
+    if screen.delivery.is_degraded() && screen.webhook_status != "SENDING" {
+        format!("FAIL{}", screen.delivery.consecutive_failures.min(999))
+    } else {
+        screen.webhook_status.to_string()
+    }
+}
+
+/// Lines of the text-only pages, most important first since small panels
+/// only show the first few.
+fn page_lines(page: Page, screen: &MainScreen) -> Vec<String> {
+    match page {
+        Page::Live => Vec::new(),
+        Page::Energy => vec![
+            format!("Today {}", screen.today_wh),
+            format!("Month {}", screen.month_wh),
+            format!("Total {}", screen.total_wh),
+        ],
+        Page::Network => vec![
+            screen.network.clone(),
+            format!("Host {}", screen.hostname),
+            format!("Up {}", format_duration(screen.uptime_s)),
+            match screen.rssi {
+                Some(rssi) => format!("RSSI {}dBm", rssi),
+                None => "RSSI -".to_string(),
+            },
+            format!("Hook {}", screen.webhook_status),
+            screen.ssid.clone(),
+        ],
+        Page::Alarms if screen.alarms.is_empty() => vec!["No alarms".to_string()],
+        Page::Alarms => screen.alarms.clone(),
+        Page::Diagnostics => vec![
+            format!("Up {}", format_duration(screen.uptime_s)),
+            format!("Boot #{} Heap {}k", screen.boots, screen.free_heap / 1024),
+            format!("Reset {}", screen.reset_reason),
+            format!("Reconnects {}", screen.wifi_reconnects),
+            match screen.webhook_http_status {
+                Some(status) => format!("Hook HTTP {}", status),
+                None => "Hook HTTP -".to_string(),
+            },
+            format!(
+                "Sent {} Fail {}",
+                screen.delivery.successes, screen.delivery.failures
+            ),
+            format!("FW {}", crate::FIRMWARE_VERSION),
+        ],
+    }
+}
+
+/// Address of the setup page while in setup mode, as served on the AP.
+pub const SETUP_URL: &str = "http://192.168.71.1/";
+
+/// Seconds each setup screen stays up, long enough to aim a phone at it.
+const SETUP_PAGE_S: u64 = 6;
+
+/// What the display cycles through in setup mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SetupPage {
+    /// AP name and key as text.
+    #[default]
+    Credentials,
+    /// QR code to join the AP.
+    JoinQr,
+    /// QR code linking to the setup page.
+    UrlQr,
+    /// Login for the setup page.
+    Admin,
+}
+
+impl SetupPage {
+    /// The page to show `uptime_s` seconds after boot.
+    pub fn at(uptime_s: u64) -> SetupPage {
+        match uptime_s / SETUP_PAGE_S % 4 {
+            0 => SetupPage::Credentials,
+            1 => SetupPage::JoinQr,
+            2 => SetupPage::UrlQr,
+            _ => SetupPage::Admin,
+        }
+    }
+}
+
+/// Escape a value for a `WIFI:` QR payload.
+fn escape_wifi_qr(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | ';' | ',' | ':' | '"') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The `WIFI:` payload phones understand to join a network from a QR code.
+fn wifi_qr_text(ssid: &str, psk: &str) -> String {
+    if psk.is_empty() {
+        format!("WIFI:T:nopass;S:{};;", escape_wifi_qr(ssid))
+    } else {
+        format!(
+            "WIFI:T:WPA;S:{};P:{};;",
+            escape_wifi_qr(ssid),
+            escape_wifi_qr(psk)
+        )
+    }
+}
+
+/// Draw `text` as a QR code in a `side` x `side` square at `top_left`, dark
+/// modules unlit on a lit background so phones read it like a printed one.
+/// Each module is as many pixels as fit while keeping a one module quiet zone.
+pub fn draw_qr_code<D>(d: &mut D, top_left: Point, side: u32, text: &str) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let qr = match QrCode::encode_text(text, QrCodeEcc::Low) {
+        Ok(qr) => qr,
+        Err(_) => {
+            log::warn!("Too much data for a QR code: {:?}", text);
+            return Ok(());
+        }
+    };
+    let modules = qr.size() as u32;
+    let scale = (side / (modules + 2)).max(1);
+    let margin = (side.saturating_sub(modules * scale) / 2) as i32;
+
+    Rectangle::new(top_left, Size::new(side, side))
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(d)?;
+    for y in 0..qr.size() {
+        for x in 0..qr.size() {
+            if qr.get_module(x, y) {
+                let offset = Point::new(x * scale as i32, y * scale as i32);
+                Rectangle::new(
+                    top_left + Point::new(margin, margin) + offset,
+                    Size::new(scale, scale),
+                )
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+                .draw(d)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Draw a QR code filling the screen height on the left, with a caption to
+/// its right when there is room for one.
+fn draw_qr_page<D>(d: &mut D, text: &str, caption: &[&str]) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let Size { width, height } = d.bounding_box().size;
+    let side = height.min(width);
+    // Room for at least 6 characters of caption, otherwise center the code
+    let has_caption = width >= side + 2 + 6 * 5;
+    let left = if has_caption {
+        0
+    } else {
+        (width - side) as i32 / 2
+    };
+    draw_qr_code(d, Point::new(left, 0), side, text)?;
+
+    if has_caption {
+        let column = side as i32 + 2;
+        for (row, line) in (0..height as i32 / LINE_HEIGHT).zip(caption) {
+            write_at(d, column, row, line)?;
+        }
+    }
+    Ok(())
+}
+
+/// Draw one of the setup mode screens for the AP `ssid` and `psk`.
+/// Lines of the setup screen, from `cfg.toml` so integrators can brand it.
+/// `{{ssid}}`, `{{key}}` and `{{version}}` are filled in.
+#[derive(Clone, Copy, Debug)]
+pub struct SetupText {
+    pub title: &'static str,
+    pub key_label: &'static str,
+    /// Extra line below the credentials, e.g. a device label; empty for none.
+    pub label: &'static str,
+}
+
+fn fill_setup_text(template: &str, ssid: &str, psk: &str) -> String {
+    template
+        .replace("{{ssid}}", ssid)
+        .replace("{{key}}", psk)
+        .replace("{{version}}", crate::FIRMWARE_VERSION)
+}
+
+pub fn draw_setup_screen<D>(
+    d: &mut D,
+    page: SetupPage,
+    ssid: &str,
+    psk: &str,
+    text: &SetupText,
+    admin_password: Option<&str>,
+    icons: &StatusIcons,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    d.clear(BinaryColor::Off)?;
+    match page {
+        SetupPage::Credentials => {
+            write_line(d, 0, &fill_setup_text(text.title, ssid, psk))?;
+            draw_status_icons(d, 0, icons)?;
+            write_line(d, 1, ssid)?;
+            write_line(d, 2, &fill_setup_text(text.key_label, ssid, psk))?;
+            write_line(d, 3, if psk.is_empty() { "(open)" } else { psk })?;
+            // Only fits on panels taller than 32 pixels
+            if !text.label.is_empty() {
+                write_line(d, 4, &fill_setup_text(text.label, ssid, psk))?;
+            }
+            Ok(())
+        }
+        SetupPage::JoinQr => draw_qr_page(
+            d,
+            &wifi_qr_text(ssid, psk),
+            &["Scan to", "join the", "setup AP"],
+        ),
+        SetupPage::UrlQr => draw_qr_page(d, SETUP_URL, &["Then scan", "to open", "setup"]),
+        SetupPage::Admin => {
+            write_line(d, 0, "SETUP LOGIN:")?;
+            draw_status_icons(d, 0, icons)?;
+            write_line(d, 1, crate::auth::USER)?;
+            write_line(d, 2, "PASSWORD:")?;
+            // Only the generated one, see `auth::generated_password`
+            write_line(d, 3, admin_password.unwrap_or("(changed)"))
+        }
+    }
+}
+
+fn draw_glyph<D>(d: &mut D, top_left: Point, glyph: &[u8; 7]) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let pixels = glyph.iter().zip(0..).flat_map(|(bits, y)| {
+        (0..5)
+            .filter(move |x| bits & (0b10000 >> x) != 0)
+            .map(move |x| Pixel(top_left + Point::new(x, y), BinaryColor::On))
+    });
+    d.draw_iter(pixels)
+}
+
+/// Draw the status icons right-aligned on a text row, replacing whatever was
+/// there: setup AP, webhook result and Wi-Fi bars, from left to right.
+pub fn draw_status_icons<D>(d: &mut D, row: i32, icons: &StatusIcons) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let right = d.bounding_box().size.width as i32;
+    let top = row * LINE_HEIGHT;
+    Rectangle::new(
+        Point::new(right - ICONS_WIDTH, top),
+        Size::new(ICONS_WIDTH as u32, LINE_HEIGHT as u32),
+    )
+    .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+    .draw(d)?;
+
+    // Four bars of growing height, the missing ones reduced to a dot
+    let bars_left = right - 7;
+    for bar in 0..4 {
+        let height = match icons.wifi_bars {
+            Some(bars) if bar < bars as i32 => 1 + 2 * bar,
+            _ => 1,
+        };
+        Rectangle::new(
+            Point::new(bars_left + 2 * bar, top + 7 - height),
+            Size::new(1, height as u32),
+        )
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(d)?;
+    }
+
+    let upload_left = bars_left - 7;
+    match icons.upload {
+        UploadStatus::Idle => (),
+        UploadStatus::Ok => draw_glyph(d, Point::new(upload_left, top), &GLYPH_UPLOAD_OK)?,
+        UploadStatus::Retrying => {
+            draw_glyph(d, Point::new(upload_left, top), &GLYPH_UPLOAD_RETRYING)?
+        }
+        UploadStatus::Failed => draw_glyph(d, Point::new(upload_left, top), &GLYPH_UPLOAD_FAILED)?,
+    }
+    if icons.setup_ap {
+        draw_glyph(d, Point::new(upload_left - 7, top), &GLYPH_SETUP_AP)?;
+    }
+    Ok(())
+}
+
+/// Shown for a moment at boot: what is running and under which hostname.
+pub fn draw_splash<D>(d: &mut D, version: &str, hostname: &str) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    d.clear(BinaryColor::Off)?;
+    write_line(d, 0, "Watt-o-meter")?;
+    write_line(d, 1, &format!("v{}", version))?;
+    write_line(d, 2, hostname)
+}
+
+/// Shown while a firmware update is being received.
+pub fn draw_update_screen<D>(d: &mut D, percent: u8) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    d.clear(BinaryColor::Off)?;
+    write_line(d, 0, "UPDATING")?;
+    write_line(d, 1, &format!("FIRMWARE {}%", percent))?;
+    draw_bar(d, 2, percent as f32 / 100.0)
+}
+
+/// Clear the screen and draw a title row followed by `lines`, as many as fit.
+pub fn draw_text_page<D>(d: &mut D, title: &str, lines: &[String]) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    d.clear(BinaryColor::Off)?;
+    write_line(d, 0, title)?;
+    let rows = d.bounding_box().size.height as i32 / LINE_HEIGHT;
+    for (row, line) in (1..rows).zip(lines) {
+        write_line(d, row, line)?;
+    }
+    Ok(())
+}
+
+fn primary_readout(screen: &MainScreen) -> String {
+    match screen.primary_unit {
+        PrimaryUnit::Amps => format!("{:.3}", screen.amps),
+        PrimaryUnit::Watts => format!("{:.1}", screen.watts),
+        PrimaryUnit::Kwh => format!("{:.2}kWh/d", screen.today_wh.kwh()),
+        PrimaryUnit::Cost => match screen.today_cost {
+            Some(cost) => format!("{:.2}{}/d", cost, screen.currency),
+            None => "No price set".to_string(),
+        },
+    }
+}
+
+/// Draw the main screen into the framebuffer using the layout for the
+/// configured panel size. The caller still has to `flush()`.
+pub fn draw_main_screen<D>(d: &mut D, screen: &MainScreen) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    if LAYOUT.readout_rows > 1 {
+        write_large(
+            d,
+            LAYOUT.readout,
+            LAYOUT.readout_rows,
+            &primary_readout(screen),
+        )?;
+    } else {
+        write_line(d, LAYOUT.readout, &primary_readout(screen))?;
+    }
+    if let Some(watts_row) = LAYOUT.watts {
+        // Room for a second reading: keep the power in sight
+        let secondary = match screen.primary_unit {
+            PrimaryUnit::Watts => format!("{:.3}", screen.amps),
+            _ => format!("{:.1}", screen.watts),
+        };
+        write_line(d, watts_row, &secondary)?;
+    }
+    // The readout row was just cleared, so only a marker needs drawing; on
+    // narrow panels it may cover the end of the readout
+    if !screen.budget_marker.is_empty() {
+        let mut marker_column = d.bounding_box().size.width as i32 - 10;
+        if LAYOUT.icons == LAYOUT.readout {
+            marker_column -= ICONS_WIDTH;
+        }
+        write_at(d, marker_column, LAYOUT.readout, screen.budget_marker)?;
+    }
+    draw_status_icons(d, LAYOUT.icons, &screen.icons)?;
+    draw_bar(d, LAYOUT.bar, screen.bar_fraction)?;
+
+    write_line(d, LAYOUT.network, &screen.network)?;
+    let (status_column, status_row) = LAYOUT.webhook_status;
+    write_at(d, status_column, status_row, &webhook_indicator(screen))?;
+
+    if let Some(row) = LAYOUT.energy {
+        write_line(
+            d,
+            row,
+            &format!("Day {} Tot {}", screen.today_wh, screen.total_wh),
+        )?;
+    }
+    draw_sparkline(
+        d,
+        LAYOUT.sparkline,
+        LAYOUT.sparkline_rows,
+        &screen.sparkline,
+    )
+}
+
+/// Pins, address and speed of the display bus: `cfg.toml` values unless
+/// overridden on the setup page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DisplayBus {
+    pub sda: u8,
+    pub scl: u8,
+    pub address: u8,
+    pub khz: u32,
+}
+
+/// The original wiring, used when neither NVS nor `cfg.toml` hold usable
+/// settings.
+impl Default for DisplayBus {
+    fn default() -> Self {
+        DisplayBus {
+            sda: 25,
+            scl: 14,
+            address: 0x3C,
+            khz: 100,
+        }
+    }
+}
+
+impl DisplayBus {
+    pub fn from_nvs(app_config: &crate::Config, nvs: &nvs::EspNvs<nvs::NvsDefault>) -> Self {
+        let configured = DisplayBus {
+            sda: app_config.display_sda,
+            scl: app_config.display_scl,
+            address: app_config.display_address,
+            khz: app_config.display_i2c_khz,
+        };
+        let read = |key, default: String| read_str_from_nvs_or_default(nvs, key, &default);
+        let bus = DisplayBus::parse(
+            &read(NVS_DISPLAY_SDA, configured.sda.to_string()),
+            &read(NVS_DISPLAY_SCL, configured.scl.to_string()),
+            &read(NVS_DISPLAY_ADDRESS, configured.address.to_string()),
+            &read(NVS_DISPLAY_KHZ, configured.khz.to_string()),
+        );
+        for candidate in [bus, Ok(configured)] {
+            match candidate.and_then(|bus| bus.validate().map(|()| bus)) {
+                Ok(bus) => return bus,
+                Err(reason) => log::warn!("Ignoring display bus settings: {}", reason),
+            }
+        }
+        DisplayBus::default()
+    }
+
+    /// Parse the settings as entered on the setup page; the address may be
+    /// given in hex with a `0x` prefix.
+    pub fn parse(sda: &str, scl: &str, address: &str, khz: &str) -> Result<Self, String> {
+        let address = match address.trim().strip_prefix("0x") {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None => address.trim().parse(),
+        };
+        Ok(DisplayBus {
+            sda: sda
+                .trim()
+                .parse()
+                .map_err(|_| format!("bad SDA pin {:?}", sda))?,
+            scl: scl
+                .trim()
+                .parse()
+                .map_err(|_| format!("bad SCL pin {:?}", scl))?,
+            address: address.map_err(|_| "bad I2C address".to_string())?,
+            khz: khz
+                .trim()
+                .parse()
+                .map_err(|_| format!("bad bus speed {:?}", khz))?,
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for pin in [self.sda, self.scl] {
+            if !usable_bus_pin(pin) {
+                return Err(format!("GPIO{} is in use or can't drive the bus", pin));
+            }
+        }
+        if self.sda == self.scl {
+            return Err("SDA and SCL are the same pin".to_string());
+        }
+        if !(0x08..=0x77).contains(&self.address) {
+            return Err(format!("{:#04x} is not a 7-bit I2C address", self.address));
+        }
+        if !(10..=1000).contains(&self.khz) {
+            return Err(format!("{} kHz is outside 10-1000 kHz", self.khz));
+        }
+        Ok(())
+    }
+
+    /// The SDA and SCL pins.
+    #[cfg(not(feature = "headless"))]
+    pub fn pins(&self) -> (gpio::AnyIOPin, gpio::AnyIOPin) {
+        // Safe: `validate` only lets through pins the rest of the firmware
+        // leaves alone
+        unsafe {
+            (
+                gpio::AnyIOPin::new(self.sda as i32),
+                gpio::AnyIOPin::new(self.scl as i32),
+            )
+        }
+    }
+}
+
+/// Whether a GPIO can drive the display bus: it has to be an output not
+/// used by anything else. Strapping pins are left out, since the bus pull-ups
+/// could change the boot mode.
+fn usable_bus_pin(pin: u8) -> bool {
+    match pin {
+        21 | 22 => !cfg!(feature = "mcp23017"),
+        26 | 27 => !cfg!(feature = "hw-394-prototype"),
+        4 | 13 | 14 | 16..=19 | 23 | 25 | 32 | 33 => true,
+        _ => false,
+    }
+}
+
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+pub fn init_display_i2c<'a, I2C: i2c::I2c>(
+    bus: DisplayBus,
+    rotation: Rotation,
     i2c: impl Peripheral<P = I2C> + 'a,
-    size: SIZE,
-) -> Result<DisplayHandler<ssd1306::prelude::I2CInterface<i2c::I2cDriver<'a>>, SIZE>, esp_idf_svc::sys::EspError> {
+) -> Result<DisplayHandler<I2cPanel<'a>>, esp_idf_svc::sys::EspError> {
     // Display
-    let i2c_config = i2c::I2cConfig::new().baudrate(100.kHz().into());
+    let (sda, scl) = bus.pins();
+    let i2c_config = i2c::I2cConfig::new().baudrate(bus.khz.kHz().into());
     let i2c = i2c::I2cDriver::new(i2c, sda, scl, &i2c_config)?;
-    let interface = I2CDisplayInterface::new(i2c);
-    let mut display_handler = DisplayHandler::new(
-        Ssd1306::new(interface, size, ssd1306::rotation::DisplayRotation::Rotate0).into_terminal_mode(),
+    #[cfg(not(feature = "sh1106"))]
+    let panel = Ssd1306::new(
+        I2CDisplayInterface::new_custom_address(i2c, bus.address),
+        Ssd1306Size {},
+        rotation.into(),
+    )
+    .into_buffered_graphics_mode();
+    #[cfg(feature = "sh1106")]
+    let panel = Sh1106Display(
+        sh1106::Builder::new()
+            .with_i2c_addr(bus.address)
+            .with_size(SH1106_SIZE)
+            .with_rotation(rotation.into())
+            .connect_i2c(i2c)
+            .into(),
     );
-    display_handler.init(Brightness::DIM);
+    let mut display_handler = DisplayHandler::new(Coalesced::new(panel));
+    display_handler.init();
+    if !display_handler.available {
+        log::warn!(
+            "No display answered at {:#04x} on SDA GPIO{} / SCL GPIO{} at {} kHz; check \
+             the wiring and the display bus settings",
+            bus.address,
+            bus.sda,
+            bus.scl,
+            bus.khz
+        );
+    }
     Ok(display_handler)
 }
 
-pub trait DisplayHandlerExt<DI, SIZE> {
-    fn run<E: std::fmt::Debug>(
-        &self,
-        f: impl FnOnce(&mut Ssd1306<DI, SIZE, TerminalMode>) -> Result<(), E>,
-    );
-    fn init(&self, brightness: Brightness);
+pub trait DisplayHandlerExt<P: Panel> {
+    fn run<E: std::fmt::Debug>(&self, f: impl FnOnce(&mut P) -> Result<(), E>);
+    fn init(&self);
+    fn on(&self);
+    fn set_dimming(&self, dimming: DimmingPolicy);
+    fn apply_dimming(&self, auto_brightness: Brightness, dark: bool, blank: bool);
+    fn show_reading(&self, watts: Option<Watts>);
+    fn show_setup(&self, ap_ssid: &str, ap_psk: &str);
+    fn show_screen(&self, screen: &MainScreen);
 }
 
-impl<DI, SIZE> DisplayHandlerExt<DI, SIZE> for Arc<Mutex<DisplayHandler<DI, SIZE>>>
-where
-    DI: WriteOnlyDataCommand,
-    SIZE: TerminalDisplaySize,
-{
-    fn run<E: std::fmt::Debug>(
-        &self,
-        f: impl FnOnce(&mut Ssd1306<DI, SIZE, TerminalMode>) -> Result<(), E>,
-    ) {
+impl<D: Display> DisplayHandlerExt<D::Panel> for Arc<Mutex<D>> {
+    fn run<E: std::fmt::Debug>(&self, f: impl FnOnce(&mut D::Panel) -> Result<(), E>) {
         self.try_lock().unwrap().run(f);
     }
 
-    fn init(&self, brightness: Brightness) {
-        self.try_lock().unwrap().init(brightness);
+    fn init(&self) {
+        self.try_lock().unwrap().init();
     }
-}
\ No newline at end of file
+
+    fn on(&self) {
+        self.try_lock().unwrap().on();
+    }
+
+    fn set_dimming(&self, dimming: DimmingPolicy) {
+        self.try_lock().unwrap().set_dimming(dimming);
+    }
+
+    fn apply_dimming(&self, auto_brightness: Brightness, dark: bool, blank: bool) {
+        self.try_lock()
+            .unwrap()
+            .apply_dimming(auto_brightness, dark, blank);
+    }
+
+    fn show_reading(&self, watts: Option<Watts>) {
+        self.try_lock().unwrap().show_reading(watts);
+    }
+
+    fn show_setup(&self, ap_ssid: &str, ap_psk: &str) {
+        self.try_lock().unwrap().show_setup(ap_ssid, ap_psk);
+    }
+
+    fn show_screen(&self, screen: &MainScreen) {
+        self.try_lock().unwrap().show_screen(screen);
+    }
+}
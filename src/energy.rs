@@ -0,0 +1,163 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use esp_idf_svc::sys::{esp_timer_get_time, EspError};
+use once_cell::sync::Lazy;
+
+use crate::nvs;
+use crate::units::{WattHours, Watts};
+use crate::uptime::{civil_from_days, unix_now};
+
+const NVS_TOTAL: &str = "energy_total";
+const NVS_TODAY: &str = "energy_today";
+const NVS_MONTH: &str = "energy_month";
+const NVS_DAY_INDEX: &str = "energy_day";
+const NVS_MONTH_INDEX: &str = "energy_mon";
+
+// Same trade-off as the uptime counters: a power cut loses at most this much.
+const PERSIST_EVERY_S: i64 = 600;
+
+// Readings further apart than this (e.g. while in setup mode) are not
+// integrated, since we cannot know what happened in between.
+const MAX_GAP_US: i64 = 10_000_000;
+
+/// Accumulated energy, in watt-hours.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EnergyCounters {
+    pub total_wh: WattHours,
+    pub today_wh: WattHours,
+    pub month_wh: WattHours,
+    /// Days since the Unix epoch `today_wh` belongs to, or 0 if unknown.
+    pub day_index: u32,
+    /// `year * 12 + month0` `month_wh` belongs to, or 0 if unknown.
+    pub month_index: u32,
+}
+
+pub(crate) static ENERGY: Lazy<Mutex<EnergyCounters>> =
+    Lazy::new(|| Mutex::new(EnergyCounters::default()));
+
+/// Set to have the next reading zero the counters.
+pub(crate) static RESET_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Convert days since the Unix epoch into `year * 12 + month0` (UTC).
+fn month_index_from_days(days: u32) -> u32 {
+    let (year, month, _) = civil_from_days(days);
+    year * 12 + month - 1
+}
+
+fn read_wh<T: nvs::NvsPartitionId>(nvs: &nvs::EspNvs<T>, key: &str) -> Result<WattHours, EspError> {
+    Ok(WattHours(
+        nvs.get_u64(key)?.map(f64::from_bits).unwrap_or(0.0),
+    ))
+}
+
+/// Integrates power readings into the persisted energy counters.
+pub struct EnergyMeter {
+    nvs: nvs::EspNvs<nvs::NvsDefault>,
+    last_reading_us: Option<i64>,
+    last_persisted_us: i64,
+}
+
+impl EnergyMeter {
+    pub fn start(nvs: nvs::EspNvs<nvs::NvsDefault>) -> Result<Self, EspError> {
+        let counters = EnergyCounters {
+            total_wh: read_wh(&nvs, NVS_TOTAL)?,
+            today_wh: read_wh(&nvs, NVS_TODAY)?,
+            month_wh: read_wh(&nvs, NVS_MONTH)?,
+            day_index: nvs.get_u32(NVS_DAY_INDEX)?.unwrap_or(0),
+            month_index: nvs.get_u32(NVS_MONTH_INDEX)?.unwrap_or(0),
+        };
+        log::info!("Energy so far: {}", counters.total_wh);
+        *ENERGY.lock().unwrap() = counters;
+
+        Ok(EnergyMeter {
+            nvs,
+            last_reading_us: None,
+            last_persisted_us: unsafe { esp_timer_get_time() },
+        })
+    }
+
+    /// Account for `watts` having been drawn since the previous reading.
+    pub fn add_reading(&mut self, watts: Watts) -> EnergyCounters {
+        // Safe: esp_timer is started by the IDF before app_main runs
+        let now_us = unsafe { esp_timer_get_time() };
+        let elapsed_us = self.last_reading_us.map(|last| now_us - last);
+        self.last_reading_us = Some(now_us);
+
+        let mut counters = ENERGY.lock().unwrap();
+        if RESET_REQUESTED.swap(false, Ordering::SeqCst) {
+            log::info!("Energy counters reset, {} so far", counters.total_wh);
+            counters.total_wh = WattHours::default();
+            counters.today_wh = WattHours::default();
+            counters.month_wh = WattHours::default();
+            self.persist(&counters);
+        }
+        Self::roll_over(&mut counters);
+
+        if let Some(elapsed_us) = elapsed_us.filter(|us| *us <= MAX_GAP_US) {
+            let wh = watts.over(Duration::from_micros(elapsed_us as u64));
+            counters.total_wh += wh;
+            counters.today_wh += wh;
+            counters.month_wh += wh;
+        }
+
+        if now_us - self.last_persisted_us >= PERSIST_EVERY_S * 1_000_000 {
+            self.last_persisted_us = now_us;
+            self.persist(&counters);
+        }
+
+        *counters
+    }
+
+    /// Reset the daily and monthly counters when the (UTC) calendar moves on.
+    fn roll_over(counters: &mut EnergyCounters) {
+        let Some(now) = unix_now() else {
+            return;
+        };
+        let day_index = (now / 86_400) as u32;
+        let month_index = month_index_from_days(day_index);
+
+        if counters.day_index != day_index {
+            if counters.day_index != 0 {
+                log::info!("New day, yesterday used {}", counters.today_wh);
+            }
+            counters.day_index = day_index;
+            counters.today_wh = WattHours::default();
+        }
+        if counters.month_index != month_index {
+            counters.month_index = month_index;
+            counters.month_wh = WattHours::default();
+        }
+    }
+
+    fn persist(&mut self, counters: &EnergyCounters) {
+        let results = [
+            (
+                NVS_TOTAL,
+                self.nvs.set_u64(NVS_TOTAL, counters.total_wh.0.to_bits()),
+            ),
+            (
+                NVS_TODAY,
+                self.nvs.set_u64(NVS_TODAY, counters.today_wh.0.to_bits()),
+            ),
+            (
+                NVS_MONTH,
+                self.nvs.set_u64(NVS_MONTH, counters.month_wh.0.to_bits()),
+            ),
+            (
+                NVS_DAY_INDEX,
+                self.nvs.set_u32(NVS_DAY_INDEX, counters.day_index),
+            ),
+            (
+                NVS_MONTH_INDEX,
+                self.nvs.set_u32(NVS_MONTH_INDEX, counters.month_index),
+            ),
+        ];
+        for (key, result) in results {
+            if let Err(err) = result {
+                log::warn!("Error setting {} in NVS: {:?}", key, err);
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Error responses shared by the handlers: the matching status with a JSON
+//! body such as `{"error":"Missing or invalid mode","code":400}`, so clients
+//! can tell failures apart without parsing the message.
+
+use esp_idf_svc::http::server::{EspHttpConnection, Request};
+use esp_idf_svc::io::EspIOError;
+use serde_json::json;
+
+/// Reason phrase of the statuses the handlers answer with.
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    }
+}
+
+/// Answer `req` with `status` and `message`.
+pub fn respond(
+    req: Request<&mut EspHttpConnection<'_>>,
+    status: u16,
+    message: &str,
+) -> Result<(), EspIOError> {
+    respond_with(req, status, message, &[])
+}
+
+/// Like `respond`, with extra headers such as `Retry-After`.
+pub fn respond_with(
+    req: Request<&mut EspHttpConnection<'_>>,
+    status: u16,
+    message: &str,
+    headers: &[(&str, &str)],
+) -> Result<(), EspIOError> {
+    let mut all_headers = vec![("Content-Type", "application/json")];
+    all_headers.extend_from_slice(headers);
+    let body = json!({ "error": message, "code": status });
+    req.into_response(status, Some(reason(status)), &all_headers)?
+        .write(body.to_string().as_bytes())?;
+    Ok(())
+}
+
+/// Answer a request whose handler failed with `err` part way, before
+/// anything was sent.
+pub fn internal(
+    req: Request<&mut EspHttpConnection<'_>>,
+    err: impl std::fmt::Debug,
+) -> Result<(), EspIOError> {
+    log::warn!("Error handling {}: {:?}", req.uri(), err);
+    respond(req, 500, &format!("{:?}", err))
+}
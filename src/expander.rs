@@ -0,0 +1,117 @@
+//! MCP23017 I2C GPIO expander, for buttons and relays beyond the few ESP32
+//! pins left free by the ADC channels and the display.
+//!
+//! Port A is used for buttons wired to GND, with the internal pull-ups, and
+//! port B drives relays (through a transistor or a relay module).
+
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio;
+use esp_idf_svc::hal::i2c;
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::sys::EspError;
+
+/// Address with A0-A2 tied to GND.
+pub const DEFAULT_ADDRESS: u8 = 0x20;
+
+// Register addresses with IOCON.BANK = 0, the power-on default
+const IODIRA: u8 = 0x00;
+const IODIRB: u8 = 0x01;
+const GPINTENA: u8 = 0x04;
+const GPPUA: u8 = 0x0C;
+const INTFA: u8 = 0x0E;
+const INTCAPA: u8 = 0x10;
+const OLATB: u8 = 0x15;
+
+pub struct Mcp23017<'a> {
+    i2c: i2c::I2cDriver<'a>,
+    address: u8,
+    /// Whether the expander answered when configured; if not, it's ignored.
+    pub available: bool,
+    /// Last value written to the port B latch.
+    relays: u8,
+}
+
+impl<'a> Mcp23017<'a> {
+    pub fn new<I2C: i2c::I2c>(
+        sda: impl Peripheral<P = impl gpio::InputPin + gpio::OutputPin> + 'a,
+        scl: impl Peripheral<P = impl gpio::InputPin + gpio::OutputPin> + 'a,
+        i2c: impl Peripheral<P = I2C> + 'a,
+        address: u8,
+    ) -> Result<Self, EspError> {
+        let i2c_config = i2c::I2cConfig::new().baudrate(100.kHz().into());
+        let mut expander = Mcp23017 {
+            i2c: i2c::I2cDriver::new(i2c, sda, scl, &i2c_config)?,
+            address,
+            available: false,
+            relays: 0,
+        };
+        match expander.configure() {
+            Ok(()) => {
+                log::info!("MCP23017 found at {:#04x}", address);
+                expander.available = true;
+            }
+            Err(err) => log::warn!("MCP23017 not found at {:#04x}: {:?}", address, err),
+        }
+        Ok(expander)
+    }
+
+    fn configure(&mut self) -> Result<(), EspError> {
+        // Buttons: inputs with pull-ups, latching any change in INTCAPA
+        self.write_register(IODIRA, 0xFF)?;
+        self.write_register(GPPUA, 0xFF)?;
+        self.write_register(GPINTENA, 0xFF)?;
+        // Relays: all released, then switch the port to outputs
+        self.write_register(OLATB, self.relays)?;
+        self.write_register(IODIRB, 0x00)
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), EspError> {
+        self.i2c.write(self.address, &[register, value], BLOCK)
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, EspError> {
+        let mut value = [0u8];
+        self.i2c
+            .write_read(self.address, &[register], &mut value, BLOCK)?;
+        Ok(value[0])
+    }
+
+    /// Port A buttons pressed since the last call, one bit per pin.
+    ///
+    /// The expander latches the port state on the first change, so a press
+    /// is caught even if it is released before we get to read it.
+    pub fn pressed_buttons(&mut self) -> u8 {
+        if !self.available {
+            return 0;
+        }
+        let result = self.read_register(INTFA).and_then(|changed| {
+            // Reading the capture also re-arms the change detection
+            let captured = self.read_register(INTCAPA)?;
+            Ok(changed & !captured)
+        });
+        result.unwrap_or_else(|err| {
+            log::warn!("Error reading the MCP23017 buttons: {:?}", err);
+            0
+        })
+    }
+
+    /// Energize or release the relay on port B pin `relay` (0-7).
+    pub fn set_relay(&mut self, relay: u8, on: bool) {
+        let relays = if on {
+            self.relays | 1 << relay
+        } else {
+            self.relays & !(1 << relay)
+        };
+        if !self.available || relays == self.relays {
+            return;
+        }
+        match self.write_register(OLATB, relays) {
+            Ok(()) => {
+                log::info!("Relay {} {}", relay, if on { "on" } else { "off" });
+                self.relays = relays;
+            }
+            Err(err) => log::warn!("Error setting relay {}: {:?}", relay, err),
+        }
+    }
+}
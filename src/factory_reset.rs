@@ -0,0 +1,100 @@
+//! Factory reset: erase everything kept in the "ssaa" NVS namespace (Wi-Fi
+//! credentials, webhook, calibration, counters...) and restart into setup
+//! mode. Asked for with `/api/factory_reset` or by holding BOOT for 10
+//! seconds, and carried out by the main loop, which owns the LED.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::gpio;
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::{
+    esp, nvs_close, nvs_commit, nvs_erase_all, nvs_handle_t, nvs_open,
+    nvs_open_mode_t_NVS_READWRITE, EspError,
+};
+
+use crate::auth;
+use crate::confirm::{self, Confirmation};
+use crate::nvs;
+use crate::rate_limit;
+use crate::state::PinDriverOutputArcExt;
+
+const BLINKS: u32 = 10;
+const NAMESPACE: &[u8] = b"ssaa\0";
+
+static CONFIRMATION: Confirmation = Confirmation::new();
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a factory reset was confirmed since the last call.
+pub fn take_requested() -> bool {
+    REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// Erase every key in the namespace. `EspNvs` doesn't expose its handle for
+/// the default partition, so this opens one of its own.
+fn erase_namespace() -> Result<(), EspError> {
+    let mut handle: nvs_handle_t = 0;
+    esp!(unsafe {
+        nvs_open(
+            NAMESPACE.as_ptr() as *const _,
+            nvs_open_mode_t_NVS_READWRITE,
+            &mut handle as *mut _,
+        )
+    })?;
+    let result =
+        esp!(unsafe { nvs_erase_all(handle) }).and_then(|()| esp!(unsafe { nvs_commit(handle) }));
+    unsafe { nvs_close(handle) };
+    result
+}
+
+/// Erase the namespace, blink the LED fast so the reset can be told apart
+/// from the other patterns, and restart. Only returns on errors.
+pub fn run<P: gpio::Pin>(
+    nvs: &mut nvs::EspNvs<nvs::NvsDefault>,
+    led: &Arc<Mutex<gpio::PinDriver<'_, P, gpio::Output>>>,
+) -> Result<(), EspError> {
+    log::warn!("Factory reset: erasing all settings");
+    erase_namespace()?;
+    // An empty SSID, unlike a missing one, doesn't fall back to `cfg.toml`,
+    // so the next boot starts in setup mode
+    nvs.set_str("wifi_ssid", "")?;
+
+    for _ in 0..BLINKS {
+        led.set_high()?;
+        FreeRtos::delay_ms(50u32);
+        led.set_low()?;
+        FreeRtos::delay_ms(50u32);
+    }
+    log::info!("Factory reset done, restarting");
+    unsafe {
+        esp_idf_svc::sys::esp_restart();
+    }
+    #[allow(unreachable_code)]
+    Ok(())
+}
+
+/// POST `/api/factory_reset` without a token answers with one; POSTing
+/// `token=...` within `confirm::TOKEN_TIMEOUT_S` confirms the reset.
+pub fn add_factory_reset_handler(server: &mut EspHttpServer<'_>) -> Result<(), EspError> {
+    server.fn_handler(
+        "/api/factory_reset",
+        esp_idf_svc::http::Method::Post,
+        |mut req| -> Result<(), EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            confirm::respond(
+                req,
+                &CONFIRMATION,
+                "Erasing all settings and restarting into setup mode",
+                || REQUESTED.store(true, Ordering::Relaxed),
+            )
+        },
+    )?;
+    Ok(())
+}
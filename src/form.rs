@@ -0,0 +1,234 @@
+//! Request bodies and `application/x-www-form-urlencoded` form data.
+//!
+//! Only reading the body needs the HTTP server, so the rest builds and is
+//! tested on the host with `rustc --edition 2021 --test src/form.rs`.
+
+#[cfg(target_os = "espidf")]
+use embedded_svc::http::Headers as _;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::server::{EspHttpConnection, Request};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::io::EspIOError;
+
+/// Largest form accepted, enough for every setup field at its longest.
+pub const MAX_FORM_LEN: usize = 4096;
+
+/// Read the whole request body, which may arrive over several reads, or
+/// `None` if it is over `max` bytes.
+#[cfg(target_os = "espidf")]
+pub fn read_body(
+    req: &mut Request<&mut EspHttpConnection<'_>>,
+    max: usize,
+) -> Result<Option<Vec<u8>>, EspIOError> {
+    let len = match req.content_len() {
+        Some(len) if len as usize > max => return Ok(None),
+        Some(len) => len as usize,
+        // Without a length, read until the connection says there is no more
+        None => max + 1,
+    };
+    let mut body = vec![0; len];
+    let mut read = 0;
+    while read < len {
+        match req.read(&mut body[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    if read > max {
+        return Ok(None);
+    }
+    body.truncate(read);
+    Ok(Some(body))
+}
+
+/// Decode `%xx` escapes and `+` for spaces. Malformed escapes are kept
+/// as they are, and the result is decoded as UTF-8 replacing invalid bytes.
+pub fn percent_decode(input: &[u8]) -> String {
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'%' => {
+                // from_str_radix alone would take a sign, e.g. `%+1`
+                let hex = input
+                    .get(i + 1..i + 3)
+                    .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(byte) => {
+                        output.push(byte);
+                        i += 3;
+                    }
+                    None => {
+                        output.push(b'%');
+                        i += 1;
+                    }
+                }
+            }
+            b'+' => {
+                output.push(b' ');
+                i += 1;
+            }
+            byte => {
+                output.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+/// Escape everything but unreserved characters, for a form value or a
+/// query string.
+pub fn percent_encode(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                output.push(byte as char)
+            }
+            _ => output.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    output
+}
+
+/// Split form data such as `wifi_ssid=My+Net&wifi_psk=p%40ss` into decoded
+/// key and value pairs, in order. A field without `=` has an empty value.
+pub fn parse(body: &[u8]) -> Vec<(String, String)> {
+    body.split(|byte| *byte == b'&')
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            let eq = field.iter().position(|byte| *byte == b'=');
+            let (key, value) = match eq {
+                Some(eq) => (&field[..eq], &field[eq + 1..]),
+                None => (field, &field[field.len()..]),
+            };
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+/// The value of the first `name` field, if there is one.
+pub fn field<'a>(fields: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_plus_as_space() {
+        assert_eq!(percent_decode(b"My+Net"), "My Net");
+        assert_eq!(percent_decode(b"a%2Bb"), "a+b");
+    }
+
+    #[test]
+    fn decodes_escapes_in_either_case() {
+        assert_eq!(percent_decode(b"p%40ss"), "p@ss");
+        assert_eq!(percent_decode(b"%2f%2F"), "//");
+        assert_eq!(percent_decode(b"%00"), "\0");
+    }
+
+    #[test]
+    fn keeps_malformed_escapes() {
+        assert_eq!(percent_decode(b"100%"), "100%");
+        assert_eq!(percent_decode(b"%4"), "%4");
+        assert_eq!(percent_decode(b"%zz"), "%zz");
+        assert_eq!(percent_decode(b"%%41"), "%A");
+        assert_eq!(percent_decode(b"%+1"), "% 1");
+    }
+
+    #[test]
+    fn decodes_utf8() {
+        assert_eq!(percent_decode(b"Espa%C3%B1a"), "España");
+        assert_eq!(percent_decode("España".as_bytes()), "España");
+        assert_eq!(percent_decode(b"%E2%82%AC+5"), "€ 5");
+    }
+
+    #[test]
+    fn replaces_invalid_utf8() {
+        assert_eq!(percent_decode(b"a%FFb"), "a\u{FFFD}b");
+        assert_eq!(percent_decode(b"%C3"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn encodes_all_but_unreserved() {
+        assert_eq!(percent_encode("AZaz09-._~"), "AZaz09-._~");
+        assert_eq!(percent_encode("My Net"), "My%20Net");
+        assert_eq!(percent_encode("a+b&c=d%"), "a%2Bb%26c%3Dd%25");
+        assert_eq!(percent_encode(""), "");
+    }
+
+    #[test]
+    fn encodes_utf8_bytes() {
+        assert_eq!(percent_encode("España"), "Espa%C3%B1a");
+        assert_eq!(percent_encode("€"), "%E2%82%AC");
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        for value in ["p@ss word", "100%", "a+b", "España €", "x=1&y=2"] {
+            assert_eq!(percent_decode(percent_encode(value).as_bytes()), value);
+        }
+    }
+
+    #[test]
+    fn parses_fields_in_order() {
+        assert_eq!(
+            parse(b"wifi_ssid=My+Net&wifi_psk=p%40ss"),
+            vec![
+                ("wifi_ssid".to_string(), "My Net".to_string()),
+                ("wifi_psk".to_string(), "p@ss".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_field_without_equals_as_empty() {
+        assert_eq!(
+            parse(b"reset&key=value"),
+            vec![
+                ("reset".to_string(), String::new()),
+                ("key".to_string(), "value".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_empty_fields_and_values() {
+        assert_eq!(parse(b""), vec![]);
+        assert_eq!(parse(b"&&a=&&"), vec![("a".to_string(), String::new())]);
+        assert_eq!(parse(b"=b"), vec![(String::new(), "b".to_string())]);
+    }
+
+    #[test]
+    fn splits_on_the_first_equals() {
+        assert_eq!(
+            parse(b"token=a=b%3D"),
+            vec![("token".to_string(), "a=b=".to_string())]
+        );
+    }
+
+    #[test]
+    fn decodes_keys_too() {
+        assert_eq!(
+            parse(b"a%26b=c%26d"),
+            vec![("a&b".to_string(), "c&d".to_string())]
+        );
+    }
+
+    #[test]
+    fn finds_the_first_matching_field() {
+        let fields = parse(b"a=1&b=&a=2");
+        assert_eq!(field(&fields, "a"), Some("1"));
+        assert_eq!(field(&fields, "b"), Some(""));
+        assert_eq!(field(&fields, "c"), None);
+        assert_eq!(field(&[], "a"), None);
+    }
+}
@@ -0,0 +1,168 @@
+//! Readings sent to Graphite (Carbon) in its plaintext protocol, one line
+//! per metric and report: `wattometer.<hostname>.watts 283.5 1718000000`.
+//!
+//! The TCP connection is kept open between reports. When it drops, the
+//! reports until it is made again are skipped, and connecting is tried again
+//! every `RETRY_EVERY_S` at most so a Carbon that is down doesn't hold up the
+//! main loop every report.
+
+use std::io::Write as _;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::template::Context;
+use crate::uptime::session_uptime_s;
+
+/// `host` or `host:port`.
+pub const NVS_GRAPHITE_HOST: &str = "graphite_host";
+/// First part of the metric paths, empty for `DEFAULT_PREFIX`.
+pub const NVS_GRAPHITE_PREFIX: &str = "graphite_prefix";
+pub const DEFAULT_PREFIX: &str = "wattometer";
+const DEFAULT_PORT: u16 = 2003;
+const TIMEOUT: Duration = Duration::from_secs(2);
+const RETRY_EVERY_S: u64 = 30;
+
+/// Whether the connection to Carbon is up, for `/api/v1/outputs`.
+pub(crate) static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+fn parse_host(host: &str) -> Option<(&str, u16)> {
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) => (name, port.parse().ok()?),
+        None => (host, DEFAULT_PORT),
+    };
+    (!name.is_empty() && !name.contains('/')).then_some((name, port))
+}
+
+/// Whether `host` can be saved: empty, or `host[:port]`.
+pub fn is_valid_host(host: &str) -> bool {
+    host.is_empty() || parse_host(host).is_some()
+}
+
+/// Whether `prefix` can be saved: empty, or dot-separated path nodes.
+pub fn is_valid_prefix(prefix: &str) -> bool {
+    prefix.is_empty()
+        || prefix.split('.').all(|node| {
+            !node.is_empty()
+                && node
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        })
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GraphiteSettings {
+    pub host: String,
+    /// Empty uses `DEFAULT_PREFIX`.
+    pub prefix: String,
+}
+
+impl GraphiteSettings {
+    pub fn from_nvs(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> Self {
+        let host = read_str_from_nvs_or_default(nvs, NVS_GRAPHITE_HOST, "");
+        let prefix = read_str_from_nvs_or_default(nvs, NVS_GRAPHITE_PREFIX, "");
+        GraphiteSettings {
+            host: if is_valid_host(&host) {
+                host
+            } else {
+                String::new()
+            },
+            prefix: if is_valid_prefix(&prefix) {
+                prefix
+            } else {
+                String::new()
+            },
+        }
+    }
+}
+
+/// Sends each report to Carbon while connected to Wi-Fi.
+pub struct GraphiteSender {
+    settings: GraphiteSettings,
+    /// Path of the metrics, up to the metric name.
+    path: String,
+    stream: Option<TcpStream>,
+    /// Session uptime of the next connection attempt.
+    next_attempt_s: u64,
+}
+
+impl GraphiteSender {
+    pub fn new(settings: GraphiteSettings, hostname: &str) -> Self {
+        // The connection of the previous settings, if any, is gone
+        CONNECTED.store(false, Ordering::Relaxed);
+        let prefix = if settings.prefix.is_empty() {
+            DEFAULT_PREFIX
+        } else {
+            settings.prefix.as_str()
+        };
+        // Dots would split the hostname into several path nodes
+        let path = format!("{}.{}", prefix, hostname.replace('.', "_"));
+        GraphiteSender {
+            settings,
+            path,
+            stream: None,
+            next_attempt_s: 0,
+        }
+    }
+
+    /// Send a report. Reports taken before the clock was synced have no
+    /// time to send them with, so they are skipped.
+    pub fn send(&mut self, report: &Context) {
+        if self.settings.host.is_empty() || report.timestamp == 0 {
+            return;
+        }
+        let now = session_uptime_s();
+        if self.stream.is_none() {
+            if now < self.next_attempt_s {
+                return;
+            }
+            match connect(&self.settings.host) {
+                Ok(stream) => {
+                    log::info!("Connected to Graphite at {}", self.settings.host);
+                    self.stream = Some(stream);
+                    CONNECTED.store(true, Ordering::Relaxed);
+                }
+                Err(err) => {
+                    log::warn!("Could not connect to Graphite: {:?}", err);
+                    self.next_attempt_s = now + RETRY_EVERY_S;
+                    return;
+                }
+            }
+        }
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+
+        let mut lines = String::new();
+        for (name, value) in [
+            ("amps", format!("{:.3}", report.amps.0)),
+            ("watts", format!("{:.1}", report.watts.0)),
+            ("kwh", format!("{:.3}", report.energy.kwh())),
+        ] {
+            lines.push_str(&format!(
+                "{}.{} {} {}\n",
+                self.path, name, value, report.timestamp
+            ));
+        }
+        if let Err(err) = stream.write_all(lines.as_bytes()) {
+            // Reconnect with the next report
+            log::warn!("Lost the connection to Graphite: {:?}", err);
+            self.stream = None;
+            CONNECTED.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+fn connect(host: &str) -> anyhow::Result<TcpStream> {
+    let Some((name, port)) = parse_host(host) else {
+        anyhow::bail!("invalid host");
+    };
+    let Some(address) = (name, port).to_socket_addrs()?.next() else {
+        anyhow::bail!("{} not found", name);
+    };
+    let stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
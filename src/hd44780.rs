@@ -0,0 +1,204 @@
+//! HD44780 16x2 character LCD (LCD1602) with a PCF8574 I2C backpack, readable
+//! from across the room. It sits on the display bus, usually at address 0x27
+//! (0x3F for PCF8574A backpacks), and shows amps and watts on the first line
+//! and the IP address on the second; the AP name and key in setup mode.
+//!
+//! The backpack maps each byte written to it onto the LCD's pins, so the
+//! controller runs in 4-bit mode, a nibble per pulse of its enable line.
+
+use esp_idf_svc::hal::delay::{Ets, FreeRtos, BLOCK};
+use esp_idf_svc::hal::i2c;
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::sys::EspError;
+
+use crate::ambient::DimmingPolicy;
+use crate::display::{Brightness, Display, DisplayBus, MainScreen, NoPanel};
+use crate::mirror;
+
+pub const COLUMNS: usize = 16;
+
+// PCF8574 pins
+const RS: u8 = 0x01;
+const ENABLE: u8 = 0x04;
+const BACKLIGHT: u8 = 0x08;
+
+// Commands
+const CLEAR: u8 = 0x01;
+const ENTRY_INCREMENT: u8 = 0x06;
+const DISPLAY_ON: u8 = 0x0C;
+const FUNCTION_4BIT_2LINES: u8 = 0x28;
+const SET_ADDRESS: u8 = 0x80;
+/// DDRAM address of the start of each line.
+const LINE_ADDRESS: [u8; 2] = [0x00, 0x40];
+
+pub struct Hd44780<'a> {
+    i2c: i2c::I2cDriver<'a>,
+    address: u8,
+    /// Whether the LCD answered the last write.
+    available: bool,
+    dimming: DimmingPolicy,
+    /// The backlight can only be on or off.
+    backlight: bool,
+    /// Lines last written, to only talk to the LCD on changes.
+    lines: [String; 2],
+}
+
+impl<'a> Hd44780<'a> {
+    pub fn new<I2C: i2c::I2c>(
+        bus: DisplayBus,
+        i2c: impl Peripheral<P = I2C> + 'a,
+    ) -> Result<Self, EspError> {
+        let (sda, scl) = bus.pins();
+        let i2c_config = i2c::I2cConfig::new().baudrate(bus.khz.kHz().into());
+        let mut lcd = Hd44780 {
+            i2c: i2c::I2cDriver::new(i2c, sda, scl, &i2c_config)?,
+            address: bus.address,
+            available: false,
+            dimming: DimmingPolicy::default(),
+            backlight: true,
+            lines: Default::default(),
+        };
+        lcd.init();
+        if !lcd.available {
+            log::warn!(
+                "No LCD answered at {:#04x} on SDA GPIO{} / SCL GPIO{}; check the wiring and \
+                 the display bus address",
+                bus.address,
+                bus.sda,
+                bus.scl
+            );
+        }
+        Ok(lcd)
+    }
+
+    fn flags(&self) -> u8 {
+        if self.backlight {
+            BACKLIGHT
+        } else {
+            0
+        }
+    }
+
+    /// Latch the high and low nibbles of `value` with a pulse of enable each.
+    fn send(&mut self, value: u8, mode: u8) -> Result<(), EspError> {
+        let high = value & 0xF0 | mode | self.flags();
+        let low = value << 4 | mode | self.flags();
+        // Each I2C byte takes far longer than the enable pulse and the 37us
+        // most commands need
+        self.i2c.write(
+            self.address,
+            &[high | ENABLE, high, low | ENABLE, low],
+            BLOCK,
+        )
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), EspError> {
+        self.send(command, 0)
+    }
+
+    /// The reset sequence from the datasheet: whatever mode the controller
+    /// was left in, this gets it to 4-bit.
+    fn configure(&mut self) -> Result<(), EspError> {
+        let flags = self.flags();
+        for (nibble, delay_us) in [(0x30, 4500), (0x30, 150), (0x30, 150), (0x20, 150)] {
+            self.i2c.write(
+                self.address,
+                &[nibble | ENABLE | flags, nibble | flags],
+                BLOCK,
+            )?;
+            Ets::delay_us(delay_us);
+        }
+        self.command(FUNCTION_4BIT_2LINES)?;
+        self.command(DISPLAY_ON)?;
+        self.command(ENTRY_INCREMENT)?;
+        self.command(CLEAR)?;
+        FreeRtos::delay_ms(2);
+        Ok(())
+    }
+
+    fn write_line(&mut self, row: usize, text: &str) -> Result<(), EspError> {
+        self.command(SET_ADDRESS | LINE_ADDRESS[row])?;
+        // The character ROM only matches ASCII; pad to clear what was there
+        let chars = text.chars().chain(std::iter::repeat(' ')).take(COLUMNS);
+        for c in chars {
+            self.send(if c.is_ascii() { c as u8 } else { b'?' }, RS)?;
+        }
+        Ok(())
+    }
+
+    /// Run a write, marking the LCD unavailable if it fails, so `init` tries
+    /// again later.
+    fn update(&mut self, write: impl FnOnce(&mut Self) -> Result<(), EspError>) {
+        if !self.available {
+            return;
+        }
+        if let Err(err) = write(self) {
+            log::info!("LCD error: {:?}", err);
+            self.available = false;
+        }
+    }
+
+    fn show_lines(&mut self, lines: [String; 2]) {
+        for (row, line) in lines.into_iter().enumerate() {
+            if line != self.lines[row] {
+                self.update(|lcd| lcd.write_line(row, &line));
+                self.lines[row] = line;
+            }
+        }
+        mirror::show_text(self.lines.to_vec());
+    }
+
+    fn set_backlight(&mut self, backlight: bool) {
+        if backlight != self.backlight {
+            self.backlight = backlight;
+            // The backlight bit goes out with any byte
+            self.update(|lcd| lcd.i2c.write(lcd.address, &[lcd.flags()], BLOCK));
+        }
+    }
+}
+
+impl Display for Hd44780<'_> {
+    type Panel = NoPanel;
+
+    fn run<E: std::fmt::Debug>(&mut self, _f: impl FnOnce(&mut NoPanel) -> Result<(), E>) {}
+
+    fn init(&mut self) {
+        if self.available {
+            return;
+        }
+        self.available = self.configure().is_ok();
+        if self.available {
+            log::info!("LCD found at {:#04x}", self.address);
+            // Write everything again, whatever the LCD missed
+            let lines = std::mem::take(&mut self.lines);
+            self.show_lines(lines);
+        }
+    }
+
+    fn on(&mut self) {
+        self.set_backlight(true);
+    }
+
+    fn set_dimming(&mut self, dimming: DimmingPolicy) {
+        self.dimming = dimming;
+    }
+
+    fn apply_dimming(&mut self, auto_brightness: Brightness, dark: bool, blank: bool) {
+        let brightness = self.dimming.brightness(auto_brightness, dark);
+        self.set_backlight(brightness.is_some() && !blank);
+    }
+
+    fn show_setup(&mut self, ap_ssid: &str, ap_psk: &str) {
+        self.show_lines([format!("AP {}", ap_ssid), format!("Key {}", ap_psk)]);
+    }
+
+    fn show_screen(&mut self, screen: &MainScreen) {
+        let amps = format!("{:.2}", screen.amps);
+        let watts = format!("{:.0}", screen.watts);
+        self.show_lines([
+            format!("{:<w$}{:>w$}", amps, watts, w = COLUMNS / 2),
+            screen.network.clone(),
+        ]);
+    }
+}
@@ -0,0 +1,93 @@
+//! Extra headers sent with every webhook request, for ingestion APIs that
+//! want e.g. `Authorization: Bearer <token>` or `X-API-Key: <key>`. Up to
+//! `MAX_HEADERS` are kept in NVS, each as a `Name: value` line; empty ones
+//! are skipped.
+//!
+//! The values usually carry a token, so the setup page only shows the names.
+//! A name sent back without a value keeps the saved one.
+
+use std::sync::Mutex;
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+
+pub const MAX_HEADERS: usize = 4;
+pub const NVS_HEADERS: [&str; MAX_HEADERS] = [
+    "hook_header_0",
+    "hook_header_1",
+    "hook_header_2",
+    "hook_header_3",
+];
+/// Longest line `read_str_from_nvs` reads back whole.
+const MAX_LINE_LEN: usize = 127;
+/// Set by the firmware itself.
+const RESERVED: [&str; 3] = ["content-length", "host", "transfer-encoding"];
+
+/// The headers in use, name and value.
+static HEADERS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+/// The lines as saved.
+static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn parse(line: &str) -> Option<(&str, &str)> {
+    let (name, value) = line.split_once(':')?;
+    let name = name.trim();
+    let valid_name = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+        && !RESERVED.contains(&name.to_ascii_lowercase().as_str());
+    valid_name.then_some((name, value.trim()))
+}
+
+/// Whether `line` can be saved: empty, or `Name: value` on a single line
+/// with a name the firmware doesn't set itself.
+pub fn is_valid(line: &str) -> bool {
+    line.is_empty()
+        || (line.len() <= MAX_LINE_LEN
+            && !line.chars().any(char::is_control)
+            && parse(line).is_some())
+}
+
+/// Read the headers from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let lines: Vec<String> = NVS_HEADERS
+        .iter()
+        .map(|key| read_str_from_nvs_or_default(nvs, key, ""))
+        .map(|line| if is_valid(&line) { line } else { String::new() })
+        .collect();
+    *HEADERS.lock().unwrap() = lines
+        .iter()
+        .filter_map(|line| parse(line))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    *LINES.lock().unwrap() = lines;
+}
+
+/// Name and value of each header to send.
+pub fn headers() -> Vec<(String, String)> {
+    HEADERS.lock().unwrap().clone()
+}
+
+fn saved_line(index: usize) -> String {
+    LINES
+        .lock()
+        .unwrap()
+        .get(index)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Header `index` as `Name: ` without its value, for the setup page.
+pub fn masked_line(index: usize) -> String {
+    parse(&saved_line(index)).map_or(String::new(), |(name, _)| format!("{}: ", name))
+}
+
+/// The line to save for header `index` from the setup form: the saved one
+/// when `line` names the same header without a value, `line` otherwise.
+pub fn merge(index: usize, line: &str) -> String {
+    let saved = saved_line(index);
+    match (parse(line), parse(&saved)) {
+        (Some((name, "")), Some((saved_name, _))) if name.eq_ignore_ascii_case(saved_name) => saved,
+        _ => line.to_string(),
+    }
+}
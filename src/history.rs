@@ -0,0 +1,84 @@
+use heapless::HistoryBuffer;
+
+use crate::units::{Amps, Watts};
+use crate::uptime::{session_uptime_s, unix_now};
+
+/// Number of points kept, one per display column on a 128px wide screen.
+pub const HISTORY_LEN: usize = 128;
+
+/// Readings are averaged over this many seconds before being stored, so the
+/// buffer covers a bit over ten minutes.
+pub const HISTORY_INTERVAL_S: u64 = 5;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Reading {
+    /// Seconds since boot at the end of the interval.
+    pub uptime_s: u64,
+    /// Unix time at the end of the interval, or 0 if the clock was not synced.
+    pub unix_time: u64,
+    pub amps: Amps,
+    pub watts: Watts,
+}
+
+/// Fixed-size history of averaged readings.
+pub struct History {
+    readings: HistoryBuffer<Reading, HISTORY_LEN>,
+    bucket_start_s: u64,
+    bucket_amps: f32,
+    bucket_watts: f32,
+    bucket_count: u32,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            readings: HistoryBuffer::new(),
+            bucket_start_s: session_uptime_s(),
+            bucket_amps: 0.0,
+            bucket_watts: 0.0,
+            bucket_count: 0,
+        }
+    }
+}
+
+impl History {
+    /// Add a raw reading, storing a new averaged point once the current
+    /// interval is over. Returns the stored point, if any.
+    pub fn record(&mut self, amps: Amps, watts: Watts) -> Option<Reading> {
+        self.bucket_amps += amps.0;
+        self.bucket_watts += watts.0;
+        self.bucket_count += 1;
+
+        let now = session_uptime_s();
+        if now - self.bucket_start_s < HISTORY_INTERVAL_S {
+            return None;
+        }
+
+        let reading = Reading {
+            uptime_s: now,
+            unix_time: unix_now().unwrap_or(0),
+            amps: Amps(self.bucket_amps / self.bucket_count as f32),
+            watts: Watts(self.bucket_watts / self.bucket_count as f32),
+        };
+        self.readings.write(reading);
+
+        self.bucket_start_s = now;
+        self.bucket_amps = 0.0;
+        self.bucket_watts = 0.0;
+        self.bucket_count = 0;
+        Some(reading)
+    }
+
+    /// Stored readings, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &Reading> {
+        self.readings.oldest_ordered()
+    }
+
+    pub fn len(&self) -> usize {
+        self.readings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.readings.len() == 0
+    }
+}
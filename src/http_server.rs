@@ -3,79 +3,1034 @@ use esp_idf_svc::{
     io::EspIOError,
     nvs,
     sys::EspError,
+    wifi::EspWifi,
 };
 use once_cell::sync::Lazy;
+use serde::Serialize;
 use std::fmt::Write;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 
-use crate::AC_VOLTS;
-
-fn percent_decode_str(input: &str) -> String {
-    // Implement percent decoding
-    let mut output = String::new();
-    let mut input = input.as_bytes();
-    while !input.is_empty() {
-        match input[0] {
-            b'%' => {
-                if input.len() < 3 {
-                    break;
-                }
-                let hex = u8::from_str_radix(std::str::from_utf8(&input[1..3]).unwrap(), 16)
-                    .unwrap_or(b'?');
-                output.push(hex as char);
-                input = &input[3..];
-            }
-            b'+' => {
-                output.push(' ');
-                input = &input[1..];
-            }
-            _ => {
-                output.push(input[0] as char);
-                input = &input[1..];
-            }
-        }
+use crate::ambient::{
+    format_time_of_day, parse_time_of_day, BrightnessSetting, DimmingPolicy, NightDisplay,
+    NightTrigger, NIGHT_OVERRIDE, NVS_BRIGHTNESS, NVS_NIGHT, NVS_NIGHT_DISPLAY, NVS_NIGHT_END,
+    NVS_NIGHT_START, NVS_UTC_OFFSET,
+};
+use crate::assets;
+use crate::auth::{self, MIN_PASSWORD_LEN, NVS_ADMIN_PASSWORD};
+use crate::batch::{self, NVS_BATCH_SIZE};
+use crate::body::{self, Method, NVS_WEBHOOK_BODY, NVS_WEBHOOK_METHOD, NVS_WEBHOOK_TYPE};
+use crate::budget::{Budget, Tariff};
+use crate::burn_in::NVS_IDLE_MINUTES;
+use crate::calibration::CALIBRATION;
+use crate::coap::{self, CoapSettings, NVS_COAP_INTERVAL, NVS_COAP_URL};
+use crate::cors::{self, ALLOWED_ORIGIN, NVS_CORS_ORIGIN};
+use crate::dashboard::{self, NVS_PEERS};
+use crate::delivery::{self, DeliveryStats, DELIVERY_STATS, NVS_WEBHOOK_EXPECT};
+use crate::display::{
+    Brightness, DisplayBus, PrimaryUnit, Rotation, NVS_DISPLAY_ADDRESS, NVS_DISPLAY_KHZ,
+    NVS_DISPLAY_ROTATION, NVS_DISPLAY_SCL, NVS_DISPLAY_SDA,
+};
+use crate::energy::ENERGY;
+use crate::error;
+use crate::factory_reset;
+use crate::form::{self, MAX_FORM_LEN};
+use crate::graphite::{self, GraphiteSettings, NVS_GRAPHITE_HOST, NVS_GRAPHITE_PREFIX};
+use crate::headers::{self, MAX_HEADERS, NVS_HEADERS};
+use crate::history::{History, Reading};
+use crate::i18n::{self, Language, Strings, NVS_LANGUAGE};
+use crate::labels::{self, CHANNEL_LABEL, DEVICE_NAME, NVS_CHANNEL_LABEL, NVS_DEVICE_NAME};
+use crate::live;
+use crate::log_buffer;
+use crate::long_poll;
+use crate::metrics;
+use crate::mirror;
+use crate::modbus::{self, NVS_MODBUS};
+use crate::mqtt::{
+    self, MqttSettings, PayloadFormat, NVS_MQTT_FORMAT, NVS_MQTT_INTERVAL, NVS_MQTT_PASSWORD,
+    NVS_MQTT_PREFIX, NVS_MQTT_SHADOW, NVS_MQTT_THING, NVS_MQTT_URL, NVS_MQTT_USER,
+};
+use crate::notify::{self, NVS_NTFY_URL, NVS_PUSHOVER_TOKEN, NVS_PUSHOVER_USER};
+use crate::nvs_admin;
+use crate::openapi::{self, Param, Route};
+use crate::ota;
+use crate::outbox::BUFFERED;
+use crate::pwa;
+use crate::rate_limit;
+use crate::report::{self, NVS_REPORT_INTERVAL};
+use crate::restart;
+use crate::settings;
+use crate::snmp::{self, NVS_SNMP_COMMUNITY};
+use crate::spool::SPOOL_STATUS;
+use crate::telegram::{self, NVS_TELEGRAM_CHAT, NVS_TELEGRAM_TOKEN};
+use crate::template;
+use crate::tls;
+use crate::units::Amps;
+use crate::uptime::UPTIME;
+use crate::weather::WEATHER;
+use crate::webhook_test;
+use crate::wifi::enterprise::{self, NVS_EAP_IDENTITY, NVS_EAP_PASSWORD, NVS_EAP_USER};
+use crate::wifi::networks::{self, MAX_NETWORKS, NVS_PSKS, NVS_SSIDS};
+use crate::wifi::pinning::{self, NVS_WIFI_BSSID, NVS_WIFI_CHANNEL};
+use crate::wifi::provision::{self, PROVISION_STATUS};
+use crate::wifi::scan;
+use crate::wifi::security::{self, Security, NVS_WIFI_AUTH};
+use crate::wifi::signal::{self, NVS_RSSI_FLOOR};
+use crate::wifi::static_ip::{
+    self, StaticIp, NVS_IP_ADDRESS, NVS_IP_DNS, NVS_IP_GATEWAY, NVS_IP_NETMASK,
+};
+use crate::wifi::{get_rssi, WIFI_RECONNECTS};
+use crate::{AC_VOLTS, FIRMWARE_VERSION};
+
+pub(crate) static CURRENT_KNOWN_WIFI_SSID: Lazy<Arc<Mutex<String>>> =
+    Lazy::new(|| Arc::new(Mutex::new(String::new())));
+pub(crate) static CURRENT_KNOWN_HOSTNAME: Lazy<Arc<Mutex<String>>> =
+    Lazy::new(|| Arc::new(Mutex::new(String::new())));
+pub(crate) static CURRENT_KNOWN_WEBHOOK: Lazy<Arc<Mutex<String>>> =
+    Lazy::new(|| Arc::new(Mutex::new(String::new())));
+pub(crate) static CURRENT_KNOWN_REPORT_INTERVAL: Lazy<Arc<Mutex<u64>>> =
+    Lazy::new(|| Arc::new(Mutex::new(report::DEFAULT_INTERVAL_S)));
+pub(crate) static CURRENT_KNOWN_MAX_WATTS: Lazy<Arc<Mutex<f32>>> =
+    Lazy::new(|| Arc::new(Mutex::new(0f32)));
+pub(crate) static CURRENT_KNOWN_WEATHER_URL: Lazy<Arc<Mutex<String>>> =
+    Lazy::new(|| Arc::new(Mutex::new(String::new())));
+pub(crate) static CURRENT_KNOWN_BUDGET: Lazy<Arc<Mutex<Budget>>> =
+    Lazy::new(|| Arc::new(Mutex::new(Budget::default())));
+pub(crate) static CURRENT_KNOWN_TARIFF: Lazy<Arc<Mutex<Tariff>>> =
+    Lazy::new(|| Arc::new(Mutex::new(Tariff::default())));
+pub(crate) static CURRENT_KNOWN_PRIMARY_UNIT: Lazy<Arc<Mutex<PrimaryUnit>>> =
+    Lazy::new(|| Arc::new(Mutex::new(PrimaryUnit::default())));
+pub(crate) static CURRENT_KNOWN_DIMMING: Lazy<Arc<Mutex<DimmingPolicy>>> =
+    Lazy::new(|| Arc::new(Mutex::new(DimmingPolicy::default())));
+pub(crate) static CURRENT_KNOWN_DISPLAY_IDLE_MINUTES: Lazy<Arc<Mutex<u32>>> =
+    Lazy::new(|| Arc::new(Mutex::new(0)));
+pub(crate) static CURRENT_KNOWN_DISPLAY_BUS: Lazy<Arc<Mutex<DisplayBus>>> =
+    Lazy::new(|| Arc::new(Mutex::new(DisplayBus::default())));
+pub(crate) static CURRENT_KNOWN_DISPLAY_ROTATION: Lazy<Arc<Mutex<Rotation>>> =
+    Lazy::new(|| Arc::new(Mutex::new(Rotation::default())));
+pub(crate) static CURRENT_KNOWN_MQTT: Lazy<Arc<Mutex<MqttSettings>>> =
+    Lazy::new(|| Arc::new(Mutex::new(MqttSettings::default())));
+pub(crate) static CURRENT_KNOWN_COAP: Lazy<Arc<Mutex<CoapSettings>>> =
+    Lazy::new(|| Arc::new(Mutex::new(CoapSettings::default())));
+pub(crate) static CURRENT_KNOWN_GRAPHITE: Lazy<Arc<Mutex<GraphiteSettings>>> =
+    Lazy::new(|| Arc::new(Mutex::new(GraphiteSettings::default())));
+pub(crate) static CURRENT_KNOWN_SNMP_COMMUNITY: Lazy<Arc<Mutex<String>>> =
+    Lazy::new(|| Arc::new(Mutex::new(String::new())));
+
+/// Fields of the setup form posted to `/save`.
+const SETUP_FIELDS: &[Param] = &[
+    Param::required("wifi_ssid", "string", "Wi-Fi network, up to 32 bytes"),
+    Param::optional(
+        "wifi_ssid_other",
+        "string",
+        "Hidden network, overrides wifi_ssid",
+    ),
+    Param::required(
+        "wifi_psk",
+        "string",
+        "Wi-Fi password, 8 to 63 characters (5 or 13 for WEP); empty for open networks and WPA2-Enterprise",
+    ),
+    Param::optional(
+        NVS_WIFI_AUTH,
+        "string",
+        "Weakest security accepted: auto (the default), open, wep, wpa, wpa2, wpa3 or wpa2-enterprise",
+    ),
+    Param::optional(
+        NVS_WIFI_BSSID,
+        "string",
+        "Access point to join the network through, aa:bb:cc:dd:ee:ff; empty for any",
+    ),
+    Param::optional(NVS_WIFI_CHANNEL, "integer", "Channel of the network, 1 to 14; empty for any"),
+    Param::optional(
+        NVS_RSSI_FLOOR,
+        "integer",
+        "Signal to warn below, -100 to -30 dBm; empty for -80",
+    ),
+    Param::optional(
+        "test_wifi",
+        "string",
+        "1 to try the network first (setup mode only)",
+    ),
+    Param::optional(
+        "wifi_ssid_1",
+        "string",
+        "Network to fall back on, empty to forget it; also _2 and _3",
+    ),
+    Param::optional(
+        "wifi_psk_1",
+        "string",
+        "Its password, empty keeps it; also _2 and _3",
+    ),
+    Param::optional(NVS_EAP_USER, "string", "WPA2-Enterprise user, empty to forget it"),
+    Param::optional(NVS_EAP_PASSWORD, "string", "Its password, empty keeps it"),
+    Param::optional(NVS_EAP_IDENTITY, "string", "Anonymous identity, empty for the user"),
+    Param::optional(NVS_IP_ADDRESS, "string", "Static IPv4 address, empty for DHCP"),
+    Param::optional(NVS_IP_NETMASK, "string", "Netmask of the static address"),
+    Param::optional(NVS_IP_GATEWAY, "string", "Gateway of the static address"),
+    Param::optional(NVS_IP_DNS, "string", "DNS server, empty for the gateway"),
+    Param::optional("webhook", "string", "URL to POST readings to, templated"),
+    Param::optional(
+        NVS_WEBHOOK_BODY,
+        "string",
+        "Body of the webhook requests, templated like the URL; empty for JSON",
+    ),
+    Param::optional(NVS_WEBHOOK_METHOD, "string", "POST, PUT or GET"),
+    Param::optional(
+        NVS_WEBHOOK_TYPE,
+        "string",
+        "Content-Type of the webhook requests; empty for application/json",
+    ),
+    Param::optional(
+        NVS_WEBHOOK_EXPECT,
+        "string",
+        "Text a webhook answer must contain to count as delivered",
+    ),
+    Param::optional(
+        "hook_header_0",
+        "string",
+        "Extra webhook header as Name: value, also _1 to _3; a name alone keeps its value",
+    ),
+    Param::optional(
+        "report_interval",
+        "integer",
+        "Seconds between webhook posts, averaged",
+    ),
+    Param::optional(
+        NVS_BATCH_SIZE,
+        "integer",
+        "Readings per webhook post, 2 or more to post JSON arrays",
+    ),
+    Param::optional("max_watts", "number", "Full scale of the power bar"),
+    Param::optional("budget_day", "number", "Daily energy budget in kWh"),
+    Param::optional("budget_month", "number", "Monthly energy budget in kWh"),
+    Param::optional(
+        "weather_url",
+        "string",
+        "URL returning the outdoor temperature",
+    ),
+    Param::optional("price_kwh", "number", "Energy price per kWh"),
+    Param::optional("currency", "string", "Currency shown after costs"),
+    Param::optional("primary_unit", "string", "Reading on the first line"),
+    Param::optional("brightness", "string", "Display brightness"),
+    Param::optional("night", "string", "never, schedule or dark"),
+    Param::optional("night_start", "string", "HH:MM"),
+    Param::optional("night_end", "string", "HH:MM"),
+    Param::optional("utc_offset", "number", "Hours from UTC"),
+    Param::optional("night_display", "string", "Display at night"),
+    Param::optional(
+        "display_idle_min",
+        "integer",
+        "Minutes until the display turns off",
+    ),
+    Param::optional("display_rotation", "string", "Display rotation in degrees"),
+    Param::optional("display_sda", "integer", "Display SDA GPIO"),
+    Param::optional("display_scl", "integer", "Display SCL GPIO"),
+    Param::optional("display_address", "string", "Display I2C address"),
+    Param::optional("display_khz", "integer", "Display I2C speed"),
+    Param::optional(
+        "cors_origin",
+        "string",
+        "Origin allowed to read the JSON readings",
+    ),
+    Param::optional("device_name", "string", "Name of the device"),
+    Param::optional("channel_label", "string", "What the clamp measures"),
+    Param::optional(
+        "peers",
+        "string",
+        "Addresses of the meters shown on /dashboard",
+    ),
+    Param::optional("mqtt_url", "string", "MQTT broker to publish readings to"),
+    Param::optional("mqtt_user", "string", "MQTT user"),
+    Param::optional("mqtt_pass", "string", "MQTT password, empty keeps it"),
+    Param::optional("mqtt_prefix", "string", "MQTT topic prefix"),
+    Param::optional("mqtt_interval", "integer", "Seconds between MQTT readings"),
+    Param::optional("mqtt_thing", "string", "AWS IoT thing name"),
+    Param::optional("mqtt_shadow", "string", "AWS IoT named shadow"),
+    Param::optional("mqtt_format", "string", "topics, senml or tasmota"),
+    Param::optional("coap_url", "string", "CoAP resource to post readings to"),
+    Param::optional("coap_interval", "integer", "Seconds between CoAP readings"),
+    Param::optional("graphite_host", "string", "Graphite host[:port] to send readings to"),
+    Param::optional("graphite_prefix", "string", "Graphite metric path prefix"),
+    Param::optional("snmp_community", "string", "SNMP v2c community, empty turns the agent off"),
+    Param::optional("modbus", "string", "on to serve Modbus TCP on port 502"),
+    Param::optional("ntfy_url", "string", "ntfy topic URL to push alarms to"),
+    Param::optional("pushover_user", "string", "Pushover user key to push alarms to"),
+    Param::optional("pushover_token", "string", "Pushover application token, empty keeps it"),
+    Param::optional("tg_chat", "string", "Telegram chat ID or @channel for alarms and commands"),
+    Param::optional("tg_token", "string", "Telegram bot token, empty keeps it"),
+    Param::optional("language", "string", "auto, en or es"),
+    Param::optional("admin_password", "string", "New admin password"),
+];
+
+/// A token confirming the action, see `confirm`.
+const TOKEN_FIELD: &[Param] = &[Param::optional(
+    "token",
+    "string",
+    "Token from a first request without one",
+)];
+
+/// Handlers `add_server_setup_handlers` registers, five slots in `tls` with
+/// three each among them. The server refuses any past its limit, so keep
+/// these in step with the handlers as well as `ROUTES`.
+const SETUP_HANDLERS: usize = 33;
+/// `/` and `/api/v1/status` on top of those, and the assets.
+const SETUP_SERVER_HANDLERS: usize = SETUP_HANDLERS + 2;
+/// `/`, `/api/v1/status`, `/ws`, `/api/next`, the dashboard (2), `/ota`,
+/// the history (2), `/amps`, `/watts`, the calibration (3), the night
+/// override, `/api/v1/outputs`, `/api/health` and `/metrics`, and the
+/// assets.
+const SERVER_HANDLERS: usize = SETUP_HANDLERS + 18;
+
+/// Every endpoint, described at `/api/openapi.json`. Keep it in step with
+/// the handlers; the hashed static assets and `/ws` are left out.
+pub(crate) static ROUTES: &[Route] = &[
+    Route::new(
+        "get",
+        "/",
+        "Setup page, or the readings outside setup mode",
+        "text/html",
+    ),
+    Route::new("get", "/save", "Setup page", "text/html"),
+    Route::new("post", "/save", "Save the setup form", "text/html")
+        .auth()
+        .form(SETUP_FIELDS),
+    Route::new(
+        "get",
+        "/api/scan",
+        "Nearby Wi-Fi networks",
+        "application/json",
+    )
+    .schema("Networks"),
+    Route::new(
+        "get",
+        "/api/v1/provision_status",
+        "Outcome of the Wi-Fi connection test",
+        "application/json",
+    )
+    .schema("ProvisionStatus"),
+    Route::new("post", "/restart", "Restart the device", "application/json")
+        .auth()
+        .form(TOKEN_FIELD)
+        .schema("Token"),
+    Route::new(
+        "post",
+        "/api/factory_reset",
+        "Erase all settings and restart into setup mode",
+        "application/json",
+    )
+    .auth()
+    .form(TOKEN_FIELD)
+    .schema("Token"),
+    Route::new("get", "/admin/nvs", "Stored keys and values", "text/html").auth(),
+    Route::new(
+        "post",
+        "/admin/nvs",
+        "Change or delete a stored key",
+        "text/html",
+    )
+    .auth()
+    .form(&[
+        Param::required("key", "string", "Key to change"),
+        Param::required("action", "string", "save or delete"),
+        Param::optional("value", "string", "New value, for save"),
+    ]),
+    Route::new(
+        "get",
+        "/api/webhook/ca",
+        "Certificate the webhook's server is checked against",
+        "application/x-pem-file",
+    )
+    .auth(),
+    Route::new(
+        "put",
+        "/api/webhook/ca",
+        "Check the webhook's server against this certificate and restart",
+        "text/plain",
+    )
+    .auth()
+    .body("application/x-pem-file", None),
+    Route::new(
+        "delete",
+        "/api/webhook/ca",
+        "Go back to the bundled CAs and restart",
+        "text/plain",
+    )
+    .auth(),
+    Route::new(
+        "post",
+        "/api/webhook/test",
+        "Post a sample reading to a webhook with the saved body and headers",
+        "application/json",
+    )
+    .auth()
+    .form(&[Param::optional(
+        "webhook",
+        "string",
+        "URL to test, the saved one if empty",
+    )])
+    .schema("WebhookTest"),
+    Route::new(
+        "get",
+        "/api/mqtt/ca",
+        "Certificate the MQTT broker is checked against",
+        "application/x-pem-file",
+    )
+    .auth(),
+    Route::new(
+        "put",
+        "/api/mqtt/ca",
+        "Install the certificate the MQTT broker is checked against and restart",
+        "text/plain",
+    )
+    .auth()
+    .body("application/x-pem-file", None),
+    Route::new(
+        "delete",
+        "/api/mqtt/ca",
+        "Remove the certificate the MQTT broker is checked against and restart",
+        "text/plain",
+    )
+    .auth(),
+    Route::new(
+        "get",
+        "/api/mqtt/cert",
+        "Client certificate for the MQTT broker",
+        "application/x-pem-file",
+    )
+    .auth(),
+    Route::new(
+        "put",
+        "/api/mqtt/cert",
+        "Install the client certificate for the MQTT broker and restart",
+        "text/plain",
+    )
+    .auth()
+    .body("application/x-pem-file", None),
+    Route::new(
+        "delete",
+        "/api/mqtt/cert",
+        "Remove the client certificate for the MQTT broker and restart",
+        "text/plain",
+    )
+    .auth(),
+    Route::new(
+        "get",
+        "/api/mqtt/key",
+        "Whether a client key for the MQTT broker is installed",
+        "text/plain",
+    )
+    .auth(),
+    Route::new(
+        "put",
+        "/api/mqtt/key",
+        "Install the client key for the MQTT broker and restart",
+        "text/plain",
+    )
+    .auth()
+    .body("application/x-pem-file", None),
+    Route::new(
+        "delete",
+        "/api/mqtt/key",
+        "Remove the client key for the MQTT broker and restart",
+        "text/plain",
+    )
+    .auth(),
+    Route::new(
+        "get",
+        "/api/wifi/ca",
+        "Certificate the WPA2-Enterprise server is checked against",
+        "application/x-pem-file",
+    )
+    .auth(),
+    Route::new(
+        "put",
+        "/api/wifi/ca",
+        "Check the WPA2-Enterprise server against this certificate and restart",
+        "text/plain",
+    )
+    .auth()
+    .body("application/x-pem-file", None),
+    Route::new(
+        "delete",
+        "/api/wifi/ca",
+        "Accept any WPA2-Enterprise server and restart",
+        "text/plain",
+    )
+    .auth(),
+    Route::new(
+        "get",
+        "/display",
+        "What the display shows, as image/bmp or text/plain",
+        "image/bmp",
+    )
+    .auth(),
+    Route::new(
+        "get",
+        "/manifest.json",
+        "Web app manifest",
+        "application/manifest+json",
+    ),
+    Route::new(
+        "get",
+        "/favicon.ico",
+        "Redirect to the icon",
+        "image/svg+xml",
+    ),
+    Route::new("get", "/logs", "Recent log lines", "text/plain")
+        .auth()
+        .query(&[Param::optional("format", "string", "json for JSON")]),
+    Route::new("get", "/api/config", "Current settings", "application/json")
+        .auth()
+        .schema("Settings"),
+    Route::new(
+        "put",
+        "/api/config",
+        "Change some settings",
+        "application/json",
+    )
+    .auth()
+    .body("application/json", Some("Settings"))
+    .schema("SettingsReport"),
+    Route::new(
+        "get",
+        "/api/config/export",
+        "Saved settings",
+        "application/json",
+    )
+    .auth()
+    .schema("Settings"),
+    Route::new(
+        "post",
+        "/api/config/import",
+        "Store exported settings and restart",
+        "application/json",
+    )
+    .auth()
+    .body("application/json", Some("Settings"))
+    .schema("SettingsReport"),
+    Route::new(
+        "get",
+        "/api/openapi.json",
+        "This description",
+        "application/json",
+    ),
+    Route::new(
+        "get",
+        "/api/v1/status",
+        "Current reading",
+        "application/json",
+    )
+    .schema("Status"),
+    Route::new(
+        "get",
+        "/api/history",
+        "Recent averaged readings",
+        "application/json",
+    )
+    .schema("History"),
+    Route::new(
+        "get",
+        "/api/history.csv",
+        "Recent averaged readings as CSV",
+        "text/csv",
+    ),
+    Route::new(
+        "get",
+        "/api/next",
+        "Next reading, or 204 after the timeout",
+        "application/json",
+    )
+    .query(&[
+        Param::optional("after", "integer", "Sequence of the last reading seen"),
+        Param::optional("timeout", "integer", "Seconds to wait, up to 10"),
+    ])
+    .schema("NextReading"),
+    Route::new("get", "/chart", "Power chart page", "text/html"),
+    Route::new("get", "/dashboard", "Every meter's readings", "text/html"),
+    Route::new(
+        "get",
+        "/api/dashboard",
+        "Every meter's readings",
+        "application/json",
+    )
+    .schema("Dashboard"),
+    Route::new("get", "/amps", "Current in amps", "text/plain"),
+    Route::new("get", "/watts", "Power in watts", "text/plain"),
+    Route::new(
+        "get",
+        "/calibration/report",
+        "Calibration report",
+        "application/json",
+    )
+    .query(&[Param::optional("format", "string", "csv for CSV")])
+    .schema("Calibration"),
+    Route::new(
+        "post",
+        "/calibration/point",
+        "Add a calibration point",
+        "application/json",
+    )
+    .auth()
+    .form(&[Param::required(
+        "reference_amps",
+        "number",
+        "Current measured with a reference meter",
+    )])
+    .schema("Calibration"),
+    Route::new(
+        "post",
+        "/calibration/reset",
+        "Clear the calibration",
+        "text/plain",
+    )
+    .auth(),
+    Route::new("post", "/api/v1/night", "Force night mode", "text/plain")
+        .auth()
+        .form(&[Param::required("mode", "string", "on, off or auto")]),
+    Route::new(
+        "get",
+        "/api/v1/outputs",
+        "Webhook and spool state",
+        "application/json",
+    )
+    .schema("Outputs"),
+    Route::new(
+        "get",
+        "/api/health",
+        "Health for fleet monitoring",
+        "application/json",
+    )
+    .schema("Health"),
+    Route::new("get", "/metrics", "Prometheus metrics", "text/plain"),
+    Route::new("post", "/ota", "Upload a firmware image", "text/plain")
+        .auth()
+        .body("application/octet-stream", None),
+];
+
+fn optional_number(value: Option<f32>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn select_options(names: impl IntoIterator<Item = &'static str>, current: &str) -> String {
+    let mut options = String::new();
+    for name in names {
+        write!(
+            options,
+            "<option value=\"{}\"{}>{}</option>",
+            name,
+            if name == current { " selected" } else { "" },
+            name
+        )
+        .unwrap();
     }
+    options
+}
 
-    output
+fn brightness_options(current: BrightnessSetting) -> String {
+    let settings = std::iter::once(BrightnessSetting::Auto)
+        .chain(Brightness::ALL.into_iter().map(BrightnessSetting::Fixed));
+    select_options(settings.map(|setting| setting.name()), current.name())
 }
 
-#[inline(always)]
-fn split_urlencoded_kv<'a>(input: &'a str) -> (&'a str, String) {
-    let eq = input.find('=').unwrap_or(input.len());
-    let (key, value) = input.split_at(eq);
-    let nul = value.find('\0').unwrap_or(value.len());
-    // Urldecode %xx
-    let value = percent_decode_str(&value[1..nul]);
-    let nul = value.find('\0').unwrap_or(value.len());
-    (key, value[..nul].to_string())
+fn primary_unit_options(current: PrimaryUnit) -> String {
+    select_options(
+        PrimaryUnit::ALL.into_iter().map(PrimaryUnit::name),
+        current.name(),
+    )
 }
 
-pub(crate) static CURRENT_KNOWN_WIFI_SSID: Lazy<Arc<Mutex<String>>> =
-    Lazy::new(|| Arc::new(Mutex::new(String::new())));
-pub(crate) static CURRENT_KNOWN_WEBHOOK: Lazy<Arc<Mutex<String>>> =
-    Lazy::new(|| Arc::new(Mutex::new(String::new())));
+fn method_options(current: Method) -> String {
+    select_options(Method::ALL.into_iter().map(Method::name), current.name())
+}
+
+fn mqtt_format_options(current: PayloadFormat) -> String {
+    select_options(
+        PayloadFormat::ALL.into_iter().map(PayloadFormat::name),
+        current.name(),
+    )
+}
+
+fn security_options(current: Security) -> String {
+    select_options(
+        Security::ALL.into_iter().map(Security::name),
+        current.name(),
+    )
+}
+
+fn rotation_options(current: Rotation) -> String {
+    select_options(
+        Rotation::ALL.into_iter().map(Rotation::name),
+        current.name(),
+    )
+}
+
+fn language_options() -> String {
+    select_options(
+        std::iter::once(i18n::AUTO).chain(Language::ALL.into_iter().map(Language::code)),
+        i18n::setting(),
+    )
+}
+
+fn night_options(current: NightTrigger) -> String {
+    // Darkness can only be told with the light sensor
+    let dark = cfg!(feature = "ambient-ldr").then_some("dark");
+    select_options(
+        ["never", "schedule"].into_iter().chain(dark),
+        current.name(),
+    )
+}
+
+fn night_display_options(current: NightDisplay) -> String {
+    let settings = std::iter::once(NightDisplay::Off)
+        .chain(Brightness::ALL.into_iter().map(NightDisplay::Dimmed))
+        .chain(std::iter::once(NightDisplay::Inverted));
+    select_options(settings.map(|setting| setting.name()), current.name())
+}
+
+fn night_schedule(dimming: DimmingPolicy) -> (String, String) {
+    match dimming.night {
+        NightTrigger::Schedule { start, end } => {
+            (format_time_of_day(start), format_time_of_day(end))
+        }
+        _ => (String::new(), String::new()),
+    }
+}
+
+/// One input per webhook header, see `headers`.
+fn header_inputs() -> String {
+    (0..MAX_HEADERS)
+        .map(|index| {
+            format!(
+                "<input type=\"text\" name=\"{}\" placeholder=\"Authorization: Bearer ...\" value=\"{}\"><br>\n",
+                NVS_HEADERS[index],
+                html_escape(&headers::masked_line(index))
+            )
+        })
+        .collect()
+}
+
+fn network_inputs(t: &Strings) -> String {
+    (0..MAX_NETWORKS - 1)
+        .map(|index| {
+            format!(
+                "<input type=\"text\" name=\"{}\" placeholder=\"SSID\" value=\"{}\">\n\
+                 <input type=\"password\" name=\"{}\" placeholder=\"{}\"><br>\n",
+                NVS_SSIDS[index],
+                html_escape(&networks::ssid(index)),
+                NVS_PSKS[index],
+                t.password
+            )
+        })
+        .collect()
+}
 
 fn render_setup_page<'r>(
     req: esp_idf_svc::http::server::Request<&mut esp_idf_svc::http::server::EspHttpConnection<'r>>,
 ) -> Result<(), EspIOError> {
+    let language = i18n::language(&req);
+    let t = language.strings();
+    let static_ip = static_ip::current();
     let mut server_msg = String::new();
     write!(
         server_msg,
         "<!DOCTYPE html>
-        <html><head><title>Coarse watt-o-meter</title></head>
+        <html lang=\"{}\"><head><title>{}</title>
+        {}
+        <link rel=\"stylesheet\" href=\"{}\"></head>
         <body>
         <form action=\"/save\" method=\"post\">
-        <label for=\"wifi_ssid\">Wi-Fi SSID:</label><br>
-        <input type=\"text\" id=\"wifi_ssid\" name=\"wifi_ssid\" value=\"{}\"><br>
-        <label for=\"wifi_psk\">Wi-Fi Password:</label><br>
-        <input type=\"password\" id=\"wifi_psk\" name=\"wifi_psk\" value\"{}\"><br><br>
-        <label for=\"webhook\">URL to POST with the Amps in {{amps}} (if non-empty)</label><br>
-        <input type=\"text\" id=\"webhook\" name=\"webhook\" value=\"{}\"><br><br>
-        <input type=\"submit\" value=\"Submit\">
+        <label for=\"wifi_ssid\">{}</label><br>
+        <select id=\"wifi_ssid\" name=\"wifi_ssid\"><option value=\"{}\">{}</option><option value=\"\">{}</option></select>
+        <button type=\"button\" id=\"scan\" data-scanning=\"{}\" data-open=\"{}\" data-not-found=\"{}\">{}</button><br>
+        <input type=\"text\" id=\"wifi_ssid_other\" name=\"wifi_ssid_other\" placeholder=\"{}\" hidden><br>
+        <label for=\"wifi_psk\">{}</label><br>
+        <input type=\"password\" id=\"wifi_psk\" name=\"wifi_psk\" value\"{}\"><br>
+        <label for=\"wifi_auth\">{}</label>
+        <select id=\"wifi_auth\" name=\"wifi_auth\">{}</select><br>
+        <label for=\"wifi_bssid\">{}</label>
+        <input type=\"text\" id=\"wifi_bssid\" name=\"wifi_bssid\" placeholder=\"aa:bb:cc:dd:ee:ff\" value=\"{}\">
+        <label for=\"wifi_channel\">{}</label>
+        <input type=\"number\" id=\"wifi_channel\" name=\"wifi_channel\" min=\"1\" max=\"14\" value=\"{}\"><br>
+        <label for=\"rssi_floor\">{}</label>
+        <input type=\"number\" id=\"rssi_floor\" name=\"rssi_floor\" min=\"-100\" max=\"-30\" value=\"{}\"><br>
+        <input type=\"checkbox\" id=\"test_wifi\" name=\"test_wifi\" value=\"1\" checked>
+        <label for=\"test_wifi\">{}</label><br><br>
+        <label>{}</label><br>
+        {}<br>
+        <label for=\"eap_user\">{}</label><br>
+        <input type=\"text\" id=\"eap_user\" name=\"eap_user\" placeholder=\"{}\" value=\"{}\">
+        <input type=\"password\" name=\"eap_pass\" placeholder=\"{}\"><br>
+        <input type=\"text\" name=\"eap_identity\" placeholder=\"{}\" value=\"{}\"><br><br>
+        <label for=\"ip_address\">{}</label><br>
+        <input type=\"text\" id=\"ip_address\" name=\"ip_address\" placeholder=\"192.168.1.50\" value=\"{}\">
+        <label for=\"ip_netmask\">{}</label>
+        <input type=\"text\" id=\"ip_netmask\" name=\"ip_netmask\" placeholder=\"255.255.255.0\" value=\"{}\"><br>
+        <label for=\"ip_gateway\">{}</label>
+        <input type=\"text\" id=\"ip_gateway\" name=\"ip_gateway\" value=\"{}\">
+        <label for=\"ip_dns\">{}</label>
+        <input type=\"text\" id=\"ip_dns\" name=\"ip_dns\" value=\"{}\"><br><br>
+        <label for=\"webhook\">{}</label><br>
+        <input type=\"text\" id=\"webhook\" name=\"webhook\" value=\"{}\">
+        <button type=\"button\" id=\"test_webhook\" data-testing=\"{}\">{}</button> <span id=\"webhook_result\"></span><br>
+        <label for=\"hook_body\">{}</label><br>
+        <input type=\"text\" id=\"hook_body\" name=\"hook_body\" maxlength=\"127\" value=\"{}\"><br>
+        <label for=\"hook_method\">{}</label>
+        <select id=\"hook_method\" name=\"hook_method\">{}</select>
+        <label for=\"hook_type\">{}</label>
+        <input type=\"text\" id=\"hook_type\" name=\"hook_type\" placeholder=\"{}\" value=\"{}\"><br>
+        <label for=\"hook_expect\">{}</label><br>
+        <input type=\"text\" id=\"hook_expect\" name=\"hook_expect\" maxlength=\"127\" value=\"{}\"><br>
+        <label>{}</label><br>
+        {}
+        <label for=\"report_interval\">{}</label><br>
+        <input type=\"number\" id=\"report_interval\" name=\"report_interval\" min=\"1\" value=\"{}\"><br>
+        <label for=\"hook_batch\">{}</label><br>
+        <input type=\"number\" id=\"hook_batch\" name=\"hook_batch\" min=\"1\" max=\"{}\" value=\"{}\"><br><br>
+        <label for=\"max_watts\">{}</label><br>
+        <input type=\"number\" id=\"max_watts\" name=\"max_watts\" min=\"1\" value=\"{}\"><br><br>
+        <label for=\"budget_day\">{}</label><br>
+        <input type=\"number\" step=\"any\" id=\"budget_day\" name=\"budget_day\" value=\"{}\"><br>
+        <label for=\"budget_month\">{}</label><br>
+        <input type=\"number\" step=\"any\" id=\"budget_month\" name=\"budget_month\" value=\"{}\"><br><br>
+        <label for=\"price_kwh\">{}</label><br>
+        <input type=\"number\" step=\"any\" id=\"price_kwh\" name=\"price_kwh\" value=\"{}\"><br>
+        <label for=\"currency\">{}</label><br>
+        <input type=\"text\" id=\"currency\" name=\"currency\" maxlength=\"4\" value=\"{}\"><br><br>
+        <label for=\"weather_url\">{}</label><br>
+        <input type=\"text\" id=\"weather_url\" name=\"weather_url\" value=\"{}\"><br><br>
+        <label for=\"primary_unit\">{}</label><br>
+        <select id=\"primary_unit\" name=\"primary_unit\">{}</select><br><br>
+        <label for=\"brightness\">{}</label><br>
+        <select id=\"brightness\" name=\"brightness\">{}</select><br><br>
+        <label for=\"night\">{}</label><br>
+        <select id=\"night\" name=\"night\">{}</select><br>
+        <label for=\"night_start\">{}</label>
+        <input type=\"time\" id=\"night_start\" name=\"night_start\" value=\"{}\">
+        <label for=\"night_end\">{}</label>
+        <input type=\"time\" id=\"night_end\" name=\"night_end\" value=\"{}\"><br>
+        <label for=\"utc_offset\">{}</label><br>
+        <input type=\"number\" step=\"any\" id=\"utc_offset\" name=\"utc_offset\" value=\"{}\"><br>
+        <label for=\"night_display\">{}</label><br>
+        <select id=\"night_display\" name=\"night_display\">{}</select><br><br>
+        <label for=\"display_idle_min\">{}</label><br>
+        <input type=\"number\" id=\"display_idle_min\" name=\"display_idle_min\" min=\"0\" value=\"{}\"><br><br>
+        <label for=\"display_rotation\">{}</label><br>
+        <select id=\"display_rotation\" name=\"display_rotation\">{}</select><br><br>
+        <label>{}</label><br>
+        <label for=\"display_sda\">{}</label>
+        <input type=\"number\" id=\"display_sda\" name=\"display_sda\" min=\"0\" max=\"39\" value=\"{}\">
+        <label for=\"display_scl\">{}</label>
+        <input type=\"number\" id=\"display_scl\" name=\"display_scl\" min=\"0\" max=\"39\" value=\"{}\"><br>
+        <label for=\"display_address\">{}</label>
+        <input type=\"text\" id=\"display_address\" name=\"display_address\" value=\"{}\">
+        <label for=\"display_khz\">{}</label>
+        <input type=\"number\" id=\"display_khz\" name=\"display_khz\" min=\"10\" max=\"1000\" value=\"{}\"><br><br>
+        <label for=\"cors_origin\">{}</label><br>
+        <input type=\"text\" id=\"cors_origin\" name=\"cors_origin\" value=\"{}\"><br><br>
+        <label for=\"device_name\">{}</label><br>
+        <input type=\"text\" id=\"device_name\" name=\"device_name\" maxlength=\"{}\" value=\"{}\"><br>
+        <label for=\"channel_label\">{}</label><br>
+        <input type=\"text\" id=\"channel_label\" name=\"channel_label\" maxlength=\"{}\" value=\"{}\"><br><br>
+        <label for=\"peers\">{}</label><br>
+        <input type=\"text\" id=\"peers\" name=\"peers\" value=\"{}\"><br><br>
+        <label for=\"mqtt_url\">{}</label><br>
+        <input type=\"text\" id=\"mqtt_url\" name=\"mqtt_url\" value=\"{}\"><br>
+        <label for=\"mqtt_user\">{}</label>
+        <input type=\"text\" id=\"mqtt_user\" name=\"mqtt_user\" value=\"{}\">
+        <label for=\"mqtt_pass\">{}</label>
+        <input type=\"password\" id=\"mqtt_pass\" name=\"mqtt_pass\"><br>
+        <label for=\"mqtt_prefix\">{}</label>
+        <input type=\"text\" id=\"mqtt_prefix\" name=\"mqtt_prefix\" value=\"{}\">
+        <label for=\"mqtt_interval\">{}</label>
+        <input type=\"number\" id=\"mqtt_interval\" name=\"mqtt_interval\" min=\"1\" value=\"{}\">
+        <label for=\"mqtt_format\">{}</label>
+        <select id=\"mqtt_format\" name=\"mqtt_format\">{}</select><br>
+        <label for=\"mqtt_thing\">{}</label>
+        <input type=\"text\" id=\"mqtt_thing\" name=\"mqtt_thing\" value=\"{}\">
+        <label for=\"mqtt_shadow\">{}</label>
+        <input type=\"text\" id=\"mqtt_shadow\" name=\"mqtt_shadow\" value=\"{}\"><br><br>
+        <label for=\"coap_url\">{}</label><br>
+        <input type=\"text\" id=\"coap_url\" name=\"coap_url\" value=\"{}\"><br>
+        <label for=\"coap_interval\">{}</label>
+        <input type=\"number\" id=\"coap_interval\" name=\"coap_interval\" min=\"1\" value=\"{}\"><br><br>
+        <label for=\"graphite_host\">{}</label><br>
+        <input type=\"text\" id=\"graphite_host\" name=\"graphite_host\" value=\"{}\"><br>
+        <label for=\"graphite_prefix\">{}</label>
+        <input type=\"text\" id=\"graphite_prefix\" name=\"graphite_prefix\" placeholder=\"{}\" value=\"{}\"><br><br>
+        <label for=\"snmp_community\">{}</label><br>
+        <input type=\"text\" id=\"snmp_community\" name=\"snmp_community\" value=\"{}\"><br><br>
+        <input type=\"checkbox\" id=\"modbus\" name=\"modbus\" value=\"on\"{}>
+        <label for=\"modbus\">{}</label><br><br>
+        <label for=\"ntfy_url\">{}</label><br>
+        <input type=\"text\" id=\"ntfy_url\" name=\"ntfy_url\" placeholder=\"https://ntfy.sh/...\" value=\"{}\"><br>
+        <label for=\"pushover_user\">{}</label>
+        <input type=\"text\" id=\"pushover_user\" name=\"pushover_user\" value=\"{}\">
+        <label for=\"pushover_token\">{}</label>
+        <input type=\"password\" id=\"pushover_token\" name=\"pushover_token\"><br>
+        <label for=\"tg_chat\">{}</label>
+        <input type=\"text\" id=\"tg_chat\" name=\"tg_chat\" value=\"{}\">
+        <label for=\"tg_token\">{}</label>
+        <input type=\"password\" id=\"tg_token\" name=\"tg_token\"><br><br>
+        <label for=\"language\">{}</label><br>
+        <select id=\"language\" name=\"language\">{}</select><br><br>
+        <label for=\"admin_password\">{}</label><br>
+        <input type=\"password\" id=\"admin_password\" name=\"admin_password\" minlength=\"{}\"><br><br>
+        <input type=\"submit\" value=\"{}\">
+        <script src=\"{}\"></script>
+        <script src=\"{}\"></script>
         </body></html>",
+        language.code(),
+        t.title,
+        pwa::head_links(),
+        assets::url("style.css"),
+        t.wifi_network,
+        with_locked_value(&CURRENT_KNOWN_WIFI_SSID.clone(), identity),
         with_locked_value(&CURRENT_KNOWN_WIFI_SSID.clone(), identity),
+        t.other_network,
+        t.scanning,
+        t.open_network,
+        t.not_found,
+        t.scan,
+        t.hidden_network,
+        t.wifi_password,
         "",
+        t.wifi_security,
+        security_options(security::current()),
+        t.bssid,
+        pinning::bssid().map_or(String::new(), |bssid| pinning::format_bssid(&bssid)),
+        t.channel,
+        pinning::channel().map_or(String::new(), |channel| channel.to_string()),
+        t.rssi_floor,
+        signal::floor(),
+        t.test_wifi,
+        t.other_networks,
+        network_inputs(t),
+        t.enterprise,
+        t.user,
+        html_escape(&enterprise::user()),
+        t.password,
+        t.anonymous_identity,
+        html_escape(&enterprise::identity()),
+        t.static_ip,
+        static_ip.map_or(String::new(), |ip| ip.address.to_string()),
+        t.netmask,
+        static_ip.map_or(String::new(), |ip| ip.netmask.to_string()),
+        t.gateway,
+        static_ip.map_or(String::new(), |ip| ip.gateway.to_string()),
+        t.dns_server,
+        static_ip.map_or(String::new(), |ip| ip.dns.to_string()),
+        t.webhook,
         with_locked_value(&CURRENT_KNOWN_WEBHOOK.clone(), identity),
+        t.testing,
+        t.test_webhook,
+        t.webhook_body,
+        html_escape(&body::template()),
+        t.webhook_method,
+        method_options(body::method()),
+        t.webhook_content_type,
+        body::DEFAULT_CONTENT_TYPE,
+        html_escape(&body::content_type_setting()),
+        t.webhook_expect,
+        html_escape(&delivery::expected()),
+        t.webhook_headers,
+        header_inputs(),
+        t.report_interval,
+        with_locked_value(&CURRENT_KNOWN_REPORT_INTERVAL.clone(), identity),
+        t.batch_size,
+        batch::MAX_SIZE,
+        batch::size(),
+        t.max_watts,
+        with_locked_value(&CURRENT_KNOWN_MAX_WATTS.clone(), identity),
+        t.budget_day,
+        with_locked_value(&CURRENT_KNOWN_BUDGET.clone(), |b| optional_number(b.daily_kwh)),
+        t.budget_month,
+        with_locked_value(&CURRENT_KNOWN_BUDGET.clone(), |b| optional_number(b.monthly_kwh)),
+        t.price_kwh,
+        with_locked_value(&CURRENT_KNOWN_TARIFF.clone(), |t| optional_number(t.price_kwh)),
+        t.currency,
+        with_locked_value(&CURRENT_KNOWN_TARIFF.clone(), |t| html_escape(&t.currency)),
+        t.weather_url,
+        html_escape(&with_locked_value(&CURRENT_KNOWN_WEATHER_URL.clone(), identity)),
+        t.primary_unit,
+        with_locked_value(&CURRENT_KNOWN_PRIMARY_UNIT.clone(), primary_unit_options),
+        t.brightness,
+        with_locked_value(&CURRENT_KNOWN_DIMMING.clone(), |d| brightness_options(d.brightness)),
+        t.night,
+        with_locked_value(&CURRENT_KNOWN_DIMMING.clone(), |d| night_options(d.night)),
+        t.night_from,
+        with_locked_value(&CURRENT_KNOWN_DIMMING.clone(), |d| night_schedule(d).0),
+        t.night_until,
+        with_locked_value(&CURRENT_KNOWN_DIMMING.clone(), |d| night_schedule(d).1),
+        t.utc_offset,
+        with_locked_value(&CURRENT_KNOWN_DIMMING.clone(), |d| d.utc_offset_min as f32 / 60.0),
+        t.night_display,
+        with_locked_value(&CURRENT_KNOWN_DIMMING.clone(), |d| night_display_options(d.night_display)),
+        t.display_idle,
+        with_locked_value(&CURRENT_KNOWN_DISPLAY_IDLE_MINUTES.clone(), identity),
+        t.display_rotation,
+        with_locked_value(&CURRENT_KNOWN_DISPLAY_ROTATION.clone(), rotation_options),
+        t.display_bus,
+        t.sda,
+        with_locked_value(&CURRENT_KNOWN_DISPLAY_BUS.clone(), |b| b.sda),
+        t.scl,
+        with_locked_value(&CURRENT_KNOWN_DISPLAY_BUS.clone(), |b| b.scl),
+        t.address,
+        with_locked_value(&CURRENT_KNOWN_DISPLAY_BUS.clone(), |b| format!("{:#04x}", b.address)),
+        t.speed,
+        with_locked_value(&CURRENT_KNOWN_DISPLAY_BUS.clone(), |b| b.khz),
+        t.cors_origin,
+        html_escape(&cors::allowed_origin()),
+        t.device_name,
+        labels::MAX_LEN,
+        html_escape(&labels::device_name()),
+        t.channel_label,
+        labels::MAX_LEN,
+        html_escape(&labels::channel_label()),
+        t.peers,
+        html_escape(&dashboard::setting()),
+        t.mqtt_url,
+        with_locked_value(&CURRENT_KNOWN_MQTT.clone(), |m| html_escape(&m.url)),
+        t.mqtt_user,
+        with_locked_value(&CURRENT_KNOWN_MQTT.clone(), |m| html_escape(&m.user)),
+        t.mqtt_password,
+        t.mqtt_prefix,
+        with_locked_value(&CURRENT_KNOWN_MQTT.clone(), |m| html_escape(&m.prefix)),
+        t.mqtt_interval,
+        with_locked_value(&CURRENT_KNOWN_MQTT.clone(), |m| m.interval_s),
+        t.mqtt_format,
+        mqtt_format_options(with_locked_value(&CURRENT_KNOWN_MQTT.clone(), |m| m.format)),
+        t.mqtt_thing,
+        with_locked_value(&CURRENT_KNOWN_MQTT.clone(), |m| html_escape(&m.thing)),
+        t.mqtt_shadow,
+        with_locked_value(&CURRENT_KNOWN_MQTT.clone(), |m| html_escape(&m.shadow)),
+        t.coap_url,
+        with_locked_value(&CURRENT_KNOWN_COAP.clone(), |c| html_escape(&c.url)),
+        t.coap_interval,
+        with_locked_value(&CURRENT_KNOWN_COAP.clone(), |c| c.interval_s),
+        t.graphite_host,
+        with_locked_value(&CURRENT_KNOWN_GRAPHITE.clone(), |g| html_escape(&g.host)),
+        t.graphite_prefix,
+        graphite::DEFAULT_PREFIX,
+        with_locked_value(&CURRENT_KNOWN_GRAPHITE.clone(), |g| html_escape(&g.prefix)),
+        t.snmp_community,
+        with_locked_value(&CURRENT_KNOWN_SNMP_COMMUNITY.clone(), |c| html_escape(&c)),
+        if modbus::is_enabled() { " checked" } else { "" },
+        t.modbus,
+        t.ntfy_url,
+        html_escape(&notify::ntfy_url()),
+        t.pushover_user,
+        html_escape(&notify::pushover_user()),
+        t.pushover_token,
+        t.telegram_chat,
+        html_escape(&telegram::chat()),
+        t.telegram_token,
+        t.language,
+        language_options(),
+        t.admin_password,
+        MIN_PASSWORD_LEN,
+        t.submit,
+        assets::url("scan.js"),
+        assets::url("webhook_test.js"),
     )
     .unwrap();
     req.into_response(200, Some("OK"), &[("Content-Type", "text/html")])?
@@ -83,58 +1038,332 @@ fn render_setup_page<'r>(
     Ok(())
 }
 
-fn add_server_setup_handlers<'a>(
-    nvs: &'a mut nvs::EspNvs<nvs::NvsDefault>,
-    server: &mut EspHttpServer<'a>,
+/// Shown after saving with the connection test on, until the main loop has
+/// tried the new credentials. `{rejected}` is replaced by `rejected_html`,
+/// the other placeholders by the texts of the same name.
+const PROVISION_PAGE: &str = "<!DOCTYPE html>
+<html lang=\"{lang}\"><head><title>{title}</title></head>
+<body>{rejected}<p id=\"status\">{trying}</p>
+<script>
+function poll() {
+  fetch('/api/v1/provision_status').then(r => r.json()).then(s => {
+    const status = document.getElementById('status');
+    if (s.state == 'connected') {
+      status.innerHTML = '{connected} <a href=\"http://' + s.ip + '/\">' + s.ip + '</a>.';
+    } else if (s.state == 'failed') {
+      status.innerHTML = '{could_not_connect} ' + s.reason + '. <a href=\"/\">{back_to_setup}</a>';
+    } else {
+      setTimeout(poll, 1000);
+    }
+  }).catch(() => setTimeout(poll, 1000));
+}
+poll();
+</script></body></html>";
+
+pub(crate) fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The fields `/save` left as they were, for the page it answers with.
+fn rejected_html(t: &Strings, rejected: &[String]) -> String {
+    if rejected.is_empty() {
+        return String::new();
+    }
+    let items: String = rejected
+        .iter()
+        .map(|field| format!("<li>{}</li>", html_escape(field)))
+        .collect();
+    format!("<p>{}</p><ul>{}</ul>", t.not_saved, items)
+}
+
+fn provision_page(language: Language, rejected: &[String]) -> String {
+    let t = language.strings();
+    PROVISION_PAGE
+        .replace("{lang}", language.code())
+        .replace("{title}", t.title)
+        .replace("{trying}", t.trying)
+        .replace("{connected}", t.connected)
+        .replace("{could_not_connect}", t.could_not_connect)
+        .replace("{back_to_setup}", t.back_to_setup)
+        .replace("{rejected}", &rejected_html(t, rejected))
+}
+
+/// Shown after saving without the connection test, counting down to the
+/// restart that applies the settings.
+fn saved_page(language: Language, ssid: &str, rejected: &[String]) -> String {
+    let t = language.strings();
+    format!(
+        "<!DOCTYPE html>
+<html lang=\"{}\"><head><title>{}</title></head>
+<body><p>{} \"{}\".</p>{}
+<p id=\"status\">{} <span id=\"left\">{}</span> {}</p>
+<form action=\"/restart\" method=\"post\"><input type=\"hidden\" name=\"token\" value=\"{}\">
+<input type=\"submit\" value=\"{}\"></form>
+<script>
+let left = {};
+const timer = setInterval(() => {{
+  left -= 1;
+  document.getElementById('left').textContent = left;
+  if (left <= 0) {{
+    clearInterval(timer);
+    document.getElementById('status').textContent = '{}';
+  }}
+}}, 1000);
+</script></body></html>",
+        language.code(),
+        t.title,
+        t.saved_for_network,
+        html_escape(ssid),
+        rejected_html(t, rejected),
+        t.restarts_in,
+        restart::CONFIRM_TIMEOUT_S,
+        t.seconds_to_apply,
+        restart::CONFIRMATION.issue(),
+        t.restart_now,
+        restart::CONFIRM_TIMEOUT_S,
+        t.restarting,
+    )
+}
+
+/// Register the configuration handlers. They get their own NVS handle so
+/// they don't borrow anything from the caller and the server can be torn
+/// down and rebuilt freely when switching in and out of setup mode. The
+/// connection test is only offered from the setup access point.
+fn add_server_setup_handlers(
+    nvs: &nvs::EspNvsPartition<nvs::NvsDefault>,
+    wifi: &Arc<Mutex<EspWifi<'static>>>,
+    server: &mut EspHttpServer<'_>,
+    setup_mode: bool,
 ) -> Result<(), EspError> {
-    let nvs = Arc::new(Mutex::new(nvs));
+    settings::add_config_handlers(nvs, server)?;
+    factory_reset::add_factory_reset_handler(server)?;
+    nvs_admin::add_nvs_admin_handlers(nvs, server)?;
+    tls::add_handlers(nvs, server)?;
+    webhook_test::add_test_handler(server)?;
+    let nvs = Arc::new(Mutex::new(nvs::EspNvs::new(nvs.clone(), "ssaa", true)?));
 
     server.fn_handler("/save", esp_idf_svc::http::Method::Get, render_setup_page)?;
 
+    let wifi = wifi.clone();
+    server.fn_handler(
+        "/api/scan",
+        esp_idf_svc::http::Method::Get,
+        move |mut req| -> Result<(), esp_idf_svc::io::EspIOError> {
+            // A scan takes the radio for a couple of seconds
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            let result = scan::scan_json(&mut wifi.lock().unwrap());
+            match result {
+                Ok(networks) => {
+                    req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+                        .write(networks.as_bytes())?;
+                }
+                Err(err) => {
+                    log::warn!("Wi-Fi scan failed: {:?}", err);
+                    error::respond(req, 503, "Wi-Fi scan failed")?;
+                }
+            }
+
+            Ok(())
+        },
+    )?;
+
     server.fn_handler(
         "/save",
         esp_idf_svc::http::Method::Post,
         move |mut req| -> Result<(), esp_idf_svc::io::EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
             // Check that we have received wifi_ssid and wifi_psk as form data
-            let mut buf = [0u8; 500];
-            let read_bytes = req.read(&mut buf)?;
-            let form_data = std::str::from_utf8(&buf).unwrap();
+            let Some(body) = form::read_body(&mut req, MAX_FORM_LEN)? else {
+                let message = format!("At most {} bytes of form data", MAX_FORM_LEN);
+                return error::respond(req, 413, &message);
+            };
             let mut wifi_ssid = String::new();
             let mut wifi_psk = String::new();
+            let mut wifi_auth = String::new();
             let mut webhook = String::new();
-            // Form data is in the format "wifi_ssid=SSID&wifi_psk=PSK&webhook=...\0\0..."
-            for (key, value) in form_data.split('&').map(split_urlencoded_kv) {
-                match key {
+            let mut webhook_body = String::new();
+            let mut webhook_method = String::new();
+            let mut webhook_type = String::new();
+            let mut webhook_expect = String::new();
+            let mut report_interval = String::new();
+            let mut batch_size = String::new();
+            let mut header_lines: [Option<String>; MAX_HEADERS] = Default::default();
+            let mut max_watts = String::new();
+            let mut budget_day = String::new();
+            let mut budget_month = String::new();
+            let mut weather_url = String::new();
+            let mut price_kwh = String::new();
+            let mut currency = String::new();
+            let mut primary_unit = String::new();
+            let mut brightness = String::new();
+            let mut night = String::new();
+            let mut night_start = String::new();
+            let mut night_end = String::new();
+            let mut utc_offset = String::new();
+            let mut night_display = String::new();
+            let mut display_idle_min = String::new();
+            let mut display_rotation = String::new();
+            let mut display_sda = String::new();
+            let mut display_scl = String::new();
+            let mut display_address = String::new();
+            let mut display_khz = String::new();
+            let mut cors_origin = String::new();
+            let mut language = String::new();
+            let mut device_name = String::new();
+            let mut channel_label = String::new();
+            let mut peers = String::new();
+            let mut mqtt_fields = Vec::new();
+            let mut coap_url = String::new();
+            let mut coap_interval = String::new();
+            let mut graphite_host = String::new();
+            let mut graphite_prefix = String::new();
+            let mut snmp_community = String::new();
+            // Unchecked boxes aren't sent
+            let mut modbus_enabled = false;
+            let mut ntfy_url = String::new();
+            let mut pushover_user = String::new();
+            let mut pushover_token = String::new();
+            let mut telegram_chat = String::new();
+            let mut telegram_token = String::new();
+            let mut admin_password = String::new();
+            let mut other_ssids: [Option<String>; MAX_NETWORKS - 1] = Default::default();
+            let mut other_psks: [String; MAX_NETWORKS - 1] = Default::default();
+            let mut wifi_bssid = String::new();
+            let mut wifi_channel = String::new();
+            let mut rssi_floor = String::new();
+            let mut eap_identity = String::new();
+            let mut eap_user = String::new();
+            let mut eap_password = String::new();
+            let mut ip_address = String::new();
+            let mut ip_netmask = String::new();
+            let mut ip_gateway = String::new();
+            let mut ip_dns = String::new();
+            let mut test_wifi = false;
+            // Form data is in the format "wifi_ssid=SSID&wifi_psk=PSK&webhook=..."
+            for (key, value) in form::parse(&body) {
+                match key.as_str() {
                     "wifi_ssid" => wifi_ssid = value,
+                    // Typed in when "Other..." is picked, and after the list
+                    "wifi_ssid_other" if !value.is_empty() => wifi_ssid = value,
                     "wifi_psk" => wifi_psk = value,
+                    NVS_WIFI_AUTH => wifi_auth = value,
+                    NVS_WIFI_BSSID => wifi_bssid = value,
+                    NVS_WIFI_CHANNEL => wifi_channel = value,
+                    NVS_RSSI_FLOOR => rssi_floor = value,
+                    NVS_EAP_IDENTITY => eap_identity = value,
+                    NVS_EAP_USER => eap_user = value,
+                    NVS_EAP_PASSWORD => eap_password = value,
+                    NVS_IP_ADDRESS => ip_address = value,
+                    NVS_IP_NETMASK => ip_netmask = value,
+                    NVS_IP_GATEWAY => ip_gateway = value,
+                    NVS_IP_DNS => ip_dns = value,
                     "webhook" => webhook = value,
-                    _ => (),
+                    NVS_WEBHOOK_BODY => webhook_body = value,
+                    NVS_WEBHOOK_METHOD => webhook_method = value,
+                    NVS_WEBHOOK_TYPE => webhook_type = value,
+                    NVS_WEBHOOK_EXPECT => webhook_expect = value,
+                    "report_interval" => report_interval = value,
+                    NVS_BATCH_SIZE => batch_size = value,
+                    "max_watts" => max_watts = value,
+                    "budget_day" => budget_day = value,
+                    "budget_month" => budget_month = value,
+                    "weather_url" => weather_url = value,
+                    "price_kwh" => price_kwh = value,
+                    "currency" => currency = value,
+                    "primary_unit" => primary_unit = value,
+                    "brightness" => brightness = value,
+                    "night" => night = value,
+                    "night_start" => night_start = value,
+                    "night_end" => night_end = value,
+                    "utc_offset" => utc_offset = value,
+                    "night_display" => night_display = value,
+                    "display_idle_min" => display_idle_min = value,
+                    "display_rotation" => display_rotation = value,
+                    "display_sda" => display_sda = value,
+                    "display_scl" => display_scl = value,
+                    "display_address" => display_address = value,
+                    "display_khz" => display_khz = value,
+                    "cors_origin" => cors_origin = value,
+                    "language" => language = value,
+                    "device_name" => device_name = value,
+                    "channel_label" => channel_label = value,
+                    "peers" => peers = value,
+                    NVS_MQTT_URL | NVS_MQTT_USER | NVS_MQTT_PASSWORD | NVS_MQTT_PREFIX
+                    | NVS_MQTT_INTERVAL | NVS_MQTT_THING | NVS_MQTT_SHADOW | NVS_MQTT_FORMAT => {
+                        mqtt_fields.push((key, value))
+                    }
+                    NVS_COAP_URL => coap_url = value,
+                    NVS_COAP_INTERVAL => coap_interval = value,
+                    NVS_GRAPHITE_HOST => graphite_host = value,
+                    NVS_GRAPHITE_PREFIX => graphite_prefix = value,
+                    NVS_SNMP_COMMUNITY => snmp_community = value,
+                    NVS_MODBUS => modbus_enabled = value == "on",
+                    NVS_NTFY_URL => ntfy_url = value,
+                    NVS_PUSHOVER_USER => pushover_user = value,
+                    NVS_PUSHOVER_TOKEN => pushover_token = value,
+                    NVS_TELEGRAM_CHAT => telegram_chat = value,
+                    NVS_TELEGRAM_TOKEN => telegram_token = value,
+                    "admin_password" => admin_password = value,
+                    "test_wifi" => test_wifi = setup_mode && value == "1",
+                    key => {
+                        if let Some(index) = NVS_HEADERS.iter().position(|k| *k == key) {
+                            header_lines[index] = Some(value);
+                        } else if let Some(index) = NVS_SSIDS.iter().position(|k| *k == key) {
+                            other_ssids[index] = Some(value);
+                        } else if let Some(index) = NVS_PSKS.iter().position(|k| *k == key) {
+                            other_psks[index] = value;
+                        }
+                    }
                 }
             }
 
-            log::info!("Received {} bytes.\nBody: {:?}", read_bytes, form_data);
+            log::info!("Received {} bytes of setup form data", body.len());
 
-            // Check that we have received both values
-            if wifi_ssid.is_empty() || wifi_psk.is_empty() {
-                req.into_response(400, Some("Bad Request"), &[("Content-Type", "text/plain")])?
-                    .write("Missing Wi-Fi SSID or Password".as_bytes())?;
-                Ok(())
+            // Networks saved without a password are joined with the
+            // WPA2-Enterprise credentials, which must be complete
+            let eap_valid = [&eap_identity, &eap_user, &eap_password]
+                .into_iter()
+                .all(|value| enterprise::is_valid(value));
+            let eap_complete = eap_valid
+                && !eap_user.is_empty()
+                && (!eap_password.is_empty()
+                    || (eap_user == enterprise::user() && enterprise::is_configured()));
+
+            // Anything else is left to the station to work out
+            let security = Security::from_name(&wifi_auth).unwrap_or_default();
+
+            // Check that we have received both values, and that they fit in
+            // the Wi-Fi configuration
+            if wifi_ssid.is_empty()
+                || (wifi_psk.is_empty() && !eap_complete && security != Security::Open)
+            {
+                error::respond(req, 400, "Missing Wi-Fi SSID or Password")
+            } else if wifi_ssid.len() > 32 || !security.is_valid_psk(&wifi_psk) {
+                error::respond(
+                    req,
+                    400,
+                    "The SSID takes up to 32 bytes and the password 8 to 63 characters (5 or 13 for WEP)",
+                )
             } else {
                 log::info!(
-                    "Received Wi-Fi SSID: {:?}, Password: {:?}, Webhook: {:?}",
+                    "Received Wi-Fi SSID: {:?}, Webhook: {:?}",
                     wifi_ssid,
-                    wifi_psk,
                     webhook
                 );
 
                 let mut nvs = nvs.lock().unwrap();
-
-                // Send the response before restarting the device!
-                let written_bytes = req
-                    .into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
-                    .write("Saved Wi-Fi credentials and restarting system".as_bytes())?;
-
-                log::info!("Sent response of {} bytes", written_bytes);
+                // Fields left as they were because of their values
+                let mut rejected: Vec<String> = Vec::new();
 
                 if let Err(x) = nvs.set_str("wifi_ssid", &wifi_ssid) {
                     log::warn!("Error setting wifi_ssid in NVS: {:?}", x);
@@ -144,35 +1373,663 @@ fn add_server_setup_handlers<'a>(
                     log::warn!("Error setting wifi_psk in NVS: {:?}", x);
                 }
                 log::info!("Setting Wi-Fi PSK in NVS");
+                if let Err(x) = nvs.set_str(NVS_WIFI_AUTH, security.name()) {
+                    log::warn!("Error setting {} in NVS: {:?}", NVS_WIFI_AUTH, x);
+                }
+                // A connection test right after uses it
+                security::load(&nvs);
                 log::info!("Saved Wi-Fi credentials to NVS");
 
+                for (index, ssid) in other_ssids.into_iter().enumerate() {
+                    let Some(ssid) = ssid else { continue };
+                    let (ssid_key, psk_key) = (NVS_SSIDS[index], NVS_PSKS[index]);
+                    let psk = other_psks[index].as_str();
+                    let values = if ssid.is_empty() {
+                        [(ssid_key, ""), (psk_key, "")]
+                    } else if !networks::is_valid_ssid(&ssid) {
+                        rejected.push(format!("{} {:?}", ssid_key, ssid));
+                        continue;
+                    } else if psk.is_empty() && ssid == networks::ssid(index) {
+                        // Empty keeps the password of a network left as it was
+                        continue;
+                    } else if !networks::is_valid_psk(psk) {
+                        rejected.push(format!("{} (8 to 63 characters)", psk_key));
+                        continue;
+                    } else {
+                        [(ssid_key, ssid.as_str()), (psk_key, psk)]
+                    };
+                    for (key, value) in values {
+                        if let Err(x) = nvs.set_str(key, value) {
+                            log::warn!("Error setting {} in NVS: {:?}", key, x);
+                        }
+                    }
+                }
+                log::info!("Setting the other Wi-Fi networks in NVS");
+
+                if pinning::is_valid_bssid(&wifi_bssid) && pinning::is_valid_channel(&wifi_channel)
+                {
+                    let bssid = pinning::parse_bssid(&wifi_bssid)
+                        .map_or(String::new(), |bssid| pinning::format_bssid(&bssid));
+                    for (key, value) in [
+                        (NVS_WIFI_BSSID, bssid),
+                        (NVS_WIFI_CHANNEL, wifi_channel.trim().to_string()),
+                    ] {
+                        if let Err(x) = nvs.set_str(key, &value) {
+                            log::warn!("Error setting {} in NVS: {:?}", key, x);
+                        }
+                    }
+                    // A connection test right after uses them
+                    pinning::load(&nvs);
+                    log::info!("Setting the pinned access point in NVS");
+                } else {
+                    rejected.push(format!(
+                        "{} {:?}, {} {:?}",
+                        NVS_WIFI_BSSID, wifi_bssid, NVS_WIFI_CHANNEL, wifi_channel
+                    ));
+                }
+
+                if signal::is_valid_floor(&rssi_floor) {
+                    if let Err(x) = nvs.set_str(NVS_RSSI_FLOOR, &rssi_floor) {
+                        log::warn!("Error setting {} in NVS: {:?}", NVS_RSSI_FLOOR, x);
+                    }
+                    log::info!("Setting the signal floor in NVS");
+                } else {
+                    rejected.push(format!("{} {:?}", NVS_RSSI_FLOOR, rssi_floor));
+                }
+
+                if eap_valid {
+                    let mut values = vec![
+                        (NVS_EAP_IDENTITY, eap_identity.as_str()),
+                        (NVS_EAP_USER, eap_user.as_str()),
+                    ];
+                    // Empty keeps the password, unless the user is forgotten
+                    if eap_user.is_empty() || !eap_password.is_empty() {
+                        values.push((NVS_EAP_PASSWORD, eap_password.as_str()));
+                    }
+                    for (key, value) in values {
+                        if let Err(x) = nvs.set_str(key, value) {
+                            log::warn!("Error setting {} in NVS: {:?}", key, x);
+                        }
+                    }
+                    // A connection test right after uses them
+                    enterprise::load(&nvs);
+                    log::info!("Setting the WPA2-Enterprise credentials in NVS");
+                } else {
+                    rejected.push("WPA2-Enterprise credentials (up to 127 characters)".to_string());
+                }
+
+                match StaticIp::parse(&ip_address, &ip_netmask, &ip_gateway, &ip_dns) {
+                    Ok(_) => {
+                        for (key, value) in [
+                            (NVS_IP_ADDRESS, ip_address.trim()),
+                            (NVS_IP_NETMASK, ip_netmask.trim()),
+                            (NVS_IP_GATEWAY, ip_gateway.trim()),
+                            (NVS_IP_DNS, ip_dns.trim()),
+                        ] {
+                            if let Err(x) = nvs.set_str(key, value) {
+                                log::warn!("Error setting {} in NVS: {:?}", key, x);
+                            }
+                        }
+                        log::info!("Setting the static IP in NVS");
+                    }
+                    Err(reason) => {
+                        log::warn!("Not saving the static IP settings: {}", reason);
+                        rejected.push(format!("static IP: {}", reason));
+                    }
+                }
+
                 if let Err(x) = nvs.set_str("webhook", &webhook) {
                     log::warn!("Error setting webhook in NVS: {:?}", x);
                 }
                 log::info!("Setting Webhook in NVS");
 
+                if body::is_valid(&webhook_body) {
+                    if let Err(x) = nvs.set_str(NVS_WEBHOOK_BODY, &webhook_body) {
+                        log::warn!("Error setting {} in NVS: {:?}", NVS_WEBHOOK_BODY, x);
+                    }
+                    log::info!("Setting the webhook body in NVS");
+                } else {
+                    rejected.push(format!("hook_body {:?}", webhook_body));
+                }
+
+                for (key, value, valid) in [
+                    (
+                        NVS_WEBHOOK_METHOD,
+                        webhook_method,
+                        body::is_valid_method as fn(&str) -> bool,
+                    ),
+                    (NVS_WEBHOOK_TYPE, webhook_type, body::is_valid_content_type),
+                ] {
+                    if !valid(&value) {
+                        rejected.push(format!("{} {:?}", key, value));
+                    } else if let Err(x) = nvs.set_str(key, &value) {
+                        log::warn!("Error setting {} in NVS: {:?}", key, x);
+                    }
+                }
+                log::info!("Setting the webhook method and content type in NVS");
+
+                if delivery::is_valid_expect(&webhook_expect) {
+                    if let Err(x) = nvs.set_str(NVS_WEBHOOK_EXPECT, &webhook_expect) {
+                        log::warn!("Error setting {} in NVS: {:?}", NVS_WEBHOOK_EXPECT, x);
+                    }
+                    log::info!("Setting the expected webhook answer in NVS");
+                } else {
+                    rejected.push(format!("hook_expect {:?}", webhook_expect));
+                }
+
+                // Headers missing from the form are kept as they were, and
+                // so are the values of those sent back by name only
+                for (index, (key, line)) in NVS_HEADERS.iter().zip(header_lines).enumerate() {
+                    let Some(line) = line else { continue };
+                    let line = headers::merge(index, &line);
+                    if headers::is_valid(&line) {
+                        if let Err(x) = nvs.set_str(key, &line) {
+                            log::warn!("Error setting {} in NVS: {:?}", key, x);
+                        }
+                    } else {
+                        rejected.push(format!("{} {:?}", key, line));
+                    }
+                }
+                log::info!("Setting the webhook headers in NVS");
+
+                if report::is_valid_interval(&report_interval) {
+                    if let Err(x) = nvs.set_str(NVS_REPORT_INTERVAL, &report_interval) {
+                        log::warn!("Error setting {} in NVS: {:?}", NVS_REPORT_INTERVAL, x);
+                    }
+                    log::info!("Setting the report interval in NVS");
+                } else {
+                    rejected.push(format!("report_interval {:?}", report_interval));
+                }
+
+                if batch::is_valid_size(&batch_size) {
+                    if let Err(x) = nvs.set_str(NVS_BATCH_SIZE, &batch_size) {
+                        log::warn!("Error setting {} in NVS: {:?}", NVS_BATCH_SIZE, x);
+                    }
+                    log::info!("Setting the batch size in NVS");
+                } else {
+                    rejected.push(format!("hook_batch {:?}", batch_size));
+                }
+
+                if max_watts.parse::<f32>().map_or(false, |max| max > 0.0) {
+                    if let Err(x) = nvs.set_str("max_watts", &max_watts) {
+                        log::warn!("Error setting max_watts in NVS: {:?}", x);
+                    }
+                    log::info!("Setting max watts in NVS");
+                } else if !max_watts.is_empty() {
+                    rejected.push(format!("max_watts {:?}", max_watts));
+                }
+
+                // An empty budget disables it, anything else must be a positive number
+                for (key, value) in [("budget_day", &budget_day), ("budget_month", &budget_month)] {
+                    if value.is_empty() || value.parse::<f32>().map_or(false, |kwh| kwh > 0.0) {
+                        if let Err(x) = nvs.set_str(key, value) {
+                            log::warn!("Error setting {} in NVS: {:?}", key, x);
+                        }
+                    } else {
+                        rejected.push(format!("{} {:?}", key, value));
+                    }
+                }
+                log::info!("Setting energy budgets in NVS");
+
+                if let Err(x) = nvs.set_str("weather_url", &weather_url) {
+                    log::warn!("Error setting weather_url in NVS: {:?}", x);
+                }
+                log::info!("Setting weather URL in NVS");
+
+                // Same rules as the budgets: empty disables costs
+                if price_kwh.is_empty()
+                    || price_kwh.parse::<f32>().map_or(false, |price| price > 0.0)
+                {
+                    if let Err(x) = nvs.set_str("price_kwh", &price_kwh) {
+                        log::warn!("Error setting price_kwh in NVS: {:?}", x);
+                    }
+                } else {
+                    rejected.push(format!("price_kwh {:?}", price_kwh));
+                }
+                if let Err(x) = nvs.set_str("currency", &currency) {
+                    log::warn!("Error setting currency in NVS: {:?}", x);
+                }
+                log::info!("Setting energy price in NVS");
+
+                if PrimaryUnit::from_name(&primary_unit).is_some() {
+                    if let Err(x) = nvs.set_str(crate::NVS_PRIMARY_UNIT, &primary_unit) {
+                        log::warn!("Error setting {} in NVS: {:?}", crate::NVS_PRIMARY_UNIT, x);
+                    }
+                    log::info!("Setting display primary unit in NVS");
+                } else if !primary_unit.is_empty() {
+                    rejected.push(format!("primary_unit {:?}", primary_unit));
+                }
 
-                // Restart the device
-                unsafe {
-                    esp_idf_svc::sys::esp_restart();
+                if BrightnessSetting::from_name(&brightness).is_some() {
+                    if let Err(x) = nvs.set_str(NVS_BRIGHTNESS, &brightness) {
+                        log::warn!("Error setting {} in NVS: {:?}", NVS_BRIGHTNESS, x);
+                    }
+                    log::info!("Setting display brightness in NVS");
+                } else if !brightness.is_empty() {
+                    rejected.push(format!("brightness {:?}", brightness));
                 }
-                #[allow(unreachable_code)]
+
+                let valid_night_settings = [
+                    (
+                        NVS_NIGHT,
+                        &night,
+                        ["never", "schedule", "dark"].contains(&night.as_str()),
+                    ),
+                    (
+                        NVS_NIGHT_START,
+                        &night_start,
+                        parse_time_of_day(&night_start).is_some(),
+                    ),
+                    (
+                        NVS_NIGHT_END,
+                        &night_end,
+                        parse_time_of_day(&night_end).is_some(),
+                    ),
+                    (
+                        NVS_UTC_OFFSET,
+                        &utc_offset,
+                        utc_offset
+                            .parse::<f32>()
+                            .map_or(false, |hours| hours.abs() <= 14.0),
+                    ),
+                    (
+                        NVS_NIGHT_DISPLAY,
+                        &night_display,
+                        NightDisplay::from_name(&night_display).is_some(),
+                    ),
+                ];
+                for (key, value, valid) in valid_night_settings {
+                    if valid {
+                        if let Err(x) = nvs.set_str(key, value) {
+                            log::warn!("Error setting {} in NVS: {:?}", key, x);
+                        }
+                    } else if !value.is_empty() {
+                        rejected.push(format!("{} {:?}", key, value));
+                    }
+                }
+                log::info!("Setting night mode in NVS");
+
+                if display_idle_min.parse::<u32>().is_ok() {
+                    if let Err(x) = nvs.set_str(NVS_IDLE_MINUTES, &display_idle_min) {
+                        log::warn!("Error setting {} in NVS: {:?}", NVS_IDLE_MINUTES, x);
+                    }
+                    log::info!("Setting display idle timeout in NVS");
+                } else if !display_idle_min.is_empty() {
+                    rejected.push(format!("display_idle_min {:?}", display_idle_min));
+                }
+
+                if Rotation::from_name(&display_rotation).is_some() {
+                    if let Err(x) = nvs.set_str(NVS_DISPLAY_ROTATION, &display_rotation) {
+                        log::warn!("Error setting {} in NVS: {:?}", NVS_DISPLAY_ROTATION, x);
+                    }
+                    log::info!("Setting display rotation in NVS");
+                } else if !display_rotation.is_empty() {
+                    rejected.push(format!("display_rotation {:?}", display_rotation));
+                }
+
+                match DisplayBus::parse(&display_sda, &display_scl, &display_address, &display_khz)
+                    .and_then(|bus| bus.validate().map(|()| bus))
+                {
+                    Ok(bus) => {
+                        for (key, value) in [
+                            (NVS_DISPLAY_SDA, bus.sda.to_string()),
+                            (NVS_DISPLAY_SCL, bus.scl.to_string()),
+                            (NVS_DISPLAY_ADDRESS, format!("{:#04x}", bus.address)),
+                            (NVS_DISPLAY_KHZ, bus.khz.to_string()),
+                        ] {
+                            if let Err(x) = nvs.set_str(key, &value) {
+                                log::warn!("Error setting {} in NVS: {:?}", key, x);
+                            }
+                        }
+                        log::info!("Setting display bus in NVS");
+                    }
+                    Err(reason) => {
+                        log::warn!("Not saving the display bus settings: {}", reason);
+                        rejected.push(format!("display bus: {}", reason));
+                    }
+                }
+
+                if cors::is_valid_origin(&cors_origin) {
+                    if let Err(x) = nvs.set_str(NVS_CORS_ORIGIN, &cors_origin) {
+                        log::warn!("Error setting {} in NVS: {:?}", NVS_CORS_ORIGIN, x);
+                    }
+                    *ALLOWED_ORIGIN.lock().unwrap() = if cors_origin.is_empty() {
+                        cors::DEFAULT_ORIGIN.to_string()
+                    } else {
+                        cors_origin
+                    };
+                    log::info!("Setting the allowed CORS origin in NVS");
+                } else {
+                    rejected.push(format!("cors_origin {:?}", cors_origin));
+                }
+
+                if i18n::is_valid_setting(&language) {
+                    if let Err(x) = nvs.set_str(NVS_LANGUAGE, &language) {
+                        log::warn!("Error setting {} in NVS: {:?}", NVS_LANGUAGE, x);
+                    }
+                    i18n::set(&language);
+                    log::info!("Setting the language in NVS");
+                } else {
+                    rejected.push(format!("language {:?}", language));
+                }
+
+                for (key, value, label) in [
+                    (NVS_DEVICE_NAME, device_name, &DEVICE_NAME),
+                    (NVS_CHANNEL_LABEL, channel_label, &CHANNEL_LABEL),
+                ] {
+                    if labels::is_valid(&value) {
+                        if let Err(x) = nvs.set_str(key, &value) {
+                            log::warn!("Error setting {} in NVS: {:?}", key, x);
+                        }
+                        *label.lock().unwrap() = value;
+                    } else {
+                        rejected.push(format!("{} {:?}", key, value));
+                    }
+                }
+                log::info!("Setting the device name and channel label in NVS");
+
+                if dashboard::is_valid_peers(&peers) {
+                    if let Err(x) = nvs.set_str(NVS_PEERS, &peers) {
+                        log::warn!("Error setting {} in NVS: {:?}", NVS_PEERS, x);
+                    }
+                    dashboard::load(&nvs);
+                    log::info!("Setting the dashboard peers in NVS");
+                } else {
+                    rejected.push(format!("peers {:?}", peers));
+                }
+
+                for (key, value) in mqtt_fields {
+                    // Empty keeps the current password
+                    if key == NVS_MQTT_PASSWORD && value.is_empty() {
+                        continue;
+                    }
+                    let valid = match key.as_str() {
+                        NVS_MQTT_URL => mqtt::is_valid_url(&value),
+                        NVS_MQTT_PREFIX => mqtt::is_valid_prefix(&value),
+                        NVS_MQTT_INTERVAL => mqtt::is_valid_interval(&value),
+                        NVS_MQTT_THING => mqtt::is_valid_thing(&value),
+                        NVS_MQTT_SHADOW => mqtt::is_valid_shadow(&value),
+                        NVS_MQTT_FORMAT => mqtt::is_valid_format(&value),
+                        _ => true,
+                    };
+                    if !valid {
+                        rejected.push(format!("{} {:?}", key, value));
+                    } else if let Err(x) = nvs.set_str(&key, &value) {
+                        log::warn!("Error setting {} in NVS: {:?}", key, x);
+                    }
+                }
+                log::info!("Setting the MQTT broker in NVS");
+
+                for (key, value, valid) in [
+                    (NVS_COAP_URL, coap_url, coap::is_valid_url as fn(&str) -> bool),
+                    (NVS_COAP_INTERVAL, coap_interval, coap::is_valid_interval),
+                ] {
+                    if !valid(&value) {
+                        rejected.push(format!("{} {:?}", key, value));
+                    } else if let Err(x) = nvs.set_str(key, &value) {
+                        log::warn!("Error setting {} in NVS: {:?}", key, x);
+                    }
+                }
+                log::info!("Setting the CoAP resource in NVS");
+
+                for (key, value, valid) in [
+                    (
+                        NVS_GRAPHITE_HOST,
+                        graphite_host,
+                        graphite::is_valid_host as fn(&str) -> bool,
+                    ),
+                    (NVS_GRAPHITE_PREFIX, graphite_prefix, graphite::is_valid_prefix),
+                ] {
+                    if !valid(&value) {
+                        rejected.push(format!("{} {:?}", key, value));
+                    } else if let Err(x) = nvs.set_str(key, &value) {
+                        log::warn!("Error setting {} in NVS: {:?}", key, x);
+                    }
+                }
+                log::info!("Setting the Graphite host in NVS");
+
+                if !snmp::is_valid_community(&snmp_community) {
+                    rejected.push(format!("{} {:?}", NVS_SNMP_COMMUNITY, snmp_community));
+                } else if let Err(x) = nvs.set_str(NVS_SNMP_COMMUNITY, &snmp_community) {
+                    log::warn!("Error setting {} in NVS: {:?}", NVS_SNMP_COMMUNITY, x);
+                }
+                log::info!("Setting the SNMP community in NVS");
+
+                let modbus_setting = if modbus_enabled { "on" } else { "off" };
+                if let Err(x) = nvs.set_str(NVS_MODBUS, modbus_setting) {
+                    log::warn!("Error setting {} in NVS: {:?}", NVS_MODBUS, x);
+                }
+                log::info!("Setting Modbus {} in NVS", modbus_setting);
+
+                for (key, value, valid) in [
+                    (NVS_NTFY_URL, ntfy_url, notify::is_valid_ntfy_url as fn(&str) -> bool),
+                    (NVS_PUSHOVER_USER, pushover_user, notify::is_valid_pushover_key),
+                    (NVS_PUSHOVER_TOKEN, pushover_token, notify::is_valid_pushover_key),
+                    (NVS_TELEGRAM_CHAT, telegram_chat, telegram::is_valid_chat),
+                    (NVS_TELEGRAM_TOKEN, telegram_token, telegram::is_valid_token),
+                ] {
+                    // Empty keeps the current token
+                    if [NVS_PUSHOVER_TOKEN, NVS_TELEGRAM_TOKEN].contains(&key) && value.is_empty() {
+                        continue;
+                    }
+                    if !valid(&value) {
+                        rejected.push(format!("{} {:?}", key, value));
+                    } else if let Err(x) = nvs.set_str(key, &value) {
+                        log::warn!("Error setting {} in NVS: {:?}", key, x);
+                    }
+                }
+                log::info!("Setting the push notifications in NVS");
+
+                // Empty keeps the current password
+                if admin_password.chars().count() >= MIN_PASSWORD_LEN {
+                    if let Err(x) = auth::set_password(&mut nvs, admin_password) {
+                        log::warn!("Error setting {} in NVS: {:?}", NVS_ADMIN_PASSWORD, x);
+                    }
+                    log::info!("Setting the admin password in NVS");
+                } else if !admin_password.is_empty() {
+                    log::warn!(
+                        "Not saving an admin password under {} characters",
+                        MIN_PASSWORD_LEN
+                    );
+                    rejected.push(format!(
+                        "admin_password (at least {} characters)",
+                        MIN_PASSWORD_LEN
+                    ));
+                }
+
+                // Answer in the language just chosen
+                let language = i18n::language(&req);
+                if test_wifi {
+                    req.into_response(200, Some("OK"), &[("Content-Type", "text/html")])?
+                        .write(provision_page(language, &rejected).as_bytes())?;
+                    // The main loop leaves setup mode once it connects
+                    provision::request(wifi_ssid, wifi_psk);
+                    return Ok(());
+                }
+
+                req.into_response(200, Some("OK"), &[("Content-Type", "text/html")])?
+                    .write(saved_page(language, &wifi_ssid, &rejected).as_bytes())?;
+                // Applied after a restart, which /restart confirms right away
+                restart::schedule();
                 Ok(())
             }
         },
     )?;
 
     server.fn_handler(
-        "/restart",
+        "/api/v1/provision_status",
         esp_idf_svc::http::Method::Get,
         |req| -> Result<(), esp_idf_svc::io::EspIOError> {
-            req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
-                .write("Restarting system".as_bytes())?;
+            let status = PROVISION_STATUS.lock().unwrap().to_json();
+            req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+                .write(status.as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+
+    restart::add_restart_handler(server)?;
+    log_buffer::add_logs_handler(server)?;
+    mirror::add_display_handler(server)?;
+    openapi::add_openapi_handler(server, ROUTES)?;
+    pwa::add_pwa_handlers(server)?;
+
+    Ok(())
+}
+
+/// Body of `/api/v1/status`.
+#[derive(Serialize)]
+struct Status {
+    /// `None` in setup mode, which doesn't sample the clamp.
+    amps: Option<f32>,
+    watts: Option<f32>,
+    energy_kwh: f64,
+    uptime_s: u64,
+    rssi_dbm: Option<i8>,
+    firmware: &'static str,
+    hostname: String,
+    /// Empty unless set, see `labels`.
+    device_name: String,
+    channel_label: String,
+    setup_mode: bool,
+}
 
-            unsafe {
-                esp_idf_svc::sys::esp_restart();
+/// Body of `/api/health`.
+#[derive(Serialize)]
+struct Health {
+    /// `degraded` after repeated webhook failures, see `delivery`.
+    status: &'static str,
+    free_heap: u32,
+    /// Lowest free heap since boot, to spot leaks before they run out.
+    min_free_heap: u32,
+    uptime_s: u64,
+    reset_reason: String,
+    rssi_dbm: Option<i8>,
+    wifi_reconnects: u32,
+    webhook: DeliveryStats,
+}
+
+fn add_status_handler<'a>(
+    server: &mut EspHttpServer<'a>,
+    expose_value: Option<&'a Arc<Mutex<Amps>>>,
+    setup_mode: bool,
+) -> Result<(), EspError> {
+    server.fn_handler(
+        "/api/v1/status",
+        esp_idf_svc::http::Method::Get,
+        move |req| -> Result<(), esp_idf_svc::io::EspIOError> {
+            let amps = expose_value.map(|value| with_locked_value(value, identity));
+            let status = Status {
+                amps: amps.map(|amps| amps.0),
+                watts: amps.map(|amps| (amps * AC_VOLTS).0),
+                energy_kwh: ENERGY.lock().unwrap().total_wh.kwh(),
+                uptime_s: crate::uptime::session_uptime_s(),
+                rssi_dbm: get_rssi(),
+                firmware: FIRMWARE_VERSION,
+                hostname: with_locked_value(&CURRENT_KNOWN_HOSTNAME.clone(), identity),
+                device_name: labels::device_name(),
+                channel_label: labels::channel_label(),
+                setup_mode,
+            };
+            let body = serde_json::to_string(&status).unwrap();
+            let origin = cors::allowed_origin();
+            req.into_response(
+                200,
+                Some("OK"),
+                &[
+                    ("Content-Type", "application/json"),
+                    ("Access-Control-Allow-Origin", &origin),
+                ],
+            )?
+            .write(body.as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
+
+/// Element of `/api/history`.
+#[derive(Serialize)]
+struct HistoryPoint {
+    uptime_s: u64,
+    /// 0 if the clock was not synced yet.
+    unix_time: u64,
+    amps: f32,
+    watts: f32,
+}
+
+/// `/api/history`, plotted by the `/chart` page from `assets/chart.html`,
+/// and the same as CSV at `/api/history.csv`.
+fn add_history_handler<'a>(
+    server: &mut EspHttpServer<'a>,
+    history: &'a Arc<Mutex<History>>,
+) -> Result<(), EspError> {
+    server.fn_handler(
+        "/api/history",
+        esp_idf_svc::http::Method::Get,
+        move |req| -> Result<(), esp_idf_svc::io::EspIOError> {
+            let points: Vec<HistoryPoint> = history
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|reading| HistoryPoint {
+                    uptime_s: reading.uptime_s,
+                    unix_time: reading.unix_time,
+                    amps: reading.amps.0,
+                    watts: reading.watts.0,
+                })
+                .collect();
+            let body = serde_json::to_string(&points).unwrap();
+            let origin = cors::allowed_origin();
+            req.into_response(
+                200,
+                Some("OK"),
+                &[
+                    ("Content-Type", "application/json"),
+                    ("Access-Control-Allow-Origin", &origin),
+                ],
+            )?
+            .write(body.as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/api/history.csv",
+        esp_idf_svc::http::Method::Get,
+        move |req| -> Result<(), esp_idf_svc::io::EspIOError> {
+            // Copy out rather than hold the lock while writing to the network
+            let readings: Vec<Reading> = history.lock().unwrap().iter().copied().collect();
+            let origin = cors::allowed_origin();
+            // Without a Content-Length, every write goes out as a chunk
+            let mut response = req.into_response(
+                200,
+                Some("OK"),
+                &[
+                    ("Content-Type", "text/csv"),
+                    (
+                        "Content-Disposition",
+                        "attachment; filename=\"history.csv\"",
+                    ),
+                    ("Access-Control-Allow-Origin", &origin),
+                ],
+            )?;
+            response.write("timestamp,uptime_s,amps,watts\r\n".as_bytes())?;
+            for reading in readings {
+                // Left empty while the clock was not synced
+                let timestamp = match reading.unix_time {
+                    0 => String::new(),
+                    unix_time => template::iso8601(unix_time),
+                };
+                let row = format!(
+                    "{},{},{:.5},{:.3}\r\n",
+                    timestamp, reading.uptime_s, reading.amps.0, reading.watts.0
+                );
+                response.write(row.as_bytes())?;
             }
-            #[allow(unreachable_code)]
+
             Ok(())
         },
     )?;
@@ -180,16 +2037,48 @@ fn add_server_setup_handlers<'a>(
     Ok(())
 }
 
+/// Serve the embedded assets and pages with their caching and encoding.
+fn add_asset_handlers(server: &mut EspHttpServer<'_>) -> Result<(), EspError> {
+    for asset in assets::ASSETS {
+        server.fn_handler(
+            asset.url,
+            esp_idf_svc::http::Method::Get,
+            move |req| -> Result<(), esp_idf_svc::io::EspIOError> {
+                let encoding = if asset.gzip { "gzip" } else { "identity" };
+                req.into_response(
+                    200,
+                    Some("OK"),
+                    &[
+                        ("Content-Type", asset.content_type),
+                        ("Cache-Control", asset.cache_control),
+                        ("Content-Encoding", encoding),
+                    ],
+                )?
+                .write(asset.body)?;
+
+                Ok(())
+            },
+        )?;
+    }
+    Ok(())
+}
+
 #[inline(always)]
-pub fn configure_setup_http_server<'a>(
-    nvs: &'a mut nvs::EspNvs<nvs::NvsDefault>,
-) -> Result<EspHttpServer<'a>, EspError> {
-    let server_config = Configuration::default();
+pub fn configure_setup_http_server(
+    nvs: &nvs::EspNvsPartition<nvs::NvsDefault>,
+    wifi: &Arc<Mutex<EspWifi<'static>>>,
+) -> Result<EspHttpServer<'static>, EspError> {
+    let server_config = Configuration {
+        max_uri_handlers: SETUP_SERVER_HANDLERS + assets::ASSETS.len(),
+        ..Default::default()
+    };
     let mut server = EspHttpServer::new(&server_config).expect("Failed to create server");
 
 
     server.fn_handler("/", esp_idf_svc::http::Method::Get, render_setup_page)?;
-    add_server_setup_handlers(nvs, &mut server)?;
+    add_server_setup_handlers(nvs, wifi, &mut server, true)?;
+    add_asset_handlers(&mut server)?;
+    add_status_handler(&mut server, None, true)?;
     Ok(server)
 }
 
@@ -204,12 +2093,9 @@ impl<'a, T: Clone> LockedValue<T> for Arc<Mutex<T>> {
     where
         F: FnOnce(T) -> R,
     {
-        match self.try_lock() {
-            Ok(guard) => f(guard.clone()),
-            Err(_) => {
-                panic!("Failed to lock mutex")
-            }
-        }
+        // Wait for the main loop to let go of the value rather than panic
+        let guard = self.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(guard.clone())
     }
 }
 
@@ -221,13 +2107,43 @@ fn identity<T>(x: T) -> T {
     x
 }
 
+/// Title of the readings pages, the device name when there is one.
+fn page_title() -> String {
+    let name = labels::device_name();
+    if name.is_empty() {
+        "Coarse watt-o-meter".to_string()
+    } else {
+        html_escape(&name)
+    }
+}
+
+/// The device name and the channel label above the readings, each left out
+/// when not set.
+fn labels_html() -> String {
+    let mut html = String::new();
+    let name = labels::device_name();
+    if !name.is_empty() {
+        write!(html, "<h1>{}</h1>", html_escape(&name)).unwrap();
+    }
+    let channel = labels::channel_label();
+    if !channel.is_empty() {
+        write!(html, "<h2>{}</h2>", html_escape(&channel)).unwrap();
+    }
+    html
+}
+
 #[inline(always)]
 pub fn configure_http_server<'a>(
-    expose_value: &'a Arc<Mutex<f32>>,
-    nvs: &'a mut nvs::EspNvs<nvs::NvsDefault>,
+    expose_value: &'a Arc<Mutex<Amps>>,
+    history: &'a Arc<Mutex<History>>,
+    nvs: &nvs::EspNvsPartition<nvs::NvsDefault>,
+    wifi: &Arc<Mutex<EspWifi<'static>>>,
 ) -> Result<EspHttpServer<'a>, EspError> {
     // // Start Http Server
-    let server_config = Configuration::default();
+    let server_config = Configuration {
+        max_uri_handlers: SERVER_HANDLERS + assets::ASSETS.len(),
+        ..Default::default()
+    };
     let mut server = EspHttpServer::new(&server_config).expect("Failed to create server");
     server.fn_handler(
         "/",
@@ -238,10 +2154,25 @@ pub fn configure_http_server<'a>(
             write!(
                 server_msg,
                 "<!DOCTYPE html>
-                    <html><head><title>Coarse watt-o-meter</title></head>
-                    <body><a href=\"/amps\">Amps: {:.5}A</a><br />
-                    <a href=\"/watts\">{:.5}W</a></body>
+                    <html><head><title>{}</title>
+                    {}
+                    <link rel=\"stylesheet\" href=\"{}\"></head>
+                    <body>{}<a href=\"/amps\">Amps: {:.5}</a><br />
+                    <a href=\"/watts\">{:.5}</a><br /><br />
+                    <form action=\"/calibration/point\" method=\"post\">
+                    <label for=\"reference_amps\">Reference meter reading (A):</label>
+                    <input type=\"number\" step=\"any\" id=\"reference_amps\" name=\"reference_amps\">
+                    <input type=\"submit\" value=\"Add calibration point\"></form>
+                    <a href=\"/calibration/report?format=csv\">Calibration report (CSV)</a> |
+                    <a href=\"/calibration/report\">(JSON)</a><br />
+                    <a href=\"/chart\">Power chart</a> |
+                    <a href=\"/api/history.csv\">(CSV)</a> |
+                    <a href=\"/dashboard\">Dashboard</a></body>
                     </html>",
+                page_title(),
+                pwa::head_links(),
+                assets::url("style.css"),
+                labels_html(),
                 with_locked_value(expose_value, identity),
                 with_locked_value(expose_value, identity) * AC_VOLTS
             )
@@ -254,7 +2185,14 @@ pub fn configure_http_server<'a>(
         },
     )?;
 
-    add_server_setup_handlers(nvs, &mut server)?;
+    add_server_setup_handlers(nvs, wifi, &mut server, false)?;
+    add_asset_handlers(&mut server)?;
+    add_status_handler(&mut server, Some(expose_value), false)?;
+    live::add_ws_handler(&mut server)?;
+    long_poll::add_next_handler(&mut server)?;
+    dashboard::add_dashboard_handlers(&mut server, expose_value)?;
+    ota::add_ota_handler(&mut server)?;
+    add_history_handler(&mut server, history)?;
 
     server.fn_handler(
         "/amps",
@@ -262,7 +2200,7 @@ pub fn configure_http_server<'a>(
         |req| -> Result<(), esp_idf_svc::io::EspIOError> {
             let mut server_msg = String::new();
             let amps = with_locked_value(expose_value, identity);
-            write!(server_msg, "{:.12}", amps).unwrap();
+            write!(server_msg, "{:.12}", amps.0).unwrap();
             req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
                 .write(server_msg.as_bytes())?;
 
@@ -277,7 +2215,7 @@ pub fn configure_http_server<'a>(
             let mut server_msg = String::new();
             let amps = expose_value.with_locked_value(identity);
             let watts = amps * AC_VOLTS;
-            write!(server_msg, "{:.12}", watts).unwrap();
+            write!(server_msg, "{:.12}", watts.0).unwrap();
             req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
                 .write(server_msg.as_bytes())?;
 
@@ -285,5 +2223,265 @@ pub fn configure_http_server<'a>(
         },
     )?;
 
+    server.fn_handler(
+        "/calibration/report",
+        esp_idf_svc::http::Method::Get,
+        |req| -> Result<(), esp_idf_svc::io::EspIOError> {
+            let calibration = CALIBRATION.lock().unwrap().clone();
+            if req.uri().contains("format=csv") {
+                req.into_response(
+                    200,
+                    Some("OK"),
+                    &[
+                        ("Content-Type", "text/csv"),
+                        (
+                            "Content-Disposition",
+                            "attachment; filename=\"calibration.csv\"",
+                        ),
+                    ],
+                )?
+                .write(calibration.report_csv().as_bytes())?;
+            } else {
+                req.into_response(
+                    200,
+                    Some("OK"),
+                    &[
+                        ("Content-Type", "application/json"),
+                        (
+                            "Content-Disposition",
+                            "attachment; filename=\"calibration.json\"",
+                        ),
+                    ],
+                )?
+                .write(calibration.report_json().as_bytes())?;
+            }
+
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/calibration/point",
+        esp_idf_svc::http::Method::Post,
+        |mut req| -> Result<(), esp_idf_svc::io::EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            // Form data is in the format "reference_amps=1.23"
+            let body = form::read_body(&mut req, MAX_FORM_LEN)?.unwrap_or_default();
+            let fields = form::parse(&body);
+            let reference_amps = form::field(&fields, "reference_amps")
+                .and_then(|value| value.parse::<f32>().ok())
+                .filter(|amps| *amps >= 0.0);
+
+            match reference_amps {
+                Some(reference_amps) => {
+                    let report = {
+                        let mut calibration = CALIBRATION.lock().unwrap();
+                        calibration.add_point(reference_amps);
+                        calibration.report_json()
+                    };
+                    req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+                        .write(report.as_bytes())?;
+                }
+                None => {
+                    error::respond(req, 400, "Missing or invalid reference_amps")?;
+                }
+            }
+
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/calibration/reset",
+        esp_idf_svc::http::Method::Post,
+        |mut req| -> Result<(), esp_idf_svc::io::EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            CALIBRATION.lock().unwrap().reset();
+            req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
+                .write("Calibration reset".as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/api/v1/night",
+        esp_idf_svc::http::Method::Post,
+        |mut req| -> Result<(), esp_idf_svc::io::EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            // Form data is in the format "mode=on", "mode=off" or "mode=auto"
+            let body = form::read_body(&mut req, MAX_FORM_LEN)?.unwrap_or_default();
+            let fields = form::parse(&body);
+
+            let mode = form::field(&fields, "mode").unwrap_or_default();
+            let night = match mode {
+                "on" => Some(true),
+                "off" => Some(false),
+                "auto" => None,
+                _ => {
+                    return error::respond(req, 400, "Missing or invalid mode, use on, off or auto")
+                }
+            };
+            *NIGHT_OVERRIDE.lock().unwrap() = night;
+            log::info!("Night mode override: {:?}", night);
+            req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
+                .write(format!("Night mode {}", mode).as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/api/v1/outputs",
+        esp_idf_svc::http::Method::Get,
+        |req| -> Result<(), esp_idf_svc::io::EspIOError> {
+            let configured = !with_locked_value(&CURRENT_KNOWN_WEBHOOK.clone(), identity).is_empty();
+            let spool = *SPOOL_STATUS.lock().unwrap();
+            let oldest = match spool.oldest_timestamp {
+                Some(timestamp) => timestamp.to_string(),
+                None => "null".to_string(),
+            };
+            let mqtt_configured =
+                !with_locked_value(&CURRENT_KNOWN_MQTT.clone(), |m| m.url).is_empty();
+            let coap_configured =
+                !with_locked_value(&CURRENT_KNOWN_COAP.clone(), |c| c.url).is_empty();
+            let graphite_configured =
+                !with_locked_value(&CURRENT_KNOWN_GRAPHITE.clone(), |g| g.host).is_empty();
+            let snmp_configured =
+                !with_locked_value(&CURRENT_KNOWN_SNMP_COMMUNITY.clone(), identity).is_empty();
+            let mut server_msg = String::new();
+            write!(
+                server_msg,
+                "{{\"webhook\":{{\"configured\":{},\"buffered\":{},\"spool\":{{\"entries\":{},\"bytes\":{},\"oldest_timestamp\":{}}}}},\"mqtt\":{{\"configured\":{},\"connected\":{}}},\"coap\":{{\"configured\":{},\"sent\":{}}},\"graphite\":{{\"configured\":{},\"connected\":{}}},\"snmp\":{{\"configured\":{},\"answered\":{}}},\"modbus\":{{\"enabled\":{},\"clients\":{}}},\"notify\":{{\"configured\":{},\"sent\":{}}}}}",
+                configured, BUFFERED.load(Ordering::Relaxed), spool.entries, spool.bytes, oldest,
+                mqtt_configured, mqtt::CONNECTED.load(Ordering::Relaxed),
+                coap_configured, coap::SENT.load(Ordering::Relaxed),
+                graphite_configured, graphite::CONNECTED.load(Ordering::Relaxed),
+                snmp_configured, snmp::ANSWERED.load(Ordering::Relaxed),
+                modbus::is_enabled(), modbus::CLIENTS.load(Ordering::Relaxed),
+                notify::is_configured(), notify::SENT.load(Ordering::Relaxed)
+            )
+            .unwrap();
+            let origin = cors::allowed_origin();
+            req.into_response(
+                200,
+                Some("OK"),
+                &[
+                    ("Content-Type", "application/json"),
+                    ("Access-Control-Allow-Origin", &origin),
+                ],
+            )?
+            .write(server_msg.as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/api/health",
+        esp_idf_svc::http::Method::Get,
+        |req| -> Result<(), esp_idf_svc::io::EspIOError> {
+            let webhook = DELIVERY_STATS.lock().unwrap().clone();
+            let health = Health {
+                status: if webhook.is_degraded() {
+                    "degraded"
+                } else {
+                    "ok"
+                },
+                // Safe: plain reads of the heap allocator's counters
+                free_heap: unsafe { esp_idf_svc::sys::esp_get_free_heap_size() },
+                min_free_heap: unsafe { esp_idf_svc::sys::esp_get_minimum_free_heap_size() },
+                uptime_s: crate::uptime::session_uptime_s(),
+                reset_reason: crate::uptime::reset_reason(),
+                rssi_dbm: get_rssi(),
+                wifi_reconnects: WIFI_RECONNECTS.load(Ordering::Relaxed),
+                webhook,
+            };
+            let health = serde_json::to_string(&health).unwrap();
+            let origin = cors::allowed_origin();
+            req.into_response(
+                200,
+                Some("OK"),
+                &[
+                    ("Content-Type", "application/json"),
+                    ("Access-Control-Allow-Origin", &origin),
+                ],
+            )?
+            .write(health.as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/metrics",
+        esp_idf_svc::http::Method::Get,
+        |req| -> Result<(), esp_idf_svc::io::EspIOError> {
+            let amps = expose_value.with_locked_value(identity);
+            let uptime = *UPTIME.lock().unwrap();
+            let energy = *ENERGY.lock().unwrap();
+            let weather = *WEATHER.lock().unwrap();
+            let samples = [
+                (metrics::CURRENT, Some(amps.0 as f64)),
+                (metrics::POWER, Some((amps * AC_VOLTS).0 as f64)),
+                (metrics::BOOTS, Some(uptime.boots as f64)),
+                (
+                    metrics::UPTIME,
+                    Some(crate::uptime::session_uptime_s() as f64),
+                ),
+                (metrics::UPTIME_TOTAL, Some(uptime.total_uptime_s() as f64)),
+                (metrics::ENERGY_TOTAL, Some(energy.total_wh.0)),
+                (metrics::ENERGY_TODAY, Some(energy.today_wh.0)),
+                (metrics::ENERGY_MONTH, Some(energy.month_wh.0)),
+                (
+                    metrics::OUTDOOR_TEMPERATURE,
+                    weather.latest_c.map(f64::from),
+                ),
+                (
+                    metrics::OUTDOOR_TEMPERATURE_TODAY_MEAN,
+                    weather.today_mean_c().map(f64::from),
+                ),
+                (
+                    metrics::OUTDOOR_TEMPERATURE_YESTERDAY_MEAN,
+                    weather.yesterday_mean_c.map(f64::from),
+                ),
+                (
+                    metrics::AVAILABILITY,
+                    uptime.availability_percent().map(f64::from),
+                ),
+                (metrics::WIFI_RSSI, get_rssi().map(f64::from)),
+            ];
+            let mut server_msg = String::new();
+            for (metric, value) in samples {
+                if let Some(value) = value {
+                    metric.write_prometheus(&mut server_msg, value);
+                }
+            }
+            req.into_response(
+                200,
+                Some("OK"),
+                &[("Content-Type", "text/plain; version=0.0.4")],
+            )?
+            .write(server_msg.as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+
     Ok(server)
 }
@@ -0,0 +1,380 @@
+//! Texts of the setup pages in each language. The `language` setting picks
+//! one, or leaves it to the browser's `Accept-Language` header when `auto`.
+//! Logs, the API and the display stay in English.
+
+use std::sync::Mutex;
+
+use esp_idf_svc::http::server::{EspHttpConnection, Request};
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+
+pub const NVS_LANGUAGE: &str = "language";
+/// Setting that follows the browser.
+pub const AUTO: &str = "auto";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Language {
+    English,
+    Spanish,
+}
+
+impl Language {
+    pub const ALL: [Language; 2] = [Language::English, Language::Spanish];
+
+    /// ISO 639-1 code, also the name stored in NVS.
+    pub fn code(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::Spanish => "es",
+        }
+    }
+
+    /// The language of a code, ignoring any region, e.g. `es-AR`.
+    pub fn from_code(code: &str) -> Option<Language> {
+        let primary = code.split('-').next()?.trim();
+        Language::ALL
+            .into_iter()
+            .find(|language| language.code().eq_ignore_ascii_case(primary))
+    }
+
+    pub fn strings(self) -> &'static Strings {
+        match self {
+            Language::English => &ENGLISH,
+            Language::Spanish => &SPANISH,
+        }
+    }
+}
+
+/// Language setting, `None` to follow the browser.
+static LANGUAGE: Mutex<Option<Language>> = Mutex::new(None);
+
+/// Whether `setting` can be saved: `auto`, empty for the same, or a code.
+pub fn is_valid_setting(setting: &str) -> bool {
+    setting.is_empty() || setting == AUTO || Language::from_code(setting).is_some()
+}
+
+/// Apply a valid `setting`.
+pub fn set(setting: &str) {
+    *LANGUAGE.lock().unwrap() = Language::from_code(setting);
+}
+
+/// The setting as saved, `auto` when following the browser.
+pub fn setting() -> &'static str {
+    LANGUAGE.lock().unwrap().map_or(AUTO, Language::code)
+}
+
+/// Read the language setting from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    set(&read_str_from_nvs_or_default(nvs, NVS_LANGUAGE, AUTO));
+}
+
+/// The preferred language in an `Accept-Language` header that there are
+/// strings for, e.g. Spanish for `fr;q=0.9, es-ES;q=0.8, en;q=0.5`.
+fn negotiate(accept_language: &str) -> Option<Language> {
+    let mut best: Option<(Language, f32)> = None;
+    for range in accept_language.split(',') {
+        let mut parts = range.split(';');
+        let Some(language) = parts.next().and_then(Language::from_code) else {
+            continue;
+        };
+        let quality = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+            .unwrap_or(0.0);
+        if quality > 0.0 && best.map_or(true, |(_, best_quality)| quality > best_quality) {
+            best = Some((language, quality));
+        }
+    }
+    best.map(|(language, _)| language)
+}
+
+/// The language to answer `req` in: the setting, else the browser's, else
+/// English.
+pub fn language(req: &Request<&mut EspHttpConnection<'_>>) -> Language {
+    if let Some(language) = *LANGUAGE.lock().unwrap() {
+        return language;
+    }
+    req.header("Accept-Language")
+        .and_then(negotiate)
+        .unwrap_or(Language::English)
+}
+
+/// Texts of the setup, saved and connection test pages. They go into HTML
+/// and, the connection test ones, JavaScript strings, so they must not
+/// contain quotes.
+pub struct Strings {
+    pub title: &'static str,
+    pub wifi_network: &'static str,
+    pub other_network: &'static str,
+    pub scan: &'static str,
+    pub scanning: &'static str,
+    pub open_network: &'static str,
+    pub not_found: &'static str,
+    pub hidden_network: &'static str,
+    pub wifi_password: &'static str,
+    pub wifi_security: &'static str,
+    pub bssid: &'static str,
+    pub channel: &'static str,
+    pub rssi_floor: &'static str,
+    pub test_wifi: &'static str,
+    pub other_networks: &'static str,
+    pub password: &'static str,
+    pub enterprise: &'static str,
+    pub user: &'static str,
+    pub anonymous_identity: &'static str,
+    pub static_ip: &'static str,
+    pub netmask: &'static str,
+    pub gateway: &'static str,
+    pub dns_server: &'static str,
+    pub webhook: &'static str,
+    pub test_webhook: &'static str,
+    pub testing: &'static str,
+    pub webhook_body: &'static str,
+    pub webhook_method: &'static str,
+    pub webhook_content_type: &'static str,
+    pub webhook_expect: &'static str,
+    pub webhook_headers: &'static str,
+    pub report_interval: &'static str,
+    pub batch_size: &'static str,
+    pub max_watts: &'static str,
+    pub budget_day: &'static str,
+    pub budget_month: &'static str,
+    pub price_kwh: &'static str,
+    pub currency: &'static str,
+    pub weather_url: &'static str,
+    pub primary_unit: &'static str,
+    pub brightness: &'static str,
+    pub night: &'static str,
+    pub night_from: &'static str,
+    pub night_until: &'static str,
+    pub utc_offset: &'static str,
+    pub night_display: &'static str,
+    pub display_idle: &'static str,
+    pub display_rotation: &'static str,
+    pub display_bus: &'static str,
+    pub sda: &'static str,
+    pub scl: &'static str,
+    pub address: &'static str,
+    pub speed: &'static str,
+    pub cors_origin: &'static str,
+    pub device_name: &'static str,
+    pub channel_label: &'static str,
+    pub peers: &'static str,
+    pub mqtt_url: &'static str,
+    pub mqtt_user: &'static str,
+    pub mqtt_password: &'static str,
+    pub mqtt_prefix: &'static str,
+    pub mqtt_interval: &'static str,
+    pub mqtt_format: &'static str,
+    pub mqtt_thing: &'static str,
+    pub mqtt_shadow: &'static str,
+    pub coap_url: &'static str,
+    pub coap_interval: &'static str,
+    pub graphite_host: &'static str,
+    pub graphite_prefix: &'static str,
+    pub snmp_community: &'static str,
+    pub modbus: &'static str,
+    pub ntfy_url: &'static str,
+    pub pushover_user: &'static str,
+    pub pushover_token: &'static str,
+    pub telegram_chat: &'static str,
+    pub telegram_token: &'static str,
+    pub admin_password: &'static str,
+    pub language: &'static str,
+    pub submit: &'static str,
+    pub saved_for_network: &'static str,
+    pub restarts_in: &'static str,
+    pub seconds_to_apply: &'static str,
+    pub restart_now: &'static str,
+    pub restarting: &'static str,
+    pub not_saved: &'static str,
+    pub trying: &'static str,
+    pub connected: &'static str,
+    pub could_not_connect: &'static str,
+    pub back_to_setup: &'static str,
+}
+
+static ENGLISH: Strings = Strings {
+    title: "Coarse watt-o-meter",
+    wifi_network: "Wi-Fi network:",
+    other_network: "Other...",
+    scan: "Scan",
+    scanning: "Scanning...",
+    open_network: "open",
+    not_found: "not found",
+    hidden_network: "Hidden network name",
+    wifi_password: "Wi-Fi Password:",
+    wifi_security: "Security:",
+    bssid: "Access point (BSSID, empty for any):",
+    channel: "Channel (empty for any):",
+    rssi_floor: "Log a warning when the signal drops below (dBm):",
+    test_wifi: "Try the Wi-Fi connection before leaving the setup access point",
+    other_networks: "Other Wi-Fi networks to fall back on, the strongest in range first (an empty password keeps the saved one):",
+    password: "Password",
+    enterprise: "WPA2-Enterprise login, used for networks saved without a Wi-Fi password (an empty password keeps the saved one):",
+    user: "User",
+    anonymous_identity: "Anonymous identity (optional)",
+    static_ip: "Fixed IP address (empty to get one by DHCP):",
+    netmask: "Netmask:",
+    gateway: "Gateway:",
+    dns_server: "DNS server (empty for the gateway):",
+    webhook: "URL to POST readings to (if non-empty). Placeholders such as {{amps}}, {{watts|round:1}}, {{kwh|comma_decimal}} or {{timestamp|iso8601}} are filled in",
+    test_webhook: "Test",
+    testing: "Sending...",
+    webhook_body: "Body to send, with the same placeholders plus {{hostname}} and {{rssi}} (empty for the default JSON, or form data):",
+    webhook_method: "Method (GET sends the body in the query string):",
+    webhook_content_type: "Content type:",
+    webhook_expect: "Text the answer must contain to count as delivered (empty to check the status only):",
+    webhook_headers: "Extra headers for the webhook, e.g. Authorization: Bearer <token> (optional, a name alone keeps its saved value):",
+    report_interval: "Seconds between posts, averaging the readings in between:",
+    batch_size: "Readings per post, sent as a JSON array when 2 or more:",
+    max_watts: "Full scale of the power bar (W):",
+    budget_day: "Daily energy budget (kWh, empty to disable):",
+    budget_month: "Monthly energy budget (kWh, empty to disable):",
+    price_kwh: "Energy price per kWh (empty to disable costs):",
+    currency: "Currency shown after costs:",
+    weather_url: "URL returning the outdoor temperature as JSON, e.g. Open-Meteo (optional)",
+    primary_unit: "Reading on the first line of the display (also changed by holding BOOT for about a second):",
+    brightness: "Display brightness (auto follows the light sensor, if fitted):",
+    night: "Night mode, following a schedule or the light sensor:",
+    night_from: "Night from:",
+    night_until: "until:",
+    utc_offset: "Local time offset from UTC (hours):",
+    night_display: "Display at night:",
+    display_idle: "Turn the display off after this many minutes without pressing BOOT (0 keeps it on):",
+    display_rotation: "Display rotation in degrees, e.g. 180 when mounted upside down (applied after a restart):",
+    display_bus: "Display I2C bus, applied after a restart:",
+    sda: "SDA GPIO",
+    scl: "SCL GPIO",
+    address: "Address",
+    speed: "Speed (kHz)",
+    cors_origin: "Other website allowed to read the JSON readings, e.g. https://dash.example.com (* for any):",
+    device_name: "Name of this device, e.g. Garage (optional):",
+    channel_label: "What the clamp measures, e.g. Heat pump (optional):",
+    peers: "Other meters to show on /dashboard, e.g. garage.local 192.168.1.40 (optional):",
+    mqtt_url: "MQTT broker to publish readings to, e.g. mqtt://192.168.1.10:1883 (optional):",
+    mqtt_user: "MQTT user",
+    mqtt_password: "MQTT password (empty keeps the current one)",
+    mqtt_prefix: "Topic prefix (empty uses the hostname):",
+    mqtt_interval: "Seconds between readings:",
+    mqtt_format: "Format:",
+    mqtt_thing: "AWS IoT thing name (empty for other brokers):",
+    mqtt_shadow: "Named shadow (empty for the classic one):",
+    coap_url: "CoAP resource to post readings to, e.g. coap://192.168.1.10/readings (optional):",
+    coap_interval: "Seconds between readings:",
+    graphite_host: "Graphite host to send readings to, e.g. 192.168.1.10 or carbon.lan:2003 (optional):",
+    graphite_prefix: "Metric path prefix:",
+    snmp_community: "SNMP v2c community for polling the readings (empty turns SNMP off):",
+    modbus: "Serve the readings over Modbus TCP, port 502",
+    ntfy_url: "ntfy topic URL for alarm notifications (optional):",
+    pushover_user: "Pushover user key:",
+    pushover_token: "Pushover application token (empty keeps the current one):",
+    telegram_chat: "Telegram chat ID for alarms and the /power command:",
+    telegram_token: "Telegram bot token (empty keeps the current one):",
+    admin_password: "New password for the admin user (empty keeps the current one):",
+    language: "Language of these pages (auto follows the browser):",
+    submit: "Submit",
+    saved_for_network: "Saved the settings for the Wi-Fi network",
+    restarts_in: "The device restarts in",
+    seconds_to_apply: "seconds to apply them.",
+    restart_now: "Restart now",
+    restarting: "Restarting...",
+    not_saved: "Not saved, the values are invalid:",
+    trying: "Saved. Trying to connect to the Wi-Fi network...",
+    connected: "Connected. The device leaves setup mode and is now at",
+    could_not_connect: "Could not connect:",
+    back_to_setup: "Back to setup",
+};
+
+static SPANISH: Strings = Strings {
+    title: "Vatímetro aproximado",
+    wifi_network: "Red Wi-Fi:",
+    other_network: "Otra...",
+    scan: "Buscar",
+    scanning: "Buscando...",
+    open_network: "abierta",
+    not_found: "no encontrada",
+    hidden_network: "Nombre de la red oculta",
+    wifi_password: "Contraseña de la Wi-Fi:",
+    wifi_security: "Seguridad:",
+    bssid: "Punto de acceso (BSSID, vacío para cualquiera):",
+    channel: "Canal (vacío para cualquiera):",
+    rssi_floor: "Avisar en el registro cuando la señal baje de (dBm):",
+    test_wifi: "Probar la conexión Wi-Fi antes de salir del punto de acceso de configuración",
+    other_networks: "Otras redes Wi-Fi a las que recurrir, primero la más fuerte a su alcance (una contraseña vacía conserva la guardada):",
+    password: "Contraseña",
+    enterprise: "Acceso WPA2-Enterprise, usado en las redes guardadas sin contraseña de Wi-Fi (una contraseña vacía conserva la guardada):",
+    user: "Usuario",
+    anonymous_identity: "Identidad anónima (opcional)",
+    static_ip: "Dirección IP fija (vacía para obtenerla por DHCP):",
+    netmask: "Máscara de red:",
+    gateway: "Puerta de enlace:",
+    dns_server: "Servidor DNS (vacío para la puerta de enlace):",
+    webhook: "URL a la que enviar las lecturas por POST (si no está vacía). Se rellenan marcadores como {{amps}}, {{watts|round:1}}, {{kwh|comma_decimal}} o {{timestamp|iso8601}}",
+    test_webhook: "Probar",
+    testing: "Enviando...",
+    webhook_body: "Cuerpo a enviar, con los mismos marcadores más {{hostname}} y {{rssi}} (vacío para el JSON por defecto, o datos de formulario):",
+    webhook_method: "Método (GET envía el cuerpo en la cadena de consulta):",
+    webhook_content_type: "Tipo de contenido:",
+    webhook_expect: "Texto que debe contener la respuesta para darla por entregada (vacío para comprobar solo el estado):",
+    webhook_headers: "Cabeceras adicionales para el webhook, p. ej. Authorization: Bearer <token> (opcional, un nombre solo conserva su valor guardado):",
+    report_interval: "Segundos entre envíos, promediando las lecturas intermedias:",
+    batch_size: "Lecturas por envío, mandadas como un array JSON si son 2 o más:",
+    max_watts: "Fondo de escala de la barra de potencia (W):",
+    budget_day: "Presupuesto diario de energía (kWh, vacío para desactivarlo):",
+    budget_month: "Presupuesto mensual de energía (kWh, vacío para desactivarlo):",
+    price_kwh: "Precio de la energía por kWh (vacío para no mostrar costes):",
+    currency: "Moneda mostrada tras los costes:",
+    weather_url: "URL que devuelve la temperatura exterior en JSON, p. ej. Open-Meteo (opcional)",
+    primary_unit: "Lectura en la primera línea de la pantalla (también se cambia manteniendo BOOT pulsado un segundo):",
+    brightness: "Brillo de la pantalla (auto sigue al sensor de luz, si lo hay):",
+    night: "Modo nocturno, según un horario o el sensor de luz:",
+    night_from: "Noche desde:",
+    night_until: "hasta:",
+    utc_offset: "Diferencia de la hora local con UTC (horas):",
+    night_display: "Pantalla de noche:",
+    display_idle: "Apagar la pantalla tras estos minutos sin pulsar BOOT (0 la deja encendida):",
+    display_rotation: "Giro de la pantalla en grados, p. ej. 180 si está montada al revés (se aplica tras reiniciar):",
+    display_bus: "Bus I2C de la pantalla, se aplica tras reiniciar:",
+    sda: "GPIO de SDA",
+    scl: "GPIO de SCL",
+    address: "Dirección",
+    speed: "Velocidad (kHz)",
+    cors_origin: "Otra web autorizada a leer las lecturas en JSON, p. ej. https://dash.example.com (* para cualquiera):",
+    device_name: "Nombre de este dispositivo, p. ej. Garaje (opcional):",
+    channel_label: "Qué mide la pinza, p. ej. Bomba de calor (opcional):",
+    peers: "Otros medidores a mostrar en /dashboard, p. ej. garage.local 192.168.1.40 (opcional):",
+    mqtt_url: "Broker MQTT al que publicar las lecturas, p. ej. mqtt://192.168.1.10:1883 (opcional):",
+    mqtt_user: "Usuario MQTT",
+    mqtt_password: "Contraseña MQTT (vacía mantiene la actual)",
+    mqtt_prefix: "Prefijo de los topics (vacío usa el nombre de host):",
+    mqtt_interval: "Segundos entre lecturas:",
+    mqtt_format: "Formato:",
+    mqtt_thing: "Nombre del thing de AWS IoT (vacío para otros brokers):",
+    mqtt_shadow: "Shadow con nombre (vacío para el clásico):",
+    coap_url: "Recurso CoAP al que enviar las lecturas, p. ej. coap://192.168.1.10/readings (opcional):",
+    coap_interval: "Segundos entre lecturas:",
+    graphite_host: "Servidor Graphite al que enviar las lecturas, p. ej. 192.168.1.10 o carbon.lan:2003 (opcional):",
+    graphite_prefix: "Prefijo de las métricas:",
+    snmp_community: "Comunidad SNMP v2c para consultar las lecturas (vacía desactiva SNMP):",
+    modbus: "Servir las lecturas por Modbus TCP, puerto 502",
+    ntfy_url: "URL del tema de ntfy para avisar de las alarmas (opcional):",
+    pushover_user: "Clave de usuario de Pushover:",
+    pushover_token: "Token de la aplicación de Pushover (vacío mantiene el actual):",
+    telegram_chat: "ID del chat de Telegram para las alarmas y el comando /power:",
+    telegram_token: "Token del bot de Telegram (vacío mantiene el actual):",
+    admin_password: "Nueva contraseña del usuario admin (vacía mantiene la actual):",
+    language: "Idioma de estas páginas (auto sigue al navegador):",
+    submit: "Enviar",
+    saved_for_network: "Configuración guardada para la red Wi-Fi",
+    restarts_in: "El dispositivo se reinicia en",
+    seconds_to_apply: "segundos para aplicarla.",
+    restart_now: "Reiniciar ahora",
+    restarting: "Reiniciando...",
+    not_saved: "No se guardó, los valores no son válidos:",
+    trying: "Guardado. Intentando conectar a la red Wi-Fi...",
+    connected: "Conectado. El dispositivo sale del modo de configuración y ahora está en",
+    could_not_connect: "No se pudo conectar:",
+    back_to_setup: "Volver a la configuración",
+};
@@ -0,0 +1,48 @@
+//! A name for the device and a label for its clamp, e.g. "Garage" and "Heat
+//! pump", so readings from several devices stay legible. They are kept in
+//! NVS and shown on the pages, in `/api/v1/status` and in the webhook body.
+//! Empty ones are left off the pages and the webhook body.
+
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+
+pub const NVS_DEVICE_NAME: &str = "device_name";
+/// Label of the clamp on `metrics::CHANNEL`.
+pub const NVS_CHANNEL_LABEL: &str = "channel_label";
+/// Longest name or label, in characters.
+pub const MAX_LEN: usize = 32;
+
+pub(crate) static DEVICE_NAME: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new(String::new()));
+pub(crate) static CHANNEL_LABEL: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new(String::new()));
+
+/// Whether `label` can be saved: up to `MAX_LEN` printable characters.
+pub fn is_valid(label: &str) -> bool {
+    label.chars().count() <= MAX_LEN && !label.chars().any(char::is_control)
+}
+
+/// Read the name and the label from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    for (key, label) in [
+        (NVS_DEVICE_NAME, &DEVICE_NAME),
+        (NVS_CHANNEL_LABEL, &CHANNEL_LABEL),
+    ] {
+        let value = read_str_from_nvs_or_default(nvs, key, "");
+        *label.lock().unwrap() = if is_valid(&value) {
+            value
+        } else {
+            String::new()
+        };
+    }
+}
+
+pub fn device_name() -> String {
+    DEVICE_NAME.lock().unwrap().clone()
+}
+
+pub fn channel_label() -> String {
+    CHANNEL_LABEL.lock().unwrap().clone()
+}
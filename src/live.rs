@@ -0,0 +1,114 @@
+//! Readings streamed over a WebSocket at `/ws`, one JSON text frame per
+//! sample, for dashboards that want more than polling `/api/v1/status`.
+//!
+//! Clients can also send commands:
+//! - `report`: reply with the latest reading right away
+//! - `reset_energy <password>`: zero the energy counters, given the admin
+//!   password, since browsers can't send credentials with the handshake
+
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use embedded_svc::ws::FrameType;
+use esp_idf_svc::http::server::ws::EspHttpWsDetachedSender;
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::sys::{EspError, ESP_ERR_INVALID_SIZE};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::auth::ADMIN_PASSWORD;
+use crate::energy::RESET_REQUESTED;
+use crate::template::Context;
+
+/// Keeps some of the server's sockets free for plain HTTP requests.
+const MAX_CLIENTS: usize = 3;
+const MAX_COMMAND_LEN: usize = 64;
+
+static CLIENTS: Lazy<Mutex<Vec<EspHttpWsDetachedSender>>> = Lazy::new(|| Mutex::new(Vec::new()));
+/// The last reading sent, for `report`.
+static LATEST: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Serialize)]
+struct Reading {
+    amps: f32,
+    watts: f32,
+    energy_kwh: f64,
+    /// Unix time, or 0 until the clock is synced.
+    timestamp: u64,
+}
+
+/// Send a reading to every connected client, forgetting those that went
+/// away.
+pub fn broadcast(context: &Context) {
+    let reading = serde_json::to_string(&Reading {
+        amps: context.amps.0,
+        watts: context.watts.0,
+        energy_kwh: context.energy.kwh(),
+        timestamp: context.timestamp,
+    })
+    .unwrap();
+    CLIENTS.lock().unwrap().retain_mut(|client| {
+        !client.is_closed()
+            && client
+                .send(FrameType::Text(false), reading.as_bytes())
+                .is_ok()
+    });
+    *LATEST.lock().unwrap() = Some(reading);
+}
+
+fn reply(command: &str) -> String {
+    let (command, argument) = command.split_once(' ').unwrap_or((command, ""));
+    match command {
+        "report" => LATEST
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| "{}".to_string()),
+        "reset_energy" if argument == *ADMIN_PASSWORD.lock().unwrap() => {
+            RESET_REQUESTED.store(true, Ordering::SeqCst);
+            "{\"ok\":true}".to_string()
+        }
+        "reset_energy" => "{\"error\":\"wrong password\"}".to_string(),
+        _ => "{\"error\":\"unknown command\"}".to_string(),
+    }
+}
+
+pub fn add_ws_handler(server: &mut EspHttpServer<'_>) -> Result<(), EspError> {
+    server.ws_handler("/ws", |ws| -> Result<(), EspError> {
+        if ws.is_new() {
+            let mut clients = CLIENTS.lock().unwrap();
+            clients.retain(|client| !client.is_closed());
+            if clients.len() >= MAX_CLIENTS {
+                log::warn!("Refusing WebSocket client, {} connected", clients.len());
+                return ws.send(FrameType::Close, &[]);
+            }
+            clients.push(ws.create_detached_sender()?);
+            log::info!("WebSocket client connected ({} open)", clients.len());
+            return Ok(());
+        } else if ws.is_closed() {
+            let session = ws.session();
+            let mut clients = CLIENTS.lock().unwrap();
+            clients.retain(|client| client.session() != session);
+            log::info!("WebSocket client gone ({} open)", clients.len());
+            return Ok(());
+        }
+
+        // The first call only gets the length, the second the payload
+        let (_frame_type, len) = ws.recv(&mut [])?;
+        if len > MAX_COMMAND_LEN {
+            ws.send(FrameType::Close, &[])?;
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+        let mut buf = [0; MAX_COMMAND_LEN];
+        ws.recv(&mut buf)?;
+        let command = std::str::from_utf8(&buf[..len])
+            .unwrap_or("")
+            .trim_end_matches('\0')
+            .trim();
+        // Only the name, the arguments may hold the admin password
+        let name = command.split_whitespace().next().unwrap_or("");
+        log::info!("WebSocket command: {:?}", name);
+        ws.send(FrameType::Text(false), reply(command).as_bytes())
+    })?;
+    Ok(())
+}
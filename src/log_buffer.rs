@@ -0,0 +1,143 @@
+//! Keeps the latest log lines in memory and serves them at `/logs`, so a
+//! device in the field can be debugged without a USB cable. The lines still
+//! go to the UART console (or RTT) as before.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::{esp_timer_get_time, EspError};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
+use crate::auth;
+use crate::rate_limit;
+
+const MAX_LINES: usize = 300;
+/// Bound on the text kept, so chatty long lines can't eat the heap.
+const MAX_BYTES: usize = 16 * 1024;
+const MAX_LINE_LEN: usize = 200;
+/// Debug and trace lines would push out everything useful in seconds.
+const MIN_LEVEL: Level = Level::Info;
+
+#[derive(Clone, Serialize)]
+struct Line {
+    uptime_ms: u64,
+    level: &'static str,
+    target: String,
+    message: String,
+}
+
+struct Buffer {
+    lines: VecDeque<Line>,
+    bytes: usize,
+}
+
+static BUFFER: Mutex<Buffer> = Mutex::new(Buffer {
+    lines: VecDeque::new(),
+    bytes: 0,
+});
+
+/// Passes every record on to `inner`, keeping a copy of the important ones.
+struct BufferLogger {
+    inner: &'static dyn Log,
+}
+
+static LOGGER: OnceCell<BufferLogger> = OnceCell::new();
+
+impl Log for BufferLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.log(record);
+        if record.level() > MIN_LEVEL {
+            return;
+        }
+        // Skip rather than block or deadlock if a line is logged while the
+        // buffer is being read or written
+        let Ok(mut buffer) = BUFFER.try_lock() else {
+            return;
+        };
+        let mut message = record.args().to_string();
+        if message.len() > MAX_LINE_LEN {
+            let mut end = MAX_LINE_LEN;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+        let line = Line {
+            // Safe: esp_timer is started by the IDF before app_main runs
+            uptime_ms: (unsafe { esp_timer_get_time() } / 1000) as u64,
+            level: record.level().as_str(),
+            target: record.target().to_string(),
+            message,
+        };
+        buffer.bytes += line.target.len() + line.message.len();
+        buffer.lines.push_back(line);
+        while buffer.lines.len() > MAX_LINES || buffer.bytes > MAX_BYTES {
+            let Some(oldest) = buffer.lines.pop_front() else {
+                break;
+            };
+            buffer.bytes -= oldest.target.len() + oldest.message.len();
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Route the `log` facade to `inner` through the buffer. Call once, before
+/// anything is logged.
+pub fn initialize(inner: &'static dyn Log, max_level: LevelFilter) {
+    let logger = LOGGER.get_or_init(|| BufferLogger { inner });
+    log::set_logger(logger).expect("Logger already set");
+    log::set_max_level(max_level);
+}
+
+fn lines() -> Vec<Line> {
+    BUFFER.lock().unwrap().lines.iter().cloned().collect()
+}
+
+/// `/logs` as plain text, or JSON with `?format=json`, oldest line first.
+/// Boot logs include credentials, so only the admin can read them.
+pub fn add_logs_handler(server: &mut EspHttpServer<'_>) -> Result<(), EspError> {
+    server.fn_handler(
+        "/logs",
+        esp_idf_svc::http::Method::Get,
+        |mut req| -> Result<(), EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            let lines = lines();
+            if req.uri().contains("format=json") {
+                let body = serde_json::to_string(&lines).unwrap();
+                req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+                    .write(body.as_bytes())?;
+            } else {
+                let mut body = String::new();
+                for line in &lines {
+                    writeln!(
+                        body,
+                        "{:>10} {:<5} {}: {}",
+                        line.uptime_ms, line.level, line.target, line.message
+                    )
+                    .unwrap();
+                }
+                req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
+                    .write(body.as_bytes())?;
+            }
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
@@ -0,0 +1,112 @@
+//! `/api/next`, which answers with the next reading as soon as the main loop
+//! takes it, for clients that can't use the WebSocket at `/ws` but want the
+//! readings as they come rather than polling `/api/v1/status`.
+//!
+//! Each reading gets a sequence number. Passing the last one seen as
+//! `after` returns a newer reading right away if one was taken meanwhile,
+//! so none are missed between requests. The server handles one request at a
+//! time, so the wait is kept short; the main loop takes a reading every
+//! second or so anyway.
+
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::EspError;
+use serde_json::json;
+
+use crate::cors;
+use crate::form;
+use crate::template::Context;
+
+/// Wait when the request doesn't say.
+const DEFAULT_TIMEOUT_S: u64 = 5;
+/// Longest wait allowed, since other requests queue up meanwhile.
+pub const MAX_TIMEOUT_S: u64 = 10;
+
+struct Latest {
+    /// Readings taken since boot, 0 before the first.
+    sequence: u64,
+    /// The latest reading as JSON.
+    body: String,
+}
+
+static LATEST: Mutex<Latest> = Mutex::new(Latest {
+    sequence: 0,
+    body: String::new(),
+});
+/// Notified on every new reading.
+static NEW_READING: Condvar = Condvar::new();
+
+/// Hand a new reading to the requests waiting for one.
+pub fn publish(context: &Context) {
+    let mut latest = LATEST.lock().unwrap();
+    latest.sequence += 1;
+    latest.body = json!({
+        "sequence": latest.sequence,
+        "amps": context.amps.0,
+        "watts": context.watts.0,
+        "energy_kwh": context.energy.kwh(),
+        "timestamp": context.timestamp,
+    })
+    .to_string();
+    NEW_READING.notify_all();
+}
+
+/// The first reading after `after`, waiting up to `timeout` for it.
+fn next(after: u64, timeout: Duration) -> Option<String> {
+    let deadline = Instant::now() + timeout;
+    let mut latest = LATEST.lock().unwrap();
+    // A sequence from before a restart waits for the next reading
+    let after = after.min(latest.sequence);
+    // Woken up spuriously or by readings other than the awaited one
+    while latest.sequence <= after {
+        let left = deadline.checked_duration_since(Instant::now())?;
+        latest = NEW_READING.wait_timeout(latest, left).unwrap().0;
+    }
+    Some(latest.body.clone())
+}
+
+/// `GET /api/next[?after=N][&timeout=S]` answers with the reading after
+/// sequence `N` (the next one to be taken if not given), or `204 No Content`
+/// when none came within `S` seconds.
+pub fn add_next_handler(server: &mut EspHttpServer<'_>) -> Result<(), EspError> {
+    server.fn_handler(
+        "/api/next",
+        esp_idf_svc::http::Method::Get,
+        |req| -> Result<(), EspIOError> {
+            let query = req.uri().split_once('?').map_or("", |(_, query)| query);
+            let fields = form::parse(query.as_bytes());
+            let after = form::field(&fields, "after")
+                .and_then(|after| after.parse::<u64>().ok())
+                .unwrap_or_else(|| LATEST.lock().unwrap().sequence);
+            let timeout_s = form::field(&fields, "timeout")
+                .and_then(|timeout| timeout.parse::<u64>().ok())
+                .unwrap_or(DEFAULT_TIMEOUT_S)
+                .min(MAX_TIMEOUT_S);
+
+            let origin = cors::allowed_origin();
+            let Some(body) = next(after, Duration::from_secs(timeout_s)) else {
+                req.into_response(
+                    204,
+                    Some("No Content"),
+                    &[("Access-Control-Allow-Origin", &origin)],
+                )?;
+                return Ok(());
+            };
+            req.into_response(
+                200,
+                Some("OK"),
+                &[
+                    ("Content-Type", "application/json"),
+                    ("Cache-Control", "no-store"),
+                    ("Access-Control-Allow-Origin", &origin),
+                ],
+            )?
+            .write(body.as_bytes())?;
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
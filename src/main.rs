@@ -1,9 +1,12 @@
 use crate::display::DisplayHandlerExt;
+#[cfg(feature = "sh1106")]
+use crate::display::Panel as _;
 use crate::state::PinDriverOutputArcExt as _;
 use embassy_executor::Spawner;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::hal::gpio::PinDriver;
 use esp_idf_svc::hal::{adc, gpio};
+use esp_idf_svc::sntp::EspSntp;
 use esp_idf_svc::{
     hal::{
         adc::{AdcChannelDriver, AdcDriver},
@@ -13,40 +16,157 @@ use esp_idf_svc::{
     sys::EspError,
 };
 use http_server::{
-    configure_http_server, configure_setup_http_server, CURRENT_KNOWN_WEBHOOK,
-    CURRENT_KNOWN_WIFI_SSID,
+    configure_http_server, configure_setup_http_server, CURRENT_KNOWN_BUDGET, CURRENT_KNOWN_COAP,
+    CURRENT_KNOWN_DIMMING, CURRENT_KNOWN_DISPLAY_BUS, CURRENT_KNOWN_DISPLAY_IDLE_MINUTES,
+    CURRENT_KNOWN_DISPLAY_ROTATION, CURRENT_KNOWN_GRAPHITE, CURRENT_KNOWN_HOSTNAME,
+    CURRENT_KNOWN_MAX_WATTS, CURRENT_KNOWN_MQTT, CURRENT_KNOWN_PRIMARY_UNIT,
+    CURRENT_KNOWN_REPORT_INTERVAL, CURRENT_KNOWN_SNMP_COMMUNITY, CURRENT_KNOWN_TARIFF,
+    CURRENT_KNOWN_WEATHER_URL, CURRENT_KNOWN_WEBHOOK, CURRENT_KNOWN_WIFI_SSID,
 };
-use ssd1306::prelude::Brightness;
-use ssd1306::size::DisplaySize128x32;
 use state::AsGlobalState;
 use std::borrow::BorrowMut;
-use std::{
-    fmt::Write,
-    sync::{Arc, Mutex},
-};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 
+pub mod ambient;
 pub mod amps;
+pub mod assets;
+pub mod auth;
+pub mod batch;
+pub mod body;
+pub mod budget;
+pub mod burn_in;
+pub mod button;
+pub mod calibration;
+pub mod coap;
+pub mod confirm;
+pub mod cors;
+pub mod dashboard;
+pub mod delivery;
 pub mod display;
+pub mod energy;
+pub mod error;
+#[cfg(feature = "mcp23017")]
+pub mod expander;
+pub mod factory_reset;
+pub mod form;
+pub mod graphite;
+#[cfg(all(
+    feature = "hd44780",
+    not(any(feature = "headless", feature = "tm1637"))
+))]
+pub mod hd44780;
+pub mod headers;
+pub mod history;
 pub mod http_server;
+pub mod i18n;
+pub mod labels;
+pub mod live;
+pub mod log_buffer;
+pub mod long_poll;
+pub mod metrics;
+pub mod mirror;
+pub mod modbus;
+pub mod mqtt;
+pub mod notify;
 pub mod nvs;
+pub mod nvs_admin;
+pub mod openapi;
+pub mod ota;
+pub mod outbox;
+pub mod pwa;
+pub mod rate_limit;
+pub mod render;
+pub mod report;
+pub mod reporter;
+pub mod restart;
+#[cfg(feature = "defmt-rtt")]
+pub mod rtt_log;
+pub mod senml;
+pub mod settings;
+pub mod snmp;
+pub mod spool;
 pub mod state;
+pub mod telegram;
+pub mod template;
+pub mod tls;
+#[cfg(all(feature = "tm1637", not(feature = "headless")))]
+pub mod tm1637;
+pub mod units;
+pub mod uptime;
+pub mod weather;
+pub mod webhook_test;
 pub mod wifi;
 use crate::nvs::read_str_from_nvs_or_default;
+use crate::units::{Amps, Volts};
 use crate::wifi::AppWifi as _;
 
+/// Version shown on the boot splash, with the commit it was built from.
+const FIRMWARE_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "-", env!("GIT_HASH"));
+
+/// How long the boot splash stays up.
+const SPLASH_MS: u32 = 2000;
+
 // AC Voltage is 220V
-const AC_VOLTS: f32 = 220.0;
+const AC_VOLTS: Volts = Volts(220.0);
 
-/// This configuration is picked up at compile time by `build.rs` from the
-/// file `cfg.toml`.
+// Full scale of the power bar on the display, unless overridden in NVS
+const DEFAULT_MAX_WATTS: f32 = 3500.0;
+
+/// This configuration is picked up at compile time from the (optional) file
+/// `cfg.toml`. Every value is only a default: whatever has been provisioned
+/// into NVS through the setup page takes precedence at runtime.
 #[toml_cfg::toml_config]
 pub struct Config {
-    #[default("Wokwi-GUEST")]
+    /// Station SSID used until one is provisioned. Empty boots into setup mode.
+    #[default("")]
     wifi_ssid: &'static str,
     #[default("")]
     wifi_psk: &'static str,
     #[default("wattometer")]
     default_hostname: &'static str,
+    /// SSID of the setup access point. Empty uses `default_hostname` and the
+    /// end of the MAC address, see `wifi::setup_ap`.
+    #[default("")]
+    ap_ssid: &'static str,
+    /// Key of the setup access point, 8 to 63 characters. Empty generates one
+    /// on first boot.
+    #[default("")]
+    ap_psk: &'static str,
+    /// Also open a TCP connection to the webhook host before each send, on
+    /// top of resolving it, to detect a collector that is down.
+    #[default(false)]
+    preflight_tcp: bool,
+    /// Keep readings that could not be delivered on the spool partition and
+    /// send them once the webhook is reachable again.
+    #[default(true)]
+    spool: bool,
+    /// Also turn the display off while the quiet mode pin is low.
+    #[default(false)]
+    quiet_blanks_display: bool,
+    /// Display bus wiring. The setup page can override it.
+    #[default(25)]
+    display_sda: u8,
+    #[default(14)]
+    display_scl: u8,
+    #[default(0x3C)]
+    display_address: u8,
+    #[default(100)]
+    display_i2c_khz: u32,
+    /// Setup screen text; `{{ssid}}`, `{{key}}` and `{{version}}` are
+    /// filled in.
+    #[default("SETUP MODE AP:")]
+    setup_title: &'static str,
+    #[default("KEY:")]
+    setup_key_label: &'static str,
+    /// Extra line on the setup screen, e.g. a device label. Empty for none.
+    #[default("")]
+    device_label: &'static str,
+    /// Password of the `admin` user for the setup and admin endpoints, used
+    /// until one is saved from the setup page. Empty generates a random one
+    /// on first boot, shown on the display in setup mode.
+    #[default("")]
+    admin_password: &'static str,
 }
 
 fn setup_peripherals<'a, 'b>(
@@ -59,15 +179,16 @@ fn setup_peripherals<'a, 'b>(
     hostname: String,
     webhook_url: String,
     setup_mode: bool,
-) -> Result<
-    state::GlobalState<
-        'a,
-        ssd1306::prelude::I2CInterface<esp_idf_svc::hal::i2c::I2cDriver<'a>>,
-        DisplaySize128x32,
-    >,
-    EspError,
-> {
+    display_bus: display::DisplayBus,
+    display_rotation: display::Rotation,
+) -> Result<state::GlobalState<'a, display::AppDisplay<'a>>, EspError> {
     let adc_config = adc::config::Config::new();
+    // Nothing sits on the display bus
+    #[cfg(feature = "headless")]
+    let _ = display_bus;
+    // Only the OLED can be rotated
+    #[cfg(any(feature = "headless", feature = "tm1637", feature = "hd44780"))]
+    let _ = display_rotation;
 
     // We'll set up these additional VCC and GND pins for the SSD1306 display,
     // in case you are using HW-394 and you want to route only one side of the
@@ -102,48 +223,161 @@ fn setup_peripherals<'a, 'b>(
         wifi: Arc::new(Mutex::new(wifi)),
         wifi_ssid: Arc::new(Mutex::new(wifi_ssid)),
         setup_mode: Arc::new(Mutex::new(setup_mode)),
-        adc_value: Arc::new(Mutex::new(0f32)),
+        adc_value: Arc::new(Mutex::new(Amps::default())),
+        #[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
         display_handler: Arc::new(Mutex::new(display::init_display_i2c(
-            peripherals.pins.gpio25,
-            peripherals.pins.gpio14,
+            display_bus,
+            display_rotation,
+            peripherals.i2c0,
+        )?)),
+        #[cfg(feature = "headless")]
+        display_handler: Arc::new(Mutex::new(display::Headless)),
+        #[cfg(all(feature = "tm1637", not(feature = "headless")))]
+        display_handler: Arc::new(Mutex::new({
+            // The module's DIO and CLK take the places of SDA and SCL
+            let (dio, clk) = display_bus.pins();
+            tm1637::Tm1637::new(clk, dio)?
+        })),
+        #[cfg(all(
+            feature = "hd44780",
+            not(any(feature = "headless", feature = "tm1637"))
+        ))]
+        display_handler: Arc::new(Mutex::new(hd44780::Hd44780::new(
+            display_bus,
             peripherals.i2c0,
-            DisplaySize128x32,
         )?)),
         webhook_url: Arc::new(Mutex::new(webhook_url)),
         gpio_btn_boot: PinDriver::input(peripherals.pins.gpio0)?,
         adc_driver: Arc::new(Mutex::new(AdcDriver::new(peripherals.adc1, &adc_config)?)),
         adc_chan_driver: Arc::new(Mutex::new(AdcChannelDriver::new(peripherals.pins.gpio35)?)),
+        #[cfg(feature = "ambient-ldr")]
+        ldr_chan_driver: Arc::new(Mutex::new(AdcChannelDriver::new(peripherals.pins.gpio36)?)),
+        #[cfg(feature = "mcp23017")]
+        expander: Arc::new(Mutex::new(expander::Mcp23017::new(
+            peripherals.pins.gpio21,
+            peripherals.pins.gpio22,
+            peripherals.i2c1,
+            expander::DEFAULT_ADDRESS,
+        )?)),
         quiet_mode_pin: PinDriver::input(peripherals.pins.gpio34)?,
         blink_led: Arc::new(Mutex::new(gpio2)),
+        history: Arc::new(Mutex::new(history::History::default())),
     })
 }
 
+fn read_max_watts(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> f32 {
+    read_str_from_nvs_or_default(nvs, "max_watts", "")
+        .parse::<f32>()
+        .ok()
+        .filter(|max| *max > 0.0)
+        .unwrap_or(DEFAULT_MAX_WATTS)
+}
+
+const NVS_PRIMARY_UNIT: &str = "primary_unit";
+
+fn read_primary_unit(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> display::PrimaryUnit {
+    display::PrimaryUnit::from_name(&read_str_from_nvs_or_default(nvs, NVS_PRIMARY_UNIT, ""))
+        .unwrap_or_default()
+}
+
 fn main() -> Result<(), EspError> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
     esp_idf_svc::sys::link_patches();
 
-    // Bind the log crate to the ESP Logging facilities
-    esp_idf_svc::log::EspLogger::initialize_default();
+    // Bind the log crate to the ESP Logging facilities, keeping the latest
+    // lines for /logs
+    #[cfg(not(feature = "defmt-rtt"))]
+    log_buffer::initialize(
+        &esp_idf_svc::log::EspLogger,
+        esp_idf_svc::log::EspLogger.get_max_level(),
+    );
+    #[cfg(feature = "defmt-rtt")]
+    rtt_log::initialize();
     let peripherals = Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take()?;
     let nvs = nvs::EspNvsPartition::<nvs::NvsDefault>::take()?;
     let mut nvs_partition = nvs::EspNvs::new(nvs.clone(), "ssaa", true)?;
 
     let app_config = CONFIG;
-
-    let (wifi_ssid, wifi_psk, hostname, mut setup_mode) =
+    auth::load(&app_config, &mut nvs_partition)?;
+    wifi::setup_ap::load(&app_config, &mut nvs_partition)?;
+    cors::load(&nvs_partition);
+    i18n::load(&nvs_partition);
+    labels::load(&nvs_partition);
+    dashboard::load(&nvs_partition);
+    headers::load(&nvs_partition);
+    body::load(&nvs_partition);
+    batch::load(&nvs_partition);
+    delivery::load(&nvs_partition);
+    modbus::load(&nvs_partition);
+    notify::load(&nvs_partition);
+    telegram::load(&nvs_partition);
+    wifi::signal::load(&nvs_partition);
+    tls::load(&nvs_partition);
+    wifi::enterprise::load(&nvs_partition);
+    wifi::security::load(&nvs_partition);
+    wifi::pinning::load(&nvs_partition);
+    wifi::static_ip::load(&nvs_partition);
+    wifi::networks::load(&nvs_partition);
+
+    let (wifi_ssid, wifi_psk, mut hostname, mut setup_mode) =
         wifi::get_ssid_psk_from_nvs(&app_config, &nvs_partition, false)?;
     log::info!(
-        "SSID: {:?} (len={}), PSK: {:?} (len={}) (setup={})",
+        "SSID: {:?} (len={}) (setup={})",
         wifi_ssid.as_bytes(),
         wifi_ssid.chars().count(),
-        wifi_psk,
-        wifi_psk.chars().count(),
         setup_mode
     );
 
+    let mut uptime_tracker =
+        uptime::UptimeTracker::start(nvs::EspNvs::new(nvs.clone(), "ssaa", true)?)?;
+
     let mut webhook_url = read_str_from_nvs_or_default(&nvs_partition, "webhook", "");
+    let mut report_interval_s = report::interval_from_nvs(&nvs_partition);
+    let mut report_averager = report::Averager::new(report_interval_s);
+    let mut batcher = batch::Batcher::default();
+    let mut max_watts = read_max_watts(&nvs_partition);
+    let mut budget = budget::Budget::from_nvs(&nvs_partition);
+    let mut tariff = budget::Tariff::from_nvs(&nvs_partition);
+    let mut primary_unit = read_primary_unit(&nvs_partition);
+    let mut budget_alerts = budget::BudgetAlerts::default();
+    let mut offline_watch = notify::OfflineWatch::new();
+    let mut signal_watch = wifi::signal::SignalWatch::new();
+    let mut weather_url = read_str_from_nvs_or_default(&nvs_partition, "weather_url", "");
+    let mut dimming = ambient::DimmingPolicy::from_nvs(&nvs_partition);
+    let mut display_idle_minutes = burn_in::BurnInGuard::idle_minutes_from_nvs(&nvs_partition);
+    #[cfg(feature = "ambient-ldr")]
+    let mut ambient_light = ambient::AmbientLight::default();
+    let mut weather_fetcher = weather::WeatherFetcher::new(weather_url.clone());
+    let mut mqtt_settings = mqtt::MqttSettings::from_nvs(&nvs_partition);
+    let mut mqtt_publisher = mqtt::MqttPublisher::new(mqtt_settings.clone(), &hostname);
+    let mut coap_settings = coap::CoapSettings::from_nvs(&nvs_partition);
+    let mut coap_publisher = coap::CoapPublisher::new(coap_settings.clone());
+    let mut graphite_settings = graphite::GraphiteSettings::from_nvs(&nvs_partition);
+    let mut graphite_sender = graphite::GraphiteSender::new(graphite_settings.clone(), &hostname);
+    let mut snmp_community = snmp::community_from_nvs(&nvs_partition);
+    let mut snmp_agent = snmp::SnmpAgent::new(snmp_community.clone());
+    let mut calibration_store =
+        calibration::CalibrationStore::start(nvs::EspNvs::new(nvs.clone(), "ssaa", true)?)?;
+    let spool = if app_config.spool {
+        match spool::Spool::start(nvs.clone()) {
+            Ok(spool) => Some(spool),
+            Err(err) => {
+                log::warn!("Cannot open the spool partition, not spooling: {:?}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let outbox = outbox::Outbox::new(spool);
+    let mut energy_meter =
+        energy::EnergyMeter::start(nvs::EspNvs::new(nvs.clone(), "ssaa", true)?)?;
+    let display_bus = display::DisplayBus::from_nvs(&app_config, &nvs_partition);
+    let display_rotation = display::Rotation::from_nvs(&nvs_partition);
+    let mut wifi_failover =
+        wifi::networks::Failover::new(wifi::networks::known(&wifi_ssid, &wifi_psk));
     let global_state = setup_peripherals(
         peripherals,
         &app_config,
@@ -151,29 +385,64 @@ fn main() -> Result<(), EspError> {
         &sysloop,
         wifi_ssid,
         wifi_psk,
-        hostname,
+        hostname.clone(),
         webhook_url.clone(),
         setup_mode,
+        display_bus,
+        display_rotation,
     )?;
+
+    global_state.display_handler.run(|d| {
+        display::draw_splash(d, FIRMWARE_VERSION, &hostname)?;
+        d.flush()
+    });
+    FreeRtos::delay_ms(SPLASH_MS);
+    global_state.display_handler.run(|d| {
+        d.clear_buffer();
+        d.flush()
+    });
     wifi::set_wifi_hostname(
         app_config.default_hostname.to_string(),
         Arc::downgrade(&global_state.wifi),
         &sysloop,
     );
+    if !setup_mode && wifi_failover.has_choice() {
+        // Start on the strongest known network in range rather than the first
+        match wifi_failover.switch(&global_state.wifi, "")? {
+            Some(network) => *global_state.wifi_ssid.lock().unwrap() = network.ssid,
+            None => global_state.wifi.connect()?,
+        }
+    }
+
+    // Keep the SNTP client alive for the whole program so the clock stays synced
+    let _sntp = EspSntp::new_default()?;
 
     let mut server = {
         let setup_mode = global_state.setup_mode.lock().unwrap();
         if *setup_mode {
             log::info!("Starting EspHttpServer in setup mode");
-            configure_setup_http_server(&mut nvs_partition)?
+            configure_setup_http_server(&nvs, &global_state.wifi)?
         } else {
-            configure_http_server(&global_state.adc_value, &mut nvs_partition)?
+            configure_http_server(
+                &global_state.adc_value,
+                &global_state.history,
+                &nvs,
+                &global_state.wifi,
+            )?
         }
     };
 
-    let display_handler = global_state.display_handler.clone();
+    let view = render::spawn(global_state.display_handler.clone())?;
+    dashboard::spawn();
 
     let mut wifi_disconnected_count = 0;
+    let mut wifi_was_connected = false;
+    let reset_reason = uptime::reset_reason();
+    let mut boot_button = button::Button::default();
+    let mut page = display::Page::default();
+    let mut status_icons = display::StatusIcons::default();
+    let mut burn_in_guard =
+        burn_in::BurnInGuard::new(display_idle_minutes, uptime::session_uptime_s());
     let mut setup_mode_changed;
     let mut last_setup_mode = setup_mode;
 
@@ -184,9 +453,36 @@ fn main() -> Result<(), EspError> {
         .unwrap()
         .clone();
 
+    *CURRENT_KNOWN_HOSTNAME.try_lock().unwrap() = hostname.clone();
     *CURRENT_KNOWN_WEBHOOK.try_lock().unwrap() = webhook_url.clone();
+    *CURRENT_KNOWN_REPORT_INTERVAL.try_lock().unwrap() = report_interval_s;
+    *CURRENT_KNOWN_MAX_WATTS.try_lock().unwrap() = max_watts;
+    *CURRENT_KNOWN_BUDGET.try_lock().unwrap() = budget;
+    *CURRENT_KNOWN_TARIFF.try_lock().unwrap() = tariff.clone();
+    *CURRENT_KNOWN_PRIMARY_UNIT.try_lock().unwrap() = primary_unit;
+    *CURRENT_KNOWN_WEATHER_URL.try_lock().unwrap() = weather_url.clone();
+    *CURRENT_KNOWN_DIMMING.try_lock().unwrap() = dimming;
+    *CURRENT_KNOWN_DISPLAY_IDLE_MINUTES.try_lock().unwrap() = display_idle_minutes;
+    *CURRENT_KNOWN_DISPLAY_BUS.try_lock().unwrap() = display_bus;
+    *CURRENT_KNOWN_DISPLAY_ROTATION.try_lock().unwrap() = display_rotation;
+    *CURRENT_KNOWN_MQTT.try_lock().unwrap() = mqtt_settings.clone();
+    *CURRENT_KNOWN_COAP.try_lock().unwrap() = coap_settings.clone();
+    *CURRENT_KNOWN_GRAPHITE.try_lock().unwrap() = graphite_settings.clone();
+    *CURRENT_KNOWN_SNMP_COMMUNITY.try_lock().unwrap() = snmp_community.clone();
+    // Only once the values they read are set, or the locks above could fail
+    modbus::spawn();
+    let webhook_reporter = reporter::spawn(outbox);
+    notify::spawn();
+    telegram::spawn();
 
     loop {
+        uptime_tracker.tick();
+        calibration_store.tick();
+        restart::tick();
+        if factory_reset::take_requested() {
+            factory_reset::run(&mut nvs_partition, &global_state.blink_led)?;
+        }
+
         if last_setup_mode != setup_mode {
             setup_mode_changed = true;
             last_setup_mode = setup_mode;
@@ -194,80 +490,173 @@ fn main() -> Result<(), EspError> {
             setup_mode_changed = false;
         }
 
-        let high_level = if global_state.quiet_mode_pin.is_high() {
+        // Keep the LED off in the dark, it's bright enough to be a nuisance
+        #[cfg(feature = "ambient-ldr")]
+        let dark = ambient_light.is_dark();
+        #[cfg(not(feature = "ambient-ldr"))]
+        let dark = false;
+        let quiet = global_state.quiet_mode_pin.is_low();
+        let high_level = if !quiet && !dark {
             gpio::Level::High
         } else {
             gpio::Level::Low
         };
 
+        // Settings changed through /api/config apply without a restart
+        if settings::take_changed() {
+            log::info!("Applying changed settings");
+            webhook_url = read_str_from_nvs_or_default(&nvs_partition, "webhook", "");
+            report_interval_s = report::interval_from_nvs(&nvs_partition);
+            report_averager = report::Averager::new(report_interval_s);
+            batcher = batch::Batcher::default();
+            max_watts = read_max_watts(&nvs_partition);
+            budget = budget::Budget::from_nvs(&nvs_partition);
+            tariff = budget::Tariff::from_nvs(&nvs_partition);
+            primary_unit = read_primary_unit(&nvs_partition);
+            weather_url = read_str_from_nvs_or_default(&nvs_partition, "weather_url", "");
+            dimming = ambient::DimmingPolicy::from_nvs(&nvs_partition);
+            display_idle_minutes = burn_in::BurnInGuard::idle_minutes_from_nvs(&nvs_partition);
+            burn_in_guard =
+                burn_in::BurnInGuard::new(display_idle_minutes, uptime::session_uptime_s());
+            weather_fetcher = weather::WeatherFetcher::new(weather_url.clone());
+            mqtt_settings = mqtt::MqttSettings::from_nvs(&nvs_partition);
+            mqtt_publisher = mqtt::MqttPublisher::new(mqtt_settings.clone(), &hostname);
+            coap_settings = coap::CoapSettings::from_nvs(&nvs_partition);
+            coap_publisher = coap::CoapPublisher::new(coap_settings.clone());
+            graphite_settings = graphite::GraphiteSettings::from_nvs(&nvs_partition);
+            graphite_sender = graphite::GraphiteSender::new(graphite_settings.clone(), &hostname);
+            snmp_community = snmp::community_from_nvs(&nvs_partition);
+            snmp_agent = snmp::SnmpAgent::new(snmp_community.clone());
+            cors::load(&nvs_partition);
+            i18n::load(&nvs_partition);
+            labels::load(&nvs_partition);
+            dashboard::load(&nvs_partition);
+            headers::load(&nvs_partition);
+            body::load(&nvs_partition);
+            batch::load(&nvs_partition);
+            delivery::load(&nvs_partition);
+            modbus::load(&nvs_partition);
+            notify::load(&nvs_partition);
+            telegram::load(&nvs_partition);
+            wifi::signal::load(&nvs_partition);
+
+            *CURRENT_KNOWN_WEBHOOK.lock().unwrap() = webhook_url.clone();
+            *CURRENT_KNOWN_REPORT_INTERVAL.lock().unwrap() = report_interval_s;
+            *CURRENT_KNOWN_MAX_WATTS.lock().unwrap() = max_watts;
+            *CURRENT_KNOWN_BUDGET.lock().unwrap() = budget;
+            *CURRENT_KNOWN_TARIFF.lock().unwrap() = tariff.clone();
+            *CURRENT_KNOWN_PRIMARY_UNIT.lock().unwrap() = primary_unit;
+            *CURRENT_KNOWN_WEATHER_URL.lock().unwrap() = weather_url.clone();
+            *CURRENT_KNOWN_DIMMING.lock().unwrap() = dimming;
+            *CURRENT_KNOWN_DISPLAY_IDLE_MINUTES.lock().unwrap() = display_idle_minutes;
+            *CURRENT_KNOWN_MQTT.lock().unwrap() = mqtt_settings.clone();
+            *CURRENT_KNOWN_COAP.lock().unwrap() = coap_settings.clone();
+            *CURRENT_KNOWN_GRAPHITE.lock().unwrap() = graphite_settings.clone();
+            *CURRENT_KNOWN_SNMP_COMMUNITY.lock().unwrap() = snmp_community.clone();
+        }
+
         if setup_mode_changed {
-            display_handler.run(|d| d.clear());
             drop(server);
             webhook_url = read_str_from_nvs_or_default(&nvs_partition, "webhook", "");
-
-            let (wifi_ssid, wifi_psk, hostname, _setup_mode) =
+            *CURRENT_KNOWN_WEBHOOK.lock().unwrap() = webhook_url.clone();
+            max_watts = read_max_watts(&nvs_partition);
+            budget = budget::Budget::from_nvs(&nvs_partition);
+            tariff = budget::Tariff::from_nvs(&nvs_partition);
+            primary_unit = read_primary_unit(&nvs_partition);
+            weather_url = read_str_from_nvs_or_default(&nvs_partition, "weather_url", "");
+            dimming = ambient::DimmingPolicy::from_nvs(&nvs_partition);
+            display_idle_minutes = burn_in::BurnInGuard::idle_minutes_from_nvs(&nvs_partition);
+            burn_in_guard =
+                burn_in::BurnInGuard::new(display_idle_minutes, uptime::session_uptime_s());
+            weather_fetcher = weather::WeatherFetcher::new(weather_url.clone());
+
+            let (wifi_ssid, wifi_psk, nvs_hostname, _setup_mode) =
                 wifi::get_ssid_psk_from_nvs(&app_config, &nvs_partition, setup_mode)?;
+            wifi::enterprise::load(&nvs_partition);
+            wifi::security::load(&nvs_partition);
+            wifi::pinning::load(&nvs_partition);
+            wifi::static_ip::load(&nvs_partition);
+            wifi::networks::load(&nvs_partition);
+            wifi_failover =
+                wifi::networks::Failover::new(wifi::networks::known(&wifi_ssid, &wifi_psk));
+            *global_state.wifi_ssid.lock().unwrap() = wifi_ssid.clone();
+            hostname = nvs_hostname;
+            *CURRENT_KNOWN_HOSTNAME.lock().unwrap() = hostname.clone();
+            mqtt_settings = mqtt::MqttSettings::from_nvs(&nvs_partition);
+            mqtt_publisher = mqtt::MqttPublisher::new(mqtt_settings.clone(), &hostname);
+            coap_settings = coap::CoapSettings::from_nvs(&nvs_partition);
+            coap_publisher = coap::CoapPublisher::new(coap_settings.clone());
+            graphite_settings = graphite::GraphiteSettings::from_nvs(&nvs_partition);
+            graphite_sender = graphite::GraphiteSender::new(graphite_settings.clone(), &hostname);
+            snmp_community = snmp::community_from_nvs(&nvs_partition);
+            snmp_agent = snmp::SnmpAgent::new(snmp_community.clone());
             log::info!(
-                "SSID: {:?} (len={}), PSK: {:?} (len={}) (setup={})",
+                "SSID: {:?} (len={}) (setup={})",
                 wifi_ssid,
                 wifi_ssid.chars().count(),
-                wifi_psk,
-                wifi_psk.chars().count(),
                 setup_mode
             );
-            wifi::reset_wifi(
-                &app_config,
-                &global_state.wifi,
-                wifi_ssid,
-                wifi_psk,
-                setup_mode,
-            )?;
-            wifi::set_wifi_hostname(hostname, Arc::downgrade(&global_state.wifi), &sysloop);
+            wifi::reset_wifi(&global_state.wifi, wifi_ssid, wifi_psk, setup_mode)?;
+            wifi::set_wifi_hostname(
+                hostname.clone(),
+                Arc::downgrade(&global_state.wifi),
+                &sysloop,
+            );
 
             server = if setup_mode {
-                configure_setup_http_server(&mut nvs_partition)?
+                configure_setup_http_server(&nvs, &global_state.wifi)?
             } else {
-                configure_http_server(&global_state.adc_value, &mut nvs_partition)?
+                configure_http_server(
+                    &global_state.adc_value,
+                    &global_state.history,
+                    &nvs,
+                    &global_state.wifi,
+                )?
             };
         };
 
+        status_icons.setup_ap = setup_mode;
         if setup_mode {
-            display_handler.run(|d| d.set_position(0, 0));
-            display_handler.run(|d| write!(d, "SETUP MODE AP:\n{}\n", app_config.wifi_ssid));
-            display_handler.run(|d| write!(d, "KEY:\n{}\n", app_config.wifi_psk));
+            view.send(render::View::Setup {
+                page: display::SetupPage::at(uptime::session_uptime_s()),
+                ap_ssid: wifi::setup_ap::ssid(),
+                ap_psk: wifi::setup_ap::psk(),
+                text: display::SetupText {
+                    title: app_config.setup_title,
+                    key_label: app_config.setup_key_label,
+                    label: app_config.device_label,
+                },
+                admin_password: auth::generated_password(),
+                icons: status_icons,
+            });
 
             // Forcefully blink the LED even if we are in "quiet" mode to identify that we are in setup mode
             global_state.blink_led.set_high()?;
             FreeRtos::delay_ms(1000u32);
             global_state.blink_led.set_low()?;
-            FreeRtos::delay_ms(1000u32); // Wait for longer, since this will just refresh the screen
 
-            if global_state.gpio_btn_boot.is_low() {
-                // If the BOOT button is pressed, we will exit setup mode
-                setup_mode = false;
-                // Blink twice to confirm
-                global_state.blink_led.set_high()?;
-                FreeRtos::delay_ms(100u32);
-                global_state.blink_led.set_low()?;
-                FreeRtos::delay_ms(100u32);
-                global_state.blink_led.set_high()?;
-                FreeRtos::delay_ms(100u32);
-                global_state.blink_led.set_low()?;
-                FreeRtos::delay_ms(500u32);
-                continue;
+            // Leave setup mode once the credentials saved from the setup page work
+            if let Ok(mut wifi) = global_state.wifi.lock() {
+                if wifi::provision::tick(&mut wifi, uptime::session_uptime_s()) {
+                    log::info!("Wi-Fi connection test passed, leaving setup mode");
+                    setup_mode = false;
+                }
             }
         } else {
-            // If the BOOT button is pressed, we will enter setup mode
-            if global_state.gpio_btn_boot.is_low() {
-                setup_mode = true;
-                continue;
-            } else {
-                log::info!("Normal mode (setup={})", setup_mode);
-            }
+            log::info!("Normal mode (setup={})", setup_mode);
 
             // Tiny blink of LED if normal mode and wifi is connected
             if global_state.wifi.is_connected()? {
+                if wifi_disconnected_count > 0 && wifi_was_connected {
+                    wifi::WIFI_RECONNECTS.fetch_add(1, Ordering::Relaxed);
+                }
+                if !wifi_was_connected {
+                    // Good enough to keep after an update
+                    ota::mark_valid();
+                }
+                wifi_was_connected = true;
                 wifi_disconnected_count = 0;
+                wifi_failover.connected();
                 global_state.blink_led.set_level(high_level)?;
                 setup_mode = false;
             } else {
@@ -276,6 +665,15 @@ fn main() -> Result<(), EspError> {
                     // Try to .connect() every 10 seconds
                     global_state.wifi.connect()?;
                 } else if wifi_disconnected_count >= 30 {
+                    // Another known network in range may still work
+                    if wifi_failover.has_choice() {
+                        let current = global_state.wifi_ssid.lock().unwrap().clone();
+                        if let Some(network) = wifi_failover.switch(&global_state.wifi, &current)? {
+                            *global_state.wifi_ssid.lock().unwrap() = network.ssid;
+                            wifi_disconnected_count = 0;
+                            continue;
+                        }
+                    }
                     // If we are disconnected for more than 30 seconds, we will enter setup mode
                     log::info!("Entering setup mode due to no Wi-Fi connection");
                     setup_mode = true;
@@ -283,12 +681,46 @@ fn main() -> Result<(), EspError> {
                 }
             }
 
-            display_handler.init(Brightness::DIM);
-            let amps = amps::read_amps(
+            #[cfg(feature = "ambient-ldr")]
+            let auto_brightness = {
+                let millivolts = global_state
+                    .adc_driver_mut()?
+                    .read(&mut *global_state.ldr_chan_driver.lock().unwrap())?;
+                ambient_light.update(millivolts)
+            };
+            #[cfg(not(feature = "ambient-ldr"))]
+            let auto_brightness = display::Brightness::Dim;
+            let now_s = uptime::session_uptime_s();
+            let blank = burn_in_guard.is_idle(now_s) || (quiet && app_config.quiet_blanks_display);
+            let page_dimming = render::Dimming {
+                policy: dimming,
+                auto_brightness,
+                dark,
+                blank,
+            };
+            let shift = burn_in_guard.shift(now_s);
+            let show_pages = |screen: &display::MainScreen| {
+                if let Some(percent) = ota::progress() {
+                    view.send(render::View::Update { percent });
+                    return;
+                }
+                view.send(render::View::Pages {
+                    page,
+                    shift,
+                    screen: screen.clone(),
+                    dimming: page_dimming,
+                })
+            };
+            let raw_amps = amps::read_amps(
                 global_state.adc_driver_mut().unwrap().borrow_mut(),
                 global_state.adc_chan_driver_mut().unwrap().borrow_mut(),
             )
             .unwrap();
+            let amps = {
+                let mut calibration = calibration::CALIBRATION.lock().unwrap();
+                calibration.last_raw_amps = raw_amps;
+                Amps(calibration.apply(raw_amps))
+            };
             {
                 let guard = global_state.adc_value.try_lock();
                 match guard {
@@ -297,37 +729,157 @@ fn main() -> Result<(), EspError> {
                 }
             };
 
-            log::info!("Amps: {:.5}A ; {:.5}W", amps, AC_VOLTS * amps);
-            display_handler.run(|d| d.set_position(0, 0));
-            display_handler.run(|d| write!(d, "{:.5}A    \n{:.5}W    \n", amps, AC_VOLTS * amps));
+            let watts = amps * AC_VOLTS;
+            log::info!("Amps: {:.5} ; {:.5}", amps, watts);
+            let energy = energy_meter.add_reading(watts);
+            let budget_level = budget_alerts.update(&budget, &energy);
+            // Shed the load wired through relay 0 while over budget
+            #[cfg(feature = "mcp23017")]
+            global_state
+                .expander
+                .lock()
+                .unwrap()
+                .set_relay(0, budget_level == budget::BudgetLevel::Exceeded);
+            let sparkline: Vec<f32> = {
+                let mut history = global_state.history.lock().unwrap();
+                history.record(amps, watts);
+                history.iter().map(|reading| reading.watts.0).collect()
+            };
+            let webhook = reporter::status();
+            let mut screen = display::MainScreen {
+                amps,
+                watts,
+                primary_unit,
+                icons: status_icons,
+                today_cost: tariff.cost(energy.today_wh),
+                currency: tariff.currency.clone(),
+                bar_fraction: watts.0 / max_watts,
+                budget_marker: budget_level.marker(),
+                today_wh: energy.today_wh,
+                month_wh: energy.month_wh,
+                total_wh: energy.total_wh,
+                sparkline,
+                ssid: global_state.wifi_ssid.lock().unwrap().clone(),
+                hostname: hostname.clone(),
+                alarms: budget_alerts.alarms(&budget, &energy),
+                uptime_s: uptime::session_uptime_s(),
+                boots: uptime::UPTIME.lock().unwrap().boots,
+                // Safe: plain read of the heap allocator's counters
+                free_heap: unsafe { esp_idf_svc::sys::esp_get_free_heap_size() },
+                reset_reason: reset_reason.clone(),
+                wifi_reconnects: wifi::WIFI_RECONNECTS.load(Ordering::Relaxed),
+                webhook_http_status: webhook.http_status,
+                delivery: delivery::DELIVERY_STATS.lock().unwrap().clone(),
+                ..Default::default()
+            };
 
-            if let Ok(wifi) = global_state.wifi.try_lock() {
-                if wifi.is_connected()? {
+            let context = template::Context {
+                amps,
+                watts,
+                energy: energy.total_wh,
+                timestamp: uptime::unix_now().unwrap_or(0),
+            };
+            live::broadcast(&context);
+            long_poll::publish(&context);
+            modbus::publish(&context);
+            telegram::publish(&context);
+            let averaged = report_averager.add(&context, now_s);
+
+            match global_state.wifi.try_lock() {
+                Ok(wifi) if wifi.is_connected()? => {
                     let ip = wifi::get_client_ip(&wifi)?;
-                    display_handler.run(|d| write!(d, "{}\n", ip));
-
-                    // Send via webhook
-                    log::info!("Webhook: {:?}", webhook_url);
-                    if webhook_url.is_empty() {
-                        display_handler.run(|d| write!(d, "NO WEBHOOK"));
-                    } else {
-                        display_handler.run(|d| write!(d, "SENDING...  "));
-                        let _ = wifi::send_webhook(&webhook_url, &wifi, amps, AC_VOLTS * amps);
-
-                        display_handler.run(|d| {
-                            let _ = d.set_column(80);
-                            write!(d, "OK")
-                        });
+                    weather_fetcher.tick(&wifi);
+                    mqtt_publisher.tick(&context);
+                    coap_publisher.tick(&context);
+                    if let Some(averaged) = &averaged {
+                        graphite_sender.send(averaged);
                     }
-                } else {
-                    display_handler.run(|d| write!(d, "CONNECTING..."));
+                    snmp_agent.tick(&context);
+                    screen.network = ip.to_string();
+                    screen.rssi = wifi::get_rssi();
+                    status_icons.set_rssi(screen.rssi);
+                    if let Some(rssi) = screen.rssi {
+                        signal_watch.update(rssi);
+                    }
+
+                    reporter::set_online(true);
+                    offline_watch.update(true, now_s);
+                    screen.webhook_status = webhook.label;
+                    status_icons.upload = webhook.upload;
+                    screen.icons = status_icons;
+                    show_pages(&screen);
                 }
+                Ok(_) => {
+                    screen.network = "CONNECTING...".to_string();
+                    status_icons.set_rssi(None);
+                    screen.icons = status_icons;
+                    reporter::set_online(false);
+                    offline_watch.update(false, now_s);
+                    show_pages(&screen);
+                }
+                Err(_) => show_pages(&screen),
+            }
+
+            // Posted on the webhook's own thread
+            if let Some(report) = averaged.and_then(|report| batcher.add(report)) {
+                webhook_reporter.send(report);
             }
         }
 
-        // Sleep 1000ms
+        // Sleep 1000ms, watching the BOOT button meanwhile
         FreeRtos::delay_ms(100u32);
         global_state.blink_led.set_low()?;
-        FreeRtos::delay_ms(900u32);
+        let press = boot_button.wait(&global_state.gpio_btn_boot, 900u32);
+        // Buttons on the expander act like a short press of BOOT
+        #[cfg(feature = "mcp23017")]
+        let press = press.or_else(|| {
+            (global_state.expander.lock().unwrap().pressed_buttons() != 0)
+                .then_some(button::Press::Short)
+        });
+        if press.is_some() {
+            let now_s = uptime::session_uptime_s();
+            // A press that wakes the display doesn't also act on it
+            let held = matches!(press, Some(button::Press::Long | button::Press::VeryLong));
+            if !held && burn_in_guard.is_idle(now_s) {
+                burn_in_guard.wake(now_s);
+                continue;
+            }
+            burn_in_guard.wake(now_s);
+        }
+        match press {
+            // A short press shows the next page
+            Some(button::Press::Short) if !setup_mode => {
+                page = page.next();
+                log::info!("Showing the {:?} page", page);
+            }
+            // A medium press changes the main reading
+            Some(button::Press::Medium) if !setup_mode => {
+                primary_unit = primary_unit.next();
+                log::info!("Showing {} on the first line", primary_unit.name());
+                if let Err(err) = nvs_partition.set_str(NVS_PRIMARY_UNIT, primary_unit.name()) {
+                    log::warn!("Error setting {} in NVS: {:?}", NVS_PRIMARY_UNIT, err);
+                }
+                *CURRENT_KNOWN_PRIMARY_UNIT.lock().unwrap() = primary_unit;
+            }
+            // A long press toggles setup mode
+            Some(button::Press::Long) if setup_mode => {
+                setup_mode = false;
+                // Blink twice to confirm
+                global_state.blink_led.set_high()?;
+                FreeRtos::delay_ms(100u32);
+                global_state.blink_led.set_low()?;
+                FreeRtos::delay_ms(100u32);
+                global_state.blink_led.set_high()?;
+                FreeRtos::delay_ms(100u32);
+                global_state.blink_led.set_low()?;
+                FreeRtos::delay_ms(500u32);
+            }
+            Some(button::Press::Long) => setup_mode = true,
+            // Holding on for 10s erases every setting
+            Some(button::Press::VeryLong) => {
+                factory_reset::run(&mut nvs_partition, &global_state.blink_led)?
+            }
+            _ => (),
+        }
     }
 }
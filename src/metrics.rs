@@ -0,0 +1,175 @@
+//! Canonical metric names and labels, shared by every output so dashboards
+//! don't need per-output translation rules.
+//!
+//! Every sample is labelled with the device, and those measured on a clamp
+//! also with its channel and phase. Names carry their unit as a suffix, which
+//! is also exported as a `unit` label where the output has labels.
+
+use std::fmt::Write;
+
+use esp_idf_svc::sys::{esp_mac_type_t_ESP_MAC_WIFI_STA, esp_read_mac};
+use once_cell::sync::Lazy;
+
+/// Prefix of the Prometheus series.
+const NAMESPACE: &str = "wattometer";
+
+/// The single current clamp, on GPIO35.
+pub const CHANNEL: u8 = 0;
+pub const PHASE: &str = "L1";
+
+/// Station MAC address in hex, which stays the same across reflashes and
+/// hostname changes.
+pub static DEVICE_ID: Lazy<String> = Lazy::new(|| {
+    let mut mac = [0u8; 6];
+    // Safe: the buffer is the 6 bytes the IDF writes
+    unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_STA) };
+    mac.iter().map(|byte| format!("{:02x}", byte)).collect()
+});
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Gauge,
+    Counter,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Gauge => "gauge",
+            Kind::Counter => "counter",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Metric {
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub kind: Kind,
+    /// Measured on a clamp rather than about the device as a whole.
+    pub per_channel: bool,
+    /// Name used before the scheme was unified, still exported alongside so
+    /// existing dashboards keep working.
+    pub legacy_name: Option<&'static str>,
+    /// Decimals in the exported value.
+    pub precision: usize,
+}
+
+const fn metric(
+    name: &'static str,
+    unit: &'static str,
+    kind: Kind,
+    per_channel: bool,
+    precision: usize,
+) -> Metric {
+    Metric {
+        name,
+        unit,
+        kind,
+        per_channel,
+        legacy_name: None,
+        precision,
+    }
+}
+
+pub const CURRENT: Metric = Metric {
+    legacy_name: Some("amps"),
+    ..metric("current_amperes", "A", Kind::Gauge, true, 5)
+};
+pub const POWER: Metric = Metric {
+    legacy_name: Some("watts"),
+    ..metric("power_watts", "W", Kind::Gauge, true, 5)
+};
+pub const ENERGY_TOTAL: Metric = metric("energy_watthours_total", "Wh", Kind::Counter, true, 3);
+pub const ENERGY_TODAY: Metric = metric("energy_today_watthours", "Wh", Kind::Gauge, true, 3);
+pub const ENERGY_MONTH: Metric = metric("energy_month_watthours", "Wh", Kind::Gauge, true, 3);
+pub const BOOTS: Metric = metric("boots_total", "", Kind::Counter, false, 0);
+pub const UPTIME: Metric = metric("uptime_seconds", "s", Kind::Gauge, false, 0);
+pub const UPTIME_TOTAL: Metric = metric("uptime_seconds_total", "s", Kind::Counter, false, 0);
+pub const AVAILABILITY: Metric = metric("availability_percent", "%", Kind::Gauge, false, 3);
+pub const WIFI_RSSI: Metric = metric("wifi_rssi_dbm", "dBm", Kind::Gauge, false, 0);
+pub const OUTDOOR_TEMPERATURE: Metric =
+    metric("outdoor_temperature_celsius", "°C", Kind::Gauge, false, 1);
+pub const OUTDOOR_TEMPERATURE_TODAY_MEAN: Metric = metric(
+    "outdoor_temperature_today_mean_celsius",
+    "°C",
+    Kind::Gauge,
+    false,
+    1,
+);
+pub const OUTDOOR_TEMPERATURE_YESTERDAY_MEAN: Metric = metric(
+    "outdoor_temperature_yesterday_mean_celsius",
+    "°C",
+    Kind::Gauge,
+    false,
+    1,
+);
+
+impl Metric {
+    /// Append the sample in the Prometheus text format, followed by the
+    /// legacy series if there is one.
+    pub fn write_prometheus(&self, out: &mut String, value: f64) {
+        write!(
+            out,
+            "# TYPE {ns}_{name} {kind}\n{ns}_{name}{{device_id=\"{device}\"",
+            ns = NAMESPACE,
+            name = self.name,
+            kind = self.kind.name(),
+            device = *DEVICE_ID,
+        )
+        .unwrap();
+        if self.per_channel {
+            write!(out, ",channel=\"{}\",phase=\"{}\"", CHANNEL, PHASE).unwrap();
+        }
+        if !self.unit.is_empty() {
+            write!(out, ",unit=\"{}\"", self.unit).unwrap();
+        }
+        writeln!(out, "}} {:.*}", self.precision, value).unwrap();
+
+        if let Some(legacy_name) = self.legacy_name {
+            write!(
+                out,
+                "# TYPE {ns}_{name} {kind}\n{ns}_{name} {value:.precision$}\n",
+                ns = NAMESPACE,
+                name = legacy_name,
+                kind = self.kind.name(),
+                precision = self.precision,
+            )
+            .unwrap();
+        }
+    }
+
+    /// Append the sample as a `"name":value` JSON member, followed by the
+    /// legacy member if there is one.
+    pub fn write_json(&self, out: &mut String, value: f64) {
+        write!(out, "\"{}\":{:.*}", self.name, self.precision, value).unwrap();
+        if let Some(legacy_name) = self.legacy_name {
+            write!(out, ",\"{}\":{:.*}", legacy_name, self.precision, value).unwrap();
+        }
+    }
+
+    /// Append the sample as a `name=value` form field, followed by the
+    /// legacy field if there is one.
+    pub fn write_form(&self, out: &mut String, value: f64) {
+        write!(out, "{}={:.*}", self.name, self.precision, value).unwrap();
+        if let Some(legacy_name) = self.legacy_name {
+            write!(out, "&{}={:.*}", legacy_name, self.precision, value).unwrap();
+        }
+    }
+}
+
+/// JSON members carrying the labels of the channel.
+pub fn json_labels() -> String {
+    format!(
+        "\"device_id\":\"{}\",\"channel\":{},\"phase\":\"{}\"",
+        *DEVICE_ID, CHANNEL, PHASE
+    )
+}
+
+/// Form fields carrying the labels of the channel.
+pub fn form_labels() -> String {
+    format!(
+        "device_id={}&channel={}&phase={}",
+        *DEVICE_ID, CHANNEL, PHASE
+    )
+}
@@ -0,0 +1,158 @@
+//! A copy of what the display shows, served at `/display`, so the screen can
+//! be checked when the device is installed out of sight. Graphic panels are
+//! served as a 1-bit BMP, which browsers show as is and takes no encoder;
+//! character displays as their lines of text.
+
+#[cfg(not(feature = "headless"))]
+use std::sync::Mutex;
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::EspError;
+
+use crate::auth;
+use crate::error;
+use crate::rate_limit;
+
+// Each build feeds the kind its display shows, see `display::AppDisplay`;
+// without one nothing is ever shown
+#[cfg(not(feature = "headless"))]
+enum Contents {
+    /// One bit per pixel, row after row, least significant bit first.
+    #[cfg(not(any(feature = "tm1637", feature = "hd44780")))]
+    Pixels {
+        width: u32,
+        height: u32,
+        bits: Vec<u8>,
+    },
+    #[cfg(any(feature = "tm1637", feature = "hd44780"))]
+    Text(Vec<String>),
+}
+
+/// What was last sent to the display, `None` until something was.
+#[cfg(not(feature = "headless"))]
+static CONTENTS: Mutex<Option<Contents>> = Mutex::new(None);
+
+/// Record the pixels a graphic panel shows.
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+pub fn show_pixels(width: u32, height: u32, bits: &[u8]) {
+    let mut contents = CONTENTS.lock().unwrap();
+    match &mut *contents {
+        // Reuse the buffer, this runs on every flush
+        Some(Contents::Pixels {
+            width: w,
+            height: h,
+            bits: b,
+        }) if *w == width && *h == height => b.copy_from_slice(bits),
+        _ => {
+            *contents = Some(Contents::Pixels {
+                width,
+                height,
+                bits: bits.to_vec(),
+            })
+        }
+    }
+}
+
+/// Record the lines a character display shows.
+#[cfg(all(
+    any(feature = "tm1637", feature = "hd44780"),
+    not(feature = "headless")
+))]
+pub fn show_text(lines: Vec<String>) {
+    *CONTENTS.lock().unwrap() = Some(Contents::Text(lines));
+}
+
+/// `bits` as a BMP with a black and white palette. BMP rows go bottom up,
+/// most significant bit first, padded to four bytes.
+#[cfg(not(any(feature = "headless", feature = "tm1637", feature = "hd44780")))]
+fn bmp(width: u32, height: u32, bits: &[u8]) -> Vec<u8> {
+    const HEADER_LEN: u32 = 14 + 40 + 8;
+    let row_len = (width + 31) / 32 * 4;
+    let image_len = row_len * height;
+    let mut out = Vec::with_capacity((HEADER_LEN + image_len) as usize);
+    // File header
+    out.extend_from_slice(b"BM");
+    out.extend_from_slice(&(HEADER_LEN + image_len).to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&HEADER_LEN.to_le_bytes());
+    // Info header
+    out.extend_from_slice(&40u32.to_le_bytes());
+    out.extend_from_slice(&(width as i32).to_le_bytes());
+    out.extend_from_slice(&(height as i32).to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&image_len.to_le_bytes());
+    out.extend_from_slice(&2835u32.to_le_bytes());
+    out.extend_from_slice(&2835u32.to_le_bytes());
+    out.extend_from_slice(&2u32.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    // Palette: off is black, on is white, as on the OLED
+    out.extend_from_slice(&[0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0]);
+    for y in (0..height).rev() {
+        let mut row = vec![0u8; row_len as usize];
+        for x in 0..width {
+            let index = (y * width + x) as usize;
+            if bits[index / 8] & 1 << (index % 8) != 0 {
+                row[x as usize / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        out.extend_from_slice(&row);
+    }
+    out
+}
+
+/// The content type and body of what the display shows.
+#[cfg(not(feature = "headless"))]
+fn contents() -> Option<(&'static str, Vec<u8>)> {
+    // Copy out rather than hold the lock while writing to the network
+    match &*CONTENTS.lock().unwrap() {
+        #[cfg(not(any(feature = "tm1637", feature = "hd44780")))]
+        Some(Contents::Pixels {
+            width,
+            height,
+            bits,
+        }) => Some(("image/bmp", bmp(*width, *height, bits))),
+        #[cfg(any(feature = "tm1637", feature = "hd44780"))]
+        Some(Contents::Text(lines)) => Some(("text/plain", (lines.join("\n") + "\n").into())),
+        None => None,
+    }
+}
+
+#[cfg(feature = "headless")]
+fn contents() -> Option<(&'static str, Vec<u8>)> {
+    None
+}
+
+/// `/display`, the display contents as `image/bmp` or `text/plain`. Only for
+/// the admin, since in setup mode the screen shows the admin password and
+/// the access point key.
+pub fn add_display_handler(server: &mut EspHttpServer<'_>) -> Result<(), EspError> {
+    server.fn_handler(
+        "/display",
+        esp_idf_svc::http::Method::Get,
+        |mut req| -> Result<(), EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            let Some((content_type, body)) = contents() else {
+                return error::respond(req, 404, "Nothing shown on a display");
+            };
+            req.into_response(
+                200,
+                Some("OK"),
+                &[
+                    ("Content-Type", content_type),
+                    ("Cache-Control", "no-store"),
+                ],
+            )?
+            .write(&body)?;
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
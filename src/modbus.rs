@@ -0,0 +1,262 @@
+//! A Modbus TCP server on port 502, so PLCs and SCADA systems can read the
+//! device like any other meter, without an HTTP adapter. Values of 32 bits
+//! take two registers, the high word first; floats are IEEE 754.
+//!
+//! Input registers (function 4), the latest reading:
+//! - 0-1: current, A (float)
+//! - 2-3: power, W (float)
+//! - 4-5: energy, kWh (float)
+//! - 6-7: Unix time of the reading, 0 until the clock is synced (unsigned)
+//!
+//! Holding registers (function 3), the configuration:
+//! - 0-1: voltage, V (float)
+//! - 2-3: calibration gain, the current transformer's scaling (float)
+//! - 4-5: calibration offset, A (float)
+//! - 6: seconds between reports (unsigned)
+//!
+//! The holding registers are read-only: the voltage is built in and the
+//! calibration has its own endpoints, as on `/api/config`, and Modbus has no
+//! way to check who is writing. Writes get an illegal function exception.
+//!
+//! The server runs on a thread of its own, so a slow client never holds up
+//! sampling. It is off unless turned on on the setup page.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::calibration::CALIBRATION;
+use crate::http_server::CURRENT_KNOWN_REPORT_INTERVAL;
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::template::Context;
+use crate::uptime::session_uptime_s;
+use crate::AC_VOLTS;
+
+/// `on` serves Modbus, anything else doesn't.
+pub const NVS_MODBUS: &str = "modbus";
+const PORT: u16 = 502;
+/// Keeps some sockets free for the web server.
+const MAX_CLIENTS: usize = 2;
+const POLL_EVERY: Duration = Duration::from_millis(20);
+const RETRY_EVERY: Duration = Duration::from_secs(30);
+/// Connections silent for longer are closed.
+const IDLE_TIMEOUT_S: u64 = 120;
+const STACK_SIZE: usize = 6 * 1024;
+
+/// Transaction and protocol identifiers, length and unit identifier.
+const MBAP_LEN: usize = 7;
+const MAX_PDU_LEN: usize = 253;
+/// Registers a read may ask for.
+const MAX_READ: usize = 125;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const EXCEPTION: u8 = 0x80;
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Clients connected, for `/api/v1/outputs`.
+pub(crate) static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+static LATEST: Mutex<Option<Context>> = Mutex::new(None);
+
+/// Whether `setting` can be saved.
+pub fn is_valid(setting: &str) -> bool {
+    ["", "on", "off"].contains(&setting)
+}
+
+/// Read whether to serve Modbus from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let enabled = read_str_from_nvs_or_default(nvs, NVS_MODBUS, "") == "on";
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Keep the latest reading for the input registers.
+pub fn publish(context: &Context) {
+    *LATEST.lock().unwrap() = Some(context.clone());
+}
+
+fn float(value: f32) -> [u16; 2] {
+    let bits = value.to_bits();
+    [(bits >> 16) as u16, bits as u16]
+}
+
+fn input_registers() -> Vec<u16> {
+    let latest = LATEST.lock().unwrap().clone().unwrap_or_default();
+    let timestamp = latest.timestamp as u32;
+    let mut registers = Vec::new();
+    registers.extend(float(latest.amps.0));
+    registers.extend(float(latest.watts.0));
+    registers.extend(float(latest.energy.kwh() as f32));
+    registers.extend([(timestamp >> 16) as u16, timestamp as u16]);
+    registers
+}
+
+fn holding_registers() -> Vec<u16> {
+    let (gain, offset) = {
+        let calibration = CALIBRATION.lock().unwrap();
+        (calibration.gain, calibration.offset)
+    };
+    let report_interval_s = *CURRENT_KNOWN_REPORT_INTERVAL.lock().unwrap();
+    let mut registers = Vec::new();
+    registers.extend(float(AC_VOLTS.0));
+    registers.extend(float(gain));
+    registers.extend(float(offset));
+    registers.push(report_interval_s.min(u16::MAX as u64) as u16);
+    registers
+}
+
+/// The response PDU to a request PDU, or the exception code.
+fn answer(pdu: &[u8]) -> Result<Vec<u8>, u8> {
+    let Some((&function, data)) = pdu.split_first() else {
+        return Err(ILLEGAL_FUNCTION);
+    };
+    let registers = match function {
+        READ_HOLDING_REGISTERS => holding_registers(),
+        READ_INPUT_REGISTERS => input_registers(),
+        _ => return Err(ILLEGAL_FUNCTION),
+    };
+    let [start_hi, start_lo, count_hi, count_lo] = data else {
+        return Err(ILLEGAL_DATA_VALUE);
+    };
+    let start = u16::from_be_bytes([*start_hi, *start_lo]) as usize;
+    let count = u16::from_be_bytes([*count_hi, *count_lo]) as usize;
+    if !(1..=MAX_READ).contains(&count) {
+        return Err(ILLEGAL_DATA_VALUE);
+    }
+    let Some(values) = registers.get(start..start + count) else {
+        return Err(ILLEGAL_DATA_ADDRESS);
+    };
+    let mut response = vec![function, (count * 2) as u8];
+    for value in values {
+        response.extend(value.to_be_bytes());
+    }
+    Ok(response)
+}
+
+/// The response to a whole request, header included.
+fn respond(request: &[u8]) -> Vec<u8> {
+    let (header, pdu) = request.split_at(MBAP_LEN);
+    let pdu = answer(pdu).unwrap_or_else(|code| vec![pdu[0] | EXCEPTION, code]);
+    // Same transaction, protocol and unit
+    let mut response = header[..4].to_vec();
+    response.extend((pdu.len() as u16 + 1).to_be_bytes());
+    response.push(header[6]);
+    response.extend(pdu);
+    response
+}
+
+struct Client {
+    stream: TcpStream,
+    /// Received bytes not yet making a whole request.
+    pending: Vec<u8>,
+    last_active_s: u64,
+}
+
+impl Client {
+    /// Answer the requests received. Returns whether to keep the
+    /// connection.
+    fn poll(&mut self) -> bool {
+        let now = session_uptime_s();
+        let mut chunk = [0u8; MBAP_LEN + MAX_PDU_LEN];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return false,
+                Ok(len) => {
+                    self.pending.extend_from_slice(&chunk[..len]);
+                    self.last_active_s = now;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return false,
+            }
+        }
+        while self.pending.len() >= MBAP_LEN {
+            let len = u16::from_be_bytes([self.pending[4], self.pending[5]]) as usize;
+            // Anything else isn't Modbus, so there's no telling where the
+            // next request starts
+            if self.pending[2..4] != [0, 0] || !(2..=MAX_PDU_LEN + 1).contains(&len) {
+                return false;
+            }
+            let request_len = MBAP_LEN - 1 + len;
+            if self.pending.len() < request_len {
+                break;
+            }
+            let request: Vec<u8> = self.pending.drain(..request_len).collect();
+            if self.stream.write_all(&respond(&request)).is_err() {
+                return false;
+            }
+        }
+        now < self.last_active_s + IDLE_TIMEOUT_S
+    }
+}
+
+fn bind() -> std::io::Result<TcpListener> {
+    let listener = TcpListener::bind(("0.0.0.0", PORT))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+fn serve_loop() {
+    let mut listener = None;
+    let mut clients: Vec<Client> = Vec::new();
+    loop {
+        if !is_enabled() {
+            if listener.take().is_some() {
+                log::info!("Modbus server stopped");
+            }
+            clients.clear();
+            CLIENTS.store(0, Ordering::Relaxed);
+            std::thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+        let Some(server) = listener.as_ref() else {
+            match bind() {
+                Ok(server) => {
+                    log::info!("Modbus server listening on port {}", PORT);
+                    listener = Some(server);
+                }
+                Err(err) => {
+                    log::warn!("Could not start the Modbus server: {:?}", err);
+                    std::thread::sleep(RETRY_EVERY);
+                }
+            }
+            continue;
+        };
+
+        match server.accept() {
+            Ok((stream, peer)) if clients.len() < MAX_CLIENTS => {
+                if stream.set_nonblocking(true).is_ok() {
+                    log::info!("Modbus client {} connected", peer);
+                    clients.push(Client {
+                        stream,
+                        pending: Vec::new(),
+                        last_active_s: session_uptime_s(),
+                    });
+                }
+            }
+            // Dropping the stream closes it
+            Ok((_, peer)) => log::warn!("Refusing Modbus client {}, too many", peer),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => log::warn!("Error accepting a Modbus client: {:?}", err),
+        }
+        clients.retain_mut(Client::poll);
+        CLIENTS.store(clients.len(), Ordering::Relaxed);
+        std::thread::sleep(POLL_EVERY);
+    }
+}
+
+/// Start the server thread. Call once; while Modbus is off it only sleeps.
+pub fn spawn() {
+    std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(serve_loop)
+        .expect("Failed to spawn the Modbus thread");
+}
@@ -0,0 +1,413 @@
+//! Readings published to an MQTT broker, next to or instead of the webhook.
+//! With a prefix of `garage`, the current, power and energy go to
+//! `garage/amps`, `garage/watts` and `garage/kwh` every interval, and
+//! `garage/status` holds `online`, or `offline` once the broker loses the
+//! device (the last will). In the SenML format the readings go to
+//! `garage/senml` instead, as one SenML pack, see `senml`.
+//!
+//! The Tasmota format publishes like a Tasmota power plug with the topic
+//! `garage`, so dashboards and Domoticz or Home Assistant setups made for
+//! those work unchanged: `tele/garage/SENSOR` carries the readings in
+//! Tasmota's `ENERGY` schema and `tele/garage/LWT` is `Online` or `Offline`.
+//! There is no `Yesterday`, since the device doesn't keep it.
+//!
+//! The broker, its credentials, the prefix and the interval are kept in NVS.
+//! An empty broker URL turns publishing off.
+//!
+//! An `mqtts://` or `wss://` broker is checked against the bundled public
+//! CAs, or the certificate installed at `/api/mqtt/ca`. A client certificate
+//! and key installed at `/api/mqtt/cert` and `/api/mqtt/key` are presented
+//! to brokers that authenticate devices by them, see `tls`.
+//!
+//! Setting a thing name switches to the AWS IoT Core profile: the thing name
+//! is the client ID, as AWS IoT policies expect, and the readings are also
+//! reported to the thing's shadow, or to one of its named shadows, so the
+//! current power can be read through the AWS API while the device is
+//! offline.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+
+use esp_idf_svc::hal;
+use esp_idf_svc::mqtt::client::{
+    EspMqttClient, EventPayload, LwtConfiguration, MqttClientConfiguration, QoS,
+};
+
+use crate::energy::ENERGY;
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::senml;
+use crate::template::{iso8601, Context};
+use crate::tls;
+use crate::uptime::{session_uptime_s, UPTIME};
+use crate::AC_VOLTS;
+
+pub const NVS_MQTT_URL: &str = "mqtt_url";
+pub const NVS_MQTT_USER: &str = "mqtt_user";
+pub const NVS_MQTT_PASSWORD: &str = "mqtt_pass";
+/// Empty uses the hostname.
+pub const NVS_MQTT_PREFIX: &str = "mqtt_prefix";
+pub const NVS_MQTT_INTERVAL: &str = "mqtt_interval";
+/// AWS IoT thing name, empty for a plain broker.
+pub const NVS_MQTT_THING: &str = "mqtt_thing";
+/// Named shadow of the thing, empty for its classic shadow.
+pub const NVS_MQTT_SHADOW: &str = "mqtt_shadow";
+/// Empty for `PayloadFormat::Topics`.
+pub const NVS_MQTT_FORMAT: &str = "mqtt_format";
+pub const DEFAULT_INTERVAL_S: u64 = 10;
+/// Longest prefix, leaving room for the topic names in MQTT's limits.
+const MAX_PREFIX_LEN: usize = 64;
+/// AWS IoT's limits.
+const MAX_THING_LEN: usize = 128;
+const MAX_SHADOW_LEN: usize = 64;
+
+// Creating the client only fails on bad settings or lack of memory, so
+// don't try again every loop iteration.
+const RETRY_EVERY_S: u64 = 60;
+
+/// Whether the client is connected to the broker, for `/api/v1/outputs`.
+pub(crate) static CONNECTED: AtomicBool = AtomicBool::new(false);
+/// Connections made to the broker, each to be announced as `online`.
+static CONNECTIONS: AtomicU32 = AtomicU32::new(0);
+
+/// How the readings are published.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PayloadFormat {
+    /// A topic per value, `amps`, `watts` and `kwh`.
+    #[default]
+    Topics,
+    /// A SenML pack on `senml`.
+    Senml,
+    /// As a Tasmota power plug.
+    Tasmota,
+}
+
+impl PayloadFormat {
+    pub const ALL: [PayloadFormat; 3] = [
+        PayloadFormat::Topics,
+        PayloadFormat::Senml,
+        PayloadFormat::Tasmota,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PayloadFormat::Topics => "topics",
+            PayloadFormat::Senml => "senml",
+            PayloadFormat::Tasmota => "tasmota",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PayloadFormat> {
+        PayloadFormat::ALL
+            .into_iter()
+            .find(|format| format.name() == name)
+    }
+}
+
+/// Whether `url` can be saved: empty, or an `mqtt://`, `mqtts://`, `ws://`
+/// or `wss://` URL.
+pub fn is_valid_url(url: &str) -> bool {
+    url.is_empty()
+        || ["mqtt://", "mqtts://", "ws://", "wss://"]
+            .iter()
+            .any(|scheme| url.len() > scheme.len() && url.starts_with(scheme))
+}
+
+/// Whether `prefix` can be saved: empty, or a topic without wildcards.
+pub fn is_valid_prefix(prefix: &str) -> bool {
+    prefix.len() <= MAX_PREFIX_LEN
+        && !prefix.starts_with('/')
+        && !prefix.ends_with('/')
+        && !prefix
+            .chars()
+            .any(|c| c == '+' || c == '#' || c.is_control())
+}
+
+/// Whether `name` can be saved as the thing or shadow name: empty, or the
+/// characters AWS IoT allows.
+fn is_valid_aws_name(name: &str, max_len: usize) -> bool {
+    name.len() <= max_len
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ":_-".contains(c))
+}
+
+pub fn is_valid_thing(thing: &str) -> bool {
+    is_valid_aws_name(thing, MAX_THING_LEN)
+}
+
+pub fn is_valid_shadow(shadow: &str) -> bool {
+    is_valid_aws_name(shadow, MAX_SHADOW_LEN)
+}
+
+/// Whether `interval` can be saved: empty for the default, or whole seconds.
+pub fn is_valid_interval(interval: &str) -> bool {
+    interval.is_empty() || interval.parse::<u64>().map_or(false, |seconds| seconds > 0)
+}
+
+/// Whether `format` can be saved: empty for the default, or one of
+/// `PayloadFormat`.
+pub fn is_valid_format(format: &str) -> bool {
+    format.is_empty() || PayloadFormat::from_name(format).is_some()
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MqttSettings {
+    pub url: String,
+    pub user: String,
+    pub password: String,
+    /// Empty uses the hostname.
+    pub prefix: String,
+    pub interval_s: u64,
+    /// AWS IoT thing name, empty for a plain broker.
+    pub thing: String,
+    /// Named shadow, empty for the classic one.
+    pub shadow: String,
+    pub format: PayloadFormat,
+}
+
+impl MqttSettings {
+    pub fn from_nvs(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> Self {
+        let url = read_str_from_nvs_or_default(nvs, NVS_MQTT_URL, "");
+        let prefix = read_str_from_nvs_or_default(nvs, NVS_MQTT_PREFIX, "");
+        let interval = read_str_from_nvs_or_default(nvs, NVS_MQTT_INTERVAL, "");
+        let thing = read_str_from_nvs_or_default(nvs, NVS_MQTT_THING, "");
+        let shadow = read_str_from_nvs_or_default(nvs, NVS_MQTT_SHADOW, "");
+        let format = read_str_from_nvs_or_default(nvs, NVS_MQTT_FORMAT, "");
+        MqttSettings {
+            url: if is_valid_url(&url) {
+                url
+            } else {
+                String::new()
+            },
+            user: read_str_from_nvs_or_default(nvs, NVS_MQTT_USER, ""),
+            password: read_str_from_nvs_or_default(nvs, NVS_MQTT_PASSWORD, ""),
+            prefix: if is_valid_prefix(&prefix) {
+                prefix
+            } else {
+                String::new()
+            },
+            interval_s: interval.parse().unwrap_or(DEFAULT_INTERVAL_S).max(1),
+            thing: if is_valid_thing(&thing) {
+                thing
+            } else {
+                String::new()
+            },
+            shadow: if is_valid_shadow(&shadow) {
+                shadow
+            } else {
+                String::new()
+            },
+            format: PayloadFormat::from_name(&format).unwrap_or_default(),
+        }
+    }
+
+    /// Topic to update the thing's shadow on, for the AWS IoT profile.
+    fn shadow_topic(&self) -> Option<String> {
+        match (self.thing.as_str(), self.shadow.as_str()) {
+            ("", _) => None,
+            (thing, "") => Some(format!("$aws/things/{}/shadow/update", thing)),
+            (thing, shadow) => Some(format!(
+                "$aws/things/{}/shadow/name/{}/update",
+                thing, shadow
+            )),
+        }
+    }
+}
+
+/// Publishes the readings every interval while the broker is reachable.
+pub struct MqttPublisher {
+    settings: MqttSettings,
+    /// The prefix in use, never empty.
+    prefix: String,
+    client: Option<EspMqttClient<'static>>,
+    /// Session uptime of the next client creation attempt.
+    next_attempt_s: u64,
+    next_publish_s: u64,
+    /// Connections already announced as `online`.
+    announced: u32,
+}
+
+impl MqttPublisher {
+    pub fn new(settings: MqttSettings, hostname: &str) -> Self {
+        // The client of the previous settings, if any, is gone
+        CONNECTED.store(false, Ordering::Relaxed);
+        let prefix = match (settings.prefix.as_str(), settings.thing.as_str()) {
+            ("", "") => hostname.to_string(),
+            ("", thing) => thing.to_string(),
+            (prefix, _) => prefix.to_string(),
+        };
+        MqttPublisher {
+            settings,
+            prefix,
+            client: None,
+            next_attempt_s: 0,
+            next_publish_s: 0,
+            announced: CONNECTIONS.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Call from the main loop while connected to Wi-Fi. The client
+    /// reconnects to the broker by itself and readings taken meanwhile are
+    /// not published.
+    pub fn tick(&mut self, context: &Context) {
+        let now = session_uptime_s();
+        if self.settings.url.is_empty() {
+            return;
+        }
+        if self.client.is_none() {
+            if now < self.next_attempt_s {
+                return;
+            }
+            match connect(&self.settings, &self.prefix) {
+                Ok(client) => {
+                    log::info!("MQTT client for {} started", self.settings.url);
+                    self.client = Some(client);
+                }
+                Err(err) => {
+                    log::warn!("Could not start the MQTT client: {:?}", err);
+                    self.next_attempt_s = now + RETRY_EVERY_S;
+                    return;
+                }
+            }
+        }
+        let Some(client) = self.client.as_mut() else {
+            return;
+        };
+        if !CONNECTED.load(Ordering::Relaxed) {
+            return;
+        }
+
+        // The broker published the last will if the connection dropped
+        let connections = CONNECTIONS.load(Ordering::Relaxed);
+        if self.announced != connections {
+            let (topic, online, _) = status_topic(self.settings.format, &self.prefix);
+            match client.enqueue(&topic, QoS::AtLeastOnce, true, online.as_bytes()) {
+                Ok(_) => self.announced = connections,
+                Err(err) => log::warn!("Could not publish to {}: {:?}", topic, err),
+            }
+        }
+
+        if now < self.next_publish_s {
+            return;
+        }
+        self.next_publish_s = now + self.settings.interval_s;
+        let topic = |name: &str| format!("{}/{}", self.prefix, name);
+        let messages = match self.settings.format {
+            PayloadFormat::Topics => vec![
+                (topic("amps"), format!("{:.3}", context.amps.0)),
+                (topic("watts"), format!("{:.1}", context.watts.0)),
+                (topic("kwh"), format!("{:.3}", context.energy.kwh())),
+            ],
+            PayloadFormat::Senml => {
+                vec![(topic("senml"), senml::pack(std::slice::from_ref(context)))]
+            }
+            PayloadFormat::Tasmota => vec![(
+                format!("tele/{}/SENSOR", self.prefix),
+                tasmota_sensor(context),
+            )],
+        };
+        for (topic, value) in messages {
+            if let Err(err) = client.enqueue(&topic, QoS::AtMostOnce, false, value.as_bytes()) {
+                log::warn!("Could not publish to {}: {:?}", topic, err);
+            }
+        }
+
+        if let Some(topic) = self.settings.shadow_topic() {
+            let document = format!(
+                "{{\"state\":{{\"reported\":{{\"amps\":{:.3},\"watts\":{:.1},\"kwh\":{:.3}}}}}}}",
+                context.amps.0,
+                context.watts.0,
+                context.energy.kwh()
+            );
+            if let Err(err) = client.enqueue(&topic, QoS::AtMostOnce, false, document.as_bytes()) {
+                log::warn!("Could not publish to {}: {:?}", topic, err);
+            }
+        }
+    }
+}
+
+/// The retained topic telling whether the device is connected, and what it
+/// holds while it is and once it isn't.
+fn status_topic(format: PayloadFormat, prefix: &str) -> (String, &'static str, &'static str) {
+    match format {
+        PayloadFormat::Tasmota => (format!("tele/{}/LWT", prefix), "Online", "Offline"),
+        _ => (format!("{}/status", prefix), "online", "offline"),
+    }
+}
+
+/// A `SENSOR` message in the schema of Tasmota's energy monitoring, e.g.
+/// `{"Time":"2024-06-10T06:13:20","ENERGY":{"Total":1234.567,"Today":3.210,
+/// "Power":283,...,"Current":1.230}}`. Tasmota's time has no zone; this one
+/// is UTC.
+fn tasmota_sensor(context: &Context) -> String {
+    let today = ENERGY.lock().unwrap().today_wh;
+    let installed_at = UPTIME.lock().unwrap().installed_at;
+    let start = if installed_at != 0 {
+        format!(
+            "\"TotalStartTime\":\"{}\",",
+            iso8601(installed_at).trim_end_matches('Z')
+        )
+    } else {
+        String::new()
+    };
+    // Power is worked out from the built-in voltage, so it is all active
+    format!(
+        "{{\"Time\":\"{}\",\"ENERGY\":{{{}\"Total\":{:.3},\"Today\":{:.3},\"Power\":{:.0},\"ApparentPower\":{:.0},\"ReactivePower\":0,\"Factor\":1.00,\"Voltage\":{:.0},\"Current\":{:.3}}}}}",
+        iso8601(context.timestamp).trim_end_matches('Z'),
+        start,
+        context.energy.kwh(),
+        today.kwh(),
+        context.watts.0,
+        context.watts.0,
+        AC_VOLTS.0,
+        context.amps.0
+    )
+}
+
+fn connect(settings: &MqttSettings, prefix: &str) -> anyhow::Result<EspMqttClient<'static>> {
+    let (status, _, offline) = status_topic(settings.format, prefix);
+    // AWS IoT policies usually only let a thing connect under its own name
+    let client_id = if settings.thing.is_empty() {
+        prefix.replace('/', "-")
+    } else {
+        settings.thing.clone()
+    };
+    let server_certificate = tls::MQTT_CA.x509();
+    let configuration = MqttClientConfiguration {
+        client_id: Some(&client_id),
+        username: (!settings.user.is_empty()).then_some(settings.user.as_str()),
+        password: (!settings.password.is_empty()).then_some(settings.password.as_str()),
+        keep_alive_interval: Some(Duration::from_secs(30)),
+        lwt: Some(LwtConfiguration {
+            topic: &status,
+            payload: offline.as_bytes(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }),
+        // The bundled CAs unless a certificate was installed for the broker
+        crt_bundle_attach: server_certificate
+            .is_none()
+            .then_some(hal::sys::esp_crt_bundle_attach),
+        server_certificate,
+        client_certificate: tls::MQTT_CERT.x509(),
+        private_key: tls::MQTT_KEY.x509(),
+        ..Default::default()
+    };
+    let client = EspMqttClient::new_cb(&settings.url, &configuration, |event| {
+        match event.payload() {
+            EventPayload::Connected(_) => {
+                log::info!("Connected to the MQTT broker");
+                CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+                CONNECTED.store(true, Ordering::Relaxed);
+            }
+            EventPayload::Disconnected => {
+                log::warn!("Disconnected from the MQTT broker");
+                CONNECTED.store(false, Ordering::Relaxed);
+            }
+            EventPayload::Error(err) => log::warn!("MQTT error: {:?}", err),
+            _ => (),
+        }
+    })?;
+    Ok(client)
+}
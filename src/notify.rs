@@ -0,0 +1,318 @@
+//! Push notifications to a phone through ntfy, Pushover or Telegram, for the
+//! energy budget alerts and for Wi-Fi coming back after being down a long
+//! time, such as after a restart that took the device offline.
+//!
+//! ntfy gets the message posted to the topic URL, e.g.
+//! `https://ntfy.sh/my-wattometer`, which also works with a self-hosted
+//! server. Pushover needs the token of an application and the user key,
+//! Telegram a bot, see `telegram`. Any of them may be set up on the setup
+//! page.
+//!
+//! Notifications are sent on a thread of their own. Those that can't be sent
+//! wait and are tried again every `RETRY_EVERY_S`, so an alert raised while
+//! offline arrives once Wi-Fi is back.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use embedded_svc::io::Write as _;
+use esp_idf_svc::{hal, http};
+
+use crate::form::percent_encode;
+use crate::http_server::CURRENT_KNOWN_HOSTNAME;
+use crate::labels;
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::telegram;
+use crate::uptime::{reset_reason, session_uptime_s};
+
+/// Topic URL on an ntfy server.
+pub const NVS_NTFY_URL: &str = "ntfy_url";
+pub const NVS_PUSHOVER_TOKEN: &str = "pushover_token";
+pub const NVS_PUSHOVER_USER: &str = "pushover_user";
+const PUSHOVER_URL: &str = "https://api.pushover.net/1/messages.json";
+/// Pushover's tokens and user keys are this many letters and digits.
+const PUSHOVER_KEY_LEN: usize = 30;
+/// Being offline at least this long is worth a notification once back.
+pub const OFFLINE_ALERT_S: u64 = 10 * 60;
+
+/// Notifications waiting to be sent. The oldest are dropped beyond this.
+const QUEUE_LEN: usize = 8;
+const IDLE_WAKEUP: Duration = Duration::from_secs(1);
+const RETRY_EVERY_S: u64 = 60;
+/// TLS needs the room.
+const STACK_SIZE: usize = 10 * 1024;
+
+/// Notifications sent since boot, for `/api/v1/outputs`.
+pub(crate) static SENT: AtomicU32 = AtomicU32::new(0);
+static SETTINGS: Mutex<NotifySettings> = Mutex::new(NotifySettings {
+    ntfy_url: String::new(),
+    pushover_token: String::new(),
+    pushover_user: String::new(),
+});
+/// The main loop's end of the channel, once the thread is running.
+static SENDER: Mutex<Option<SyncSender<Notification>>> = Mutex::new(None);
+
+#[derive(Clone, Debug, Default)]
+struct NotifySettings {
+    ntfy_url: String,
+    pushover_token: String,
+    pushover_user: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Notification {
+    pub message: String,
+    /// Shown prominently, e.g. an exceeded budget rather than a warning.
+    pub urgent: bool,
+}
+
+/// Whether `url` can be saved: empty, or an HTTP(S) URL with a topic.
+pub fn is_valid_ntfy_url(url: &str) -> bool {
+    if url.is_empty() {
+        return true;
+    }
+    let Some(rest) = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+    else {
+        return false;
+    };
+    url.len() <= 127
+        && !url.chars().any(|c| c.is_whitespace() || c.is_control())
+        && rest
+            .split_once('/')
+            .is_some_and(|(host, topic)| !host.is_empty() && !topic.is_empty())
+}
+
+/// Whether a Pushover token or user key can be saved.
+pub fn is_valid_pushover_key(key: &str) -> bool {
+    key.is_empty()
+        || (key.len() == PUSHOVER_KEY_LEN && key.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// Read where to send notifications from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let ntfy_url = read_str_from_nvs_or_default(nvs, NVS_NTFY_URL, "");
+    let pushover_token = read_str_from_nvs_or_default(nvs, NVS_PUSHOVER_TOKEN, "");
+    let pushover_user = read_str_from_nvs_or_default(nvs, NVS_PUSHOVER_USER, "");
+    let pushover_valid =
+        is_valid_pushover_key(&pushover_token) && is_valid_pushover_key(&pushover_user);
+    *SETTINGS.lock().unwrap() = NotifySettings {
+        ntfy_url: if is_valid_ntfy_url(&ntfy_url) {
+            ntfy_url
+        } else {
+            String::new()
+        },
+        pushover_token: if pushover_valid {
+            pushover_token
+        } else {
+            String::new()
+        },
+        pushover_user: if pushover_valid {
+            pushover_user
+        } else {
+            String::new()
+        },
+    };
+}
+
+impl NotifySettings {
+    fn has_pushover(&self) -> bool {
+        !self.pushover_token.is_empty() && !self.pushover_user.is_empty()
+    }
+}
+
+/// Whether notifications go anywhere.
+pub fn is_configured() -> bool {
+    let settings = SETTINGS.lock().unwrap();
+    !settings.ntfy_url.is_empty() || settings.has_pushover() || telegram::is_configured()
+}
+
+/// The ntfy topic URL, for the setup page.
+pub fn ntfy_url() -> String {
+    SETTINGS.lock().unwrap().ntfy_url.clone()
+}
+
+/// The Pushover user key, for the setup page. The token is never shown.
+pub fn pushover_user() -> String {
+    SETTINGS.lock().unwrap().pushover_user.clone()
+}
+
+/// Queue a notification. Dropped when none are set up.
+pub fn push(message: String, urgent: bool) {
+    if !is_configured() {
+        return;
+    }
+    let Some(sender) = SENDER.lock().unwrap().clone() else {
+        return;
+    };
+    match sender.try_send(Notification { message, urgent }) {
+        Ok(()) => (),
+        Err(TrySendError::Full(_)) => log::warn!("Notification thread behind, dropping one"),
+        Err(TrySendError::Disconnected(_)) => log::error!("Notification thread is gone"),
+    }
+}
+
+/// Start sending notifications. Call once.
+pub fn spawn() {
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+    std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || send_loop(receiver))
+        .expect("Failed to spawn the notification thread");
+    *SENDER.lock().unwrap() = Some(sender);
+}
+
+fn send_loop(receiver: Receiver<Notification>) {
+    let mut pending = VecDeque::new();
+    let mut next_attempt_s = 0;
+    loop {
+        match receiver.recv_timeout(IDLE_WAKEUP) {
+            Ok(notification) => {
+                if pending.len() >= QUEUE_LEN {
+                    log::warn!("Too many notifications waiting, dropping the oldest");
+                    pending.pop_front();
+                }
+                pending.push_back(notification);
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        let now = session_uptime_s();
+        if now < next_attempt_s {
+            continue;
+        }
+        while let Some(notification) = pending.front() {
+            if let Err(err) = send(notification) {
+                log::warn!("Could not send a notification: {:?}", err);
+                next_attempt_s = now + RETRY_EVERY_S;
+                break;
+            }
+            SENT.fetch_add(1, Ordering::Relaxed);
+            pending.pop_front();
+        }
+    }
+}
+
+/// The device name if it can go in a header, else the hostname.
+fn title() -> String {
+    let name = labels::device_name();
+    if !name.is_empty() && name.is_ascii() {
+        name
+    } else {
+        CURRENT_KNOWN_HOSTNAME.lock().unwrap().clone()
+    }
+}
+
+/// Send a notification to every service set up. When one fails the
+/// notification is sent again to all of them.
+fn send(notification: &Notification) -> anyhow::Result<()> {
+    let settings = SETTINGS.lock().unwrap().clone();
+    let title = title();
+    log::info!("Sending notification: {}", notification.message);
+    if !settings.ntfy_url.is_empty() {
+        let priority = if notification.urgent {
+            "high"
+        } else {
+            "default"
+        };
+        post(
+            &settings.ntfy_url,
+            &[
+                ("Content-Type", "text/plain"),
+                ("Title", title.as_str()),
+                ("Priority", priority),
+                ("Tags", "zap"),
+            ],
+            &notification.message,
+        )?;
+    }
+    if settings.has_pushover() {
+        let body = format!(
+            "token={}&user={}&title={}&message={}&priority={}",
+            settings.pushover_token,
+            settings.pushover_user,
+            percent_encode(&title),
+            percent_encode(&notification.message),
+            if notification.urgent { 1 } else { 0 }
+        );
+        post(
+            PUSHOVER_URL,
+            &[("Content-Type", "application/x-www-form-urlencoded")],
+            &body,
+        )?;
+    }
+    if telegram::is_configured() {
+        telegram::send_message(&format!("{}: {}", title, notification.message))?;
+    }
+    Ok(())
+}
+
+fn post(url: &str, headers: &[(&str, &str)], body: &str) -> anyhow::Result<()> {
+    let httpconnection = http::client::EspHttpConnection::new(&http::client::Configuration {
+        use_global_ca_store: true,
+        crt_bundle_attach: Some(hal::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    let mut client = embedded_svc::http::client::Client::wrap(httpconnection);
+    let content_length = body.len().to_string();
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Length", content_length.as_str()));
+    let mut request = client.post(url, &headers)?;
+    request.write_all(body.as_bytes())?;
+    let response = request.submit()?;
+    if !(200..300).contains(&response.status()) {
+        anyhow::bail!("HTTP status {}", response.status());
+    }
+    Ok(())
+}
+
+/// Watches the Wi-Fi connection for outages worth a notification. The time
+/// from boot until the first connection counts as one.
+#[derive(Clone, Copy, Debug)]
+pub struct OfflineWatch {
+    /// Session uptime when the connection was lost, 0 since boot, or
+    /// `None` while connected.
+    offline_since_s: Option<u64>,
+    connected_once: bool,
+}
+
+impl OfflineWatch {
+    pub fn new() -> Self {
+        OfflineWatch {
+            offline_since_s: Some(0),
+            connected_once: false,
+        }
+    }
+
+    /// Call every loop with whether Wi-Fi is up.
+    pub fn update(&mut self, online: bool, now_s: u64) {
+        if !online {
+            self.offline_since_s.get_or_insert(now_s);
+            return;
+        }
+        let Some(since) = self.offline_since_s.take() else {
+            return;
+        };
+        let offline_s = now_s.saturating_sub(since);
+        let after_boot = !self.connected_once;
+        self.connected_once = true;
+        if offline_s < OFFLINE_ALERT_S {
+            return;
+        }
+        let message = if after_boot {
+            format!(
+                "Online {} min after restarting ({})",
+                offline_s / 60,
+                reset_reason()
+            )
+        } else {
+            format!("Back online after {} min offline", offline_s / 60)
+        };
+        push(message, false);
+    }
+}
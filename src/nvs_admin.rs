@@ -0,0 +1,353 @@
+//! `/admin/nvs`, every key kept in the "ssaa" NVS namespace with its type and
+//! value, for troubleshooting without reflashing. Single keys can be changed
+//! or deleted; passwords are never shown, only replaced.
+
+use std::ffi::CStr;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::{
+    esp, nvs_entry_find, nvs_entry_info, nvs_entry_info_t, nvs_entry_next, nvs_iterator_t,
+    nvs_release_iterator, nvs_type_t, nvs_type_t_NVS_TYPE_ANY, nvs_type_t_NVS_TYPE_BLOB,
+    nvs_type_t_NVS_TYPE_I16, nvs_type_t_NVS_TYPE_I32, nvs_type_t_NVS_TYPE_I64,
+    nvs_type_t_NVS_TYPE_I8, nvs_type_t_NVS_TYPE_STR, nvs_type_t_NVS_TYPE_U16,
+    nvs_type_t_NVS_TYPE_U32, nvs_type_t_NVS_TYPE_U64, nvs_type_t_NVS_TYPE_U8, EspError,
+    ESP_ERR_NVS_NOT_FOUND, ESP_OK,
+};
+
+use crate::assets;
+use crate::auth::{self, NVS_ADMIN_PASSWORD};
+use crate::error;
+use crate::form::{self, MAX_FORM_LEN};
+use crate::headers::NVS_HEADERS;
+use crate::http_server::html_escape;
+use crate::mqtt::NVS_MQTT_PASSWORD;
+use crate::notify::NVS_PUSHOVER_TOKEN;
+use crate::nvs;
+use crate::rate_limit;
+use crate::restart;
+use crate::settings;
+use crate::telegram::NVS_TELEGRAM_TOKEN;
+use crate::tls::NVS_MQTT_KEY;
+use crate::wifi::enterprise::NVS_EAP_PASSWORD;
+use crate::wifi::networks::NVS_PSKS;
+use crate::wifi::setup_ap::NVS_AP_PSK;
+
+const PARTITION: &[u8] = b"nvs\0";
+const NAMESPACE: &[u8] = b"ssaa\0";
+/// Shown masked, and only replaced by a non-empty value.
+const SECRET_KEYS: &[&str] = &[
+    "wifi_psk",
+    NVS_PSKS[0],
+    NVS_PSKS[1],
+    NVS_PSKS[2],
+    NVS_EAP_PASSWORD,
+    NVS_AP_PSK,
+    NVS_ADMIN_PASSWORD,
+    NVS_MQTT_PASSWORD,
+    NVS_MQTT_KEY,
+    NVS_PUSHOVER_TOKEN,
+    NVS_TELEGRAM_TOKEN,
+    NVS_HEADERS[0],
+    NVS_HEADERS[1],
+    NVS_HEADERS[2],
+    NVS_HEADERS[3],
+];
+/// Leading bytes of a blob shown in hex.
+const BLOB_PREVIEW_LEN: usize = 32;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    Str,
+    Blob,
+}
+
+impl Kind {
+    fn from_raw(kind: nvs_type_t) -> Option<Kind> {
+        Some(match kind {
+            nvs_type_t_NVS_TYPE_U8 => Kind::U8,
+            nvs_type_t_NVS_TYPE_I8 => Kind::I8,
+            nvs_type_t_NVS_TYPE_U16 => Kind::U16,
+            nvs_type_t_NVS_TYPE_I16 => Kind::I16,
+            nvs_type_t_NVS_TYPE_U32 => Kind::U32,
+            nvs_type_t_NVS_TYPE_I32 => Kind::I32,
+            nvs_type_t_NVS_TYPE_U64 => Kind::U64,
+            nvs_type_t_NVS_TYPE_I64 => Kind::I64,
+            nvs_type_t_NVS_TYPE_STR => Kind::Str,
+            nvs_type_t_NVS_TYPE_BLOB => Kind::Blob,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::U8 => "u8",
+            Kind::I8 => "i8",
+            Kind::U16 => "u16",
+            Kind::I16 => "i16",
+            Kind::U32 => "u32",
+            Kind::I32 => "i32",
+            Kind::U64 => "u64",
+            Kind::I64 => "i64",
+            Kind::Str => "string",
+            Kind::Blob => "blob",
+        }
+    }
+}
+
+/// Keys in the namespace, in storage order. `EspNvs` can't list them, so
+/// this walks the partition with an iterator of its own.
+fn keys() -> Result<Vec<(String, Kind)>, EspError> {
+    let mut keys = Vec::new();
+    let mut iterator: nvs_iterator_t = std::ptr::null_mut();
+    let mut result = unsafe {
+        nvs_entry_find(
+            PARTITION.as_ptr() as *const _,
+            NAMESPACE.as_ptr() as *const _,
+            nvs_type_t_NVS_TYPE_ANY,
+            &mut iterator as *mut _,
+        )
+    };
+    while result == ESP_OK {
+        let mut info: nvs_entry_info_t = unsafe { core::mem::zeroed() };
+        result = unsafe { nvs_entry_info(iterator, &mut info as *mut _) };
+        if result != ESP_OK {
+            break;
+        }
+        let key = unsafe { CStr::from_ptr(info.key.as_ptr()) };
+        if let Some(kind) = Kind::from_raw(info.type_) {
+            keys.push((key.to_string_lossy().into_owned(), kind));
+        }
+        result = unsafe { nvs_entry_next(&mut iterator as *mut _) };
+    }
+    // Safe on the null iterator left behind by the last entry
+    unsafe { nvs_release_iterator(iterator) };
+    if result == ESP_ERR_NVS_NOT_FOUND {
+        Ok(keys)
+    } else {
+        esp!(result).map(|()| keys)
+    }
+}
+
+fn kind_of(key: &str) -> Result<Option<Kind>, EspError> {
+    Ok(keys()?
+        .into_iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, kind)| kind))
+}
+
+/// `key` as text, `None` if it went away.
+fn read(
+    nvs: &nvs::EspNvs<nvs::NvsDefault>,
+    key: &str,
+    kind: Kind,
+) -> Result<Option<String>, EspError> {
+    Ok(match kind {
+        Kind::U8 => nvs.get_u8(key)?.map(|v| v.to_string()),
+        Kind::I8 => nvs.get_i8(key)?.map(|v| v.to_string()),
+        Kind::U16 => nvs.get_u16(key)?.map(|v| v.to_string()),
+        Kind::I16 => nvs.get_i16(key)?.map(|v| v.to_string()),
+        Kind::U32 => nvs.get_u32(key)?.map(|v| v.to_string()),
+        Kind::I32 => nvs.get_i32(key)?.map(|v| v.to_string()),
+        Kind::U64 => nvs.get_u64(key)?.map(|v| v.to_string()),
+        Kind::I64 => nvs.get_i64(key)?.map(|v| v.to_string()),
+        Kind::Str => {
+            let Some(len) = nvs.str_len(key)? else {
+                return Ok(None);
+            };
+            let mut buf = vec![0; len];
+            nvs.get_str(key, &mut buf)?.map(str::to_string)
+        }
+        Kind::Blob => {
+            let Some(len) = nvs.blob_len(key)? else {
+                return Ok(None);
+            };
+            let mut buf = vec![0; len];
+            nvs.get_blob(key, &mut buf)?.map(|blob| {
+                let mut text = format!("{} bytes:", blob.len());
+                for byte in blob.iter().take(BLOB_PREVIEW_LEN) {
+                    write!(text, " {:02x}", byte).unwrap();
+                }
+                if blob.len() > BLOB_PREVIEW_LEN {
+                    text.push_str(" ...");
+                }
+                text
+            })
+        }
+    })
+}
+
+/// Store `value` under `key` as `kind`, failing with a message for the page.
+fn write(
+    nvs: &mut nvs::EspNvs<nvs::NvsDefault>,
+    key: &str,
+    kind: Kind,
+    value: &str,
+) -> Result<(), String> {
+    let invalid = |_| format!("\"{}\" is not a valid {}", value, kind.name());
+    let result = match kind {
+        Kind::U8 => nvs.set_u8(key, value.parse().map_err(invalid)?),
+        Kind::I8 => nvs.set_i8(key, value.parse().map_err(invalid)?),
+        Kind::U16 => nvs.set_u16(key, value.parse().map_err(invalid)?),
+        Kind::I16 => nvs.set_i16(key, value.parse().map_err(invalid)?),
+        Kind::U32 => nvs.set_u32(key, value.parse().map_err(invalid)?),
+        Kind::I32 => nvs.set_i32(key, value.parse().map_err(invalid)?),
+        Kind::U64 => nvs.set_u64(key, value.parse().map_err(invalid)?),
+        Kind::I64 => nvs.set_i64(key, value.parse().map_err(invalid)?),
+        Kind::Str => nvs.set_str(key, value),
+        Kind::Blob => return Err("Blobs can only be deleted".to_string()),
+    };
+    result.map_err(|err| format!("Could not write {}: {:?}", key, err))
+}
+
+fn page(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> Result<String, EspError> {
+    let mut rows = String::new();
+    for (key, kind) in keys()? {
+        let secret = SECRET_KEYS.contains(&key.as_str());
+        let value = if secret {
+            String::new()
+        } else {
+            read(nvs, &key, kind)?.unwrap_or_default()
+        };
+        let key = html_escape(&key);
+        let input = match kind {
+            Kind::Blob => html_escape(&value),
+            _ if secret => format!(
+                "<input type=\"password\" name=\"value\" placeholder=\"Unchanged\" form=\"{}\">",
+                key
+            ),
+            _ => format!(
+                "<input type=\"text\" name=\"value\" value=\"{}\" form=\"{}\">",
+                html_escape(&value),
+                key
+            ),
+        };
+        let save = if kind == Kind::Blob {
+            ""
+        } else {
+            "<button name=\"action\" value=\"save\">Save</button>"
+        };
+        write!(
+            rows,
+            "<tr><td>{key}</td><td>{}</td><td>{input}</td><td>\
+             <form id=\"{key}\" action=\"/admin/nvs\" method=\"post\">\
+             <input type=\"hidden\" name=\"key\" value=\"{key}\">{save}\
+             <button name=\"action\" value=\"delete\" onclick=\"return confirm('Delete {key}?')\">Delete</button>\
+             </form></td></tr>",
+            kind.name(),
+        )
+        .unwrap();
+    }
+    Ok(format!(
+        "<!DOCTYPE html>
+<html><head><title>Coarse watt-o-meter: NVS</title>
+<link rel=\"stylesheet\" href=\"{}\"></head>
+<body><h1>NVS namespace \"ssaa\"</h1>
+<table><tr><th>Key</th><th>Type</th><th>Value</th><th></th></tr>{}</table>
+<p>Most settings are only read at boot.</p>
+<form action=\"/restart\" method=\"post\"><input type=\"hidden\" name=\"token\" value=\"{}\">
+<input type=\"submit\" value=\"Restart now\"></form>
+</body></html>",
+        assets::url("style.css"),
+        rows,
+        restart::CONFIRMATION.issue(),
+    ))
+}
+
+/// Carry out a POST to `/admin/nvs`, failing with a message for the page.
+fn apply(
+    nvs: &mut nvs::EspNvs<nvs::NvsDefault>,
+    fields: &[(String, String)],
+) -> Result<(), String> {
+    let key = form::field(fields, "key").unwrap_or_default();
+    let kind = kind_of(key)
+        .map_err(|err| format!("Could not list the keys: {:?}", err))?
+        .ok_or_else(|| format!("No key named \"{}\"", key))?;
+    match form::field(fields, "action") {
+        Some("delete") => {
+            nvs.remove(key)
+                .map_err(|err| format!("Could not delete {}: {:?}", key, err))?;
+            log::warn!("Deleted {} from NVS", key);
+        }
+        Some("save") => {
+            let value = form::field(fields, "value").unwrap_or_default();
+            if SECRET_KEYS.contains(&key) && value.is_empty() {
+                return Ok(());
+            }
+            write(nvs, key, kind, value)?;
+            log::warn!("Changed {} in NVS", key);
+            if key == NVS_ADMIN_PASSWORD {
+                auth::forget_generated(nvs)
+                    .map_err(|err| format!("Could not forget the generated password: {:?}", err))?;
+            }
+        }
+        _ => return Err("Unknown action".to_string()),
+    }
+    // Settings the main loop reads while running apply right away
+    settings::mark_changed();
+    Ok(())
+}
+
+/// `GET /admin/nvs` lists the keys; `POST /admin/nvs` with `key` and
+/// `action=save` (with `value`) or `action=delete` changes one and goes back
+/// to the list.
+pub fn add_nvs_admin_handlers(
+    nvs: &nvs::EspNvsPartition<nvs::NvsDefault>,
+    server: &mut EspHttpServer<'_>,
+) -> Result<(), EspError> {
+    let nvs = Arc::new(Mutex::new(nvs::EspNvs::new(nvs.clone(), "ssaa", true)?));
+
+    let page_nvs = nvs.clone();
+    server.fn_handler(
+        "/admin/nvs",
+        esp_idf_svc::http::Method::Get,
+        move |mut req| -> Result<(), EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            let body = match page(&page_nvs.lock().unwrap()) {
+                Ok(body) => body,
+                Err(err) => return error::internal(req, err),
+            };
+            req.into_response(200, Some("OK"), &[("Content-Type", "text/html")])?
+                .write(body.as_bytes())?;
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/admin/nvs",
+        esp_idf_svc::http::Method::Post,
+        move |mut req| -> Result<(), EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            let Some(body) = form::read_body(&mut req, MAX_FORM_LEN)? else {
+                return error::respond(req, 413, "Form too large");
+            };
+            let fields = form::parse(&body);
+            if let Err(message) = apply(&mut nvs.lock().unwrap(), &fields) {
+                return error::respond(req, 400, &message);
+            }
+            req.into_response(303, Some("See Other"), &[("Location", "/admin/nvs")])?;
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
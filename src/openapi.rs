@@ -0,0 +1,464 @@
+//! `/api/openapi.json`, an OpenAPI 3 description of the HTTP endpoints built
+//! from the route table in `http_server`, so integrators can generate
+//! clients instead of reading the README.
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::EspError;
+use serde_json::{json, Map, Value};
+
+use crate::FIRMWARE_VERSION;
+
+/// A query parameter or form field.
+pub struct Param {
+    pub name: &'static str,
+    /// An OpenAPI type: `string`, `number` or `integer`.
+    pub kind: &'static str,
+    pub description: &'static str,
+    pub required: bool,
+}
+
+/// What an endpoint takes and answers, for the description only; the
+/// handlers are registered on their own.
+pub struct Route {
+    pub path: &'static str,
+    /// Lowercase HTTP method, e.g. `get`.
+    pub method: &'static str,
+    pub summary: &'static str,
+    /// Needs the admin credentials.
+    pub auth: bool,
+    pub query: &'static [Param],
+    /// Fields of an `application/x-www-form-urlencoded` body.
+    pub form: &'static [Param],
+    /// Content type of a raw request body, e.g. a firmware image.
+    pub body: Option<&'static str>,
+    /// Name of the request body schema, binary data if `None`.
+    pub body_schema: Option<&'static str>,
+    /// Content type of a successful response.
+    pub response: &'static str,
+    /// Name of the response schema under `components/schemas`.
+    pub schema: Option<&'static str>,
+}
+
+impl Param {
+    pub const fn required(
+        name: &'static str,
+        kind: &'static str,
+        description: &'static str,
+    ) -> Self {
+        Param {
+            name,
+            kind,
+            description,
+            required: true,
+        }
+    }
+
+    pub const fn optional(
+        name: &'static str,
+        kind: &'static str,
+        description: &'static str,
+    ) -> Self {
+        Param {
+            name,
+            kind,
+            description,
+            required: false,
+        }
+    }
+}
+
+impl Route {
+    pub const fn new(
+        method: &'static str,
+        path: &'static str,
+        summary: &'static str,
+        response: &'static str,
+    ) -> Self {
+        Route {
+            path,
+            method,
+            summary,
+            auth: false,
+            query: &[],
+            form: &[],
+            body: None,
+            body_schema: None,
+            response,
+            schema: None,
+        }
+    }
+
+    pub const fn auth(self) -> Self {
+        Route { auth: true, ..self }
+    }
+
+    pub const fn query(self, query: &'static [Param]) -> Self {
+        Route { query, ..self }
+    }
+
+    pub const fn form(self, form: &'static [Param]) -> Self {
+        Route { form, ..self }
+    }
+
+    pub const fn body(self, content_type: &'static str, schema: Option<&'static str>) -> Self {
+        Route {
+            body: Some(content_type),
+            body_schema: schema,
+            ..self
+        }
+    }
+
+    pub const fn schema(self, schema: &'static str) -> Self {
+        Route {
+            schema: Some(schema),
+            ..self
+        }
+    }
+}
+
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// Every failure answers with an `Error`, see `error`.
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": reference("Error") } }
+    })
+}
+
+fn params(params: &[Param]) -> Value {
+    let properties: Map<String, Value> = params
+        .iter()
+        .map(|param| {
+            let schema = json!({ "type": param.kind, "description": param.description });
+            (param.name.to_string(), schema)
+        })
+        .collect();
+    let required: Vec<&str> = params
+        .iter()
+        .filter(|param| param.required)
+        .map(|param| param.name)
+        .collect();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+fn operation(route: &Route) -> Value {
+    let mut operation = json!({ "summary": route.summary });
+    if !route.query.is_empty() {
+        operation["parameters"] = route
+            .query
+            .iter()
+            .map(|param| {
+                json!({
+                    "name": param.name,
+                    "in": "query",
+                    "required": param.required,
+                    "description": param.description,
+                    "schema": { "type": param.kind },
+                })
+            })
+            .collect();
+    }
+    if !route.form.is_empty() {
+        operation["requestBody"] = json!({
+            "content": { "application/x-www-form-urlencoded": { "schema": params(route.form) } }
+        });
+    } else if let Some(body) = route.body {
+        let schema = match route.body_schema {
+            Some(name) => reference(name),
+            None => json!({ "type": "string", "format": "binary" }),
+        };
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": { body: { "schema": schema } }
+        });
+    }
+    let schema = match route.schema {
+        Some(name) => reference(name),
+        None => json!({ "type": "string" }),
+    };
+    operation["responses"] = json!({
+        "200": { "description": "OK", "content": { route.response: { "schema": schema } } }
+    });
+    if route.auth {
+        operation["security"] = json!([{ "basic": [] }]);
+        operation["responses"]["401"] = error_response("Missing or wrong credentials");
+        operation["responses"]["429"] = error_response("Too many requests, see Retry-After");
+    }
+    operation["responses"]["default"] = error_response("Error");
+    operation
+}
+
+fn schemas() -> Value {
+    let nullable_number = json!({ "type": "number", "nullable": true });
+    json!({
+        "Status": {
+            "type": "object",
+            "properties": {
+                "amps": nullable_number,
+                "watts": nullable_number,
+                "energy_kwh": { "type": "number" },
+                "uptime_s": { "type": "integer" },
+                "rssi_dbm": { "type": "integer", "nullable": true },
+                "firmware": { "type": "string" },
+                "hostname": { "type": "string" },
+                "device_name": { "type": "string" },
+                "channel_label": { "type": "string" },
+                "setup_mode": { "type": "boolean" },
+            },
+        },
+        "Health": {
+            "type": "object",
+            "properties": {
+                "status": { "type": "string", "enum": ["ok", "degraded"] },
+                "free_heap": { "type": "integer" },
+                "min_free_heap": { "type": "integer" },
+                "uptime_s": { "type": "integer" },
+                "reset_reason": { "type": "string" },
+                "rssi_dbm": { "type": "integer", "nullable": true },
+                "wifi_reconnects": { "type": "integer" },
+                "webhook": {
+                    "type": "object",
+                    "properties": {
+                        "successes": { "type": "integer" },
+                        "failures": { "type": "integer" },
+                        "consecutive_failures": { "type": "integer" },
+                        "last_success": { "type": "integer", "nullable": true },
+                        "last_error": { "type": "string", "nullable": true },
+                        "last_status": { "type": "integer", "nullable": true },
+                    },
+                },
+            },
+        },
+        "History": {
+            "type": "array",
+            "items": {
+                "type": "object",
+                "properties": {
+                    "uptime_s": { "type": "integer" },
+                    "unix_time": { "type": "integer" },
+                    "amps": { "type": "number" },
+                    "watts": { "type": "number" },
+                },
+            },
+        },
+        "Dashboard": {
+            "type": "object",
+            "properties": {
+                "circuits": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "url": { "type": "string", "nullable": true },
+                            "amps": nullable_number,
+                            "watts": nullable_number,
+                            "energy_kwh": { "type": "number" },
+                            "hostname": { "type": "string" },
+                            "device_name": { "type": "string" },
+                            "channel_label": { "type": "string" },
+                            "online": { "type": "boolean" },
+                            "age_s": { "type": "integer" },
+                            "error": { "type": "string", "nullable": true },
+                        },
+                    },
+                },
+                "total_watts": { "type": "number" },
+            },
+        },
+        "NextReading": {
+            "type": "object",
+            "properties": {
+                "sequence": { "type": "integer" },
+                "amps": { "type": "number" },
+                "watts": { "type": "number" },
+                "energy_kwh": { "type": "number" },
+                "timestamp": { "type": "integer" },
+            },
+        },
+        "Networks": {
+            "type": "array",
+            "items": {
+                "type": "object",
+                "properties": {
+                    "ssid": { "type": "string" },
+                    "rssi": { "type": "integer" },
+                    "auth": { "type": "string" },
+                },
+            },
+        },
+        "ProvisionStatus": {
+            "type": "object",
+            "properties": {
+                "state": { "type": "string", "enum": ["idle", "trying", "connected", "failed"] },
+                "ip": { "type": "string" },
+                "reason": { "type": "string" },
+            },
+        },
+        "Outputs": {
+            "type": "object",
+            "properties": {
+                "webhook": {
+                    "type": "object",
+                    "properties": {
+                        "configured": { "type": "boolean" },
+                        "buffered": { "type": "integer" },
+                        "spool": {
+                            "type": "object",
+                            "properties": {
+                                "entries": { "type": "integer" },
+                                "bytes": { "type": "integer" },
+                                "oldest_timestamp": { "type": "integer", "nullable": true },
+                            },
+                        },
+                    },
+                },
+                "mqtt": {
+                    "type": "object",
+                    "properties": {
+                        "configured": { "type": "boolean" },
+                        "connected": { "type": "boolean" },
+                    },
+                },
+                "coap": {
+                    "type": "object",
+                    "properties": {
+                        "configured": { "type": "boolean" },
+                        "sent": { "type": "integer" },
+                    },
+                },
+                "graphite": {
+                    "type": "object",
+                    "properties": {
+                        "configured": { "type": "boolean" },
+                        "connected": { "type": "boolean" },
+                    },
+                },
+                "snmp": {
+                    "type": "object",
+                    "properties": {
+                        "configured": { "type": "boolean" },
+                        "answered": { "type": "integer" },
+                    },
+                },
+                "modbus": {
+                    "type": "object",
+                    "properties": {
+                        "enabled": { "type": "boolean" },
+                        "clients": { "type": "integer" },
+                    },
+                },
+                "notify": {
+                    "type": "object",
+                    "properties": {
+                        "configured": { "type": "boolean" },
+                        "sent": { "type": "integer" },
+                    },
+                },
+            },
+        },
+        "Calibration": {
+            "type": "object",
+            "properties": {
+                "firmware": { "type": "string" },
+                "calibrated_at": { "type": "integer" },
+                "gain": { "type": "number" },
+                "offset_amps": { "type": "number" },
+                "rms_residual_amps": { "type": "number" },
+                "points": { "type": "array", "items": { "type": "object" } },
+            },
+        },
+        "Settings": {
+            "type": "object",
+            "description": "Settings keyed by their NVS name, as strings",
+            "additionalProperties": true,
+        },
+        "SettingsReport": {
+            "type": "object",
+            "properties": {
+                "applied": { "type": "array", "items": { "type": "string" } },
+                "rejected": { "type": "object", "additionalProperties": { "type": "string" } },
+                "restart_required": { "type": "array", "items": { "type": "string" } },
+            },
+        },
+        "WebhookTest": {
+            "type": "object",
+            "properties": {
+                "ok": { "type": "boolean" },
+                "http_status": { "type": "integer", "nullable": true },
+                "latency_ms": { "type": "integer" },
+                "error": { "type": "string", "nullable": true },
+            },
+        },
+        "Token": {
+            "type": "object",
+            "description": "Answered when no token is given; post it back to confirm",
+            "properties": {
+                "token": { "type": "string" },
+                "expires_in_s": { "type": "integer" },
+            },
+        },
+        "Error": {
+            "type": "object",
+            "properties": {
+                "error": { "type": "string" },
+                "code": { "type": "integer" },
+            },
+        },
+        "LogLines": {
+            "type": "array",
+            "items": {
+                "type": "object",
+                "properties": {
+                    "uptime_ms": { "type": "integer" },
+                    "level": { "type": "string" },
+                    "target": { "type": "string" },
+                    "message": { "type": "string" },
+                },
+            },
+        },
+    })
+}
+
+/// The OpenAPI document for `routes`.
+pub fn document(routes: &[Route]) -> Value {
+    let mut paths = Map::new();
+    for route in routes {
+        let path = paths
+            .entry(route.path.to_string())
+            .or_insert_with(|| json!({}));
+        path[route.method] = operation(route);
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "ESP32 amp sensor",
+            "version": FIRMWARE_VERSION,
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": { "basic": { "type": "http", "scheme": "basic" } },
+        },
+    })
+}
+
+pub fn add_openapi_handler(
+    server: &mut EspHttpServer<'_>,
+    routes: &'static [Route],
+) -> Result<(), EspError> {
+    server.fn_handler(
+        "/api/openapi.json",
+        esp_idf_svc::http::Method::Get,
+        move |req| -> Result<(), EspIOError> {
+            let body = document(routes).to_string();
+            req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+                .write(body.as_bytes())?;
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//! Firmware updates uploaded to `/ota`, so a device installed in a breaker
+//! box doesn't have to come out to be flashed. The image is written to the
+//! inactive OTA slot while it arrives, checked by the IDF when complete and
+//! booted into; the bootloader rolls back to the previous slot unless the new
+//! firmware gets on the network once, see `mark_valid`.
+
+use std::sync::Mutex;
+
+use embedded_svc::http::Headers as _;
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::ota::{EspOta, EspOtaUpdate};
+use esp_idf_svc::sys::{EspError, ESP_ERR_INVALID_SIZE};
+use once_cell::sync::Lazy;
+
+use crate::auth;
+use crate::error;
+use crate::rate_limit;
+use crate::uptime;
+
+const CHUNK_SIZE: usize = 4096;
+
+/// Percentage of the image received while an update is running.
+static PROGRESS: Lazy<Mutex<Option<u8>>> = Lazy::new(|| Mutex::new(None));
+
+/// How far the running update got, if there is one.
+pub fn progress() -> Option<u8> {
+    *PROGRESS.lock().unwrap()
+}
+
+/// Tell the bootloader the running firmware works, so it doesn't roll back
+/// on the next reset. Firmware that never runs this is rolled back.
+pub fn mark_valid() {
+    match EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid()) {
+        Ok(()) => log::info!("Running firmware marked valid"),
+        Err(err) => log::warn!("Error marking the running firmware valid: {:?}", err),
+    }
+}
+
+/// Copy the request body into `update`, `size` bytes long.
+fn receive(
+    req: &mut Request<&mut EspHttpConnection<'_>>,
+    update: &mut EspOtaUpdate<'_>,
+    size: u64,
+) -> Result<(), EspIOError> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut received = 0u64;
+    loop {
+        let read = req.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        update.write(&buf[..read])?;
+        received += read as u64;
+        *PROGRESS.lock().unwrap() = Some((received * 100 / size).min(100) as u8);
+    }
+    if received != size {
+        log::warn!(
+            "Firmware upload cut short at {} of {} bytes",
+            received,
+            size
+        );
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>().into());
+    }
+    Ok(())
+}
+
+pub fn add_ota_handler(server: &mut EspHttpServer<'_>) -> Result<(), EspError> {
+    server.fn_handler(
+        "/ota",
+        esp_idf_svc::http::Method::Post,
+        |mut req| -> Result<(), EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            let Some(size) = req.content_len().filter(|size| *size > 0) else {
+                return error::respond(req, 411, "Send the firmware image as the request body");
+            };
+            log::info!("Receiving a {} byte firmware image", size);
+
+            let mut ota = match EspOta::new() {
+                Ok(ota) => ota,
+                Err(err) => return error::internal(req, err),
+            };
+            let mut update = match ota.initiate_update() {
+                Ok(update) => update,
+                Err(err) => return error::internal(req, err),
+            };
+            *PROGRESS.lock().unwrap() = Some(0);
+            let result = match receive(&mut req, &mut update, size) {
+                // Checks the image and switches the boot slot to it
+                Ok(()) => update.complete().map_err(EspIOError::from),
+                // Dropping the update aborts it
+                Err(err) => Err(err),
+            };
+            *PROGRESS.lock().unwrap() = None;
+
+            if let Err(err) = result {
+                log::warn!("Firmware update failed: {:?}", err);
+                return error::respond(req, 500, &format!("Update failed: {:?}", err));
+            }
+
+            log::info!("Firmware update complete, restarting");
+            req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
+                .write("Update complete, restarting".as_bytes())?;
+            // Give the response time to go out
+            FreeRtos::delay_ms(1000);
+            uptime::persist();
+            unsafe {
+                esp_idf_svc::sys::esp_restart();
+            }
+            #[allow(unreachable_code)]
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
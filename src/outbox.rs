@@ -0,0 +1,97 @@
+//! Readings waiting for the webhook while it or the network is down, sent
+//! oldest first once it answers again so an outage leaves no gap.
+//!
+//! The latest `RAM_READINGS` are kept in a ring in RAM, every one of them,
+//! which covers an outage of a few minutes. When the ring is full its oldest
+//! reading spills to the flash spool, if enabled, which keeps one every 15
+//! minutes for days; otherwise it is dropped.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::spool::Spool;
+use crate::template::Context;
+
+/// About five minutes of readings, 24 bytes each plus the queue's overhead.
+pub const RAM_READINGS: usize = 300;
+
+/// Waiting readings sent in a row once the webhook answers, so new reports
+/// are taken from the `reporter` channel while catching up.
+pub const FLUSH_PER_LOOP: usize = 5;
+
+/// Readings in the RAM ring, for `/api/v1/outputs`.
+pub(crate) static BUFFERED: AtomicU32 = AtomicU32::new(0);
+
+pub struct Outbox {
+    ram: VecDeque<Context>,
+    spool: Option<Spool>,
+}
+
+impl Outbox {
+    pub fn new(spool: Option<Spool>) -> Self {
+        Outbox {
+            ram: VecDeque::new(),
+            spool,
+        }
+    }
+
+    /// Queue a reading that could not be sent. Readings taken before the
+    /// clock was synced are useless once delivered late, so they are
+    /// dropped.
+    pub fn offer(&mut self, context: &Context) {
+        if context.timestamp == 0 {
+            return;
+        }
+        if self.ram.len() >= RAM_READINGS {
+            if let (Some(oldest), Some(spool)) = (self.ram.pop_front(), self.spool.as_mut()) {
+                spool.offer(&oldest);
+            }
+        }
+        self.ram.push_back(context.clone());
+        self.publish_status();
+    }
+
+    /// Oldest reading waiting, those spilled to flash first.
+    pub fn front(&self) -> Option<&Context> {
+        self.spool
+            .as_ref()
+            .and_then(Spool::front)
+            .or_else(|| self.ram.front())
+    }
+
+    /// Up to `max` of the oldest readings waiting, for a batched post. A
+    /// reading spilled to flash goes on its own, as the spool only shows its
+    /// oldest one.
+    pub fn front_batch(&self, max: usize) -> Vec<Context> {
+        match self.spool.as_ref().and_then(Spool::front) {
+            Some(spooled) => vec![spooled.clone()],
+            None => self.ram.iter().take(max).cloned().collect(),
+        }
+    }
+
+    /// Forget the oldest reading, once delivered or given up on.
+    pub fn pop_front(&mut self) {
+        match self.spool.as_mut().filter(|spool| spool.front().is_some()) {
+            Some(spool) => spool.pop_front(),
+            None => {
+                self.ram.pop_front();
+                self.publish_status();
+            }
+        }
+    }
+
+    /// Forget the `count` oldest readings, see `front_batch`.
+    pub fn pop_front_batch(&mut self, count: usize) {
+        for _ in 0..count {
+            self.pop_front();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.front().is_none()
+    }
+
+    fn publish_status(&self) {
+        BUFFERED.store(self.ram.len() as u32, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,90 @@
+//! A favicon and a web app manifest, so phones offer to add the readings to
+//! the home screen and open them full screen like an app. There is no
+//! service worker: the pages only make sense while the device is reachable.
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::EspError;
+use serde_json::json;
+
+use crate::assets;
+use crate::http_server::CURRENT_KNOWN_HOSTNAME;
+
+/// Browser chrome color on phones, the background of the icon.
+pub const THEME_COLOR: &str = "#222222";
+
+/// Tags for the `<head>` of the pages generated at runtime; the pages under
+/// `assets/` carry their own.
+pub fn head_links() -> String {
+    format!(
+        "<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">
+        <meta name=\"theme-color\" content=\"{}\">
+        <link rel=\"icon\" href=\"{}\" type=\"image/svg+xml\">
+        <link rel=\"manifest\" href=\"/manifest.json\">",
+        THEME_COLOR,
+        assets::url("icon.svg"),
+    )
+}
+
+/// The manifest, named after the device so several can be told apart on
+/// the home screen.
+fn manifest() -> String {
+    let hostname = CURRENT_KNOWN_HOSTNAME.lock().unwrap().clone();
+    let name = if hostname.is_empty() {
+        "Watt-o-meter".to_string()
+    } else {
+        hostname
+    };
+    json!({
+        "name": name,
+        "short_name": name,
+        "start_url": "/",
+        "scope": "/",
+        "display": "standalone",
+        "background_color": THEME_COLOR,
+        "theme_color": THEME_COLOR,
+        "icons": [{
+            "src": assets::url("icon.svg"),
+            "sizes": "any",
+            "type": "image/svg+xml",
+            "purpose": "any",
+        }],
+    })
+    .to_string()
+}
+
+/// `/manifest.json`, and `/favicon.ico` for the browsers that ask for it
+/// without looking at the page. Both are open like the pages linking them.
+pub fn add_pwa_handlers(server: &mut EspHttpServer<'_>) -> Result<(), EspError> {
+    server.fn_handler(
+        "/manifest.json",
+        esp_idf_svc::http::Method::Get,
+        |req| -> Result<(), EspIOError> {
+            req.into_response(
+                200,
+                Some("OK"),
+                &[
+                    ("Content-Type", "application/manifest+json"),
+                    ("Cache-Control", assets::PAGE_CACHE_CONTROL),
+                ],
+            )?
+            .write(manifest().as_bytes())?;
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/favicon.ico",
+        esp_idf_svc::http::Method::Get,
+        |req| -> Result<(), EspIOError> {
+            req.into_response(
+                // Not permanent, the icon URL changes with its contents
+                302,
+                Some("Found"),
+                &[("Location", assets::url("icon.svg"))],
+            )?;
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
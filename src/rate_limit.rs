@@ -0,0 +1,94 @@
+//! Per-client rate limit for the endpoints that change settings or act on
+//! the device. The HTTP server runs one handler at a time on its own task,
+//! so a client hammering one of them would keep everybody else waiting and
+//! compete with the main loop for the shared state. Checked before the
+//! credentials, it also slows down password guessing.
+
+use std::mem::ManuallyDrop;
+use std::net::{IpAddr, TcpStream};
+use std::os::fd::FromRawFd;
+use std::sync::Mutex;
+
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::http::server::{EspHttpConnection, Request};
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::httpd_req_to_sockfd;
+
+use crate::error;
+use crate::uptime::session_uptime_s;
+
+/// Requests allowed per client in each window.
+const MAX_REQUESTS: u32 = 10;
+const WINDOW_S: u64 = 10;
+/// Clients tracked at once; the one seen least recently makes room.
+const MAX_CLIENTS: usize = 8;
+
+struct Client {
+    ip: IpAddr,
+    window_start_s: u64,
+    requests: u32,
+}
+
+static CLIENTS: Mutex<Vec<Client>> = Mutex::new(Vec::new());
+
+/// Address of the client that sent `req`.
+pub fn client_ip(req: &mut Request<&mut EspHttpConnection<'_>>) -> Option<IpAddr> {
+    // Safe: the socket stays open for as long as the request, and is only
+    // borrowed here, never closed
+    let fd = unsafe { httpd_req_to_sockfd(req.connection().handle()) };
+    let stream = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+    stream.peer_addr().ok().map(|addr| addr.ip())
+}
+
+/// Count a request from `ip`, telling whether it is within the limit.
+fn count(ip: IpAddr, now_s: u64) -> bool {
+    let mut clients = CLIENTS.lock().unwrap();
+    let index = match clients.iter().position(|client| client.ip == ip) {
+        Some(index) => index,
+        None => {
+            if clients.len() >= MAX_CLIENTS {
+                let oldest = (0..clients.len())
+                    .min_by_key(|&index| clients[index].window_start_s)
+                    .unwrap();
+                clients.swap_remove(oldest);
+            }
+            clients.push(Client {
+                ip,
+                window_start_s: now_s,
+                requests: 0,
+            });
+            clients.len() - 1
+        }
+    };
+    let client = &mut clients[index];
+    if now_s - client.window_start_s >= WINDOW_S {
+        client.window_start_s = now_s;
+        client.requests = 0;
+    }
+    client.requests += 1;
+    client.requests <= MAX_REQUESTS
+}
+
+/// Whether the client that sent `req` may go on. Requests whose address
+/// can't be told are let through.
+pub fn allow(req: &mut Request<&mut EspHttpConnection<'_>>) -> bool {
+    let Some(ip) = client_ip(req) else {
+        return true;
+    };
+    let allowed = count(ip, session_uptime_s());
+    if !allowed {
+        log::warn!("Too many requests from {} to {}", ip, req.uri());
+    }
+    allowed
+}
+
+/// Answer a request over the limit.
+pub fn reject(req: Request<&mut EspHttpConnection<'_>>) -> Result<(), EspIOError> {
+    let retry_after = WINDOW_S.to_string();
+    error::respond_with(
+        req,
+        429,
+        "Too many requests, try again in a few seconds",
+        &[("Retry-After", retry_after.as_str())],
+    )
+}
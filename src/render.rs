@@ -0,0 +1,183 @@
+//! Drawing on a thread of its own, pinned to the second core, so slow I2C
+//! writes to the panel never hold up sampling or the webhook. The main loop
+//! publishes what to show and moves on; the render thread only ever draws the
+//! latest view, skipping any it did not get to in time.
+
+use std::mem::{discriminant, Discriminant};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use embedded_graphics::prelude::Point;
+use esp_idf_svc::hal::cpu::Core;
+use esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration;
+use esp_idf_svc::sys::EspError;
+
+use crate::ambient::{DimmingPolicy, NIGHT_REFRESH_S};
+use crate::display::{
+    self, Brightness, Display, DisplayHandlerExt, MainScreen, Page, PageManager, Panel, SetupPage,
+    SetupText, StatusIcons,
+};
+
+const STACK_SIZE: usize = 8 * 1024;
+
+/// Inputs of `DisplayHandler::apply_dimming`.
+#[derive(Clone, Copy, Debug)]
+pub struct Dimming {
+    pub policy: DimmingPolicy,
+    pub auto_brightness: Brightness,
+    pub dark: bool,
+    pub blank: bool,
+}
+
+/// What the display should show.
+#[derive(Clone, Debug)]
+pub enum View {
+    Setup {
+        page: SetupPage,
+        ap_ssid: &'static str,
+        ap_psk: &'static str,
+        text: SetupText,
+        /// `None` once someone chose one.
+        admin_password: Option<String>,
+        icons: StatusIcons,
+    },
+    Pages {
+        page: Page,
+        /// Offset the pages are drawn at, see `burn_in`.
+        shift: Point,
+        screen: MainScreen,
+        dimming: Dimming,
+    },
+    /// A firmware update is being received, see `ota`.
+    Update { percent: u8 },
+}
+
+type Latest = Arc<(Mutex<Option<View>>, Condvar)>;
+
+/// Hands views to the render thread.
+#[derive(Clone)]
+pub struct ViewSender(Latest);
+
+impl ViewSender {
+    /// Show `view`, replacing any the render thread has not drawn yet.
+    pub fn send(&self, view: View) {
+        let (latest, changed) = &*self.0;
+        *latest.lock().unwrap() = Some(view);
+        changed.notify_one();
+    }
+}
+
+/// Start the render thread on the second core. From then on it is the only
+/// one touching `display`.
+pub fn spawn<D>(display: Arc<Mutex<D>>) -> Result<ViewSender, EspError>
+where
+    D: Display + Send + 'static,
+{
+    let latest: Latest = Arc::new((Mutex::new(None), Condvar::new()));
+    let receiver = latest.clone();
+
+    ThreadSpawnConfiguration {
+        name: Some(b"render\0"),
+        pin_to_core: Some(Core::Core1),
+        ..Default::default()
+    }
+    .set()?;
+    std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || render_loop(display, receiver))
+        .expect("Failed to spawn the render thread");
+    // Threads spawned later go back to the defaults
+    ThreadSpawnConfiguration::default().set()?;
+
+    Ok(ViewSender(latest))
+}
+
+fn render_loop<D: Display>(display: Arc<Mutex<D>>, latest: Latest) {
+    let mut pages = PageManager::default();
+    let mut last_kind: Option<Discriminant<View>> = None;
+    // Page last drawn, and when
+    let mut last_drawn: Option<(Page, Instant)> = None;
+    loop {
+        let view = {
+            let (latest, changed) = &*latest;
+            let mut latest = changed
+                .wait_while(latest.lock().unwrap(), |view| view.is_none())
+                .unwrap();
+            latest.take().unwrap()
+        };
+
+        // Neither screen clears what the other one left behind
+        let kind = discriminant(&view);
+        if last_kind.replace(kind) != Some(kind) {
+            last_drawn = None;
+            display.run(|d| {
+                d.clear_buffer();
+                d.flush()
+            });
+        }
+
+        match view {
+            View::Setup {
+                page,
+                ap_ssid,
+                ap_psk,
+                text,
+                admin_password,
+                icons,
+            } => {
+                display.on();
+                display.show_reading(None);
+                display.show_setup(ap_ssid, ap_psk);
+                display.run(|d| {
+                    display::draw_setup_screen(
+                        d,
+                        page,
+                        ap_ssid,
+                        ap_psk,
+                        &text,
+                        admin_password.as_deref(),
+                        &icons,
+                    )?;
+                    d.flush()
+                });
+            }
+            View::Pages {
+                page,
+                shift,
+                screen,
+                dimming,
+            } => {
+                display.init();
+                display.set_dimming(dimming.policy);
+                display.apply_dimming(dimming.auto_brightness, dimming.dark, dimming.blank);
+                // The inverted night display only follows a page change
+                // right away
+                let slow = dimming.policy.is_inverted(dimming.dark);
+                if slow
+                    && last_drawn.is_some_and(|(drawn, at)| {
+                        drawn == page && at.elapsed() < Duration::from_secs(NIGHT_REFRESH_S)
+                    })
+                {
+                    continue;
+                }
+                last_drawn = Some((page, Instant::now()));
+                pages.show(page);
+                pages.set_shift(shift);
+                display.show_reading(Some(screen.watts));
+                display.show_screen(&screen);
+                display.run(|d| {
+                    pages.draw(d, &screen)?;
+                    d.flush()
+                });
+            }
+            View::Update { percent } => {
+                display.on();
+                display.show_reading(None);
+                display.run(|d| {
+                    display::draw_update_screen(d, percent)?;
+                    d.flush()
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! How often readings are posted to the webhook, apart from how often they
+//! are taken (about every second, for the display). A report carries the
+//! mean current and power over its interval, so short spikes still count,
+//! and the energy counter and time at its end.
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::template::Context;
+use crate::units::{Amps, Watts};
+
+pub const NVS_REPORT_INTERVAL: &str = "report_interval";
+/// Every reading, as before the interval could be set.
+pub const DEFAULT_INTERVAL_S: u64 = 1;
+
+/// Whether `interval` can be saved: empty for the default, or whole seconds.
+pub fn is_valid_interval(interval: &str) -> bool {
+    interval.is_empty() || interval.parse::<u64>().map_or(false, |seconds| seconds > 0)
+}
+
+/// Interval set on the setup page, in seconds.
+pub fn interval_from_nvs(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> u64 {
+    read_str_from_nvs_or_default(nvs, NVS_REPORT_INTERVAL, "")
+        .parse()
+        .ok()
+        .filter(|seconds| *seconds > 0)
+        .unwrap_or(DEFAULT_INTERVAL_S)
+}
+
+/// Averages the readings taken since the last report.
+#[derive(Debug)]
+pub struct Averager {
+    interval_s: u64,
+    /// Session uptime the current interval ends at, 0 before the first
+    /// reading.
+    due_s: u64,
+    sum_amps: f64,
+    sum_watts: f64,
+    samples: u32,
+}
+
+impl Averager {
+    pub fn new(interval_s: u64) -> Self {
+        Averager {
+            interval_s,
+            due_s: 0,
+            sum_amps: 0.0,
+            sum_watts: 0.0,
+            samples: 0,
+        }
+    }
+
+    /// Add a reading taken at `now_s`, returning the report once the
+    /// interval is over.
+    pub fn add(&mut self, context: &Context, now_s: u64) -> Option<Context> {
+        if self.due_s == 0 {
+            self.due_s = now_s + self.interval_s;
+        }
+        self.sum_amps += context.amps.0 as f64;
+        self.sum_watts += context.watts.0 as f64;
+        self.samples += 1;
+        if now_s < self.due_s {
+            return None;
+        }
+
+        let samples = self.samples as f64;
+        let report = Context {
+            amps: Amps((self.sum_amps / samples) as f32),
+            watts: Watts((self.sum_watts / samples) as f32),
+            ..context.clone()
+        };
+        // Stay on the interval's grid, unless readings stopped for a while
+        self.due_s += self.interval_s;
+        if self.due_s <= now_s {
+            self.due_s = now_s + self.interval_s;
+        }
+        self.sum_amps = 0.0;
+        self.sum_watts = 0.0;
+        self.samples = 0;
+        Some(report)
+    }
+}
@@ -0,0 +1,196 @@
+//! Webhook delivery on a thread of its own. The main loop hands it the
+//! readings over a channel, so a slow collector, a TLS handshake or catching
+//! up after an outage never holds up sampling or the display.
+//!
+//! The thread owns the `outbox` and the backoff of `delivery`, and leaves
+//! what the display shows about it in `STATUS` for the main loop.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::batch;
+use crate::delivery::{self, DELIVERY_STATS};
+use crate::display::UploadStatus;
+use crate::http_server::CURRENT_KNOWN_WEBHOOK;
+use crate::outbox::{self, Outbox};
+use crate::template::Context;
+use crate::uptime::session_uptime_s;
+
+/// Reports waiting for the thread while it posts. More than this are
+/// dropped rather than let the main loop wait.
+const QUEUE_LEN: usize = 16;
+/// How often waiting readings are tried again when nothing new comes.
+const IDLE_WAKEUP: Duration = Duration::from_secs(1);
+/// TLS needs the room.
+const STACK_SIZE: usize = 10 * 1024;
+
+/// Whether Wi-Fi is up, as last seen by the main loop.
+static ONLINE: AtomicBool = AtomicBool::new(false);
+static STATUS: Mutex<Status> = Mutex::new(Status {
+    label: "",
+    http_status: None,
+    upload: UploadStatus::Idle,
+});
+
+/// Webhook delivery as shown on the display.
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    /// e.g. `OK`, `RETRY` or `NO HOOK`.
+    pub label: &'static str,
+    /// Status of the collector's last answer.
+    pub http_status: Option<u16>,
+    pub upload: UploadStatus,
+}
+
+pub fn status() -> Status {
+    *STATUS.lock().unwrap()
+}
+
+/// Tell the thread whether Wi-Fi is up. Readings wait in the outbox while it
+/// isn't.
+pub fn set_online(online: bool) {
+    ONLINE.store(online, Ordering::Relaxed);
+}
+
+/// The main loop's end of the channel.
+pub struct Reporter {
+    sender: SyncSender<Vec<Context>>,
+}
+
+impl Reporter {
+    /// Queue a report, a whole batch of readings, for the webhook.
+    pub fn send(&self, report: Vec<Context>) {
+        match self.sender.try_send(report) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                log::warn!("Webhook thread behind, dropping a report")
+            }
+            Err(TrySendError::Disconnected(_)) => log::error!("Webhook thread is gone"),
+        }
+    }
+}
+
+/// Start delivering. Call once.
+pub fn spawn(outbox: Outbox) -> Reporter {
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+    std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || deliver_loop(outbox, receiver))
+        .expect("Failed to spawn the webhook thread");
+    Reporter { sender }
+}
+
+fn deliver_loop(mut outbox: Outbox, receiver: Receiver<Vec<Context>>) {
+    let app_config = crate::CONFIG;
+    let mut url = String::new();
+    let mut retry = delivery::Retry::default();
+    loop {
+        let report = match receiver.recv_timeout(IDLE_WAKEUP) {
+            Ok(report) => Some(report),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        let current_url = CURRENT_KNOWN_WEBHOOK.lock().unwrap().clone();
+        if current_url != url {
+            // A new webhook starts without the backoff of the old one
+            url = current_url;
+            retry = delivery::Retry::default();
+        }
+        deliver(&app_config, &url, &mut retry, &mut outbox, report);
+    }
+}
+
+/// Post the new report and those waiting, unless backing off.
+fn deliver(
+    app_config: &crate::Config,
+    url: &String,
+    retry: &mut delivery::Retry,
+    outbox: &mut Outbox,
+    report: Option<Vec<Context>>,
+) {
+    let now_s = session_uptime_s();
+    if url.is_empty() {
+        let mut status = STATUS.lock().unwrap();
+        status.label = "NO HOOK";
+        status.upload = UploadStatus::Idle;
+        return;
+    }
+    if !ONLINE.load(Ordering::Relaxed) {
+        for reading in report.iter().flatten() {
+            outbox.offer(reading);
+        }
+        return;
+    }
+    if !retry.is_due(now_s) {
+        // Backing off after a failure, behind the older readings
+        for reading in report.iter().flatten() {
+            outbox.offer(reading);
+        }
+        STATUS.lock().unwrap().label = "RETRY";
+        return;
+    }
+    if report.is_none() && outbox.is_empty() {
+        // Nothing to send until the next report
+        return;
+    }
+
+    STATUS.lock().unwrap().label = "SENDING";
+    // Older readings go first, a few posts at a time
+    if !outbox.is_empty() {
+        for reading in report.iter().flatten() {
+            outbox.offer(reading);
+        }
+    }
+    let mut posts = 0;
+    let attempt = loop {
+        let queued = outbox.front_batch(batch::size());
+        // Either is there, or we returned above
+        let readings = match (&report, queued.is_empty()) {
+            (_, false) => &queued,
+            (Some(report), true) => report,
+            (None, true) => break None,
+        };
+        let attempt = delivery::attempt(app_config, url, readings);
+        DELIVERY_STATS.lock().unwrap().record(&attempt);
+        posts += 1;
+        if !attempt.is_ok() {
+            if retry.failed(attempt.is_refused(), now_s) {
+                log::warn!(
+                    "Giving up on readings refused {} times",
+                    delivery::MAX_ATTEMPTS
+                );
+                outbox.pop_front_batch(queued.len());
+            } else if queued.is_empty() {
+                for reading in readings {
+                    outbox.offer(reading);
+                }
+            }
+            break Some(attempt);
+        }
+        retry.succeeded();
+        if queued.is_empty() {
+            break Some(attempt);
+        }
+        outbox.pop_front_batch(queued.len());
+        if outbox.is_empty() || posts >= outbox::FLUSH_PER_LOOP {
+            break Some(attempt);
+        }
+    };
+
+    let mut status = STATUS.lock().unwrap();
+    if let Some(attempt) = attempt {
+        if attempt.http_status.is_some() {
+            status.http_status = attempt.http_status;
+        }
+        status.label = attempt.label();
+        status.upload = if attempt.is_ok() {
+            UploadStatus::Ok
+        } else if outbox.is_empty() {
+            UploadStatus::Failed
+        } else {
+            UploadStatus::Retrying
+        };
+    }
+}
@@ -0,0 +1,73 @@
+//! Restarts asked for by the web UI, put off for a while so the browser can
+//! show what was saved before the device goes away. POSTing a token to
+//! `/restart` restarts right away, or as soon as the response went out.
+
+use std::sync::Mutex;
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::EspError;
+use once_cell::sync::Lazy;
+
+use crate::auth;
+use crate::confirm::{self, Confirmation};
+use crate::rate_limit;
+use crate::uptime::{self, session_uptime_s};
+
+/// How long a restart waits for confirmation.
+pub const CONFIRM_TIMEOUT_S: u64 = 30;
+/// Time for the response in flight to go out before restarting.
+const FLUSH_DELAY_S: u64 = 2;
+
+/// Confirms `/restart`, see `confirm`.
+pub(crate) static CONFIRMATION: Confirmation = Confirmation::new();
+
+/// Session uptime to restart at.
+static RESTART_AT_S: Lazy<Mutex<Option<u64>>> = Lazy::new(|| Mutex::new(None));
+
+/// Restart in `CONFIRM_TIMEOUT_S`, unless confirmed earlier.
+pub fn schedule() {
+    let at = session_uptime_s() + CONFIRM_TIMEOUT_S;
+    log::info!("Restarting in {}s", CONFIRM_TIMEOUT_S);
+    *RESTART_AT_S.lock().unwrap() = Some(at);
+}
+
+/// Restart once the response being sent had time to go out.
+pub fn soon() {
+    log::info!("Restarting in {}s", FLUSH_DELAY_S);
+    *RESTART_AT_S.lock().unwrap() = Some(session_uptime_s() + FLUSH_DELAY_S);
+}
+
+/// Call periodically from the main loop.
+pub fn tick() {
+    let due = RESTART_AT_S
+        .lock()
+        .unwrap()
+        .is_some_and(|at| session_uptime_s() >= at);
+    if due {
+        log::info!("Restarting as scheduled");
+        uptime::persist();
+        unsafe {
+            esp_idf_svc::sys::esp_restart();
+        }
+    }
+}
+
+/// POST `/restart` without a token answers with one; POSTing `token=...`
+/// restarts. Browsers prefetching or crawling links only ever GET it.
+pub fn add_restart_handler(server: &mut EspHttpServer<'_>) -> Result<(), EspError> {
+    server.fn_handler(
+        "/restart",
+        esp_idf_svc::http::Method::Post,
+        |mut req| -> Result<(), EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            confirm::respond(req, &CONFIRMATION, "Restarting system", soon)
+        },
+    )?;
+    Ok(())
+}
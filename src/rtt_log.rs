@@ -0,0 +1,40 @@
+//! Logging over RTT with defmt, for bench debugging with a probe attached
+//! instead of the UART console. Only built with the `defmt-rtt` feature;
+//! everything keeps logging through the `log` facade either way.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::log_buffer;
+
+struct RttLogger;
+
+static LOGGER: RttLogger = RttLogger;
+
+impl Log for RttLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        // The messages are already formatted by the `log` macros, so they go
+        // out as strings rather than interned defmt formats
+        let message = format!("{}: {}", record.target(), record.args());
+        match record.level() {
+            Level::Error => defmt::error!("{=str}", message),
+            Level::Warn => defmt::warn!("{=str}", message),
+            Level::Info => defmt::info!("{=str}", message),
+            Level::Debug => defmt::debug!("{=str}", message),
+            Level::Trace => defmt::trace!("{=str}", message),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Set up the RTT channel and route the `log` facade to it, in place of
+/// `EspLogger`. Which levels get through is chosen with `DEFMT_LOG` at build
+/// time.
+pub fn initialize() {
+    rtt_target::rtt_init_defmt!();
+    log_buffer::initialize(&LOGGER, LevelFilter::Trace);
+}
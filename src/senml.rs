@@ -0,0 +1,51 @@
+//! Readings as SenML (RFC 8428) JSON, which LwM2M and many IoT platforms
+//! ingest without a converter. Each reading is three records, `current` in
+//! A, `power` in W and `energy` in J, SenML's own units, e.g.
+//! `{"n":"power","u":"W","v":283.5,"t":1718000000}`.
+//!
+//! The base name, on the first record, identifies the device by its MAC
+//! address: `urn:dev:mac:24d7eb0a1b2c:`, see `metrics::DEVICE_ID`. Readings
+//! taken before the clock was synced have no time, which SenML reads as
+//! "now".
+
+use std::fmt::Write;
+
+use crate::metrics::DEVICE_ID;
+use crate::template::Context;
+
+pub const CONTENT_TYPE: &str = "application/senml+json";
+
+/// The base name of the records, `urn:dev:mac:<MAC address>:`.
+pub fn base_name() -> String {
+    format!("urn:dev:mac:{}:", *DEVICE_ID)
+}
+
+/// A pack with the records of every reading, oldest first.
+pub fn pack(readings: &[Context]) -> String {
+    let mut records = Vec::with_capacity(readings.len() * 3);
+    for reading in readings {
+        for (name, unit, value) in [
+            ("current", "A", format!("{:.5}", reading.amps.0)),
+            ("power", "W", format!("{:.5}", reading.watts.0)),
+            // Joules are SenML's unit of energy
+            ("energy", "J", format!("{:.0}", reading.energy.0 * 3600.0)),
+        ] {
+            let mut record = String::from("{");
+            if records.is_empty() {
+                write!(record, "\"bn\":\"{}\",", base_name()).unwrap();
+            }
+            write!(
+                record,
+                "\"n\":\"{}\",\"u\":\"{}\",\"v\":{}",
+                name, unit, value
+            )
+            .unwrap();
+            if reading.timestamp != 0 {
+                write!(record, ",\"t\":{}", reading.timestamp).unwrap();
+            }
+            record.push('}');
+            records.push(record);
+        }
+    }
+    format!("[{}]", records.join(","))
+}
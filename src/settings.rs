@@ -0,0 +1,523 @@
+//! All settings from the setup page as one JSON document:
+//! - `/api/config` to read and change them without the form, most of them
+//!   without a restart
+//! - `/api/config/export` and `/api/config/import` to provision a
+//!   replacement unit with a single request
+//!
+//! The Wi-Fi, admin and MQTT passwords, the Pushover and Telegram tokens and
+//! the webhook headers (which carry API keys) are left out, as are the
+//! counters. The calibration belongs to the clamp,
+//! so it is only shown.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::EspError;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::ambient::{
+    parse_time_of_day, BrightnessSetting, NightDisplay, NVS_BRIGHTNESS, NVS_NIGHT,
+    NVS_NIGHT_DISPLAY, NVS_NIGHT_END, NVS_NIGHT_START, NVS_UTC_OFFSET,
+};
+use crate::auth;
+use crate::batch::{self, NVS_BATCH_SIZE};
+use crate::body::{self, NVS_WEBHOOK_BODY, NVS_WEBHOOK_METHOD, NVS_WEBHOOK_TYPE};
+use crate::burn_in::NVS_IDLE_MINUTES;
+use crate::calibration::CALIBRATION;
+use crate::coap::{self, NVS_COAP_INTERVAL, NVS_COAP_URL};
+use crate::cors::{self, NVS_CORS_ORIGIN};
+use crate::dashboard::{self, NVS_PEERS};
+use crate::delivery::{self, NVS_WEBHOOK_EXPECT};
+use crate::display::{
+    DisplayBus, PrimaryUnit, Rotation, NVS_DISPLAY_ADDRESS, NVS_DISPLAY_KHZ, NVS_DISPLAY_ROTATION,
+    NVS_DISPLAY_SCL, NVS_DISPLAY_SDA,
+};
+use crate::error;
+use crate::form;
+use crate::graphite::{self, NVS_GRAPHITE_HOST, NVS_GRAPHITE_PREFIX};
+use crate::i18n::{self, NVS_LANGUAGE};
+use crate::labels::{self, NVS_CHANNEL_LABEL, NVS_DEVICE_NAME};
+use crate::modbus::{self, NVS_MODBUS};
+use crate::mqtt::{
+    self, NVS_MQTT_FORMAT, NVS_MQTT_INTERVAL, NVS_MQTT_PREFIX, NVS_MQTT_SHADOW, NVS_MQTT_THING,
+    NVS_MQTT_URL, NVS_MQTT_USER,
+};
+use crate::notify::{self, NVS_NTFY_URL, NVS_PUSHOVER_USER};
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::rate_limit;
+use crate::report::{self, NVS_REPORT_INTERVAL};
+use crate::restart;
+use crate::snmp::{self, NVS_SNMP_COMMUNITY};
+use crate::telegram::{self, NVS_TELEGRAM_CHAT};
+use crate::wifi::enterprise::{self, NVS_EAP_IDENTITY, NVS_EAP_USER};
+use crate::wifi::networks::{self, NVS_SSIDS};
+use crate::wifi::pinning::{self, NVS_WIFI_BSSID, NVS_WIFI_CHANNEL};
+use crate::wifi::security::{self, NVS_WIFI_AUTH};
+use crate::wifi::signal::{self, NVS_RSSI_FLOOR};
+use crate::wifi::static_ip::{
+    self, StaticIp, NVS_IP_ADDRESS, NVS_IP_DNS, NVS_IP_GATEWAY, NVS_IP_NETMASK,
+};
+use crate::AC_VOLTS;
+
+/// Longest value `read_str_from_nvs` reads back whole.
+const MAX_VALUE_LEN: usize = 127;
+const MAX_DOCUMENT_LEN: usize = 4096;
+
+/// The display bus keys only make sense together, see `check_display_bus`.
+const DISPLAY_BUS_KEYS: [&str; 4] = [
+    NVS_DISPLAY_SDA,
+    NVS_DISPLAY_SCL,
+    NVS_DISPLAY_ADDRESS,
+    NVS_DISPLAY_KHZ,
+];
+
+fn any(_value: &str) -> bool {
+    true
+}
+
+fn positive(value: &str) -> bool {
+    value.parse::<f32>().map_or(false, |number| number > 0.0)
+}
+
+/// Empty disables budgets and costs.
+fn empty_or_positive(value: &str) -> bool {
+    value.is_empty() || positive(value)
+}
+
+/// The settings exported and accepted, with the same rules as `/save`.
+const SETTINGS: &[(&str, fn(&str) -> bool)] = &[
+    ("wifi_ssid", |ssid| (1..=32).contains(&ssid.len())),
+    (NVS_WIFI_AUTH, security::is_valid),
+    (NVS_WIFI_BSSID, pinning::is_valid_bssid),
+    (NVS_WIFI_CHANNEL, pinning::is_valid_channel),
+    (NVS_RSSI_FLOOR, signal::is_valid_floor),
+    (NVS_SSIDS[0], networks::is_valid_ssid),
+    (NVS_SSIDS[1], networks::is_valid_ssid),
+    (NVS_SSIDS[2], networks::is_valid_ssid),
+    (NVS_EAP_IDENTITY, enterprise::is_valid),
+    (NVS_EAP_USER, enterprise::is_valid),
+    (NVS_IP_ADDRESS, any),
+    (NVS_IP_NETMASK, any),
+    (NVS_IP_GATEWAY, any),
+    (NVS_IP_DNS, any),
+    ("webhook", any),
+    (NVS_WEBHOOK_BODY, body::is_valid),
+    (NVS_WEBHOOK_METHOD, body::is_valid_method),
+    (NVS_WEBHOOK_TYPE, body::is_valid_content_type),
+    (NVS_WEBHOOK_EXPECT, delivery::is_valid_expect),
+    (NVS_REPORT_INTERVAL, report::is_valid_interval),
+    (NVS_BATCH_SIZE, batch::is_valid_size),
+    ("max_watts", positive),
+    ("budget_day", empty_or_positive),
+    ("budget_month", empty_or_positive),
+    ("weather_url", any),
+    ("price_kwh", empty_or_positive),
+    ("currency", |currency| currency.chars().count() <= 4),
+    (crate::NVS_PRIMARY_UNIT, |unit| {
+        PrimaryUnit::from_name(unit).is_some()
+    }),
+    (NVS_BRIGHTNESS, |brightness| {
+        BrightnessSetting::from_name(brightness).is_some()
+    }),
+    (NVS_NIGHT, |night| {
+        ["never", "schedule", "dark"].contains(&night)
+    }),
+    (NVS_NIGHT_START, |time| parse_time_of_day(time).is_some()),
+    (NVS_NIGHT_END, |time| parse_time_of_day(time).is_some()),
+    (NVS_UTC_OFFSET, |offset| {
+        offset
+            .parse::<f32>()
+            .map_or(false, |hours| hours.abs() <= 14.0)
+    }),
+    (NVS_NIGHT_DISPLAY, |display| {
+        NightDisplay::from_name(display).is_some()
+    }),
+    (NVS_IDLE_MINUTES, |minutes| minutes.parse::<u32>().is_ok()),
+    (NVS_DISPLAY_ROTATION, |rotation| {
+        Rotation::from_name(rotation).is_some()
+    }),
+    (NVS_DISPLAY_SDA, any),
+    (NVS_DISPLAY_SCL, any),
+    (NVS_DISPLAY_ADDRESS, any),
+    (NVS_DISPLAY_KHZ, any),
+    (NVS_CORS_ORIGIN, cors::is_valid_origin),
+    (NVS_LANGUAGE, i18n::is_valid_setting),
+    (NVS_DEVICE_NAME, labels::is_valid),
+    (NVS_CHANNEL_LABEL, labels::is_valid),
+    (NVS_PEERS, dashboard::is_valid_peers),
+    (NVS_MQTT_URL, mqtt::is_valid_url),
+    (NVS_MQTT_USER, any),
+    (NVS_MQTT_PREFIX, mqtt::is_valid_prefix),
+    (NVS_MQTT_INTERVAL, mqtt::is_valid_interval),
+    (NVS_MQTT_THING, mqtt::is_valid_thing),
+    (NVS_MQTT_SHADOW, mqtt::is_valid_shadow),
+    (NVS_MQTT_FORMAT, mqtt::is_valid_format),
+    (NVS_COAP_URL, coap::is_valid_url),
+    (NVS_COAP_INTERVAL, coap::is_valid_interval),
+    (NVS_GRAPHITE_HOST, graphite::is_valid_host),
+    (NVS_GRAPHITE_PREFIX, graphite::is_valid_prefix),
+    (NVS_SNMP_COMMUNITY, snmp::is_valid_community),
+    (NVS_MODBUS, modbus::is_valid),
+    (NVS_NTFY_URL, notify::is_valid_ntfy_url),
+    (NVS_PUSHOVER_USER, notify::is_valid_pushover_key),
+    (NVS_TELEGRAM_CHAT, telegram::is_valid_chat),
+];
+
+/// Settings only read at boot; changing them over `/api/config` needs a
+/// restart to take effect. The main loop picks up the others.
+const RESTART_KEYS: &[&str] = &[
+    "wifi_ssid",
+    NVS_WIFI_AUTH,
+    NVS_WIFI_BSSID,
+    NVS_WIFI_CHANNEL,
+    NVS_SSIDS[0],
+    NVS_SSIDS[1],
+    NVS_SSIDS[2],
+    NVS_EAP_IDENTITY,
+    NVS_EAP_USER,
+    NVS_IP_ADDRESS,
+    NVS_IP_NETMASK,
+    NVS_IP_GATEWAY,
+    NVS_IP_DNS,
+    NVS_DISPLAY_ROTATION,
+    NVS_DISPLAY_SDA,
+    NVS_DISPLAY_SCL,
+    NVS_DISPLAY_ADDRESS,
+    NVS_DISPLAY_KHZ,
+];
+
+/// Shown by `GET /api/config` but not settable there: the voltage is built
+/// in and the calibration has its own endpoints.
+const READ_ONLY_KEYS: &[&str] = &["volts", "calibration"];
+
+/// Set when settings the main loop applies while running were changed.
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// Whether settings changed since the last call, for the main loop to read
+/// them again from NVS.
+pub fn take_changed() -> bool {
+    CHANGED.swap(false, Ordering::SeqCst)
+}
+
+/// Have the main loop apply the settings again after they were changed some
+/// other way, e.g. on `/admin/nvs`.
+pub fn mark_changed() {
+    CHANGED.store(true, Ordering::SeqCst);
+}
+
+/// The settings stored in NVS. Those never saved are left out, so the
+/// importing unit keeps its `cfg.toml` defaults for them.
+fn export(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> String {
+    let settings: BTreeMap<&str, String> = SETTINGS
+        .iter()
+        .map(|(key, _)| (*key, read_str_from_nvs_or_default(nvs, key, "")))
+        .filter(|(_, value)| !value.is_empty())
+        .collect();
+    serde_json::to_string(&settings).unwrap()
+}
+
+/// Body of `GET /api/config`: every setting, empty when never saved, and the
+/// read-only values.
+fn current(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> String {
+    let mut settings: BTreeMap<&str, Value> = SETTINGS
+        .iter()
+        .map(|(key, _)| (*key, read_str_from_nvs_or_default(nvs, key, "").into()))
+        .collect();
+    let calibration = CALIBRATION.lock().unwrap();
+    settings.insert("volts", AC_VOLTS.0.into());
+    settings.insert(
+        "calibration",
+        json!({
+            "gain": calibration.gain,
+            "offset_amps": calibration.offset,
+            "points": calibration.points.len(),
+        }),
+    );
+    serde_json::to_string(&settings).unwrap()
+}
+
+/// Outcome of storing settings, sent back as the response.
+#[derive(Default, Serialize)]
+struct Report {
+    applied: Vec<String>,
+    /// Why each of the other keys was not.
+    rejected: BTreeMap<String, String>,
+    /// Applied keys that only take effect after a restart.
+    restart_required: Vec<String>,
+}
+
+/// Numbers are taken as they are written, everything else has to be a
+/// string like the export has.
+fn as_setting(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// The display bus is only taken whole and valid, like from the setup page.
+fn check_display_bus(settings: &BTreeMap<String, String>) -> Result<(), String> {
+    let values: Vec<&str> = DISPLAY_BUS_KEYS
+        .iter()
+        .filter_map(|key| settings.get(*key).map(String::as_str))
+        .collect();
+    let [sda, scl, address, khz] = values.as_slice() else {
+        return Err("the display bus needs all of its keys".to_string());
+    };
+    DisplayBus::parse(sda, scl, address, khz).and_then(|bus| bus.validate())
+}
+
+/// An empty address turns the static IP off; otherwise the netmask and
+/// gateway must come with it.
+fn check_static_ip(settings: &BTreeMap<String, String>) -> Result<(), String> {
+    let value = |key| settings.get(key).map(String::as_str);
+    match (
+        value(NVS_IP_ADDRESS),
+        value(NVS_IP_NETMASK),
+        value(NVS_IP_GATEWAY),
+    ) {
+        (Some(""), _, _) => Ok(()),
+        (Some(address), Some(netmask), Some(gateway)) => {
+            let dns = value(NVS_IP_DNS).unwrap_or("");
+            StaticIp::parse(address, netmask, gateway, dns).map(|_| ())
+        }
+        _ => Err("the static IP needs its address, netmask and gateway".to_string()),
+    }
+}
+
+/// Split a JSON object into the valid settings and the reasons the other
+/// keys were rejected.
+fn check(document: &[u8]) -> Result<(BTreeMap<String, String>, BTreeMap<String, String>), String> {
+    let document: BTreeMap<String, Value> =
+        serde_json::from_slice(document).map_err(|err| format!("Not a JSON object: {}", err))?;
+
+    let mut settings = BTreeMap::new();
+    let mut rejected = BTreeMap::new();
+    for (key, value) in document {
+        if READ_ONLY_KEYS.contains(&key.as_str()) {
+            rejected.insert(key, "read-only".to_string());
+            continue;
+        }
+        let Some(valid) = SETTINGS
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, f)| f)
+        else {
+            rejected.insert(key, "unknown setting".to_string());
+            continue;
+        };
+        match as_setting(&value) {
+            Some(value) if value.len() > MAX_VALUE_LEN => {
+                rejected.insert(key, "too long".to_string());
+            }
+            Some(value) if valid(&value) => {
+                settings.insert(key, value);
+            }
+            _ => {
+                rejected.insert(key, format!("invalid value {}", value));
+            }
+        }
+    }
+    if DISPLAY_BUS_KEYS
+        .iter()
+        .any(|key| settings.contains_key(*key))
+    {
+        if let Err(reason) = check_display_bus(&settings) {
+            for key in DISPLAY_BUS_KEYS {
+                if settings.remove(key).is_some() {
+                    rejected.insert(key.to_string(), reason.clone());
+                }
+            }
+        }
+    }
+    if static_ip::KEYS
+        .iter()
+        .any(|key| settings.contains_key(*key))
+    {
+        if let Err(reason) = check_static_ip(&settings) {
+            for key in static_ip::KEYS {
+                if settings.remove(key).is_some() {
+                    rejected.insert(key.to_string(), reason.clone());
+                }
+            }
+        }
+    }
+    Ok((settings, rejected))
+}
+
+fn store(nvs: &mut nvs::EspNvs<nvs::NvsDefault>, settings: BTreeMap<String, String>) -> Report {
+    let mut report = Report::default();
+    for (key, value) in settings {
+        match nvs.set_str(&key, &value) {
+            Ok(()) => {
+                if RESTART_KEYS.contains(&key.as_str()) {
+                    report.restart_required.push(key.clone());
+                }
+                report.applied.push(key);
+            }
+            Err(err) => {
+                log::warn!("Error setting {} in NVS: {:?}", key, err);
+                report
+                    .rejected
+                    .insert(key, "could not be stored".to_string());
+            }
+        }
+    }
+    report
+}
+
+fn respond(
+    req: Request<&mut EspHttpConnection<'_>>,
+    status: u16,
+    body: &str,
+) -> Result<(), EspIOError> {
+    let message = match status {
+        200 => "OK",
+        status => error::reason(status),
+    };
+    req.into_response(
+        status,
+        Some(message),
+        &[("Content-Type", "application/json")],
+    )?
+    .write(body.as_bytes())?;
+    Ok(())
+}
+
+pub fn add_config_handlers(
+    nvs: &nvs::EspNvsPartition<nvs::NvsDefault>,
+    server: &mut EspHttpServer<'_>,
+) -> Result<(), EspError> {
+    let nvs = Arc::new(Mutex::new(nvs::EspNvs::new(nvs.clone(), "ssaa", true)?));
+
+    let export_nvs = nvs.clone();
+    server.fn_handler(
+        "/api/config/export",
+        esp_idf_svc::http::Method::Get,
+        move |mut req| -> Result<(), EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            let body = export(&export_nvs.lock().unwrap());
+            req.into_response(
+                200,
+                Some("OK"),
+                &[
+                    ("Content-Type", "application/json"),
+                    (
+                        "Content-Disposition",
+                        "attachment; filename=\"amp-sensor.json\"",
+                    ),
+                ],
+            )?
+            .write(body.as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+
+    let import_nvs = nvs.clone();
+    server.fn_handler(
+        "/api/config/import",
+        esp_idf_svc::http::Method::Post,
+        move |mut req| -> Result<(), EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            let Some(document) = form::read_body(&mut req, MAX_DOCUMENT_LEN)? else {
+                let reason = format!("At most {} bytes", MAX_DOCUMENT_LEN);
+                return error::respond(req, 413, &reason);
+            };
+            let (settings, rejected) = match check(&document) {
+                Ok(checked) => checked,
+                Err(reason) => return error::respond(req, 400, &reason),
+            };
+            let mut report = store(&mut import_nvs.lock().unwrap(), settings);
+            report.rejected.extend(rejected);
+            log::info!(
+                "Imported settings {:?}, rejected {:?}",
+                report.applied,
+                report.rejected
+            );
+            respond(req, 200, &serde_json::to_string(&report).unwrap())?;
+
+            if report.applied.is_empty() {
+                return Ok(());
+            }
+            // Like saving from the setup page, the settings apply from boot
+            log::info!("Restarting to apply the imported settings");
+            restart::soon();
+            Ok(())
+        },
+    )?;
+
+    let get_nvs = nvs.clone();
+    server.fn_handler(
+        "/api/config",
+        esp_idf_svc::http::Method::Get,
+        move |mut req| -> Result<(), EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            let body = current(&get_nvs.lock().unwrap());
+            respond(req, 200, &body)
+        },
+    )?;
+
+    server.fn_handler(
+        "/api/config",
+        esp_idf_svc::http::Method::Put,
+        move |mut req| -> Result<(), EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            let Some(document) = form::read_body(&mut req, MAX_DOCUMENT_LEN)? else {
+                let reason = format!("At most {} bytes", MAX_DOCUMENT_LEN);
+                return error::respond(req, 413, &reason);
+            };
+            let (settings, rejected) = match check(&document) {
+                Ok(checked) => checked,
+                Err(reason) => return error::respond(req, 400, &reason),
+            };
+            // Unlike an import, nothing is stored unless all of it is valid
+            if !rejected.is_empty() {
+                let body = json!({
+                    "error": "Invalid settings, nothing was stored",
+                    "code": 400,
+                    "rejected": rejected,
+                });
+                return respond(req, 400, &body.to_string());
+            }
+            let report = store(&mut nvs.lock().unwrap(), settings);
+            if report.applied.len() > report.restart_required.len() {
+                CHANGED.store(true, Ordering::SeqCst);
+            }
+            log::info!(
+                "Changed settings {:?}, {:?} after a restart",
+                report.applied,
+                report.restart_required
+            );
+            let body = serde_json::to_string(&report).unwrap();
+            respond(req, 200, &body)
+        },
+    )?;
+    Ok(())
+}
@@ -0,0 +1,334 @@
+//! A minimal SNMP v2c agent, so monitoring systems such as Zabbix or PRTG
+//! can poll the device like any other industrial meter. It answers `get`
+//! and `get-next` (and so walks) for three objects, read-only:
+//!
+//! | OID                             | Object            | Type      |
+//! |---------------------------------|-------------------|-----------|
+//! | `1.3.6.1.4.1.32473.1.1.0`       | Current, in mA    | Gauge32   |
+//! | `1.3.6.1.4.1.32473.1.2.0`       | Power, in W       | Gauge32   |
+//! | `1.3.6.1.4.1.32473.1.3.0`       | Energy, in Wh     | Counter64 |
+//!
+//! The project has no enterprise number of its own, so the objects live
+//! under the one RFC 5612 sets aside for examples.
+//!
+//! Requests are answered from the main loop, with the latest reading, so an
+//! answer can take up to a second. Those with another community are dropped
+//! without an answer, as the RFC asks; an empty community turns the agent
+//! off.
+
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::template::Context;
+use crate::uptime::session_uptime_s;
+
+pub const NVS_SNMP_COMMUNITY: &str = "snmp_community";
+const PORT: u16 = 161;
+/// The size every SNMP agent must accept.
+const MAX_MESSAGE_LEN: usize = 1472;
+/// More variables than there are objects is never a useful request.
+const MAX_BINDINGS: usize = 16;
+const RETRY_EVERY_S: u64 = 60;
+const MAX_COMMUNITY_LEN: usize = 32;
+
+const VERSION_2C: i64 = 1;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_GAUGE32: u8 = 0x42;
+const TAG_COUNTER64: u8 = 0x46;
+const TAG_NO_SUCH_OBJECT: u8 = 0x80;
+const TAG_END_OF_MIB_VIEW: u8 = 0x82;
+const PDU_GET: u8 = 0xa0;
+const PDU_GET_NEXT: u8 = 0xa1;
+const PDU_RESPONSE: u8 = 0xa2;
+
+const OID_AMPS: &[u32] = &[1, 3, 6, 1, 4, 1, 32473, 1, 1, 0];
+const OID_WATTS: &[u32] = &[1, 3, 6, 1, 4, 1, 32473, 1, 2, 0];
+const OID_ENERGY: &[u32] = &[1, 3, 6, 1, 4, 1, 32473, 1, 3, 0];
+
+/// Requests answered since boot, for `/api/v1/outputs`.
+pub(crate) static ANSWERED: AtomicU32 = AtomicU32::new(0);
+
+/// Whether `community` can be saved: empty, or printable ASCII.
+pub fn is_valid_community(community: &str) -> bool {
+    community.len() <= MAX_COMMUNITY_LEN && community.chars().all(|c| c.is_ascii_graphic())
+}
+
+pub fn community_from_nvs(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> String {
+    let community = read_str_from_nvs_or_default(nvs, NVS_SNMP_COMMUNITY, "");
+    if is_valid_community(&community) {
+        community
+    } else {
+        String::new()
+    }
+}
+
+/// Reads BER type-length-value items one after the other.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.data.split_first()?;
+        let (&first, mut rest) = rest.split_first()?;
+        let len = if first < 0x80 {
+            first as usize
+        } else {
+            // Long form, up to two length bytes for our message sizes
+            let bytes = (first & 0x7f) as usize;
+            if bytes == 0 || bytes > 2 || rest.len() < bytes {
+                return None;
+            }
+            let len = rest[..bytes]
+                .iter()
+                .fold(0, |len, &b| len << 8 | b as usize);
+            rest = &rest[bytes..];
+            len
+        };
+        if rest.len() < len {
+            return None;
+        }
+        let (value, rest) = rest.split_at(len);
+        self.data = rest;
+        Some((tag, value))
+    }
+
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        self.next()
+            .and_then(|(t, value)| (t == tag).then_some(value))
+    }
+}
+
+fn decode_integer(bytes: &[u8]) -> Option<i64> {
+    if bytes.is_empty() || bytes.len() > 8 {
+        return None;
+    }
+    // Sign-extended from the first byte
+    let first = bytes[0] as i8 as i64;
+    Some(bytes[1..].iter().fold(first, |n, &b| n << 8 | b as i64))
+}
+
+fn decode_oid(bytes: &[u8]) -> Option<Vec<u32>> {
+    let (&first, rest) = bytes.split_first()?;
+    let (first, second) = if first < 80 {
+        (first / 40, first % 40)
+    } else {
+        (2, first - 80)
+    };
+    let mut oid = vec![first as u32, second as u32];
+    let mut arc: u32 = 0;
+    for &b in rest {
+        arc = arc.checked_mul(128)? | (b & 0x7f) as u32;
+        if b & 0x80 == 0 {
+            oid.push(arc);
+            arc = 0;
+        }
+    }
+    Some(oid)
+}
+
+fn push_tlv(out: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    out.push(tag);
+    match value.len() {
+        len @ 0..=127 => out.push(len as u8),
+        len @ 128..=255 => out.extend([0x81, len as u8]),
+        len => {
+            out.push(0x82);
+            out.extend((len as u16).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(value);
+}
+
+/// `n` as an unsigned BER integer, with a leading zero byte if the first
+/// one would read as negative.
+fn encode_unsigned(n: u64) -> Vec<u8> {
+    let mut bytes = vec![0];
+    bytes.extend(n.to_be_bytes());
+    while bytes.len() > 1 && bytes[0] == 0 && bytes[1] & 0x80 == 0 {
+        bytes.remove(0);
+    }
+    bytes
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut bytes = vec![(oid[0] * 40 + oid[1]) as u8];
+    for &arc in &oid[2..] {
+        let mut groups = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            groups.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
+        }
+        bytes.extend(groups.iter().rev());
+    }
+    bytes
+}
+
+/// The objects in OID order, with their values encoded.
+fn objects(context: &Context) -> [(&'static [u32], Vec<u8>); 3] {
+    let mut amps = Vec::new();
+    let milliamps = (context.amps.0.max(0.0) * 1000.0).round() as u64;
+    push_tlv(&mut amps, TAG_GAUGE32, &encode_unsigned(milliamps));
+    let mut watts = Vec::new();
+    let watts_value = context.watts.0.max(0.0).round() as u64;
+    push_tlv(&mut watts, TAG_GAUGE32, &encode_unsigned(watts_value));
+    let mut energy = Vec::new();
+    let watt_hours = context.energy.0.max(0.0).round() as u64;
+    push_tlv(&mut energy, TAG_COUNTER64, &encode_unsigned(watt_hours));
+    [(OID_AMPS, amps), (OID_WATTS, watts), (OID_ENERGY, energy)]
+}
+
+/// The response to `request`, or `None` to drop it.
+fn respond(request: &[u8], community: &str, context: &Context) -> Option<Vec<u8>> {
+    let mut message = Reader::new(Reader::new(request).expect(TAG_SEQUENCE)?);
+    if decode_integer(message.expect(TAG_INTEGER)?)? != VERSION_2C
+        || message.expect(TAG_OCTET_STRING)? != community.as_bytes()
+    {
+        return None;
+    }
+    let (pdu_type, pdu) = message.next()?;
+    if pdu_type != PDU_GET && pdu_type != PDU_GET_NEXT {
+        return None;
+    }
+    let mut pdu = Reader::new(pdu);
+    let request_id = pdu.expect(TAG_INTEGER)?;
+    pdu.expect(TAG_INTEGER)?;
+    pdu.expect(TAG_INTEGER)?;
+    let mut bindings = Reader::new(pdu.expect(TAG_SEQUENCE)?);
+
+    let objects = objects(context);
+    let mut response_bindings = Vec::new();
+    let mut count = 0;
+    while !bindings.is_empty() {
+        count += 1;
+        if count > MAX_BINDINGS {
+            return None;
+        }
+        let mut binding = Reader::new(bindings.expect(TAG_SEQUENCE)?);
+        let oid = decode_oid(binding.expect(TAG_OID)?)?;
+        let found = if pdu_type == PDU_GET {
+            objects.iter().find(|(object, _)| *object == oid.as_slice())
+        } else {
+            objects.iter().find(|(object, _)| *object > oid.as_slice())
+        };
+        let mut response_binding = Vec::new();
+        match found {
+            Some((object, value)) => {
+                push_tlv(&mut response_binding, TAG_OID, &encode_oid(object));
+                response_binding.extend_from_slice(value);
+            }
+            None => {
+                let tag = if pdu_type == PDU_GET {
+                    TAG_NO_SUCH_OBJECT
+                } else {
+                    TAG_END_OF_MIB_VIEW
+                };
+                push_tlv(&mut response_binding, TAG_OID, &encode_oid(&oid));
+                push_tlv(&mut response_binding, tag, &[]);
+            }
+        }
+        push_tlv(&mut response_bindings, TAG_SEQUENCE, &response_binding);
+    }
+
+    let mut response_pdu = Vec::new();
+    push_tlv(&mut response_pdu, TAG_INTEGER, request_id);
+    // No error, at no index
+    push_tlv(&mut response_pdu, TAG_INTEGER, &[0]);
+    push_tlv(&mut response_pdu, TAG_INTEGER, &[0]);
+    push_tlv(&mut response_pdu, TAG_SEQUENCE, &response_bindings);
+    let mut response = Vec::new();
+    push_tlv(&mut response, TAG_INTEGER, &[VERSION_2C as u8]);
+    push_tlv(&mut response, TAG_OCTET_STRING, community.as_bytes());
+    push_tlv(&mut response, PDU_RESPONSE, &response_pdu);
+    let mut out = Vec::new();
+    push_tlv(&mut out, TAG_SEQUENCE, &response);
+    Some(out)
+}
+
+/// Answers the requests received since the last tick.
+pub struct SnmpAgent {
+    community: String,
+    socket: Option<UdpSocket>,
+    /// Session uptime of the next attempt at binding the port.
+    next_bind_s: u64,
+}
+
+impl SnmpAgent {
+    pub fn new(community: String) -> Self {
+        SnmpAgent {
+            community,
+            socket: None,
+            next_bind_s: 0,
+        }
+    }
+
+    /// Call from the main loop while connected to Wi-Fi.
+    pub fn tick(&mut self, context: &Context) {
+        if self.community.is_empty() {
+            return;
+        }
+        if self.socket.is_none() {
+            let now = session_uptime_s();
+            if now < self.next_bind_s {
+                return;
+            }
+            match bind() {
+                Ok(socket) => {
+                    log::info!("SNMP agent listening on port {}", PORT);
+                    self.socket = Some(socket);
+                }
+                Err(err) => {
+                    log::warn!("Could not start the SNMP agent: {:?}", err);
+                    self.next_bind_s = now + RETRY_EVERY_S;
+                    return;
+                }
+            }
+        }
+        let Some(socket) = self.socket.as_ref() else {
+            return;
+        };
+
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::warn!("Error receiving an SNMP request: {:?}", err);
+                    break;
+                }
+            };
+            let Some(response) = respond(&buf[..len], &self.community, context) else {
+                continue;
+            };
+            match socket.send_to(&response, peer) {
+                Ok(_) => {
+                    ANSWERED.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(err) => log::warn!("Could not answer {}: {:?}", peer, err),
+            }
+        }
+    }
+}
+
+fn bind() -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(("0.0.0.0", PORT))?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
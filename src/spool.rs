@@ -0,0 +1,264 @@
+//! Readings that could not be delivered to the webhook, kept on flash so a
+//! collector outage of a few days doesn't leave a gap in the data.
+//!
+//! Only one reading every `SPOOL_EVERY_S` is kept, both to bound the space
+//! used and to spare the flash. Records are appended to a ring of files on a
+//! LittleFS partition of their own, so they neither crowd out the settings
+//! nor wear their pages; when the ring is full the oldest file is dropped.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Write as _;
+use std::sync::Mutex;
+
+use esp_idf_svc::sys::littlefs::{esp_vfs_littlefs_conf_t, esp_vfs_littlefs_register};
+use esp_idf_svc::sys::{esp, EspError};
+use once_cell::sync::Lazy;
+
+use crate::nvs;
+use crate::template::Context;
+use crate::units::{Amps, WattHours, Watts};
+
+/// Partition in `partitions.csv`, and where it is mounted.
+const PARTITION: &[u8] = b"spool\0";
+const BASE_PATH: &[u8] = b"/spool\0";
+const DIR: &str = "/spool";
+/// Sequence number of the oldest reading not delivered yet.
+const FIRST_PATH: &str = "/spool/first";
+
+const CHUNKS: u32 = 4;
+const RECORDS_PER_CHUNK: u32 = 64;
+/// amps (f32), watts (f32), kWh (f64) and Unix time (u64), little endian.
+const RECORD_SIZE: usize = 24;
+
+/// At most one reading is spooled per this many seconds, which with the ring
+/// above covers a bit over two and a half days.
+const SPOOL_EVERY_S: u64 = 900;
+
+/// Where older firmware spooled, in the settings partition.
+const LEGACY_NAMESPACE: &str = "spool";
+const LEGACY_CHUNKS: u32 = 16;
+/// Set in the legacy namespace once it has been emptied.
+const NVS_LEGACY_DROPPED: &str = "dropped";
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpoolStatus {
+    pub entries: u32,
+    pub bytes: u32,
+    /// Unix time of the oldest spooled reading.
+    pub oldest_timestamp: Option<u64>,
+}
+
+pub(crate) static SPOOL_STATUS: Lazy<Mutex<SpoolStatus>> =
+    Lazy::new(|| Mutex::new(SpoolStatus::default()));
+
+/// File holding the readings from `chunk * RECORDS_PER_CHUNK` on.
+fn chunk_path(chunk: u32) -> String {
+    format!("{}/chunk_{}", DIR, chunk)
+}
+
+/// Readings held in the file of `chunk`, none if there is no such file.
+fn chunk_len(chunk: u32) -> u32 {
+    fs::metadata(chunk_path(chunk)).map_or(0, |meta| (meta.len() / RECORD_SIZE as u64) as u32)
+}
+
+fn encode(context: &Context) -> [u8; RECORD_SIZE] {
+    let mut record = [0u8; RECORD_SIZE];
+    record[..4].copy_from_slice(&context.amps.0.to_le_bytes());
+    record[4..8].copy_from_slice(&context.watts.0.to_le_bytes());
+    record[8..16].copy_from_slice(&context.energy.kwh().to_le_bytes());
+    record[16..].copy_from_slice(&context.timestamp.to_le_bytes());
+    record
+}
+
+fn decode(record: &[u8]) -> Context {
+    Context {
+        amps: Amps(f32::from_le_bytes(record[..4].try_into().unwrap())),
+        watts: Watts(f32::from_le_bytes(record[4..8].try_into().unwrap())),
+        energy: WattHours::from_kwh(f64::from_le_bytes(record[8..16].try_into().unwrap())),
+        timestamp: u64::from_le_bytes(record[16..24].try_into().unwrap()),
+    }
+}
+
+/// Older firmware spooled to the settings partition. Drop what it left
+/// there, once.
+fn drop_legacy(settings: nvs::EspDefaultNvsPartition) -> Result<(), EspError> {
+    let mut nvs = nvs::EspNvs::new(settings, LEGACY_NAMESPACE, true)?;
+    if nvs.get_u8(NVS_LEGACY_DROPPED)?.is_some() {
+        return Ok(());
+    }
+    for chunk in 0..LEGACY_CHUNKS {
+        nvs.remove(&format!("chunk_{}", chunk))?;
+    }
+    nvs.remove("first")?;
+    nvs.remove("next")?;
+    nvs.set_u8(NVS_LEGACY_DROPPED, 1)?;
+    log::info!("Dropped the readings spooled to the settings partition");
+    Ok(())
+}
+
+/// Mount the spool partition, formatting it the first time.
+fn mount() -> Result<(), EspError> {
+    let mut conf = esp_vfs_littlefs_conf_t {
+        base_path: BASE_PATH.as_ptr() as *const _,
+        partition_label: PARTITION.as_ptr() as *const _,
+        ..Default::default()
+    };
+    conf.set_format_if_mount_failed(1);
+    // Safe: the driver copies what it keeps of the static strings
+    esp!(unsafe { esp_vfs_littlefs_register(&conf) })
+}
+
+pub struct Spool {
+    /// Sequence number of `records[0]`.
+    first: u32,
+    records: VecDeque<Context>,
+    last_spooled_at: u64,
+}
+
+impl Spool {
+    /// Mount the spool partition and load the spooled readings. Fails on
+    /// units still running the partition table they were first flashed with.
+    pub fn start(settings: nvs::EspDefaultNvsPartition) -> Result<Self, EspError> {
+        if let Err(err) = drop_legacy(settings) {
+            log::warn!("Error dropping the readings spooled to settings: {:?}", err);
+        }
+        mount()?;
+
+        let mut first = fs::read(FIRST_PATH)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .map_or(0, u32::from_le_bytes);
+        // Readings lost with their file, e.g. to a power cut while it was
+        // being dropped, aren't coming back
+        let held = chunk_len(first / RECORDS_PER_CHUNK);
+        if held < first % RECORDS_PER_CHUNK {
+            first = first - first % RECORDS_PER_CHUNK + held;
+        }
+
+        let mut records = VecDeque::new();
+        let mut chunk = first / RECORDS_PER_CHUNK;
+        while let Ok(mut bytes) = fs::read(chunk_path(chunk)) {
+            let torn = bytes.len() % RECORD_SIZE;
+            if torn != 0 {
+                // A power cut while appending leaves part of a record
+                log::warn!("Spool chunk {} is truncated, dropping the rest", chunk);
+                bytes.truncate(bytes.len() - torn);
+                if let Err(err) = fs::write(chunk_path(chunk), &bytes) {
+                    log::warn!("Error writing {}: {}", chunk_path(chunk), err);
+                }
+            }
+            let skip = (first.max(chunk * RECORDS_PER_CHUNK) % RECORDS_PER_CHUNK) as usize;
+            records.extend(bytes.chunks_exact(RECORD_SIZE).skip(skip).map(decode));
+            if torn != 0 || bytes.len() < RECORDS_PER_CHUNK as usize * RECORD_SIZE {
+                break;
+            }
+            chunk += 1;
+        }
+        if !records.is_empty() {
+            log::info!("{} readings spooled from previous outages", records.len());
+        }
+
+        let spool = Spool {
+            first,
+            records,
+            last_spooled_at: 0,
+        };
+        spool.remove_delivered_chunks();
+        spool.publish_status();
+        Ok(spool)
+    }
+
+    fn next_seq(&self) -> u32 {
+        self.first + self.records.len() as u32
+    }
+
+    /// Oldest reading still to be delivered.
+    pub fn front(&self) -> Option<&Context> {
+        self.records.front()
+    }
+
+    /// Keep a reading that could not be delivered, unless another one was
+    /// spooled less than `SPOOL_EVERY_S` before it. Readings taken before the
+    /// clock was synced are useless once delivered late, so they are dropped.
+    pub fn offer(&mut self, context: &Context) {
+        if context.timestamp == 0 || context.timestamp < self.last_spooled_at + SPOOL_EVERY_S {
+            return;
+        }
+        self.last_spooled_at = context.timestamp;
+
+        let seq = self.next_seq();
+        // Starting a chunk past the ring drops the oldest one
+        let oldest_allowed =
+            (seq / RECORDS_PER_CHUNK + 1).saturating_sub(CHUNKS) * RECORDS_PER_CHUNK;
+        if self.first < oldest_allowed {
+            let dropped = (oldest_allowed - self.first) as usize;
+            self.records.drain(..dropped.min(self.records.len()));
+            self.first = oldest_allowed;
+            self.persist_first();
+            self.remove_delivered_chunks();
+        }
+        self.records.push_back(context.clone());
+
+        let path = chunk_path(seq / RECORDS_PER_CHUNK);
+        let appended = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&encode(context)));
+        if let Err(err) = appended {
+            log::warn!("Error writing {}: {}", path, err);
+        }
+        log::info!(
+            "Spooled reading, {} waiting for delivery",
+            self.records.len()
+        );
+        self.publish_status();
+    }
+
+    /// Forget the oldest reading once it has been delivered.
+    pub fn pop_front(&mut self) {
+        if self.records.pop_front().is_some() {
+            self.first += 1;
+            self.persist_first();
+            if self.first % RECORDS_PER_CHUNK == 0 {
+                self.remove_delivered_chunks();
+            }
+            self.publish_status();
+        }
+    }
+
+    fn persist_first(&self) {
+        if let Err(err) = fs::write(FIRST_PATH, self.first.to_le_bytes()) {
+            log::warn!("Error writing {}: {}", FIRST_PATH, err);
+        }
+    }
+
+    /// Remove the files whose readings were all delivered or dropped.
+    fn remove_delivered_chunks(&self) {
+        let Ok(entries) = fs::read_dir(DIR) else {
+            return;
+        };
+        let first_chunk = self.first / RECORDS_PER_CHUNK;
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let chunk = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("chunk_"))
+                .and_then(|chunk| chunk.parse::<u32>().ok());
+            if chunk.is_some_and(|chunk| chunk < first_chunk) {
+                if let Err(err) = fs::remove_file(entry.path()) {
+                    log::warn!("Error removing {:?}: {}", entry.path(), err);
+                }
+            }
+        }
+    }
+
+    fn publish_status(&self) {
+        *SPOOL_STATUS.lock().unwrap() = SpoolStatus {
+            entries: self.records.len() as u32,
+            bytes: (self.records.len() * RECORD_SIZE) as u32,
+            oldest_timestamp: self.records.front().map(|record| record.timestamp),
+        };
+    }
+}
@@ -1,39 +1,47 @@
 use std::sync::{Arc, Mutex, MutexGuard};
 
 use esp_idf_svc::hal::{adc::attenuation, *};
-use ssd1306::{mode::TerminalDisplaySize, prelude::WriteOnlyDataCommand};
 
-use crate::display;
+use crate::display::Display;
+use crate::history;
 
-pub trait AsGlobalState<'a, DI: WriteOnlyDataCommand, SIZE: TerminalDisplaySize> {
-    fn as_global_state(&self) -> &GlobalState<'a, DI, SIZE>;
+pub trait AsGlobalState<'a, D: Display> {
+    fn as_global_state(&self) -> &GlobalState<'a, D>;
 }
 
-pub struct GlobalState<'a, DI: WriteOnlyDataCommand, SIZE: TerminalDisplaySize> {
+pub struct GlobalState<'a, D: Display> {
     pub wifi: Arc<Mutex<esp_idf_svc::wifi::EspWifi<'a>>>,
     pub wifi_ssid: Arc<Mutex<String>>,
     pub setup_mode: Arc<Mutex<bool>>,
-    pub adc_value: Arc<Mutex<f32>>,
-    pub display_handler: Arc<Mutex<display::DisplayHandler<DI, SIZE>>>,
+    pub adc_value: Arc<Mutex<crate::units::Amps>>,
+    pub display_handler: Arc<Mutex<D>>,
     pub webhook_url: Arc<Mutex<String>>,
     pub adc_driver: Arc<Mutex<adc::AdcDriver<'a, adc::ADC1>>>,
     pub adc_chan_driver: Arc<Mutex<adc::AdcChannelDriver<'a, { attenuation::DB_2_5 }, gpio::Gpio35>>>,
+    /// LDR used to follow the ambient light, wired to GPIO36 (VP)
+    #[cfg(feature = "ambient-ldr")]
+    pub ldr_chan_driver: Arc<Mutex<adc::AdcChannelDriver<'a, { attenuation::DB_11 }, gpio::Gpio36>>>,
     pub gpio_btn_boot: gpio::PinDriver<'a, gpio::Gpio0, gpio::Input>,
+    /// Buttons and relays beyond the ESP32 pins
+    #[cfg(feature = "mcp23017")]
+    pub expander: Arc<Mutex<crate::expander::Mcp23017<'a>>>,
     /**
      * Quiet mode pin. If set to low, do not blink the LED
      * If set to high, blink the LED to indicate that the device is running
      */
     pub quiet_mode_pin: gpio::PinDriver<'a, gpio::Gpio34, gpio::Input>,
     pub blink_led: Arc<Mutex<gpio::PinDriver<'a, gpio::Gpio2, gpio::Output>>>,
+    /// Averaged readings of the last few minutes, oldest first
+    pub history: Arc<Mutex<history::History>>,
 }
 
-impl<'a, DI: WriteOnlyDataCommand, SIZE: TerminalDisplaySize> AsGlobalState<'a, DI, SIZE> for GlobalState<'a, DI, SIZE> {
-    fn as_global_state(&self) -> &GlobalState<'a, DI, SIZE> {
+impl<'a, D: Display> AsGlobalState<'a, D> for GlobalState<'a, D> {
+    fn as_global_state(&self) -> &GlobalState<'a, D> {
         self
     }
 }
 
-impl<'a, DI, SIZE> GlobalState<'a, DI, SIZE> where DI: WriteOnlyDataCommand, SIZE: TerminalDisplaySize {
+impl<'a, D> GlobalState<'a, D> where D: Display {
     pub fn adc_driver_mut(&self) -> Result<MutexGuard<adc::AdcDriver<'a, adc::ADC1>>, sys::EspError> {
         self.adc_driver.lock().map_err(|_| sys::EspError::from_non_zero(
             core::num::NonZeroI32::new(esp_idf_svc::sys::ESP_ERR_INVALID_STATE).unwrap(),
@@ -0,0 +1,298 @@
+//! A Telegram bot: the alarms of `notify` go to a chat, and `/power` sent in
+//! that chat answers with the latest reading. The bot is made with
+//! @BotFather, whose token goes on the setup page with the ID of the chat.
+//!
+//! Commands are fetched with `getUpdates` long polling on a thread of its
+//! own, so the bot needs no public address. Messages from other chats are
+//! ignored, but their ID is logged to help find the one to set.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use embedded_svc::io::Write as _;
+use esp_idf_svc::{hal, http};
+use serde::Deserialize;
+
+use crate::energy::ENERGY;
+use crate::form::percent_encode;
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::template::Context;
+
+pub const NVS_TELEGRAM_TOKEN: &str = "tg_token";
+/// Numeric ID of the chat, or `@name` of a channel.
+pub const NVS_TELEGRAM_CHAT: &str = "tg_chat";
+const API_URL: &str = "https://api.telegram.org/bot";
+/// How long Telegram holds `getUpdates` open waiting for a message.
+const LONG_POLL_S: u64 = 25;
+/// Longer than the long poll, so it isn't taken for a dead connection.
+const TIMEOUT: Duration = Duration::from_secs(LONG_POLL_S + 10);
+const IDLE_WAKEUP: Duration = Duration::from_secs(5);
+const RETRY_EVERY: Duration = Duration::from_secs(60);
+/// Enough for an update with the longest message Telegram allows.
+const MAX_RESPONSE_LEN: usize = 16 * 1024;
+/// TLS needs the room.
+const STACK_SIZE: usize = 12 * 1024;
+
+static SETTINGS: Mutex<TelegramSettings> = Mutex::new(TelegramSettings {
+    token: String::new(),
+    chat: String::new(),
+});
+static LATEST: Mutex<Option<Context>> = Mutex::new(None);
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct TelegramSettings {
+    token: String,
+    chat: String,
+}
+
+#[derive(Deserialize)]
+struct Updates {
+    result: Vec<Update>,
+}
+
+#[derive(Deserialize)]
+struct Update {
+    update_id: u64,
+    message: Option<Message>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    chat: Chat,
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Chat {
+    id: i64,
+    username: Option<String>,
+}
+
+/// Whether `token` can be saved: empty, or the `<bot ID>:<secret>` that
+/// @BotFather hands out.
+pub fn is_valid_token(token: &str) -> bool {
+    token.is_empty()
+        || token.split_once(':').is_some_and(|(id, secret)| {
+            !id.is_empty()
+                && id.chars().all(|c| c.is_ascii_digit())
+                && !secret.is_empty()
+                && secret
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        })
+}
+
+/// Whether `chat` can be saved: empty, a numeric ID, negative for groups,
+/// or `@name`.
+pub fn is_valid_chat(chat: &str) -> bool {
+    if let Some(name) = chat.strip_prefix('@') {
+        return !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    }
+    chat.is_empty() || chat.parse::<i64>().is_ok()
+}
+
+/// Read the bot token and chat from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let token = read_str_from_nvs_or_default(nvs, NVS_TELEGRAM_TOKEN, "");
+    let chat = read_str_from_nvs_or_default(nvs, NVS_TELEGRAM_CHAT, "");
+    *SETTINGS.lock().unwrap() = TelegramSettings {
+        token: if is_valid_token(&token) {
+            token
+        } else {
+            String::new()
+        },
+        chat: if is_valid_chat(&chat) {
+            chat
+        } else {
+            String::new()
+        },
+    };
+}
+
+/// Whether alarms can be sent: both the token and the chat are set.
+pub fn is_configured() -> bool {
+    let settings = SETTINGS.lock().unwrap();
+    !settings.token.is_empty() && !settings.chat.is_empty()
+}
+
+/// The chat, for the setup page. The token is never shown.
+pub fn chat() -> String {
+    SETTINGS.lock().unwrap().chat.clone()
+}
+
+/// Keep the latest reading for `/power`.
+pub fn publish(context: &Context) {
+    *LATEST.lock().unwrap() = Some(context.clone());
+}
+
+/// Send `text` to the chat.
+pub fn send_message(text: &str) -> anyhow::Result<()> {
+    let settings = SETTINGS.lock().unwrap().clone();
+    send_to(&settings, &settings.chat, text)
+}
+
+fn send_to(settings: &TelegramSettings, chat: &str, text: &str) -> anyhow::Result<()> {
+    let body = format!(
+        "chat_id={}&text={}",
+        percent_encode(chat),
+        percent_encode(text)
+    );
+    let url = format!("{}{}/sendMessage", API_URL, settings.token);
+    request(&url, Some(&body))?;
+    Ok(())
+}
+
+/// POST `body` as form data, or GET without one. Returns the response body.
+fn request(url: &str, body: Option<&str>) -> anyhow::Result<String> {
+    let httpconnection = http::client::EspHttpConnection::new(&http::client::Configuration {
+        use_global_ca_store: true,
+        crt_bundle_attach: Some(hal::sys::esp_crt_bundle_attach),
+        timeout: Some(TIMEOUT),
+        ..Default::default()
+    })?;
+    let mut client = embedded_svc::http::client::Client::wrap(httpconnection);
+    let mut response = match body {
+        Some(body) => {
+            let content_length = body.len().to_string();
+            let headers = [
+                ("Content-Type", "application/x-www-form-urlencoded"),
+                ("Content-Length", content_length.as_str()),
+            ];
+            let mut request = client.post(url, &headers)?;
+            request.write_all(body.as_bytes())?;
+            request.submit()?
+        }
+        None => client.get(url)?.submit()?,
+    };
+    if response.status() != 200 {
+        // The token is part of the URL, so it isn't logged
+        anyhow::bail!("HTTP status {}", response.status());
+    }
+
+    let mut answer = Vec::new();
+    let mut buf = [0u8; 256];
+    while answer.len() < MAX_RESPONSE_LEN {
+        let read = response.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        answer.extend_from_slice(&buf[..read]);
+    }
+    Ok(String::from_utf8_lossy(&answer).into_owned())
+}
+
+/// The first `update_id` in a response too long or malformed to parse, so
+/// the update can still be skipped.
+fn first_update_id(body: &str) -> Option<u64> {
+    let key = "\"update_id\":";
+    let start = body.find(key)? + key.len();
+    let rest = body[start..].trim_start();
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+/// The answer to `command`, or `None` for text that isn't one.
+fn answer(command: &str) -> Option<String> {
+    // In groups commands come as `/power@name_bot`
+    let command = command.split(['@', ' ']).next()?;
+    match command {
+        "/power" => {
+            let latest = LATEST.lock().unwrap().clone();
+            let today = ENERGY.lock().unwrap().today_wh;
+            Some(match latest {
+                Some(latest) => format!(
+                    "{:.1} W, {:.2} A\nToday {:.2} kWh",
+                    latest.watts.0,
+                    latest.amps.0,
+                    today.kwh()
+                ),
+                None => "No reading yet".to_string(),
+            })
+        }
+        "/start" | "/help" => Some("/power shows the latest reading".to_string()),
+        _ => None,
+    }
+}
+
+fn is_configured_chat(settings: &TelegramSettings, chat: &Chat) -> bool {
+    match settings.chat.strip_prefix('@') {
+        Some(name) => chat.username.as_deref() == Some(name),
+        None => settings.chat == chat.id.to_string(),
+    }
+}
+
+/// Fetch the updates after `offset` and answer the commands among them.
+/// Returns the offset of the next updates.
+fn poll(settings: &TelegramSettings, offset: u64) -> anyhow::Result<u64> {
+    let url = format!(
+        "{}{}/getUpdates?offset={}&limit=1&timeout={}&allowed_updates=%5B%22message%22%5D",
+        API_URL, settings.token, offset, LONG_POLL_S
+    );
+    let body = request(&url, None)?;
+    let updates: Updates = match serde_json::from_str(&body) {
+        Ok(updates) => updates,
+        Err(err) => {
+            let Some(id) = first_update_id(&body) else {
+                anyhow::bail!("unreadable updates: {}", err);
+            };
+            log::warn!("Skipping a Telegram update that can't be read: {}", err);
+            return Ok(id + 1);
+        }
+    };
+    let mut next = offset;
+    for update in updates.result {
+        next = next.max(update.update_id + 1);
+        let Some(message) = update.message else {
+            continue;
+        };
+        if !is_configured_chat(settings, &message.chat) {
+            log::warn!(
+                "Ignoring a Telegram message from chat {}, not the one set up",
+                message.chat.id
+            );
+            continue;
+        }
+        let reply = message.text.as_deref().and_then(answer);
+        if let Some(reply) = reply {
+            send_to(settings, &message.chat.id.to_string(), &reply)?;
+        }
+    }
+    Ok(next)
+}
+
+fn poll_loop() {
+    let mut token = String::new();
+    let mut offset = 0;
+    loop {
+        let settings = SETTINGS.lock().unwrap().clone();
+        // Without a chat the messages are only logged, to find its ID
+        if settings.token.is_empty() {
+            std::thread::sleep(IDLE_WAKEUP);
+            continue;
+        }
+        if settings.token != token {
+            // Another bot has updates of its own
+            token = settings.token.clone();
+            offset = 0;
+        }
+        match poll(&settings, offset) {
+            Ok(next) => offset = next,
+            Err(err) => {
+                // Also while offline
+                log::warn!("Could not get the Telegram updates: {:?}", err);
+                std::thread::sleep(RETRY_EVERY);
+            }
+        }
+    }
+}
+
+/// Start answering commands. Call once; without a bot set up it only sleeps.
+pub fn spawn() {
+    std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(poll_loop)
+        .expect("Failed to spawn the Telegram thread");
+}
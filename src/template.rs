@@ -0,0 +1,149 @@
+//! Minimal `{{name|filter:arg|filter}}` templating for the webhook URL and
+//! body.
+//!
+//! Placeholders: `amps`, `watts`, `kwh` and `timestamp` of the reading, and
+//! `hostname` and `rssi` (dBm) of the device when it is sent.
+//!
+//! Supported filters:
+//! - `round:N` formats a number with `N` decimals
+//! - `comma_decimal` uses a comma as decimal separator
+//! - `int` truncates a number to an integer
+//! - `unix` / `unix_ms` render a timestamp as Unix seconds / milliseconds
+//! - `iso8601` renders a timestamp as `YYYY-MM-DDTHH:MM:SSZ`
+//!
+//! Unknown placeholders are left untouched so typos are visible on the
+//! receiving end.
+
+use crate::units::{Amps, WattHours, Watts};
+use crate::uptime::civil_from_days;
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Number(f64),
+    /// Unix time in seconds.
+    Timestamp(u64),
+    Text(String),
+}
+
+impl Value {
+    /// Widen an `f32` without the noise of its binary expansion, so
+    /// `{{amps}}` keeps rendering as e.g. `1.23456` like it always did.
+    fn from_f32(value: f32) -> Value {
+        Value::Number(value.to_string().parse().unwrap_or(value as f64))
+    }
+
+    fn into_text(self) -> String {
+        match self {
+            Value::Number(n) => n.to_string(),
+            Value::Timestamp(t) => t.to_string(),
+            Value::Text(s) => s,
+        }
+    }
+
+    fn apply(self, filter: &str) -> Option<Value> {
+        let (name, arg) = match filter.split_once(':') {
+            Some((name, arg)) => (name.trim(), Some(arg.trim())),
+            None => (filter.trim(), None),
+        };
+
+        Some(match (name, self) {
+            ("round", Value::Number(n)) => {
+                let decimals = arg?.parse::<usize>().ok()?;
+                Value::Text(format!("{:.*}", decimals, n))
+            }
+            ("int", Value::Number(n)) => Value::Text((n.trunc() as i64).to_string()),
+            ("unix", Value::Timestamp(t)) => Value::Text(t.to_string()),
+            ("unix_ms", Value::Timestamp(t)) => Value::Text((t * 1000).to_string()),
+            ("iso8601", Value::Timestamp(t)) => Value::Text(iso8601(t)),
+            ("comma_decimal", value) => Value::Text(value.into_text().replace('.', ",")),
+            _ => return None,
+        })
+    }
+}
+
+pub fn iso8601(unix_time: u64) -> String {
+    let (year, month, day) = civil_from_days((unix_time / 86_400) as u32);
+    let seconds = unix_time % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Values available to templates.
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    pub amps: Amps,
+    pub watts: Watts,
+    /// Total energy.
+    pub energy: WattHours,
+    /// Unix time of the reading, 0 if the clock is not synced yet.
+    pub timestamp: u64,
+}
+
+/// Values of the device rather than of the reading, taken when sending.
+/// Queued readings are rendered with those of the moment they are sent.
+#[derive(Clone, Debug, Default)]
+pub struct Device {
+    pub hostname: String,
+    /// Signal of the access point, `None` while not connected.
+    pub rssi: Option<i8>,
+}
+
+impl Context {
+    fn lookup(&self, device: &Device, name: &str) -> Option<Value> {
+        Some(match name {
+            "amps" => Value::from_f32(self.amps.0),
+            "watts" => Value::from_f32(self.watts.0),
+            "kwh" => Value::Number(self.energy.kwh()),
+            "timestamp" => Value::Timestamp(self.timestamp),
+            "hostname" => Value::Text(device.hostname.clone()),
+            "rssi" => Value::Number(device.rssi? as f64),
+            _ => return None,
+        })
+    }
+
+    /// Evaluate a placeholder body such as `watts|round:1`.
+    fn evaluate(&self, device: &Device, expression: &str) -> Option<String> {
+        let mut parts = expression.split('|');
+        let mut value = self.lookup(device, parts.next()?.trim())?;
+        for filter in parts {
+            value = value.apply(filter)?;
+        }
+        Some(value.into_text())
+    }
+}
+
+/// Substitute every `{{...}}` placeholder in `template`.
+pub fn render(template: &str, context: &Context, device: &Device) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                let expression = &after[..end];
+                match context.evaluate(device, expression) {
+                    Some(value) => output.push_str(&value),
+                    None => {
+                        log::warn!("Unknown template placeholder {{{{{}}}}}", expression);
+                        output.push_str(&rest[start..start + 2 + end + 2]);
+                    }
+                }
+                rest = &after[end + 2..];
+            }
+            None => {
+                output.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
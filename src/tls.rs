@@ -0,0 +1,226 @@
+//! Certificates and keys uploaded for TLS connections, kept as NVS blobs:
+//! - the certificate the webhook's server is checked against, for
+//!   self-hosted collectors behind a private CA or a self-signed certificate,
+//!   instead of the bundled public CAs. Installing the server's own
+//!   certificate pins it: only a server holding its key is accepted.
+//! - the same for the MQTT broker, and the client certificate and key the
+//!   device proves itself with to brokers that want them, e.g. AWS IoT.
+//! - the CA a WPA2-Enterprise network's authentication server is checked
+//!   against, see `wifi::enterprise`.
+//!
+//! `PUT /api/<slot>` with the PEM as the body installs one and
+//! `DELETE /api/<slot>` removes it, both restarting the device;
+//! `GET /api/<slot>` shows the certificate installed, or only whether a key
+//! is.
+
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::EspError;
+use esp_idf_svc::tls::X509;
+use once_cell::sync::OnceCell;
+
+use crate::auth;
+use crate::error;
+use crate::form;
+use crate::nvs;
+use crate::rate_limit;
+use crate::restart;
+
+/// Enough for a chain of a couple of certificates, or an RSA key.
+const MAX_PEM_LEN: usize = 4096;
+const PEM_END: &str = "-----";
+
+/// Where one certificate or key is kept and served.
+pub struct Slot {
+    nvs_key: &'static str,
+    path: &'static str,
+    /// End of the PEM label, e.g. `CERTIFICATE`, or `PRIVATE KEY` for any
+    /// kind of key.
+    label: &'static str,
+    /// Never shown back.
+    secret: bool,
+    /// The PEM read at boot, NUL-terminated for mbedtls. The TLS clients
+    /// keep a pointer to it, hence `'static`; it only changes across
+    /// restarts.
+    pem: OnceCell<Option<&'static [u8]>>,
+}
+
+pub const NVS_MQTT_KEY: &str = "mqtt_key";
+
+pub static WEBHOOK_CA: Slot = Slot::new("hook_ca", "/api/webhook/ca", "CERTIFICATE", false);
+pub static MQTT_CA: Slot = Slot::new("mqtt_ca", "/api/mqtt/ca", "CERTIFICATE", false);
+pub static MQTT_CERT: Slot = Slot::new("mqtt_cert", "/api/mqtt/cert", "CERTIFICATE", false);
+pub static MQTT_KEY: Slot = Slot::new(NVS_MQTT_KEY, "/api/mqtt/key", "PRIVATE KEY", true);
+pub static WIFI_CA: Slot = Slot::new("wifi_ca", "/api/wifi/ca", "CERTIFICATE", false);
+
+static SLOTS: [&Slot; 5] = [&WEBHOOK_CA, &MQTT_CA, &MQTT_CERT, &MQTT_KEY, &WIFI_CA];
+
+impl Slot {
+    const fn new(
+        nvs_key: &'static str,
+        path: &'static str,
+        label: &'static str,
+        secret: bool,
+    ) -> Self {
+        Slot {
+            nvs_key,
+            path,
+            label,
+            secret,
+            pem: OnceCell::new(),
+        }
+    }
+
+    fn is_valid(&self, pem: &str) -> bool {
+        let pem = pem.trim();
+        pem.len() <= MAX_PEM_LEN
+            && pem.starts_with("-----BEGIN ")
+            && pem
+                .strip_suffix(PEM_END)
+                .is_some_and(|pem| pem.ends_with(self.label))
+            && !pem.contains('\0')
+    }
+
+    fn read(&self, nvs: &nvs::EspNvs<nvs::NvsDefault>) -> Result<Option<String>, EspError> {
+        let mut buf = vec![0u8; MAX_PEM_LEN];
+        let pem = nvs
+            .get_raw(self.nvs_key, &mut buf)?
+            .map(|pem| String::from_utf8_lossy(pem).into_owned());
+        Ok(pem.filter(|pem| self.is_valid(pem)))
+    }
+
+    /// Read the PEM from NVS. Only the first call counts.
+    fn load(&self, nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+        let pem = match self.read(nvs) {
+            Ok(pem) => pem,
+            Err(err) => {
+                log::warn!("Error reading {} from NVS: {:?}", self.nvs_key, err);
+                None
+            }
+        };
+        if pem.is_some() {
+            log::info!("Using the installed {}", self.nvs_key);
+        }
+        self.pem.get_or_init(|| {
+            pem.map(|pem| &*Box::leak(format!("{}\0", pem).into_bytes().into_boxed_slice()))
+        });
+    }
+
+    /// The PEM installed when the device started, if any.
+    pub fn x509(&self) -> Option<X509<'static>> {
+        self.pem().map(X509::pem_until_nul)
+    }
+
+    /// The same as bytes, NUL included.
+    pub fn pem(&self) -> Option<&'static [u8]> {
+        self.pem.get().copied().flatten()
+    }
+}
+
+/// Read every certificate and key from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    for slot in SLOTS {
+        slot.load(nvs);
+    }
+}
+
+pub fn add_handlers(
+    nvs: &nvs::EspNvsPartition<nvs::NvsDefault>,
+    server: &mut EspHttpServer<'_>,
+) -> Result<(), EspError> {
+    let nvs = Arc::new(Mutex::new(nvs::EspNvs::new(nvs.clone(), "ssaa", true)?));
+
+    for slot in SLOTS {
+        let get_nvs = nvs.clone();
+        server.fn_handler(
+            slot.path,
+            esp_idf_svc::http::Method::Get,
+            move |mut req| -> Result<(), EspIOError> {
+                if !rate_limit::allow(&mut req) {
+                    return rate_limit::reject(req);
+                }
+                if !auth::is_authorized(&req) {
+                    return auth::reject(req);
+                }
+                match slot.read(&get_nvs.lock().unwrap()) {
+                    Ok(Some(_)) if slot.secret => {
+                        req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
+                            .write("Installed".as_bytes())?;
+                        Ok(())
+                    }
+                    Ok(Some(pem)) => {
+                        req.into_response(
+                            200,
+                            Some("OK"),
+                            &[("Content-Type", "application/x-pem-file")],
+                        )?
+                        .write(pem.as_bytes())?;
+                        Ok(())
+                    }
+                    Ok(None) => error::respond(req, 404, "None installed"),
+                    Err(err) => error::internal(req, err),
+                }
+            },
+        )?;
+
+        let put_nvs = nvs.clone();
+        server.fn_handler(
+            slot.path,
+            esp_idf_svc::http::Method::Put,
+            move |mut req| -> Result<(), EspIOError> {
+                if !rate_limit::allow(&mut req) {
+                    return rate_limit::reject(req);
+                }
+                if !auth::is_authorized(&req) {
+                    return auth::reject(req);
+                }
+                let Some(body) = form::read_body(&mut req, MAX_PEM_LEN)? else {
+                    let message = format!("At most {} bytes of PEM", MAX_PEM_LEN);
+                    return error::respond(req, 413, &message);
+                };
+                let pem = String::from_utf8_lossy(&body);
+                if !slot.is_valid(&pem) {
+                    let message = format!("Send a PEM {} as the request body", slot.label);
+                    return error::respond(req, 400, &message);
+                }
+                if let Err(err) = put_nvs
+                    .lock()
+                    .unwrap()
+                    .set_raw(slot.nvs_key, pem.as_bytes())
+                {
+                    return error::internal(req, err);
+                }
+                log::info!("Installed {}, restarting", slot.nvs_key);
+                req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
+                    .write("Installed, restarting".as_bytes())?;
+                restart::soon();
+                Ok(())
+            },
+        )?;
+
+        let delete_nvs = nvs.clone();
+        server.fn_handler(
+            slot.path,
+            esp_idf_svc::http::Method::Delete,
+            move |mut req| -> Result<(), EspIOError> {
+                if !rate_limit::allow(&mut req) {
+                    return rate_limit::reject(req);
+                }
+                if !auth::is_authorized(&req) {
+                    return auth::reject(req);
+                }
+                if let Err(err) = delete_nvs.lock().unwrap().remove(slot.nvs_key) {
+                    return error::internal(req, err);
+                }
+                log::info!("Removed {}, restarting", slot.nvs_key);
+                req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
+                    .write("Removed, restarting".as_bytes())?;
+                restart::soon();
+                Ok(())
+            },
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,268 @@
+//! TM1637 4-digit 7-segment module, for big digits instead of an OLED. It
+//! takes the display's pins, CLK on GPIO14 and DIO on GPIO25, and shows the
+//! watts: whole watts up to 9999, then kW with the decimal point lit.
+//!
+//! The TM1637 speaks a two-wire protocol close to I2C but without addresses
+//! and LSB first, so it is bit-banged on open-drain pins.
+
+use esp_idf_svc::hal::delay::Ets;
+use esp_idf_svc::hal::gpio::{AnyIOPin, InputOutput, PinDriver};
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::sys::EspError;
+
+use crate::ambient::DimmingPolicy;
+use crate::display::{Brightness, Display, NoPanel};
+use crate::mirror;
+use crate::units::Watts;
+
+// Commands
+const DATA_AUTO_INCREMENT: u8 = 0x40;
+const ADDRESS_FIRST_DIGIT: u8 = 0xC0;
+const DISPLAY_OFF: u8 = 0x80;
+const DISPLAY_ON: u8 = 0x88;
+
+/// Half a clock period; the TM1637 manages about 250kHz.
+const BIT_DELAY_US: u32 = 5;
+
+/// Segments of 0-9, bit 0 being segment A.
+const DIGITS: [u8; 10] = [0x3F, 0x06, 0x5B, 0x4F, 0x66, 0x6D, 0x7D, 0x07, 0x7F, 0x6F];
+const DECIMAL_POINT: u8 = 0x80;
+const DASH: u8 = 0x40;
+
+pub struct Tm1637<'a> {
+    clk: PinDriver<'a, AnyIOPin, InputOutput>,
+    dio: PinDriver<'a, AnyIOPin, InputOutput>,
+    /// Whether the module acknowledged the last command.
+    available: bool,
+    dimming: DimmingPolicy,
+    /// Brightness last applied, `None` while switched off.
+    brightness: Option<Brightness>,
+    /// Segments last written, to only talk to the module on changes.
+    segments: [u8; 4],
+}
+
+impl<'a> Tm1637<'a> {
+    pub fn new(
+        clk: impl Peripheral<P = impl Into<AnyIOPin>> + 'a,
+        dio: impl Peripheral<P = impl Into<AnyIOPin>> + 'a,
+    ) -> Result<Self, EspError> {
+        let mut clk = PinDriver::input_output_od(clk.into_ref().map_into())?;
+        let mut dio = PinDriver::input_output_od(dio.into_ref().map_into())?;
+        // Idle with both lines released
+        clk.set_high()?;
+        dio.set_high()?;
+        let mut display = Tm1637 {
+            clk,
+            dio,
+            available: false,
+            dimming: DimmingPolicy::default(),
+            brightness: None,
+            segments: [0; 4],
+        };
+        display.init();
+        Ok(display)
+    }
+
+    fn delay() {
+        Ets::delay_us(BIT_DELAY_US);
+    }
+
+    fn start(&mut self) -> Result<(), EspError> {
+        self.dio.set_low()?;
+        Self::delay();
+        self.clk.set_low()
+    }
+
+    fn stop(&mut self) -> Result<(), EspError> {
+        self.dio.set_low()?;
+        Self::delay();
+        self.clk.set_high()?;
+        Self::delay();
+        self.dio.set_high()?;
+        Self::delay();
+        Ok(())
+    }
+
+    /// Clock out a byte, LSB first. Returns whether it was acknowledged.
+    fn write_byte(&mut self, byte: u8) -> Result<bool, EspError> {
+        for bit in 0..8 {
+            self.dio.set_level(((byte >> bit) & 1 == 1).into())?;
+            Self::delay();
+            self.clk.set_high()?;
+            Self::delay();
+            self.clk.set_low()?;
+        }
+        // The module pulls DIO low on the ninth clock to acknowledge
+        self.dio.set_high()?;
+        Self::delay();
+        self.clk.set_high()?;
+        Self::delay();
+        let ack = self.dio.is_low();
+        self.clk.set_low()?;
+        Ok(ack)
+    }
+
+    /// Send one command made of `bytes`, between a start and a stop.
+    fn command(&mut self, bytes: &[u8]) -> Result<bool, EspError> {
+        self.start()?;
+        let mut ack = true;
+        for &byte in bytes {
+            ack &= self.write_byte(byte)?;
+        }
+        self.stop()?;
+        Ok(ack)
+    }
+
+    fn write_segments(&mut self, segments: [u8; 4]) -> Result<bool, EspError> {
+        let [a, b, c, d] = segments;
+        Ok(self.command(&[DATA_AUTO_INCREMENT])?
+            && self.command(&[ADDRESS_FIRST_DIGIT, a, b, c, d])?)
+    }
+
+    fn write_control(&mut self) -> Result<bool, EspError> {
+        let control = match self.brightness {
+            Some(brightness) => DISPLAY_ON | level(brightness),
+            None => DISPLAY_OFF,
+        };
+        self.command(&[control])
+    }
+
+    /// Run a write, marking the module unavailable if it fails or isn't
+    /// acknowledged, so `init` tries again later.
+    fn update(&mut self, write: impl FnOnce(&mut Self) -> Result<bool, EspError>) {
+        if !self.available {
+            return;
+        }
+        match write(self) {
+            Ok(true) => (),
+            Ok(false) => {
+                log::info!("TM1637 did not acknowledge");
+                self.available = false;
+            }
+            Err(err) => {
+                log::info!("TM1637 error: {:?}", err);
+                self.available = false;
+            }
+        }
+    }
+
+    fn show_segments(&mut self, segments: [u8; 4]) {
+        if segments != self.segments {
+            self.segments = segments;
+            self.update(|d| d.write_segments(segments));
+            mirror::show_text(vec![segments_text(segments)]);
+        }
+    }
+
+    fn set_brightness(&mut self, brightness: Option<Brightness>) {
+        if brightness != self.brightness {
+            self.brightness = brightness;
+            self.update(Self::write_control);
+        }
+    }
+}
+
+/// The TM1637 has eight brightness levels, 0 to 7.
+fn level(brightness: Brightness) -> u8 {
+    match brightness {
+        Brightness::Dimmest => 0,
+        Brightness::Dim => 2,
+        Brightness::Normal => 4,
+        Brightness::Bright => 6,
+        Brightness::Brightest => 7,
+    }
+}
+
+/// Right-aligned digits of `value`, without leading zeros.
+fn digits(mut value: u32) -> [u8; 4] {
+    let mut segments = [0; 4];
+    for (i, segment) in segments.iter_mut().enumerate().rev() {
+        *segment = DIGITS[(value % 10) as usize];
+        value /= 10;
+        if value == 0 && i > 0 {
+            break;
+        }
+    }
+    segments
+}
+
+/// Whole watts up to 9999, then kW with as many decimals as fit and the
+/// decimal point lit.
+fn watts_segments(watts: Watts) -> [u8; 4] {
+    let watts = watts.0.max(0.0);
+    if watts.round() < 10_000.0 {
+        return digits(watts.round() as u32);
+    }
+    let kw = watts / 1000.0;
+    let decimals = if kw < 99.995 {
+        2
+    } else if kw < 999.95 {
+        1
+    } else {
+        0
+    };
+    let scaled = (kw * 10f32.powi(decimals)).round().min(9999.0) as u32;
+    let mut segments = digits(scaled);
+    segments[3 - decimals as usize] |= DECIMAL_POINT;
+    segments
+}
+
+/// `segments` as text, for the mirror.
+fn segments_text(segments: [u8; 4]) -> String {
+    let mut text = String::new();
+    for segment in segments {
+        text.push(match segment & !DECIMAL_POINT {
+            0 => ' ',
+            DASH => '-',
+            digit => DIGITS
+                .iter()
+                .position(|&d| d == digit)
+                .map_or('?', |d| char::from(b'0' + d as u8)),
+        });
+        if segment & DECIMAL_POINT != 0 {
+            text.push('.');
+        }
+    }
+    text
+}
+
+impl Display for Tm1637<'_> {
+    type Panel = NoPanel;
+
+    fn run<E: std::fmt::Debug>(&mut self, _f: impl FnOnce(&mut NoPanel) -> Result<(), E>) {}
+
+    fn init(&mut self) {
+        if self.available {
+            return;
+        }
+        // Write everything again, whatever the module missed
+        let segments = self.segments;
+        self.available = matches!(self.write_segments(segments), Ok(true))
+            && matches!(self.write_control(), Ok(true));
+        if self.available {
+            log::info!("TM1637 found");
+        }
+    }
+
+    fn on(&mut self) {
+        if self.brightness.is_none() {
+            self.set_brightness(Some(Brightness::Dim));
+        }
+    }
+
+    fn set_dimming(&mut self, dimming: DimmingPolicy) {
+        self.dimming = dimming;
+    }
+
+    fn apply_dimming(&mut self, auto_brightness: Brightness, dark: bool, blank: bool) {
+        let brightness = self
+            .dimming
+            .brightness(auto_brightness, dark)
+            .filter(|_| !blank);
+        self.set_brightness(brightness);
+    }
+
+    fn show_reading(&mut self, watts: Option<Watts>) {
+        self.show_segments(watts.map_or([DASH; 4], watts_segments));
+    }
+}
@@ -0,0 +1,105 @@
+//! Units of the readings as distinct types, so mixing them up (passing amps
+//! where watts are expected, or kWh where Wh are) fails to compile instead of
+//! silently corrupting the data. Power only comes from current times voltage
+//! and energy from power over time.
+
+use std::fmt;
+use std::ops::{AddAssign, Mul};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Amps(pub f32);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Volts(pub f32);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Watts(pub f32);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct WattHours(pub f64);
+
+impl Mul<Volts> for Amps {
+    type Output = Watts;
+
+    fn mul(self, volts: Volts) -> Watts {
+        Watts(self.0 * volts.0)
+    }
+}
+
+impl Mul<Amps> for Volts {
+    type Output = Watts;
+
+    fn mul(self, amps: Amps) -> Watts {
+        amps * self
+    }
+}
+
+impl Watts {
+    /// Energy drawn at this power for `duration`.
+    pub fn over(self, duration: Duration) -> WattHours {
+        WattHours(self.0 as f64 * duration.as_secs_f64() / 3600.0)
+    }
+}
+
+impl WattHours {
+    pub fn from_kwh(kwh: f64) -> Self {
+        WattHours(kwh * 1000.0)
+    }
+
+    pub fn kwh(self) -> f64 {
+        self.0 / 1000.0
+    }
+}
+
+impl AddAssign for WattHours {
+    fn add_assign(&mut self, other: WattHours) {
+        self.0 += other.0;
+    }
+}
+
+/// The value, honouring the precision given, followed by the unit.
+fn fmt_with_unit(value: f32, unit: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Display::fmt(&value, f)?;
+    f.write_str(unit)
+}
+
+impl fmt::Display for Amps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_with_unit(self.0, "A", f)
+    }
+}
+
+impl fmt::Display for Volts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_with_unit(self.0, "V", f)
+    }
+}
+
+impl fmt::Display for Watts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_with_unit(self.0, "W", f)
+    }
+}
+
+/// In Wh, kWh or MWh, whichever keeps it short, with three significant
+/// digits.
+impl fmt::Display for WattHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (value, unit) = if self.0 < 1000.0 {
+            (self.0, "Wh")
+        } else if self.0 < 1_000_000.0 {
+            (self.0 / 1000.0, "kWh")
+        } else {
+            (self.0 / 1_000_000.0, "MWh")
+        };
+        let decimals = if value < 10.0 {
+            2
+        } else if value < 100.0 {
+            1
+        } else {
+            0
+        };
+        write!(f, "{:.*}{}", decimals, value, unit)
+    }
+}
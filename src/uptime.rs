@@ -0,0 +1,157 @@
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use esp_idf_svc::hal::reset::ResetReason;
+use esp_idf_svc::sys::{esp_timer_get_time, EspError};
+use once_cell::sync::Lazy;
+
+use crate::nvs;
+
+const NVS_BOOTS: &str = "boot_count";
+const NVS_UPTIME: &str = "uptime_s";
+const NVS_INSTALLED_AT: &str = "installed_at";
+
+// Persisting every loop iteration would wear out the flash; losing up to
+// this many seconds of uptime on a power cut is an acceptable trade-off.
+// Restarts the firmware asks for persist right before, see `persist`.
+const PERSIST_EVERY_S: u64 = 60;
+
+// Anything before this means SNTP has not synced the clock yet.
+const MIN_VALID_UNIX_TIME: u64 = 1_700_000_000;
+
+/// Reliability counters accumulated since the device was first installed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UptimeStats {
+    pub boots: u32,
+    /// Uptime accumulated by previous boots, as last persisted.
+    pub previous_uptime_s: u64,
+    /// Unix time of the first boot, or 0 while it is still unknown.
+    pub installed_at: u64,
+}
+
+pub(crate) static UPTIME: Lazy<Mutex<UptimeStats>> =
+    Lazy::new(|| Mutex::new(UptimeStats::default()));
+
+/// Where the counters are persisted, set by `UptimeTracker::start`.
+static STORE: Lazy<Mutex<Option<nvs::EspNvs<nvs::NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Seconds since this boot.
+pub fn session_uptime_s() -> u64 {
+    // Safe: esp_timer is started by the IDF before app_main runs
+    (unsafe { esp_timer_get_time() } / 1_000_000) as u64
+}
+
+/// Why the chip last reset, e.g. `PowerOn` or `Brownout`.
+pub fn reset_reason() -> String {
+    format!("{:?}", ResetReason::get())
+}
+
+/// Current Unix time, if the clock has been synced.
+pub fn unix_now() -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    (now >= MIN_VALID_UNIX_TIME).then_some(now)
+}
+
+/// Convert days since the Unix epoch into a (year, month, day) UTC date.
+pub fn civil_from_days(days: u32) -> (u32, u32, u32) {
+    // Howard Hinnant's civil_from_days
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as u32, month as u32, day as u32)
+}
+
+impl UptimeStats {
+    pub fn total_uptime_s(&self) -> u64 {
+        self.previous_uptime_s + session_uptime_s()
+    }
+
+    /// Percentage of wall-clock time since install during which the device
+    /// was running, or `None` until the install time is known.
+    pub fn availability_percent(&self) -> Option<f32> {
+        if self.installed_at == 0 {
+            return None;
+        }
+        let elapsed = unix_now()?.checked_sub(self.installed_at)?;
+        if elapsed == 0 {
+            return None;
+        }
+        Some((self.total_uptime_s() as f32 / elapsed as f32).min(1.0) * 100.0)
+    }
+}
+
+/// Persist the uptime accumulated so far. Call right before restarting, so
+/// a restart loses none of it.
+pub fn persist() {
+    let total = UPTIME.lock().unwrap().total_uptime_s();
+    if let Some(nvs) = STORE.lock().unwrap().as_mut() {
+        if let Err(err) = nvs.set_u64(NVS_UPTIME, total) {
+            log::warn!("Error setting {} in NVS: {:?}", NVS_UPTIME, err);
+        }
+    }
+}
+
+/// Keeps the persisted counters in NVS up to date.
+pub struct UptimeTracker {
+    last_persisted_s: u64,
+}
+
+impl UptimeTracker {
+    /// Load the counters from NVS and record this boot.
+    pub fn start(mut nvs: nvs::EspNvs<nvs::NvsDefault>) -> Result<Self, EspError> {
+        let stats = UptimeStats {
+            boots: nvs.get_u32(NVS_BOOTS)?.unwrap_or(0) + 1,
+            previous_uptime_s: nvs.get_u64(NVS_UPTIME)?.unwrap_or(0),
+            installed_at: nvs.get_u64(NVS_INSTALLED_AT)?.unwrap_or(0),
+        };
+        nvs.set_u32(NVS_BOOTS, stats.boots)?;
+        log::info!(
+            "Boot #{} after a {:?} reset, {}s of uptime since install",
+            stats.boots,
+            ResetReason::get(),
+            stats.previous_uptime_s
+        );
+        *UPTIME.lock().unwrap() = stats;
+        *STORE.lock().unwrap() = Some(nvs);
+
+        Ok(UptimeTracker {
+            last_persisted_s: session_uptime_s(),
+        })
+    }
+
+    /// Call periodically from the main loop.
+    pub fn tick(&mut self) {
+        {
+            let mut stats = match UPTIME.try_lock() {
+                Ok(stats) => stats,
+                Err(_) => return,
+            };
+
+            if stats.installed_at == 0 {
+                if let Some(now) = unix_now() {
+                    // The clock was not synced on the very first boot, so
+                    // estimate the install time from the uptime accumulated
+                    // so far.
+                    stats.installed_at = now.saturating_sub(stats.total_uptime_s());
+                    if let Some(nvs) = STORE.lock().unwrap().as_mut() {
+                        if let Err(err) = nvs.set_u64(NVS_INSTALLED_AT, stats.installed_at) {
+                            log::warn!("Error setting {} in NVS: {:?}", NVS_INSTALLED_AT, err);
+                        }
+                    }
+                }
+            }
+        }
+
+        let session = session_uptime_s();
+        if session - self.last_persisted_s >= PERSIST_EVERY_S {
+            self.last_persisted_s = session;
+            persist();
+        }
+    }
+}
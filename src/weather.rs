@@ -0,0 +1,131 @@
+use std::sync::Mutex;
+
+use esp_idf_svc::wifi::EspWifi;
+use esp_idf_svc::{hal, http};
+use once_cell::sync::Lazy;
+
+use crate::energy::ENERGY;
+use crate::uptime::{session_uptime_s, unix_now};
+
+const FETCH_EVERY_S: u64 = 3600;
+
+// Retry sooner after a failure, but not every loop iteration.
+const RETRY_EVERY_S: u64 = 300;
+
+/// Keys tried, in order, when looking for the temperature in the response.
+/// The first two match Open-Meteo's `current` and `current_weather` blocks.
+const TEMPERATURE_KEYS: [&str; 3] = ["\"temperature_2m\"", "\"temperature\"", "\"temp\""];
+
+/// Outdoor temperature, rolled up per day like the energy counters.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WeatherState {
+    pub latest_c: Option<f32>,
+    /// Unix time of the last successful fetch, or 0.
+    pub fetched_at: u64,
+    /// Day index (see `EnergyCounters::day_index`) the daily mean belongs to.
+    pub day_index: u32,
+    pub today_sum_c: f32,
+    pub today_samples: u32,
+    pub yesterday_mean_c: Option<f32>,
+}
+
+impl WeatherState {
+    pub fn today_mean_c(&self) -> Option<f32> {
+        (self.today_samples > 0).then(|| self.today_sum_c / self.today_samples as f32)
+    }
+
+    fn record(&mut self, temperature_c: f32, day_index: u32) {
+        if self.day_index != day_index {
+            self.yesterday_mean_c = self.today_mean_c();
+            self.day_index = day_index;
+            self.today_sum_c = 0.0;
+            self.today_samples = 0;
+        }
+        self.latest_c = Some(temperature_c);
+        self.fetched_at = unix_now().unwrap_or(0);
+        self.today_sum_c += temperature_c;
+        self.today_samples += 1;
+    }
+}
+
+pub(crate) static WEATHER: Lazy<Mutex<WeatherState>> =
+    Lazy::new(|| Mutex::new(WeatherState::default()));
+
+/// Find the first numeric value of `key` in a JSON document, skipping
+/// occurrences with non-numeric values (e.g. Open-Meteo's `current_units`).
+/// Good enough for weather API responses without pulling in a JSON parser.
+pub fn extract_json_number(body: &str, key: &str) -> Option<f32> {
+    body.match_indices(key).find_map(|(start, _)| {
+        let rest = body[start + key.len()..].trim_start();
+        let rest = rest.strip_prefix(':')?.trim_start();
+        let end = rest
+            .find(|c: char| !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')))
+            .unwrap_or(rest.len());
+        rest[..end].parse().ok()
+    })
+}
+
+/// Periodically fetches the outdoor temperature from a user-provided URL.
+pub struct WeatherFetcher {
+    url: String,
+    next_fetch_s: u64,
+}
+
+impl WeatherFetcher {
+    pub fn new(url: String) -> Self {
+        WeatherFetcher {
+            url,
+            next_fetch_s: 0,
+        }
+    }
+
+    /// Call from the main loop while connected; fetches at most once an hour.
+    pub fn tick(&mut self, wifi: &EspWifi) {
+        let now = session_uptime_s();
+        if self.url.is_empty() || now < self.next_fetch_s || !wifi.is_connected().unwrap_or(false) {
+            return;
+        }
+
+        match fetch_temperature(&self.url) {
+            Ok(temperature_c) => {
+                log::info!("Outdoor temperature: {:.1}C", temperature_c);
+                let day_index = ENERGY.lock().unwrap().day_index;
+                WEATHER.lock().unwrap().record(temperature_c, day_index);
+                self.next_fetch_s = now + FETCH_EVERY_S;
+            }
+            Err(err) => {
+                log::warn!("Could not fetch the outdoor temperature: {:?}", err);
+                self.next_fetch_s = now + RETRY_EVERY_S;
+            }
+        }
+    }
+}
+
+fn fetch_temperature(url: &str) -> anyhow::Result<f32> {
+    let httpconnection = http::client::EspHttpConnection::new(&http::client::Configuration {
+        use_global_ca_store: true,
+        crt_bundle_attach: Some(hal::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    let mut client = embedded_svc::http::client::Client::wrap(httpconnection);
+    let mut response = client.get(url)?.submit()?;
+    if response.status() != 200 {
+        anyhow::bail!("HTTP status {}", response.status());
+    }
+
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let read = response.read(&mut buf)?;
+        if read == 0 || body.len() > 4096 {
+            break;
+        }
+        body.extend_from_slice(&buf[..read]);
+    }
+    let body = String::from_utf8_lossy(&body);
+
+    TEMPERATURE_KEYS
+        .iter()
+        .find_map(|key| extract_json_number(&body, key))
+        .ok_or_else(|| anyhow::anyhow!("no temperature in response"))
+}
@@ -0,0 +1,82 @@
+//! `/api/webhook/test`, behind the test button next to the webhook on the
+//! setup page: post a sample reading to a webhook right away and answer with
+//! how it went, so a URL can be checked before it is saved.
+//!
+//! Only the URL comes from the request, the saved one if it has none. The
+//! body, method, content type, headers and expected answer are the saved
+//! ones. The test doesn't count in the delivery statistics.
+
+use std::time::Instant;
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::sys::EspError;
+use serde_json::json;
+
+use crate::auth;
+use crate::delivery;
+use crate::energy::ENERGY;
+use crate::error;
+use crate::form::{self, MAX_FORM_LEN};
+use crate::http_server::CURRENT_KNOWN_WEBHOOK;
+use crate::rate_limit;
+use crate::template::Context;
+use crate::units::Amps;
+use crate::uptime::unix_now;
+use crate::AC_VOLTS;
+
+/// Current of the sample reading, so it is told apart from real ones.
+const SAMPLE_AMPS: Amps = Amps(1.0);
+
+/// A reading to send, with the real energy counter.
+fn sample() -> Context {
+    Context {
+        amps: SAMPLE_AMPS,
+        watts: SAMPLE_AMPS * AC_VOLTS,
+        energy: ENERGY.lock().unwrap().total_wh,
+        timestamp: unix_now().unwrap_or(0),
+    }
+}
+
+/// POST `/api/webhook/test` with an optional `webhook=<URL>` answers with
+/// `{"ok":true,"http_status":200,"latency_ms":312,"error":null}`.
+pub fn add_test_handler(server: &mut EspHttpServer<'_>) -> Result<(), EspError> {
+    server.fn_handler(
+        "/api/webhook/test",
+        esp_idf_svc::http::Method::Post,
+        |mut req| -> Result<(), EspIOError> {
+            if !rate_limit::allow(&mut req) {
+                return rate_limit::reject(req);
+            }
+            if !auth::is_authorized(&req) {
+                return auth::reject(req);
+            }
+            let Some(body) = form::read_body(&mut req, MAX_FORM_LEN)? else {
+                return error::respond(req, 413, "Form too large");
+            };
+            let fields = form::parse(&body);
+            let url = match form::field(&fields, "webhook") {
+                Some(url) if !url.is_empty() => url.to_string(),
+                _ => CURRENT_KNOWN_WEBHOOK.lock().unwrap().clone(),
+            };
+            if url.is_empty() {
+                return error::respond(req, 400, "No webhook to test");
+            }
+
+            log::info!("Testing the webhook {}", url);
+            let started = Instant::now();
+            let attempt = delivery::attempt(&crate::CONFIG, &url, &[sample()]);
+            let latency_ms = started.elapsed().as_millis() as u64;
+            let answer = json!({
+                "ok": attempt.is_ok(),
+                "http_status": attempt.http_status,
+                "latency_ms": latency_ms,
+                "error": attempt.error,
+            });
+            req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+                .write(answer.to_string().as_bytes())?;
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
@@ -0,0 +1,122 @@
+//! WPA2-Enterprise, for office and university networks that log each user
+//! in rather than sharing a password. The user and password, and optionally
+//! an anonymous identity, are set on the setup page; the network is then
+//! saved without a Wi-Fi password. PEAP and TTLS are both accepted, with
+//! MSCHAPv2 inside.
+//!
+//! The authentication server is checked against the CA uploaded to
+//! `/api/wifi/ca`, see `tls`. Without one, any server is accepted.
+
+use std::sync::Mutex;
+
+use esp_idf_svc::sys::{
+    esp, esp_eap_client_clear_ca_cert, esp_eap_client_set_ca_cert, esp_eap_client_set_identity,
+    esp_eap_client_set_password, esp_eap_client_set_username, esp_wifi_sta_enterprise_disable,
+    esp_wifi_sta_enterprise_enable, EspError,
+};
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+use crate::tls::WIFI_CA;
+
+/// Outer identity sent in the clear, the user if empty.
+pub const NVS_EAP_IDENTITY: &str = "eap_identity";
+pub const NVS_EAP_USER: &str = "eap_user";
+pub const NVS_EAP_PASSWORD: &str = "eap_pass";
+/// Longest value `read_str_from_nvs` reads back whole.
+const MAX_LEN: usize = 127;
+
+static SETTINGS: Mutex<EapSettings> = Mutex::new(EapSettings {
+    identity: String::new(),
+    user: String::new(),
+    password: String::new(),
+});
+
+#[derive(Clone, Debug, Default)]
+struct EapSettings {
+    identity: String,
+    user: String,
+    password: String,
+}
+
+/// Whether an identity, user or password can be saved.
+pub fn is_valid(value: &str) -> bool {
+    value.len() <= MAX_LEN && !value.chars().any(char::is_control)
+}
+
+/// Read the credentials from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let read = |key| {
+        let value = read_str_from_nvs_or_default(nvs, key, "");
+        if is_valid(&value) {
+            value
+        } else {
+            String::new()
+        }
+    };
+    *SETTINGS.lock().unwrap() = EapSettings {
+        identity: read(NVS_EAP_IDENTITY),
+        user: read(NVS_EAP_USER),
+        password: read(NVS_EAP_PASSWORD),
+    };
+}
+
+/// Whether there are credentials to log in with.
+pub fn is_configured() -> bool {
+    let settings = SETTINGS.lock().unwrap();
+    !settings.user.is_empty() && !settings.password.is_empty()
+}
+
+/// The identity, for the setup page.
+pub fn identity() -> String {
+    SETTINGS.lock().unwrap().identity.clone()
+}
+
+/// The user, for the setup page. The password is never shown.
+pub fn user() -> String {
+    SETTINGS.lock().unwrap().user.clone()
+}
+
+/// The station joins networks saved without a password with the
+/// credentials, when there are any.
+pub fn is_used(psk: &str) -> bool {
+    psk.is_empty() && is_configured()
+}
+
+/// Hand the credentials to the supplicant for the network with `psk`, or
+/// turn it off for networks that don't need it. Call after setting the
+/// configuration and before connecting.
+pub fn apply(psk: &str) -> Result<(), EspError> {
+    if !is_used(psk) {
+        // Safe: only resets the supplicant's state
+        return esp!(unsafe { esp_wifi_sta_enterprise_disable() });
+    }
+    let settings = SETTINGS.lock().unwrap().clone();
+    let identity = if settings.identity.is_empty() {
+        &settings.user
+    } else {
+        &settings.identity
+    };
+    log::info!("Logging in to the Wi-Fi network as {:?}", settings.user);
+    // Safe because the supplicant copies the values before returning
+    unsafe {
+        esp!(esp_eap_client_set_identity(
+            identity.as_ptr(),
+            identity.len() as _
+        ))?;
+        esp!(esp_eap_client_set_username(
+            settings.user.as_ptr(),
+            settings.user.len() as _
+        ))?;
+        esp!(esp_eap_client_set_password(
+            settings.password.as_ptr(),
+            settings.password.len() as _
+        ))?;
+        match WIFI_CA.pem() {
+            // The NUL is part of a PEM for mbedtls
+            Some(pem) => esp!(esp_eap_client_set_ca_cert(pem.as_ptr(), pem.len() as _))?,
+            None => esp_eap_client_clear_ca_cert(),
+        }
+        esp!(esp_wifi_sta_enterprise_enable())
+    }
+}
@@ -0,0 +1,51 @@
+//! mDNS responder, so the web UI is at `http://<hostname>.local/` and shows
+//! up in service browsers without looking up the address the router gave.
+
+use std::sync::Mutex;
+
+use esp_idf_svc::mdns::EspMdns;
+use esp_idf_svc::sys::EspError;
+use once_cell::sync::Lazy;
+
+const MODEL: &str = env!("CARGO_PKG_NAME");
+
+/// The responder, once started, and the hostname it answers to.
+static MDNS: Lazy<Mutex<Option<(EspMdns, String)>>> = Lazy::new(|| Mutex::new(None));
+
+fn start(hostname: &str) -> Result<EspMdns, EspError> {
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name(hostname)?;
+    mdns.add_service(
+        None,
+        "_http",
+        "_tcp",
+        80,
+        &[("model", MODEL), ("version", crate::FIRMWARE_VERSION)],
+    )?;
+    Ok(mdns)
+}
+
+/// Answer as `<hostname>.local` and advertise the web UI. Called whenever
+/// the station has an address; only does anything the first time and when
+/// the hostname changed.
+pub fn advertise(hostname: &str) {
+    let mut mdns = MDNS.lock().unwrap();
+    match &mut *mdns {
+        Some((_, current)) if current == hostname => {}
+        Some((responder, current)) => match responder.set_hostname(hostname) {
+            Ok(()) => {
+                log::info!("mDNS hostname changed to {}.local", hostname);
+                *current = hostname.to_string();
+            }
+            Err(err) => log::warn!("Error changing the mDNS hostname: {:?}", err),
+        },
+        None => match start(hostname) {
+            Ok(responder) => {
+                log::info!("mDNS responder started as {}.local", hostname);
+                *mdns = Some((responder, hostname.to_string()));
+            }
+            Err(err) => log::warn!("Error starting the mDNS responder: {:?}", err),
+        },
+    }
+}
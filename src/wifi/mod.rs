@@ -1,6 +1,8 @@
 use std::net::Ipv4Addr;
+use std::sync::atomic::AtomicU32;
 use std::sync::{Arc, Mutex};
 
+use embedded_svc::io::Write as _;
 use esp_idf_svc::hal::modem::WifiModemPeripheral;
 use esp_idf_svc::hal::peripheral::Peripheral;
 use esp_idf_svc::handle::RawHandle as _;
@@ -18,10 +20,24 @@ pub use esp_idf_svc::{
     wifi::{self, AuthMethod, ClientConfiguration, Configuration, EspWifi},
 };
 use esp_idf_svc::{hal, http, nvs};
-use ssd1306::size::DisplaySize128x32;
 
 use crate::state::AsGlobalState;
 
+pub mod enterprise;
+pub mod mdns;
+pub mod networks;
+pub mod pinning;
+pub mod preflight;
+pub mod provision;
+pub mod scan;
+pub mod security;
+pub mod setup_ap;
+pub mod signal;
+pub mod static_ip;
+
+/// Times the station got the connection back since boot.
+pub(crate) static WIFI_RECONNECTS: AtomicU32 = AtomicU32::new(0);
+
 pub fn non_empty_string_or_fail(s: String) -> Result<String, EspError> {
     if s.len() == 0 {
         Err(EspError::from_non_zero(
@@ -32,64 +48,63 @@ pub fn non_empty_string_or_fail(s: String) -> Result<String, EspError> {
     }
 }
 
+/// Resolve the credentials of the first network and the hostname to use.
+/// Other networks to fall back on are in `networks`.
+///
+/// Values provisioned into NVS always win. The compile-time `cfg.toml` values
+/// are only used as a fallback, so a generic binary built without them boots
+/// straight into setup mode.
 pub fn get_ssid_psk_from_nvs(
     app_config: &crate::Config,
     nvs: &nvs::EspNvs<nvs::NvsDefault>,
     force_setup: bool,
 ) -> Result<(String, String, String, bool), EspError> {
     let mut setup_mode = force_setup;
-    let wifi_ssid =
-        match crate::nvs::read_str_from_nvs(nvs, "wifi_ssid").and_then(non_empty_string_or_fail) {
-            Ok(ssid) => ssid,
-            Err(_) => {
-                setup_mode = true;
-                app_config.wifi_ssid.to_string()
-            }
-        };
-    let wifi_psk =
-        match crate::nvs::read_str_from_nvs(nvs, "wifi_psk").and_then(non_empty_string_or_fail) {
-            Ok(psk) => psk,
-            Err(_) => {
-                setup_mode = true;
-                app_config.wifi_psk.to_string()
-            }
-        };
+    let wifi_ssid = match crate::nvs::read_str_from_nvs(nvs, "wifi_ssid")
+        .or_else(|_| non_empty_string_or_fail(app_config.wifi_ssid.to_string()))
+        .and_then(non_empty_string_or_fail)
+    {
+        Ok(ssid) => ssid,
+        Err(_) => {
+            setup_mode = true;
+            String::new()
+        }
+    };
+    // An empty PSK is valid for open networks, so only the SSID decides
+    // whether we have usable credentials.
+    let wifi_psk = match crate::nvs::read_str_from_nvs(nvs, "wifi_psk") {
+        Ok(psk) => psk,
+        Err(_) => app_config.wifi_psk.to_string(),
+    };
     let hostname =
         match crate::nvs::read_str_from_nvs(nvs, "hostname").and_then(non_empty_string_or_fail) {
             Ok(hostname) => hostname,
             Err(_) => app_config.default_hostname.to_string(),
         };
 
-    if setup_mode {
-        Ok((
-            app_config.wifi_ssid.to_string(),
-            app_config.wifi_psk.to_string(),
-            hostname,
-            true,
-        ))
-    } else {
-        Ok((wifi_ssid, wifi_psk, hostname, setup_mode))
-    }
+    Ok((wifi_ssid, wifi_psk, hostname, setup_mode))
 }
 
-pub fn render_wifi_config(
-    app_config: &crate::Config,
-    ssid: String,
-    psk: String,
-    setup_mode: bool,
-) -> wifi::Configuration {
+pub fn render_wifi_config(ssid: String, psk: String, setup_mode: bool) -> wifi::Configuration {
+    let (bssid, channel) = pinning::for_network(&ssid);
     if setup_mode {
         wifi::Configuration::Mixed(
             ClientConfiguration {
                 ssid: heapless::String::try_from(ssid.as_str()).expect("SSID too long"),
                 password: heapless::String::try_from(psk.as_str()).expect("Password too long"),
+                auth_method: security::auth_method(&psk),
+                bssid,
+                channel,
                 ..Default::default()
             },
             AccessPointConfiguration {
-                ssid: heapless::String::try_from(app_config.wifi_ssid).expect("SSID too long"),
-                password: heapless::String::try_from(app_config.wifi_psk)
-                    .expect("Password too long"),
-                auth_method: wifi::AuthMethod::WPA2Personal,
+                ssid: heapless::String::try_from(setup_ap::ssid()).expect("SSID too long"),
+                password: heapless::String::try_from(setup_ap::psk()).expect("Password too long"),
+                auth_method: if setup_ap::psk().is_empty() {
+                    wifi::AuthMethod::None
+                } else {
+                    wifi::AuthMethod::WPA2Personal
+                },
                 ..Default::default()
             },
         )
@@ -97,6 +112,9 @@ pub fn render_wifi_config(
         wifi::Configuration::Client(ClientConfiguration {
             ssid: heapless::String::try_from(ssid.as_str()).expect("SSID too long"),
             password: heapless::String::try_from(psk.as_str()).expect("Password too long"),
+            auth_method: security::auth_method(&psk),
+            bssid,
+            channel,
             ..Default::default()
         })
     }
@@ -114,12 +132,18 @@ pub fn setup_wifi<'d, M: WifiModemPeripheral>(
 ) -> Result<EspWifi<'d>, EspError> {
     let mut wifi = EspWifi::new(modem, sysloop.clone(), Some(nvs.clone()))?;
 
-    let wifi_config = render_wifi_config(app_config, ssid, psk, setup_mode);
+    let wifi_config = render_wifi_config(ssid, psk.clone(), setup_mode);
     {
         set_wifi_hostname_once(hostname, &wifi);
+        if let Err(err) = static_ip::apply(&wifi) {
+            log::warn!("Could not set the static IP, using DHCP: {}", err);
+        }
         if let Err(err) = wifi.set_configuration(&wifi_config) {
             log::info!("Wifi not started, error={}, starting now", err);
         }
+        if let Err(err) = enterprise::apply(&psk) {
+            log::warn!("Could not set the WPA2-Enterprise credentials: {}", err);
+        }
         wifi.start()?;
         let raw_handle = wifi.sta_netif().handle();
         log::info!("Setting hostname to {}!", app_config.default_hostname);
@@ -134,7 +158,6 @@ pub fn setup_wifi<'d, M: WifiModemPeripheral>(
 }
 
 pub fn reset_wifi<'a>(
-    app_config: &crate::Config,
     wifi: &Arc<Mutex<EspWifi<'a>>>,
     ssid: String,
     psk: String,
@@ -147,11 +170,17 @@ pub fn reset_wifi<'a>(
             let _ = wifi.stop();
 
             // Reset configuration
-            let wifi_config = render_wifi_config(app_config, ssid, psk, setup_mode);
+            let wifi_config = render_wifi_config(ssid, psk.clone(), setup_mode);
             {
+                if let Err(err) = static_ip::apply(&wifi) {
+                    log::warn!("Could not set the static IP, using DHCP: {}", err);
+                }
                 if let Err(err) = wifi.set_configuration(&wifi_config) {
                     log::info!("Wifi not started, error={}, starting now", err);
                 }
+                if let Err(err) = enterprise::apply(&psk) {
+                    log::warn!("Could not set the WPA2-Enterprise credentials: {}", err);
+                }
                 wifi.start()?;
                 if !setup_mode {
                     wifi.connect()?;
@@ -171,11 +200,7 @@ pub fn reset_wifi<'a>(
 pub async fn wifi_handle_task(
     app_config: crate::Config,
     nvs: nvs::EspNvsPartition<nvs::NvsDefault>,
-    global_state: impl AsGlobalState<
-            'static,
-            ssd1306::prelude::I2CInterface<esp_idf_svc::hal::i2c::I2cDriver<'static>>,
-            DisplaySize128x32,
-        > + 'static,
+    global_state: impl AsGlobalState<'static, crate::display::AppDisplay<'static>> + 'static,
 ) -> ! {
     loop {
         let _ = wifi_handle_task_worker(&app_config, &nvs, &global_state).await;
@@ -185,11 +210,7 @@ pub async fn wifi_handle_task(
 pub async fn wifi_handle_task_worker(
     app_config: &crate::Config,
     nvs: &nvs::EspNvsPartition<nvs::NvsDefault>,
-    global_state: &impl AsGlobalState<
-        'static,
-        ssd1306::prelude::I2CInterface<esp_idf_svc::hal::i2c::I2cDriver<'static>>,
-        DisplaySize128x32,
-    >,
+    global_state: &impl AsGlobalState<'static, crate::display::AppDisplay<'static>>,
 ) -> Result<(), EspError> {
     let mut seconds_disconnected = 0;
     let global_state = global_state.as_global_state();
@@ -200,10 +221,9 @@ pub async fn wifi_handle_task_worker(
             &nvs_partition,
             *global_state.setup_mode.lock().unwrap(),
         )?;
-        let wifi_config =
-            render_wifi_config(app_config, ssid.clone(), psk.clone(), rendered_setup_mode);
+        let wifi_config = render_wifi_config(ssid.clone(), psk.clone(), rendered_setup_mode);
         if let Ok(mut wifi) = global_state.wifi.lock() {
-            set_wifi_hostname_once(hostname, &wifi);
+            set_wifi_hostname_once(hostname.clone(), &wifi);
             if let Err(err) = wifi.set_configuration(&wifi_config) {
                 log::info!("Wifi not started, error={}, starting now", err);
             }
@@ -229,10 +249,17 @@ pub async fn wifi_handle_task_worker(
                         *setup_mode = true;
                         // Release the lock early since we don't really need it anymore
                         drop(setup_mode);
-                        reset_wifi(app_config, &global_state.wifi, ssid, psk, true)?;
+                        reset_wifi(&global_state.wifi, ssid, psk, true)?;
                     }
                 } else {
                     seconds_disconnected = 0;
+                    if global_state
+                        .wifi
+                        .get_client_ip()
+                        .is_ok_and(|ip| !ip.is_unspecified())
+                    {
+                        mdns::advertise(&hostname);
+                    }
                 }
             }
         }
@@ -288,6 +315,14 @@ pub fn get_client_ip(wifi: &EspWifi) -> Result<Ipv4Addr, EspError> {
     wifi.sta_netif().get_ip_info().map(|info| info.ip)
 }
 
+/// Signal strength of the AP we are associated to, if any.
+pub fn get_rssi() -> Option<i8> {
+    // Safe: the record is plain data and only read if the call succeeded
+    let mut info: esp_idf_svc::sys::wifi_ap_record_t = unsafe { core::mem::zeroed() };
+    let err = unsafe { esp_idf_svc::sys::esp_wifi_sta_get_ap_info(&mut info) };
+    (err == esp_idf_svc::sys::ESP_OK).then_some(info.rssi)
+}
+
 impl<'d> AppWifi for Arc<Mutex<EspWifi<'d>>> {
     fn connect(&self) -> Result<(), EspError> {
         match self.try_lock() {
@@ -319,43 +354,194 @@ impl<'d> AppWifi for Arc<Mutex<EspWifi<'d>>> {
     }
 }
 
-pub fn send_webhook<'a>(
+/// Send readings to the webhook and return the HTTP status of the response,
+/// and the start of its body if `read_answer`. With batches enabled the body
+/// holds several of them, otherwise `readings` holds a single one.
+pub fn send_webhook(
+    app_config: &crate::Config,
     webhook_url: &String,
-    wifi: &EspWifi<'a>,
-    amps: f32,
-    watts: f32,
-) -> anyhow::Result<usize> {
-    if !wifi.is_connected()? {
+    readings: &[crate::template::Context],
+    read_answer: bool,
+) -> anyhow::Result<(u16, String)> {
+    // Fill in placeholders such as {{amps}} or {{watts|round:1}}, those of
+    // the URL from the latest reading
+    let Some(context) = readings.last() else {
         return Err(EspError::from_non_zero(
-            core::num::NonZeroI32::new(esp_idf_svc::sys::ESP_ERR_WIFI_NOT_CONNECT).unwrap(),
+            core::num::NonZeroI32::new(esp_idf_svc::sys::ESP_ERR_INVALID_ARG).unwrap(),
         )
         .into());
-    }
-    log::info!("Sending webhook to {}", webhook_url);
+    };
+    let device = crate::template::Device {
+        hostname: crate::http_server::CURRENT_KNOWN_HOSTNAME
+            .lock()
+            .unwrap()
+            .clone(),
+        rssi: get_rssi(),
+    };
+    let webhook_url = crate::template::render(webhook_url, context, &device);
 
-    let datum = format!("{{\"amps\":{:.5},\"watts\":{:.5}}}", amps, watts);
+    let body_template = crate::body::template();
+    let method = crate::body::method();
+    let content_type = crate::body::content_type();
+    let form = crate::body::is_form();
+    let senml = body_template.is_empty() && crate::body::is_senml();
+    // A batch is a single SenML pack with the records of every reading, a
+    // JSON array, repeated form fields, or one body per line
+    let datum = if senml {
+        crate::senml::pack(readings)
+    } else {
+        let mut bodies = Vec::with_capacity(readings.len());
+        for reading in readings {
+            bodies.push(if !body_template.is_empty() {
+                crate::template::render(&body_template, reading, &device)
+            } else if form {
+                form_body(reading, &device)
+            } else {
+                json_body(reading, &device)?
+            });
+        }
+        if !crate::batch::is_enabled() {
+            bodies.join("")
+        } else if form {
+            bodies.join("&")
+        } else if content_type.contains("json") {
+            format!("[{}]", bodies.join(","))
+        } else {
+            bodies.join("\n")
+        }
+    };
+    let webhook_url = if method == crate::body::Method::Get && !datum.is_empty() {
+        let separator = if webhook_url.contains('?') { '&' } else { '?' };
+        format!("{}{}{}", webhook_url, separator, datum)
+    } else {
+        webhook_url
+    };
 
-    // Create HTTPS Connection Handle
-    let httpconnection = http::client::EspHttpConnection::new(&http::client::Configuration {
-        use_global_ca_store: true,
-        crt_bundle_attach: Some(hal::sys::esp_crt_bundle_attach),
-        ..Default::default()
-    })?;
+    // Bail out early rather than letting the TLS stack block when offline
+    preflight::check(&webhook_url, app_config.preflight_tcp)?;
+    log::info!("Sending webhook to {}", webhook_url);
+
+    // Create HTTPS Connection Handle, trusting the installed certificate
+    // rather than the bundled CAs if there is one
+    let configuration = match crate::tls::WEBHOOK_CA.x509() {
+        Some(certificate) => http::client::Configuration {
+            server_certificate: Some(certificate),
+            ..Default::default()
+        },
+        None => http::client::Configuration {
+            use_global_ca_store: true,
+            crt_bundle_attach: Some(hal::sys::esp_crt_bundle_attach),
+            ..Default::default()
+        },
+    };
+    let httpconnection = http::client::EspHttpConnection::new(&configuration)?;
     let mut client = embedded_svc::http::client::Client::wrap(httpconnection);
 
-    // If the URL contains {{amps}}, replace it with the actual amps
-    let webhook_url = webhook_url.clone().replace("{{amps}}", &amps.to_string());
+    // Send the request, with the extra headers from the setup page. A GET
+    // carries the body in its query string instead.
+    let content_length = datum.len().to_string();
+    let extra_headers = crate::headers::headers();
+    let mut headers = Vec::new();
+    if method != crate::body::Method::Get {
+        headers.push(("Content-Type", content_type.as_str()));
+        headers.push(("Content-Length", content_length.as_str()));
+    }
+    headers.extend(
+        extra_headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str())),
+    );
+    let http_method = match method {
+        crate::body::Method::Post => embedded_svc::http::Method::Post,
+        crate::body::Method::Put => embedded_svc::http::Method::Put,
+        crate::body::Method::Get => embedded_svc::http::Method::Get,
+    };
+    let mut request = client.request(http_method, &webhook_url, &headers)?;
+    if method != crate::body::Method::Get {
+        request.write_all(datum.as_bytes())?;
+    }
+    let mut response = request.submit()?;
 
-    // Send POST Request
-    let response = client
-        .post(
-            &webhook_url,
-            &[
-                ("Content-Type", "application/json"),
-                ("Content-Length", &datum.len().to_string()),
-            ],
-        )?
-        .write(datum.as_bytes())?;
+    let mut answer = Vec::new();
+    if read_answer {
+        let mut buf = [0u8; 256];
+        while answer.len() < crate::delivery::MAX_RESPONSE_LEN {
+            let read = response.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            answer.extend_from_slice(&buf[..read]);
+        }
+        answer.truncate(crate::delivery::MAX_RESPONSE_LEN);
+    }
+
+    Ok((
+        response.status(),
+        String::from_utf8_lossy(&answer).into_owned(),
+    ))
+}
+
+/// The firmware's own webhook body as form data, for form content types and
+/// `GET`.
+fn form_body(context: &crate::template::Context, device: &crate::template::Device) -> String {
+    let mut datum = crate::metrics::form_labels();
+    for (key, label) in [
+        ("device_name", crate::labels::device_name()),
+        ("channel_label", crate::labels::channel_label()),
+    ] {
+        if !label.is_empty() {
+            datum.push_str(&format!("&{}={}", key, crate::form::percent_encode(&label)));
+        }
+    }
+    for (metric, value) in [
+        (crate::metrics::CURRENT, context.amps.0 as f64),
+        (crate::metrics::POWER, context.watts.0 as f64),
+        (crate::metrics::ENERGY_TOTAL, context.energy.0),
+    ] {
+        datum.push('&');
+        metric.write_form(&mut datum, value);
+    }
+    if let Some(rssi) = device.rssi {
+        datum.push('&');
+        crate::metrics::WIFI_RSSI.write_form(&mut datum, rssi.into());
+    }
+    if context.timestamp != 0 {
+        datum.push_str(&format!("&timestamp={}", context.timestamp));
+    }
+    datum
+}
 
-    Ok(response)
+/// The firmware's own webhook body, used unless the setup page sets one.
+fn json_body(
+    context: &crate::template::Context,
+    device: &crate::template::Device,
+) -> anyhow::Result<String> {
+    let mut datum = format!("{{{}", crate::metrics::json_labels());
+    for (key, label) in [
+        ("device_name", crate::labels::device_name()),
+        ("channel_label", crate::labels::channel_label()),
+    ] {
+        if !label.is_empty() {
+            datum.push_str(&format!(",\"{}\":{}", key, serde_json::to_string(&label)?));
+        }
+    }
+    for (metric, value) in [
+        (crate::metrics::CURRENT, context.amps.0 as f64),
+        (crate::metrics::POWER, context.watts.0 as f64),
+        (crate::metrics::ENERGY_TOTAL, context.energy.0),
+    ] {
+        datum.push(',');
+        metric.write_json(&mut datum, value);
+    }
+    if let Some(rssi) = device.rssi {
+        datum.push(',');
+        crate::metrics::WIFI_RSSI.write_json(&mut datum, rssi.into());
+    }
+    // Spooled readings are delivered late, so tell the collector when they
+    // were taken
+    if context.timestamp != 0 {
+        datum.push_str(&format!(",\"timestamp\":{}", context.timestamp));
+    }
+    datum.push('}');
+    Ok(datum)
 }
@@ -0,0 +1,148 @@
+//! Other Wi-Fi networks the station may join besides the one on top of the
+//! setup page, e.g. the access point in the shed and the one in the house.
+//! At boot, and when the network in use has been down for a while, the
+//! device scans and joins the strongest known network in range. Setup mode
+//! only comes up once every one in range was tried without success.
+//!
+//! The first network keeps `wifi_ssid` and `wifi_psk`; up to
+//! `MAX_NETWORKS - 1` more are kept as `wifi_ssid_<n>` and `wifi_psk_<n>`.
+
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::sys::EspError;
+use esp_idf_svc::wifi::EspWifi;
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+
+/// Known networks, the first one included.
+pub const MAX_NETWORKS: usize = 4;
+pub const NVS_SSIDS: [&str; MAX_NETWORKS - 1] = ["wifi_ssid_1", "wifi_ssid_2", "wifi_ssid_3"];
+pub const NVS_PSKS: [&str; MAX_NETWORKS - 1] = ["wifi_psk_1", "wifi_psk_2", "wifi_psk_3"];
+
+/// The networks after the first, one per slot, `None` for empty slots.
+static EXTRA: Mutex<[Option<Network>; MAX_NETWORKS - 1]> = Mutex::new([None, None, None]);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Network {
+    pub ssid: String,
+    pub psk: String,
+}
+
+/// Whether `ssid` fits in the Wi-Fi configuration. Empty clears the slot.
+pub fn is_valid_ssid(ssid: &str) -> bool {
+    ssid.len() <= 32
+}
+
+/// Whether `psk` can be saved, 8 to 63 characters like the first network's.
+pub fn is_valid_psk(psk: &str) -> bool {
+    (8..=63).contains(&psk.len())
+}
+
+/// Read the networks after the first from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let mut extra = EXTRA.lock().unwrap();
+    for (slot, (ssid_key, psk_key)) in extra.iter_mut().zip(NVS_SSIDS.iter().zip(NVS_PSKS)) {
+        let ssid = read_str_from_nvs_or_default(nvs, ssid_key, "");
+        let psk = read_str_from_nvs_or_default(nvs, psk_key, "");
+        *slot = (!ssid.is_empty() && is_valid_ssid(&ssid) && is_valid_psk(&psk))
+            .then_some(Network { ssid, psk });
+    }
+}
+
+/// The SSID in slot `index` of the networks after the first, for the setup
+/// page. Passwords are never shown.
+pub fn ssid(index: usize) -> String {
+    EXTRA.lock().unwrap()[index]
+        .as_ref()
+        .map_or(String::new(), |network| network.ssid.clone())
+}
+
+/// Every known network, `first` and then the others, each SSID once.
+pub fn known(first: &str, first_psk: &str) -> Vec<Network> {
+    let mut networks = vec![Network {
+        ssid: first.to_string(),
+        psk: first_psk.to_string(),
+    }];
+    for network in EXTRA.lock().unwrap().iter().flatten() {
+        if !networks.iter().any(|known| known.ssid == network.ssid) {
+            networks.push(network.clone());
+        }
+    }
+    networks
+}
+
+/// Picks the network to join among the known ones.
+#[derive(Debug, Default)]
+pub struct Failover {
+    known: Vec<Network>,
+    /// SSIDs tried since the last connection.
+    tried: Vec<String>,
+}
+
+impl Failover {
+    pub fn new(known: Vec<Network>) -> Self {
+        Failover {
+            known,
+            tried: Vec::new(),
+        }
+    }
+
+    /// Whether there is more than one network to choose from.
+    pub fn has_choice(&self) -> bool {
+        self.known.len() > 1
+    }
+
+    /// Call while connected, so every network gets tried again next time.
+    pub fn connected(&mut self) {
+        self.tried.clear();
+    }
+
+    /// Give up on `current` and scan for the strongest known network in
+    /// range not tried since the last connection.
+    fn next(&mut self, wifi: &mut EspWifi, current: &str) -> Option<Network> {
+        if !current.is_empty() && !self.tried.iter().any(|ssid| ssid == current) {
+            self.tried.push(current.to_string());
+        }
+        // Scanning fails while the station is trying to connect
+        let _ = wifi.disconnect();
+        let mut access_points = match wifi.scan() {
+            Ok(access_points) => access_points,
+            Err(err) => {
+                log::warn!("Could not scan for known Wi-Fi networks: {:?}", err);
+                return None;
+            }
+        };
+        access_points.sort_by_key(|ap| -(ap.signal_strength as i16));
+        let network = access_points
+            .iter()
+            .find_map(|ap| {
+                self.known.iter().find(|known| {
+                    known.ssid == ap.ssid.as_str() && !self.tried.contains(&known.ssid)
+                })
+            })
+            .cloned()?;
+        self.tried.push(network.ssid.clone());
+        Some(network)
+    }
+
+    /// Move the station to the strongest known network in range not tried
+    /// since the last connection, and return it. `None` when there is none
+    /// left, with the station disconnected.
+    pub fn switch(
+        &mut self,
+        wifi: &Arc<Mutex<EspWifi<'_>>>,
+        current: &str,
+    ) -> Result<Option<Network>, EspError> {
+        let next = match wifi.lock() {
+            Ok(mut wifi) => self.next(&mut wifi, current),
+            Err(_) => None,
+        };
+        let Some(network) = next else {
+            return Ok(None);
+        };
+        log::info!("Joining the Wi-Fi network {:?}", network.ssid);
+        super::reset_wifi(wifi, network.ssid.clone(), network.psk.clone(), false)?;
+        Ok(Some(network))
+    }
+}
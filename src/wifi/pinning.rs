@@ -0,0 +1,103 @@
+//! Keeping the station on one access point of the first network, for mesh
+//! and multi-AP networks where it otherwise associates to a far one with a
+//! poor signal. The BSSID picks the access point and the channel saves
+//! scanning the others; either can be left empty.
+//!
+//! Only the first network is pinned, the others in `networks` are joined
+//! through whichever access point answers.
+
+use std::fmt::Write as _;
+use std::sync::Mutex;
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+
+pub const NVS_WIFI_BSSID: &str = "wifi_bssid";
+pub const NVS_WIFI_CHANNEL: &str = "wifi_channel";
+
+static PIN: Mutex<Pin> = Mutex::new(Pin {
+    ssid: String::new(),
+    bssid: None,
+    channel: None,
+});
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Pin {
+    /// The first network's, the one pinned.
+    ssid: String,
+    bssid: Option<[u8; 6]>,
+    channel: Option<u8>,
+}
+
+/// Parse a BSSID written as `aa:bb:cc:dd:ee:ff`, or with dashes.
+pub fn parse_bssid(value: &str) -> Option<[u8; 6]> {
+    let mut bssid = [0; 6];
+    let mut octets = value.trim().split([':', '-']);
+    for octet in bssid.iter_mut() {
+        let hex = octets.next()?;
+        if hex.len() != 2 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return None;
+        }
+        *octet = u8::from_str_radix(hex, 16).ok()?;
+    }
+    octets.next().is_none().then_some(bssid)
+}
+
+pub fn format_bssid(bssid: &[u8; 6]) -> String {
+    let mut text = String::new();
+    for (index, octet) in bssid.iter().enumerate() {
+        let separator = if index == 0 { "" } else { ":" };
+        write!(text, "{}{:02x}", separator, octet).unwrap();
+    }
+    text
+}
+
+/// Parse a 2.4 GHz channel, 1 to 14.
+pub fn parse_channel(value: &str) -> Option<u8> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|channel| (1..=14).contains(channel))
+}
+
+/// Whether `value` can be saved as the BSSID: empty for any access point.
+pub fn is_valid_bssid(value: &str) -> bool {
+    value.trim().is_empty() || parse_bssid(value).is_some()
+}
+
+/// Whether `value` can be saved as the channel: empty for any.
+pub fn is_valid_channel(value: &str) -> bool {
+    value.trim().is_empty() || parse_channel(value).is_some()
+}
+
+/// Read the pinned access point from NVS, along with the network it
+/// belongs to.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let read = |key| read_str_from_nvs_or_default(nvs, key, "");
+    *PIN.lock().unwrap() = Pin {
+        ssid: read("wifi_ssid"),
+        bssid: parse_bssid(&read(NVS_WIFI_BSSID)),
+        channel: parse_channel(&read(NVS_WIFI_CHANNEL)),
+    };
+}
+
+/// The pinned BSSID, for the setup page.
+pub fn bssid() -> Option<[u8; 6]> {
+    PIN.lock().unwrap().bssid
+}
+
+/// The pinned channel, for the setup page.
+pub fn channel() -> Option<u8> {
+    PIN.lock().unwrap().channel
+}
+
+/// The BSSID and channel to join `ssid` on, `None` for any.
+pub fn for_network(ssid: &str) -> (Option<[u8; 6]>, Option<u8>) {
+    let pin = PIN.lock().unwrap();
+    if pin.ssid == ssid {
+        (pin.bssid, pin.channel)
+    } else {
+        (None, None)
+    }
+}
@@ -0,0 +1,140 @@
+//! Cheap connectivity checks run before talking to a remote endpoint.
+//!
+//! When the collector is unreachable, the TLS stack can block for many
+//! seconds before giving up, stalling the main loop and the display. A DNS
+//! lookup bounded by a timeout (and optionally a plain TCP connect) fails in
+//! a fraction of that, so the send can be skipped early instead.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+
+const DNS_TIMEOUT: Duration = Duration::from_secs(2);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// getaddrinfo needs a bit more than the default pthread stack on lwIP
+const RESOLVER_STACK_SIZE: usize = 4096;
+
+/// A host and port to look up, and where to send the answer.
+type Lookup = (String, u16, mpsc::Sender<Option<SocketAddr>>);
+
+/// Lookups for the resolver thread, started on the first one.
+static RESOLVER: OnceCell<Mutex<mpsc::SyncSender<Lookup>>> = OnceCell::new();
+
+#[derive(Debug)]
+pub enum PreflightError {
+    InvalidUrl,
+    Dns(String),
+    DnsTimeout(String),
+    Connect(SocketAddr, std::io::Error),
+}
+
+impl fmt::Display for PreflightError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreflightError::InvalidUrl => write!(f, "cannot find a host in the URL"),
+            PreflightError::Dns(host) => write!(f, "cannot resolve {}", host),
+            PreflightError::DnsTimeout(host) => write!(f, "timed out resolving {}", host),
+            PreflightError::Connect(addr, err) => write!(f, "cannot connect to {}: {}", addr, err),
+        }
+    }
+}
+
+impl std::error::Error for PreflightError {}
+
+/// Extract the host and port of an `http://` or `https://` URL, using the
+/// scheme's default port when none is given.
+pub fn host_and_port(url: &str) -> Option<(&str, u16)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let default_port = match scheme.to_ascii_lowercase().as_str() {
+        "http" => 80,
+        "https" => 443,
+        _ => return None,
+    };
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+
+    let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+        // IPv6 literal, e.g. [::1]:8080
+        let (host, after) = bracketed.split_once(']')?;
+        (host, after.strip_prefix(':'))
+    } else {
+        match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port,
+    };
+    (!host.is_empty()).then_some((host, port))
+}
+
+fn resolver() -> std::io::Result<&'static Mutex<mpsc::SyncSender<Lookup>>> {
+    RESOLVER.get_or_try_init(|| {
+        // Room for one lookup waiting behind the one in flight
+        let (tx, rx) = mpsc::sync_channel::<Lookup>(1);
+        std::thread::Builder::new()
+            .stack_size(RESOLVER_STACK_SIZE)
+            .spawn(move || {
+                for (host, port, reply) in rx {
+                    let addr = (host.as_str(), port)
+                        .to_socket_addrs()
+                        .ok()
+                        .and_then(|mut addrs| addrs.next());
+                    // The receiver is gone if the caller already timed out
+                    let _ = reply.send(addr);
+                }
+            })?;
+        Ok(Mutex::new(tx))
+    })
+}
+
+/// Resolve `host`, giving up after `timeout`.
+///
+/// The lookup runs on a resolver thread of its own since lwIP offers no way
+/// to bound it; on timeout it is left to finish there. While that thread is
+/// still stuck on an earlier lookup, new ones time out right away instead
+/// of piling up.
+pub fn resolve(host: &str, port: u16, timeout: Duration) -> Result<SocketAddr, PreflightError> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    let resolver = resolver().map_err(|_| PreflightError::Dns(host.to_string()))?;
+    let (tx, rx) = mpsc::channel();
+    let lookup = (host.to_string(), port, tx);
+    match resolver.lock().unwrap().try_send(lookup) {
+        Ok(()) => (),
+        Err(mpsc::TrySendError::Full(_)) => {
+            return Err(PreflightError::DnsTimeout(host.to_string()))
+        }
+        Err(mpsc::TrySendError::Disconnected(_)) => {
+            return Err(PreflightError::Dns(host.to_string()))
+        }
+    }
+
+    match rx.recv_timeout(timeout) {
+        Ok(Some(addr)) => Ok(addr),
+        Ok(None) => Err(PreflightError::Dns(host.to_string())),
+        Err(_) => Err(PreflightError::DnsTimeout(host.to_string())),
+    }
+}
+
+/// Check that the host in `url` resolves and, if `tcp_probe` is set, that
+/// it accepts TCP connections.
+pub fn check(url: &str, tcp_probe: bool) -> Result<(), PreflightError> {
+    let (host, port) = host_and_port(url).ok_or(PreflightError::InvalidUrl)?;
+    let addr = resolve(host, port, DNS_TIMEOUT)?;
+    if tcp_probe {
+        TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+            .map_err(|err| PreflightError::Connect(addr, err))?;
+    }
+    Ok(())
+}
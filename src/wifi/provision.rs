@@ -0,0 +1,141 @@
+//! Trying the credentials saved from the setup page while the access point is
+//! still up, so the page can tell the person at the panel whether they work
+//! instead of leaving them to guess when the AP disappears.
+
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+use esp_idf_svc::wifi::{Configuration, EspWifi};
+use once_cell::sync::Lazy;
+
+/// How long to wait for the station to get an address.
+const CONNECT_TIMEOUT_S: u64 = 20;
+/// How long to keep the AP up after connecting, so the page gets to see it.
+const LINGER_S: u64 = 5;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ProvisionStatus {
+    #[default]
+    Idle,
+    /// Asked for by the setup page, picked up by the main loop.
+    Requested {
+        ssid: String,
+        psk: String,
+    },
+    Trying {
+        ssid: String,
+        since_s: u64,
+    },
+    Connected {
+        ip: Ipv4Addr,
+        since_s: u64,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+pub(crate) static PROVISION_STATUS: Lazy<Mutex<ProvisionStatus>> =
+    Lazy::new(|| Mutex::new(ProvisionStatus::default()));
+
+impl ProvisionStatus {
+    /// Body of `/api/v1/provision_status`.
+    pub fn to_json(&self) -> String {
+        match self {
+            ProvisionStatus::Idle => "{\"state\":\"idle\"}".to_string(),
+            ProvisionStatus::Requested { .. } | ProvisionStatus::Trying { .. } => {
+                "{\"state\":\"trying\"}".to_string()
+            }
+            ProvisionStatus::Connected { ip, .. } => {
+                format!("{{\"state\":\"connected\",\"ip\":\"{}\"}}", ip)
+            }
+            ProvisionStatus::Failed { reason } => format!(
+                "{{\"state\":\"failed\",\"reason\":\"{}\"}}",
+                reason.replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+        }
+    }
+}
+
+/// Ask the main loop to try the credentials just saved to NVS.
+pub fn request(ssid: String, psk: String) {
+    *PROVISION_STATUS.lock().unwrap() = ProvisionStatus::Requested { ssid, psk };
+}
+
+/// Point the station at the new network, leaving the AP as it is.
+fn configure_station(wifi: &mut EspWifi, ssid: &str, psk: &str) -> Result<(), String> {
+    let (mut client, ap) = match wifi.get_configuration() {
+        Ok(Configuration::Mixed(client, ap)) => (client, ap),
+        Ok(_) => return Err("Not in setup mode".to_string()),
+        Err(err) => return Err(err.to_string()),
+    };
+    client.ssid = heapless::String::try_from(ssid).map_err(|_| "SSID too long".to_string())?;
+    client.password =
+        heapless::String::try_from(psk).map_err(|_| "Password too long".to_string())?;
+    client.auth_method = super::security::auth_method(psk);
+    (client.bssid, client.channel) = super::pinning::for_network(ssid);
+    wifi.set_configuration(&Configuration::Mixed(client, ap))
+        .and_then(|()| super::enterprise::apply(psk))
+        .and_then(|()| wifi.connect())
+        .map_err(|err| err.to_string())
+}
+
+/// Advance a connection test, if any, from the setup mode loop. Returns
+/// whether the test succeeded and setup mode can be left.
+pub fn tick(wifi: &mut EspWifi, now_s: u64) -> bool {
+    let mut status = PROVISION_STATUS.lock().unwrap();
+    let next = match &*status {
+        ProvisionStatus::Idle | ProvisionStatus::Failed { .. } => return false,
+        ProvisionStatus::Requested { ssid, psk } => {
+            log::info!("Trying to connect to {:?}", ssid);
+            match configure_station(wifi, ssid, psk) {
+                Ok(()) => ProvisionStatus::Trying {
+                    ssid: ssid.clone(),
+                    since_s: now_s,
+                },
+                Err(reason) => ProvisionStatus::Failed { reason },
+            }
+        }
+        ProvisionStatus::Trying { ssid, since_s } => {
+            if wifi.is_up().unwrap_or(false) {
+                match wifi.sta_netif().get_ip_info() {
+                    Ok(info) => ProvisionStatus::Connected {
+                        ip: info.ip,
+                        since_s: now_s,
+                    },
+                    Err(err) => ProvisionStatus::Failed {
+                        reason: format!("Connected but got no address: {}", err),
+                    },
+                }
+            } else if now_s.saturating_sub(*since_s) >= CONNECT_TIMEOUT_S {
+                let _ = wifi.disconnect();
+                ProvisionStatus::Failed {
+                    reason: failure_reason(wifi, ssid),
+                }
+            } else {
+                return false;
+            }
+        }
+        ProvisionStatus::Connected { since_s, .. } => {
+            if now_s.saturating_sub(*since_s) < LINGER_S {
+                return false;
+            }
+            *status = ProvisionStatus::Idle;
+            return true;
+        }
+    };
+    log::info!("Wi-Fi connection test: {}", next.to_json());
+    *status = next;
+    false
+}
+
+/// Tell a network out of range from a wrong password with a scan.
+fn failure_reason(wifi: &mut EspWifi, ssid: &str) -> String {
+    match wifi.scan() {
+        Ok(networks) if !networks.iter().any(|network| network.ssid == ssid) => {
+            format!("Network \"{}\" not found", ssid)
+        }
+        Ok(_) => format!("Could not join \"{}\", check the password", ssid),
+        Err(_) => format!("Could not join \"{}\" in {}s", ssid, CONNECT_TIMEOUT_S),
+    }
+}
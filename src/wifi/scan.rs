@@ -0,0 +1,57 @@
+//! Networks in range, for the setup page to offer instead of having the SSID
+//! typed in, which is where most failed setups came from.
+
+use esp_idf_svc::sys::EspError;
+use esp_idf_svc::wifi::{AccessPointInfo, AuthMethod, EspWifi};
+use serde::Serialize;
+
+/// Element of the `/api/scan` body.
+#[derive(Serialize)]
+struct Network {
+    ssid: String,
+    rssi: i8,
+    auth: &'static str,
+    /// The strongest access point's, to pin it.
+    bssid: String,
+    channel: u8,
+}
+
+fn auth_name(auth_method: Option<AuthMethod>) -> &'static str {
+    match auth_method {
+        None | Some(AuthMethod::None) => "open",
+        Some(AuthMethod::WEP) => "wep",
+        Some(AuthMethod::WPA) => "wpa",
+        Some(AuthMethod::WPA2Personal) | Some(AuthMethod::WPAWPA2Personal) => "wpa2",
+        Some(AuthMethod::WPA2Enterprise) => "wpa2-enterprise",
+        Some(AuthMethod::WPA3Personal) | Some(AuthMethod::WPA2WPA3Personal) => "wpa3",
+        Some(AuthMethod::WAPIPersonal) => "wapi",
+    }
+}
+
+/// Networks from `access_points`, strongest first, each SSID once and hidden
+/// ones left out.
+fn networks(mut access_points: Vec<AccessPointInfo>) -> Vec<Network> {
+    access_points.sort_by_key(|ap| -(ap.signal_strength as i16));
+    let mut networks: Vec<Network> = Vec::new();
+    for ap in access_points {
+        if ap.ssid.is_empty() || networks.iter().any(|network| network.ssid == ap.ssid) {
+            continue;
+        }
+        networks.push(Network {
+            ssid: ap.ssid.to_string(),
+            rssi: ap.signal_strength,
+            auth: auth_name(ap.auth_method),
+            bssid: super::pinning::format_bssid(&ap.bssid),
+            channel: ap.channel,
+        });
+    }
+    networks
+}
+
+/// Scan for networks, blocking for a few seconds, and return them as the
+/// JSON array `/api/scan` answers with.
+pub fn scan_json(wifi: &mut EspWifi) -> Result<String, EspError> {
+    let networks = networks(wifi.scan()?);
+    log::info!("Wi-Fi scan found {} networks", networks.len());
+    Ok(serde_json::to_string(&networks).unwrap())
+}
@@ -0,0 +1,105 @@
+//! Security the station asks of the networks it joins. With `auto` it
+//! follows the password: WPA2 or better with one, WPA2-Enterprise without
+//! one when `enterprise` has credentials, and open networks otherwise. The
+//! others name the weakest security accepted, e.g. `wpa3` for WPA3-only
+//! networks, `open` for guest networks without a password, or `wep` and
+//! `wpa` for old routers the station won't join by default.
+
+use std::sync::Mutex;
+
+use esp_idf_svc::wifi::AuthMethod;
+
+use super::enterprise;
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+
+pub const NVS_WIFI_AUTH: &str = "wifi_auth";
+
+static SECURITY: Mutex<Security> = Mutex::new(Security::Auto);
+
+/// Named like the `auth` of `/api/scan`, so a network found there picks its
+/// own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Security {
+    #[default]
+    Auto,
+    Open,
+    Wep,
+    Wpa,
+    Wpa2,
+    Wpa3,
+    Wpa2Enterprise,
+}
+
+impl Security {
+    pub const ALL: [Security; 7] = [
+        Security::Auto,
+        Security::Open,
+        Security::Wep,
+        Security::Wpa,
+        Security::Wpa2,
+        Security::Wpa3,
+        Security::Wpa2Enterprise,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Security::Auto => "auto",
+            Security::Open => "open",
+            Security::Wep => "wep",
+            Security::Wpa => "wpa",
+            Security::Wpa2 => "wpa2",
+            Security::Wpa3 => "wpa3",
+            Security::Wpa2Enterprise => "wpa2-enterprise",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Security> {
+        Security::ALL
+            .into_iter()
+            .find(|security| security.name() == name)
+    }
+
+    /// Whether `psk` can be saved for a network with this security: WEP
+    /// keys take 5 or 13 characters (10 or 26 hex digits), WPA passwords 8
+    /// to 63, and WPA2-Enterprise none.
+    pub fn is_valid_psk(self, psk: &str) -> bool {
+        match self {
+            Security::Auto => psk.is_empty() || (8..=63).contains(&psk.len()),
+            Security::Open => psk.len() <= 63,
+            Security::Wep => [5, 10, 13, 26].contains(&psk.len()),
+            Security::Wpa | Security::Wpa2 | Security::Wpa3 => (8..=63).contains(&psk.len()),
+            Security::Wpa2Enterprise => psk.is_empty(),
+        }
+    }
+}
+
+/// Whether `value` can be saved as the security.
+pub fn is_valid(value: &str) -> bool {
+    Security::from_name(value).is_some()
+}
+
+/// Read the security from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let security = read_str_from_nvs_or_default(nvs, NVS_WIFI_AUTH, "");
+    *SECURITY.lock().unwrap() = Security::from_name(&security).unwrap_or_default();
+}
+
+pub fn current() -> Security {
+    *SECURITY.lock().unwrap()
+}
+
+/// The weakest security the station accepts from the network with `psk`.
+pub fn auth_method(psk: &str) -> AuthMethod {
+    match current() {
+        Security::Auto if enterprise::is_used(psk) => AuthMethod::WPA2Enterprise,
+        Security::Auto if psk.is_empty() => AuthMethod::None,
+        Security::Auto => AuthMethod::WPA2Personal,
+        Security::Open => AuthMethod::None,
+        Security::Wep => AuthMethod::WEP,
+        Security::Wpa => AuthMethod::WPA,
+        Security::Wpa2 => AuthMethod::WPA2Personal,
+        Security::Wpa3 => AuthMethod::WPA3Personal,
+        Security::Wpa2Enterprise => AuthMethod::WPA2Enterprise,
+    }
+}
@@ -0,0 +1,82 @@
+//! Name and key of the setup access point, different for every unit so a
+//! batch of them set up side by side can be told apart and a neighbour
+//! can't join the one next door. Unless `cfg.toml` sets them, the name is
+//! the hostname followed by the end of the MAC address, e.g.
+//! `wattometer-3F2A`, and the key is generated on first boot (also when the
+//! configured one is too short for WPA2). Both are kept in NVS and shown on
+//! the display in setup mode, never logged.
+
+use esp_idf_svc::sys::EspError;
+use once_cell::sync::OnceCell;
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+
+pub const NVS_AP_SSID: &str = "ap_ssid";
+pub const NVS_AP_PSK: &str = "ap_psk";
+/// WPA2 keys take 8 to 63 characters.
+const PSK_LEN: std::ops::RangeInclusive<usize> = 8..=63;
+/// Hex digits of the MAC address in the name.
+const MAC_SUFFIX_LEN: usize = 4;
+
+static SETUP_AP: OnceCell<SetupAp> = OnceCell::new();
+
+#[derive(Clone, Debug)]
+struct SetupAp {
+    ssid: String,
+    psk: String,
+}
+
+fn generate_ssid(app_config: &crate::Config) -> String {
+    let device_id = &*crate::metrics::DEVICE_ID;
+    let suffix = device_id[device_id.len() - MAC_SUFFIX_LEN..].to_uppercase();
+    // SSIDs take up to 32 bytes
+    let hostname: String = app_config
+        .default_hostname
+        .chars()
+        .take(32 - MAC_SUFFIX_LEN - 1)
+        .collect();
+    format!("{}-{}", hostname, suffix)
+}
+
+/// Read the name and key from NVS, storing the configured or generated ones
+/// the first time. Only the first call at boot counts.
+pub fn load(
+    app_config: &crate::Config,
+    nvs: &mut nvs::EspNvs<nvs::NvsDefault>,
+) -> Result<(), EspError> {
+    let mut ssid = read_str_from_nvs_or_default(nvs, NVS_AP_SSID, "");
+    if ssid.is_empty() {
+        ssid = if app_config.ap_ssid.is_empty() {
+            generate_ssid(app_config)
+        } else {
+            app_config.ap_ssid.to_string()
+        };
+        nvs.set_str(NVS_AP_SSID, &ssid)?;
+    }
+    let mut psk = read_str_from_nvs_or_default(nvs, NVS_AP_PSK, "");
+    if !PSK_LEN.contains(&psk.len()) {
+        psk = if PSK_LEN.contains(&app_config.ap_psk.len()) {
+            app_config.ap_psk.to_string()
+        } else {
+            if !app_config.ap_psk.is_empty() {
+                log::warn!("Ignoring ap_psk, WPA2 keys take 8 to 63 characters");
+            }
+            crate::auth::generate_password()
+        };
+        nvs.set_str(NVS_AP_PSK, &psk)?;
+    }
+    log::info!("Setup access point: {}", ssid);
+    let _ = SETUP_AP.set(SetupAp { ssid, psk });
+    Ok(())
+}
+
+/// SSID broadcast by the setup access point.
+pub fn ssid() -> &'static str {
+    SETUP_AP.get().map_or("", |ap| ap.ssid.as_str())
+}
+
+/// Key of the setup access point.
+pub fn psk() -> &'static str {
+    SETUP_AP.get().map_or("", |ap| ap.psk.as_str())
+}
@@ -0,0 +1,71 @@
+//! Watching the signal of the access point, to find a place for the device
+//! (or its antenna) where the connection holds, e.g. around a metal breaker
+//! box. A warning is logged when the RSSI drops below the floor set on the
+//! setup page, and a note once it's back.
+
+use std::sync::atomic::{AtomicI8, Ordering};
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+
+pub const NVS_RSSI_FLOOR: &str = "rssi_floor";
+/// Below this connections start dropping.
+pub const DEFAULT_FLOOR_DBM: i8 = -80;
+/// Above the floor the signal must get back to, so one hovering around it
+/// warns once.
+const HYSTERESIS_DB: i8 = 3;
+
+static FLOOR: AtomicI8 = AtomicI8::new(DEFAULT_FLOOR_DBM);
+
+fn parse_floor(floor: &str) -> Option<i8> {
+    floor
+        .parse()
+        .ok()
+        .filter(|floor| (-100..=-30).contains(floor))
+}
+
+/// Whether `floor` can be saved: empty for the default, or -100 to -30 dBm.
+pub fn is_valid_floor(floor: &str) -> bool {
+    floor.is_empty() || parse_floor(floor).is_some()
+}
+
+/// Read the floor from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let floor = parse_floor(&read_str_from_nvs_or_default(nvs, NVS_RSSI_FLOOR, ""))
+        .unwrap_or(DEFAULT_FLOOR_DBM);
+    FLOOR.store(floor, Ordering::Relaxed);
+}
+
+/// Weakest signal, in dBm, not worth a warning.
+pub fn floor() -> i8 {
+    FLOOR.load(Ordering::Relaxed)
+}
+
+/// Warns about the signal dropping below the floor.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SignalWatch {
+    /// Warned about, and not back yet.
+    weak: bool,
+}
+
+impl SignalWatch {
+    pub fn new() -> Self {
+        SignalWatch::default()
+    }
+
+    /// Call every loop with the RSSI while connected.
+    pub fn update(&mut self, rssi: i8) {
+        let floor = floor();
+        if !self.weak && rssi < floor {
+            self.weak = true;
+            log::warn!(
+                "Weak Wi-Fi signal: {} dBm, below the floor of {} dBm",
+                rssi,
+                floor
+            );
+        } else if self.weak && rssi >= floor.saturating_add(HYSTERESIS_DB) {
+            self.weak = false;
+            log::info!("Wi-Fi signal back to {} dBm", rssi);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! A fixed IPv4 address for the station, for networks without DHCP or to
+//! keep the device where bookmarks and collectors expect it. Set on the setup
+//! page as the address, netmask and gateway, plus a DNS server that defaults
+//! to the gateway. Without an address DHCP is used as before.
+//!
+//! Read at boot and on leaving setup mode, and applied before the station
+//! connects.
+
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+use esp_idf_svc::handle::RawHandle as _;
+use esp_idf_svc::sys::{
+    esp, esp_ip4_addr_t, esp_netif_dhcpc_start, esp_netif_dhcpc_stop, esp_netif_dns_info_t,
+    esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN, esp_netif_ip_info_t, esp_netif_set_dns_info,
+    esp_netif_set_ip_info, EspError, ESP_ERR_ESP_NETIF_DHCP_ALREADY_STARTED,
+    ESP_ERR_ESP_NETIF_DHCP_ALREADY_STOPPED, ESP_IPADDR_TYPE_V4,
+};
+use esp_idf_svc::wifi::EspWifi;
+
+use crate::nvs;
+use crate::nvs::read_str_from_nvs_or_default;
+
+pub const NVS_IP_ADDRESS: &str = "ip_address";
+pub const NVS_IP_NETMASK: &str = "ip_netmask";
+pub const NVS_IP_GATEWAY: &str = "ip_gateway";
+/// Empty for the gateway.
+pub const NVS_IP_DNS: &str = "ip_dns";
+/// Only make sense together, see `StaticIp::parse`.
+pub const KEYS: [&str; 4] = [NVS_IP_ADDRESS, NVS_IP_NETMASK, NVS_IP_GATEWAY, NVS_IP_DNS];
+
+/// The settings last read, `None` for DHCP.
+static STATIC_IP: Mutex<Option<StaticIp>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StaticIp {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub dns: Ipv4Addr,
+}
+
+impl StaticIp {
+    /// Parse the settings as entered on the setup page. An empty address
+    /// means DHCP, whatever the other fields say.
+    pub fn parse(
+        address: &str,
+        netmask: &str,
+        gateway: &str,
+        dns: &str,
+    ) -> Result<Option<Self>, String> {
+        if address.trim().is_empty() {
+            return Ok(None);
+        }
+        let parse = |what: &str, value: &str| {
+            value
+                .trim()
+                .parse::<Ipv4Addr>()
+                .map_err(|_| format!("bad {} {:?}", what, value))
+        };
+        let gateway = parse("gateway", gateway)?;
+        let static_ip = StaticIp {
+            address: parse("address", address)?,
+            netmask: parse("netmask", netmask)?,
+            gateway,
+            dns: if dns.trim().is_empty() {
+                gateway
+            } else {
+                parse("DNS server", dns)?
+            },
+        };
+        static_ip.validate()?;
+        Ok(Some(static_ip))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let mask = u32::from(self.netmask);
+        if mask == 0 || mask.leading_ones() + mask.trailing_zeros() != 32 {
+            return Err(format!("{} is not a netmask", self.netmask));
+        }
+        let address = u32::from(self.address);
+        let host = address & !mask;
+        if self.address.is_unspecified()
+            || self.address.is_multicast()
+            || host == 0
+            || host == !mask
+        {
+            return Err(format!("{} can't be the device's address", self.address));
+        }
+        if u32::from(self.gateway) & mask != address & mask || self.gateway == self.address {
+            return Err(format!(
+                "the gateway {} is not another address on {}/{}",
+                self.gateway,
+                Ipv4Addr::from(address & mask),
+                mask.leading_ones()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Read the static IP settings from NVS.
+pub fn load(nvs: &nvs::EspNvs<nvs::NvsDefault>) {
+    let read = |key| read_str_from_nvs_or_default(nvs, key, "");
+    let static_ip = StaticIp::parse(
+        &read(NVS_IP_ADDRESS),
+        &read(NVS_IP_NETMASK),
+        &read(NVS_IP_GATEWAY),
+        &read(NVS_IP_DNS),
+    )
+    .unwrap_or_else(|reason| {
+        log::warn!("Ignoring the static IP settings, using DHCP: {}", reason);
+        None
+    });
+    *STATIC_IP.lock().unwrap() = static_ip;
+}
+
+/// The settings in use, `None` for DHCP.
+pub fn current() -> Option<StaticIp> {
+    *STATIC_IP.lock().unwrap()
+}
+
+/// An address as lwIP keeps it, in network byte order.
+fn raw(ip: Ipv4Addr) -> esp_ip4_addr_t {
+    // The ESP32 is little endian, so the first octet goes in the lowest byte
+    esp_ip4_addr_t {
+        addr: u32::from_le_bytes(ip.octets()),
+    }
+}
+
+/// Give the station the static settings instead of asking DHCP, or go back
+/// to DHCP without them. Call before connecting.
+pub fn apply(wifi: &EspWifi) -> Result<(), EspError> {
+    let raw_handle = wifi.sta_netif().handle();
+    let Some(static_ip) = current() else {
+        // Safe because sta_netif must exist (as it is safe Rust)
+        let started = unsafe { esp_netif_dhcpc_start(raw_handle) };
+        if started != ESP_ERR_ESP_NETIF_DHCP_ALREADY_STARTED as i32 {
+            esp!(started)?;
+        }
+        return Ok(());
+    };
+    log::info!(
+        "Using the static address {} (netmask {}, gateway {}, DNS {})",
+        static_ip.address,
+        static_ip.netmask,
+        static_ip.gateway,
+        static_ip.dns
+    );
+    let ip_info = esp_netif_ip_info_t {
+        ip: raw(static_ip.address),
+        netmask: raw(static_ip.netmask),
+        gw: raw(static_ip.gateway),
+    };
+    let mut dns = esp_netif_dns_info_t::default();
+    dns.ip.u_addr.ip4 = raw(static_ip.dns);
+    dns.ip.type_ = ESP_IPADDR_TYPE_V4 as _;
+    // Safe because sta_netif must exist (as it is safe Rust) and the infos
+    // are copied before returning
+    unsafe {
+        let stopped = esp_netif_dhcpc_stop(raw_handle);
+        if stopped != ESP_ERR_ESP_NETIF_DHCP_ALREADY_STOPPED as i32 {
+            esp!(stopped)?;
+        }
+        esp!(esp_netif_set_ip_info(raw_handle, &ip_info))?;
+        esp!(esp_netif_set_dns_info(
+            raw_handle,
+            esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN,
+            &mut dns
+        ))?;
+    }
+    Ok(())
+}